@@ -0,0 +1,136 @@
+//! SSH agent keys
+//!
+//! Machines with `auto_add_key: true` get their identities added to the SSH
+//! agent with `ssh-add` before connecting, when the agent does not hold them
+//! yet. The passphrase of a key is then asked once, by `ssh-add`, instead of
+//! by every `ssh` command. Identities are only added for interactive
+//! commands, which have a terminal to ask it on.
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::sync::{Arc, Mutex};
+
+use config::{expand_home, MachineConfig};
+
+/// Agent socket and identity
+type AgentKey = (Option<PathBuf>, String);
+
+/// Agent sockets and identities seen by this process, each flagged once
+/// handled, added or not
+static HANDLED: Mutex<Option<HashMap<AgentKey, Arc<Mutex<bool>>>>> = Mutex::new(None);
+
+/// Get the flag of an identity, only holding the global lock while looking
+/// it up
+fn handled_flag(key: AgentKey) -> Arc<Mutex<bool>> {
+    let mut handled = HANDLED.lock().unwrap_or_else(|x| x.into_inner());
+    handled.get_or_insert_with(HashMap::new).entry(key).or_default().clone()
+}
+
+/// Parse key fingerprints, from `ssh-add -l` or `ssh-keygen -l` lines like
+/// `256 SHA256:... comment (ED25519)`.
+///
+/// # Arguments
+///
+/// * `output` - Command output
+///
+pub fn parse_fingerprints(output: &str) -> Vec<String> {
+    output.lines()
+        .filter_map(|x| x.split_whitespace().nth(1))
+        .filter(|x| x.contains(':'))
+        .map(String::from)
+        .collect()
+}
+
+/// Get the fingerprints of the agent keys, none when no agent is running
+fn agent_fingerprints(config: &MachineConfig) -> Option<Vec<String>> {
+    let output = agent_command(config).arg("-l").stdin(Stdio::null()).output().ok()?;
+
+    // 1 means the agent holds no keys, 2 that it cannot be reached
+    match output.status.code() {
+        Some(0) | Some(1) => Some(parse_fingerprints(&String::from_utf8_lossy(&output.stdout))),
+        _ => None
+    }
+}
+
+/// Build a `ssh-add` command talking to the agent of a machine
+fn agent_command(config: &MachineConfig) -> Command {
+    let mut command = Command::new("ssh-add");
+    if let Some(path) = config.agent_socket_path() {
+        command.env("SSH_AUTH_SOCK", path);
+    }
+
+    command
+}
+
+/// Get the fingerprint of a key file
+fn key_fingerprint(path: &str) -> Option<String> {
+    let output = Command::new("ssh-keygen").arg("-l").arg("-f").arg(path).stdin(Stdio::null()).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+
+    parse_fingerprints(&String::from_utf8_lossy(&output.stdout)).into_iter().next()
+}
+
+/// Add the identities of a machine missing from the SSH agent, if its
+/// `auto_add_key` value is set.
+///
+/// Each identity is handled once per process: concurrent connections using
+/// it wait for the first one to add it, so that the passphrase is asked
+/// once, while connections using other identities go on.
+///
+/// # Arguments
+///
+/// * `config` - Machine configuration
+///
+pub fn add_identities(config: &MachineConfig) {
+    if !config.adds_keys() || config.identities().is_empty() {
+        return;
+    }
+
+    let socket = config.agent_socket_path();
+    let mut loaded = None;
+    for identity in config.identities() {
+        let flag = handled_flag((socket.clone(), identity.clone()));
+        let mut handled = flag.lock().unwrap_or_else(|x| x.into_inner());
+        if *handled {
+            continue;
+        }
+        *handled = true;
+        let path = expand_home(identity).to_string_lossy().into_owned();
+
+        let loaded = match *loaded.get_or_insert_with(|| agent_fingerprints(config)) {
+            Some(ref x) => x,
+            None => {
+                warn!("No SSH agent is running, `{}` is not added.", identity);
+                continue;
+            }
+        };
+        match key_fingerprint(&path) {
+            Some(ref fingerprint) if loaded.contains(fingerprint) => continue,
+            Some(_) => (),
+            // Missing keys are left for `ssh` to report
+            None => continue
+        }
+
+        // `ssh-add` asks the passphrase on the terminal
+        match agent_command(config).arg(&path).status() {
+            Ok(status) if status.success() => (),
+            Ok(_) => warn!("Could not add `{}` to the SSH agent.", identity),
+            Err(error) => warn!("Could not run ssh-add: {}", error)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn fingerprints() {
+        let output = "256 SHA256:AbCd/ef+1 me@laptop (ED25519)\n3072 SHA256:xYz me@work (RSA)\n";
+        assert_eq!(parse_fingerprints(output), vec!["SHA256:AbCd/ef+1", "SHA256:xYz"]);
+        assert!(parse_fingerprints("The agent has no identities.\n").is_empty());
+    }
+}
@@ -0,0 +1,429 @@
+//! REST API
+//!
+//! `pssh serve` answers HTTP requests with JSON, for dashboards and bots:
+//!
+//! * `GET /machines` - resolved machines, filtered by an optional `selector`
+//!   query parameter
+//! * `GET /machines/NAME` - one resolved machine
+//! * `GET /status` - reachability of the machines
+//! * `POST /exec` - run a command on machines, with a JSON body holding
+//!   `selector`, `command`, and optionally `timeout` and `force`. As on the
+//!   command line, primary machines are left out unless named, and so are
+//!   machines in maintenance
+//!
+//! The configuration is loaded on each request. Commands can only be run when
+//! enabled, and with a bearer token.
+
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::thread;
+use std::time::Duration;
+
+use serde_json::{self, Value};
+
+use audit;
+use batch::{parse_duration, run_parallel, DEFAULT_PARALLELISM};
+use config::{load_configuration_file, ConfigMap};
+use error::Error;
+use expand::expand_placeholders;
+use exec::{exec_command_with, ExecOptions};
+use plugin::machines_to_json;
+use probe::probe_machines;
+use selection::{exclude_primaries, select_machines};
+use state::StateStore;
+
+/// Default listening address
+pub const DEFAULT_LISTEN: &str = "127.0.0.1:7070";
+
+/// Largest accepted request body, in bytes
+const MAX_BODY: usize = 64 * 1024;
+
+/// Timeout of request reads
+pub const READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// API server options
+#[derive(Debug, Clone, Default)]
+pub struct ServerOptions {
+    /// Configuration file path
+    pub config_file: Option<String>,
+    /// Accept `POST /exec`
+    pub allow_exec: bool,
+    /// Bearer token required by all requests
+    pub token: Option<String>
+}
+
+/// HTTP request
+#[derive(Debug, Clone, PartialEq)]
+pub struct Request {
+    /// Method
+    pub method: String,
+    /// Path, without the query string
+    pub path: String,
+    /// Query parameters
+    pub query: HashMap<String, String>,
+    /// Headers, with lowercase names
+    pub headers: HashMap<String, String>,
+    /// Body
+    pub body: Vec<u8>
+}
+
+/// Read an HTTP request.
+///
+/// # Arguments
+///
+/// * `reader` - Connection reader
+///
+pub fn read_request<R: BufRead>(reader: &mut R) -> Result<Request, Error> {
+    let bad_request = |detail: &str| Error::Transport(format!("bad request: {}", detail));
+    let read_line = |reader: &mut R| -> Result<String, Error> {
+        let mut line = String::new();
+        reader.read_line(&mut line).map_err(|e| Error::io("could not read the request", e))?;
+        Ok(line.trim_end().to_string())
+    };
+
+    let line = read_line(reader)?;
+    let mut parts = line.split(' ');
+    let (method, target) = match (parts.next(), parts.next()) {
+        (Some(method), Some(target)) if !method.is_empty() => (method.to_string(), target),
+        _ => return Err(bad_request(&line))
+    };
+    let (path, query) = match target.find('?') {
+        Some(index) => (&target[..index], parse_query(&target[index + 1..])),
+        None => (target, HashMap::new())
+    };
+
+    let mut headers = HashMap::new();
+    loop {
+        let line = read_line(reader)?;
+        if line.is_empty() {
+            break;
+        }
+        let (name, value) = line.split_once(':').ok_or_else(|| bad_request(&line))?;
+        headers.insert(name.trim().to_lowercase(), value.trim().to_string());
+    }
+
+    let length: usize = match headers.get("content-length") {
+        Some(x) => x.parse().map_err(|_| bad_request("bad content length"))?,
+        None => 0
+    };
+    if length > MAX_BODY {
+        return Err(bad_request("body too large"));
+    }
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body).map_err(|e| Error::io("could not read the request body", e))?;
+
+    Ok(Request { method, path: decode(path), query, headers, body })
+}
+
+/// Parse a query string
+fn parse_query(query: &str) -> HashMap<String, String> {
+    query.split('&')
+        .filter(|x| !x.is_empty())
+        .map(|x| match x.split_once('=') {
+            Some((key, value)) => (decode(key), decode(value)),
+            None => (decode(x), String::new())
+        })
+        .collect()
+}
+
+/// Decode a percent-encoded URL component, where `+` is a space
+fn decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = vec![];
+    let mut index = 0;
+    while index < bytes.len() {
+        match bytes[index] {
+            b'%' if index + 2 < bytes.len() => {
+                let hex = String::from_utf8_lossy(&bytes[index + 1..index + 3]).into_owned();
+                match u8::from_str_radix(&hex, 16) {
+                    Ok(byte) => {
+                        decoded.push(byte);
+                        index += 3;
+                        continue;
+                    },
+                    Err(_) => decoded.push(b'%')
+                }
+            },
+            b'+' => decoded.push(b' '),
+            byte => decoded.push(byte)
+        }
+        index += 1;
+    }
+
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// Answer a request, returning the HTTP status and the JSON body.
+///
+/// # Arguments
+///
+/// * `request` - Request
+/// * `options` - Server options
+///
+pub fn route(request: &Request, options: &ServerOptions) -> (u16, Value) {
+    if let Some(ref token) = options.token {
+        let authorized = request.headers.get("authorization")
+            .and_then(|x| x.strip_prefix("Bearer "))
+            .map(|x| x == token)
+            .unwrap_or(false);
+        if !authorized {
+            return (401, json!({"error": "missing or bad token"}));
+        }
+    }
+
+    let segments: Vec<&str> = request.path.trim_matches('/').split('/').collect();
+    match (request.method.as_str(), segments.as_slice()) {
+        ("GET", ["machines"]) => {
+            let machines = match load_machines(options) {
+                Ok(x) => x,
+                Err(response) => return response
+            };
+            let selected = match select(&machines, request.query.get("selector").map(|x| x.as_str())) {
+                Ok(x) => x,
+                Err(response) => return response
+            };
+            (200, json!({"machines": machines_to_json(&selected)}))
+        },
+        ("GET", ["machines", name]) => {
+            let machines = match load_machines(options) {
+                Ok(x) => x,
+                Err(response) => return response
+            };
+            match machines.get(*name) {
+                Some(config) => {
+                    let single: ConfigMap = vec![(name.to_string(), config.clone())].into_iter().collect();
+                    (200, machines_to_json(&single)[*name].clone())
+                },
+                None => (404, json!({"error": format!("unknown machine `{}`", name)}))
+            }
+        },
+        ("GET", ["status"]) => {
+            let machines = match load_machines(options) {
+                Ok(x) => x,
+                Err(response) => return response
+            };
+            let selected = match select(&machines, request.query.get("selector").map(|x| x.as_str())) {
+                Ok(x) => x,
+                Err(response) => return response
+            };
+            let mut names: Vec<String> = selected.into_iter().filter(|x| x.1.ip.is_some()).map(|x| x.0).collect();
+            names.sort();
+            let status: serde_json::Map<String, Value> = probe_machines(&machines, &names).into_iter()
+                .map(|(name, reachability)| (name, json!(reachability.name())))
+                .collect();
+            (200, json!({"status": status}))
+        },
+        ("POST", ["exec"]) if options.allow_exec && options.token.is_some() => exec(&request.body, options),
+        ("POST", ["exec"]) => (403, json!({"error": "command execution is disabled"})),
+        (_, ["machines"]) | (_, ["machines", _]) | (_, ["status"]) | (_, ["exec"]) =>
+            (405, json!({"error": format!("method {} not allowed", request.method)})),
+        _ => (404, json!({"error": format!("unknown path {}", request.path)}))
+    }
+}
+
+/// Load the machines, or the response to send when they cannot be loaded
+fn load_machines(options: &ServerOptions) -> Result<ConfigMap, (u16, Value)> {
+    load_configuration_file(options.config_file.as_deref())
+        .map(|x| x.machine_values)
+        .map_err(|e| (500, json!({"error": format!("could not load the configuration: {}", e)})))
+}
+
+/// Select machines, or get the response to send for a bad selector
+fn select(machines: &ConfigMap, selector: Option<&str>) -> Result<ConfigMap, (u16, Value)> {
+    match selector {
+        Some(selector) => select_machines(selector, machines)
+            .map(|names| names.into_iter().map(|name| (name.clone(), machines[&name].clone())).collect())
+            .map_err(|e| (400, json!({"error": e.to_string()}))),
+        None => Ok(machines.clone())
+    }
+}
+
+/// Run a command from a `POST /exec` body
+fn exec(body: &[u8], options: &ServerOptions) -> (u16, Value) {
+    let body: Value = match serde_json::from_slice(body) {
+        Ok(x) => x,
+        Err(error) => return (400, json!({"error": format!("bad body: {}", error)}))
+    };
+    let (selector, command) = match (body["selector"].as_str(), body["command"].as_str()) {
+        (Some(selector), Some(command)) => (selector, command),
+        _ => return (400, json!({"error": "`selector` and `command` are required"}))
+    };
+    let timeout = match body["timeout"].as_str().map(parse_duration) {
+        None => None,
+        Some(Some(x)) => Some(x),
+        Some(None) => return (400, json!({"error": format!("bad timeout: `{}`", body["timeout"])}))
+    };
+
+    let machines = match load_machines(options) {
+        Ok(x) => x,
+        Err(response) => return response
+    };
+    let names: Vec<String> = match select_machines(selector, &machines) {
+        Ok(x) => x.into_iter().filter(|x| machines[x].ip.is_some()).collect(),
+        Err(error) => return (400, json!({"error": error.to_string()}))
+    };
+    if names.is_empty() {
+        return (404, json!({"error": format!("no machine matches `{}`", selector)}));
+    }
+    let (names, primaries) = exclude_primaries(selector, names, &machines);
+    let state = StateStore::load_default();
+    let (maintenance, names): (Vec<String>, Vec<String>) = names.into_iter().partition(|x| state.get(x).in_maintenance());
+    let protected: Vec<&String> = names.iter().filter(|x| machines[*x].is_protected()).collect();
+    if !protected.is_empty() && body["force"].as_bool() != Some(true) {
+        return (403, json!({"error": "protected machines, set `force` to proceed", "machines": protected}));
+    }
+
+    let exec_options = ExecOptions { timeout, ..Default::default() };
+    let results: serde_json::Map<String, Value> = run_parallel(&names, DEFAULT_PARALLELISM, |name| {
+        expand_placeholders(command, name, &machines[name]).and_then(|command| {
+            audit::record(name, "exec", &command);
+            exec_command_with(&machines[name], &command, &exec_options)
+        })
+    }).into_iter().map(|(name, result)| {
+        let value = match result {
+            Ok(output) => json!({
+                "status": output.status,
+                "stdout": output.stdout,
+                "stderr": output.stderr,
+                "timed_out": output.timed_out
+            }),
+            Err(error) => json!({"error": error.to_string()})
+        };
+        (name, value)
+    }).collect();
+
+    (200, json!({"results": results, "skipped": {"primary": primaries, "maintenance": maintenance}}))
+}
+
+/// Get the reason phrase of an HTTP status
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        _ => "Internal Server Error"
+    }
+}
+
+/// Answer one connection
+fn handle(stream: TcpStream, options: &ServerOptions) -> Result<(), Error> {
+    stream.set_read_timeout(Some(READ_TIMEOUT)).ok();
+    let mut reader = BufReader::new(&stream);
+    let (status, body) = match read_request(&mut reader) {
+        Ok(request) => {
+            let (status, body) = route(&request, options);
+            info!("{} {} {}", request.method, request.path, status);
+            (status, body)
+        },
+        Err(error) => (400, json!({"error": error.to_string()}))
+    };
+
+    write_response(&stream, status, "application/json", &format!("{}\n", body))
+}
+
+/// Write an HTTP response, closing the connection.
+///
+/// # Arguments
+///
+/// * `stream` - Connection
+/// * `status` - HTTP status
+/// * `content_type` - Body type
+/// * `body` - Body
+///
+pub fn write_response(mut stream: &TcpStream, status: u16, content_type: &str, body: &str) -> Result<(), Error> {
+    write!(stream, "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+           status, reason(status), content_type, body.len(), body)
+        .map_err(|e| Error::io("could not send the response", e))
+}
+
+/// Serve the API until the process is stopped.
+///
+/// # Arguments
+///
+/// * `address` - Listening address
+/// * `options` - Server options
+///
+pub fn serve(address: &str, options: ServerOptions) -> Result<(), Error> {
+    let listener = TcpListener::bind(address).map_err(|e| Error::io(format!("could not listen on {}", address), e))?;
+    info!("Listening on http://{}", address);
+
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(x) => x,
+            Err(error) => {
+                warn!("Could not accept a connection: {}", error);
+                continue;
+            }
+        };
+
+        // Commands can run for long: each connection has its own thread
+        let options = options.clone();
+        thread::spawn(move || {
+            if let Err(error) = handle(stream, &options) {
+                warn!("{}", error);
+            }
+        });
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::env;
+    use std::fs;
+    use std::io::Cursor;
+
+    fn request(method: &str, path: &str) -> Request {
+        let raw = format!("{} {} HTTP/1.1\r\nHost: localhost\r\n\r\n", method, path);
+        read_request(&mut Cursor::new(raw.into_bytes())).unwrap()
+    }
+
+    #[test]
+    fn requests() {
+        let raw = "POST /exec?selector=web%3A*&x HTTP/1.1\r\nContent-Length: 4\r\nAuthorization: Bearer abc\r\n\r\n{}\n\nignored";
+        let request = read_request(&mut Cursor::new(raw.as_bytes())).unwrap();
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.path, "/exec");
+        assert_eq!(request.query["selector"], "web:*");
+        assert_eq!(request.query["x"], "");
+        assert_eq!(request.headers["authorization"], "Bearer abc");
+        assert_eq!(request.body, b"{}\n\n");
+
+        assert!(read_request(&mut Cursor::new(&b"\r\n"[..])).is_err());
+        assert!(read_request(&mut Cursor::new(&b"GET / HTTP/1.1\r\nContent-Length: 999999\r\n\r\n"[..])).is_err());
+        assert_eq!(decode("a+b%20c%2"), "a b c%2");
+    }
+
+    #[test]
+    fn routes() {
+        let options = ServerOptions::default();
+        assert_eq!(route(&request("GET", "/nowhere"), &options).0, 404);
+        assert_eq!(route(&request("DELETE", "/machines"), &options).0, 405);
+        assert_eq!(route(&request("POST", "/exec"), &options).0, 403);
+
+        let options = ServerOptions { allow_exec: true, token: Some("abc".to_string()), ..Default::default() };
+        assert_eq!(route(&request("GET", "/machines"), &options).0, 401);
+        let mut authorized = request("POST", "/exec");
+        authorized.headers.insert("authorization".to_string(), "Bearer abc".to_string());
+        authorized.body = b"{\"command\": \"uptime\"}".to_vec();
+        assert_eq!(route(&authorized, &options).0, 400);
+
+        // Primary machines are left out unless named
+        let path = env::temp_dir().join(format!("pssh-api-{}.yml", std::process::id()));
+        fs::write(&path, "defaults:\nmachines:\n    db1:\n        $:\n            ip: 10.0.0.1\n            role: primary\n").unwrap();
+        let options = ServerOptions { config_file: Some(path.to_string_lossy().into_owned()), ..options };
+        authorized.body = b"{\"selector\": \"db*\", \"command\": \"uptime\"}".to_vec();
+        let (status, body) = route(&authorized, &options);
+        assert_eq!(status, 200);
+        assert_eq!(body["results"], json!({}));
+        assert_eq!(body["skipped"]["primary"], json!(["db1"]));
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,190 @@
+//! Atomic file pushes
+//!
+//! An atomic push uploads the file under a temporary name next to its
+//! destination, then renames it into place with `mv`, so that readers of the
+//! destination see either the old file or the new one, never a partial one.
+//!
+//! With `sudo`, the file is uploaded to a temporary file created by `mktemp`
+//! on the machine, which only the user can write, then copied next to the
+//! destination and renamed by root.
+//!
+//! The new file keeps the mode of the one it replaces, and with `sudo` its
+//! owner too.
+
+use std::path::Path;
+use std::process::ExitStatus;
+
+use config::MachineConfig;
+use error::Error;
+use quote::{quote, quote_path};
+use wrapper::{copy, execute, execute_capture, ssh_remote, CopyOptions, ScpDirection};
+
+/// Suffix of the backups of replaced files
+pub const BACKUP_SUFFIX: &str = ".bak";
+
+/// Options of an atomic push
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct AtomicOptions {
+    /// Install the file with `sudo`
+    pub sudo: bool,
+    /// Keep the replaced file, with `BACKUP_SUFFIX`
+    pub backup: bool
+}
+
+/// Get the remote path a file is uploaded to before being renamed.
+///
+/// # Arguments
+///
+/// * `destination` - Remote destination, a file or a directory ending with `/`
+/// * `name` - File name
+/// * `token` - Unique token, so that concurrent pushes do not collide
+///
+pub fn staging_path(destination: &str, name: &str, token: &str) -> String {
+    let staged = format!(".{}.pssh-{}", name, token);
+    let dir = if destination.ends_with('/') {
+        destination.trim_end_matches('/')
+    } else {
+        destination.rsplit_once('/').map(|x| x.0).unwrap_or("")
+    };
+
+    match dir {
+        "" if destination.starts_with('/') => format!("/{}", staged),
+        "" => staged,
+        _ => format!("{}/{}", dir, staged)
+    }
+}
+
+/// Build the remote command moving a staged file into place.
+///
+/// The staged file is removed when anything fails. The mode of a replaced
+/// file is kept, and its owner with `sudo`, using the `stat` of GNU or BSD
+/// systems.
+///
+/// # Arguments
+///
+/// * `staged` - Staged file
+/// * `destination` - Remote destination, a file or a directory
+/// * `name` - File name, used when the destination is a directory
+/// * `options` - Atomic push options
+///
+pub fn install_command(staged: &str, destination: &str, name: &str, options: AtomicOptions) -> String {
+    let sudo = if options.sudo { "sudo -n " } else { "" };
+    let mut command = format!(
+        "set -e; t={}; d={}; trap 'rm -f \"$t\"' EXIT; if [ -d \"$d\" ]; then d=\"${{d%/}}\"/{}; fi; ",
+        quote_path(staged), quote_path(destination), quote(name)
+    );
+
+    if options.backup {
+        command.push_str(&format!("if [ -e \"$d\" ]; then {}cp -p \"$d\" \"$d{}\"; fi; ", sudo, BACKUP_SUFFIX));
+    }
+    command.push_str("m=; o=; if [ -e \"$d\" ]; then \
+        m=$(stat -c %a \"$d\" 2>/dev/null || stat -f %Lp \"$d\"); \
+        o=$(stat -c %u:%g \"$d\" 2>/dev/null || stat -f %u:%g \"$d\"); fi; ");
+
+    if options.sudo {
+        // The copy lands next to the destination, where renaming is atomic
+        command.push_str(&format!(
+            "trap 'rm -f \"$t\"; {0}rm -f \"$d.pssh-new\"' EXIT; {0}cp \"$t\" \"$d.pssh-new\"; \
+            if [ -n \"$m\" ]; then {0}chown \"$o\" \"$d.pssh-new\"; {0}chmod \"$m\" \"$d.pssh-new\"; fi; \
+            {0}mv -f \"$d.pssh-new\" \"$d\"",
+            sudo
+        ));
+    } else {
+        command.push_str("if [ -n \"$m\" ]; then chmod \"$m\" \"$t\"; fi; mv -f \"$t\" \"$d\"");
+    }
+
+    command
+}
+
+/// Push a file atomically.
+///
+/// Returns the status of the upload, or an error when the file could not be
+/// moved into place.
+///
+/// # Arguments
+///
+/// * `config` - Machine configuration
+/// * `source` - Local file
+/// * `destination` - Remote destination
+/// * `options` - Atomic push options
+/// * `copy_options` - Copy options of the upload
+///
+pub fn push_atomic(config: &MachineConfig, source: &str, destination: &str, options: AtomicOptions, copy_options: &CopyOptions) -> Result<ExitStatus, Error> {
+    config.check_values()?;
+    let name = Path::new(source).file_name()
+        .map(|x| x.to_string_lossy().into_owned())
+        .ok_or_else(|| Error::Config(format!("`{}` is not a file", source)))?;
+
+    let staged = if options.sudo {
+        // Unlike a fixed name, other users cannot create it first
+        let output = execute_capture(ssh_remote(config, "mktemp"))?;
+        if !output.status.success() {
+            return Err(Error::tool("could not create a temporary file", &String::from_utf8_lossy(&output.stderr)));
+        }
+        String::from_utf8_lossy(&output.stdout).trim().to_string()
+    } else {
+        staging_path(destination, &name, &std::process::id().to_string())
+    };
+    let status = execute(copy(config, &[source], &staged, ScpDirection::Push, copy_options))?;
+    if !status.success() {
+        // An interrupted upload may leave a partial file behind
+        execute_capture(ssh_remote(config, &format!("rm -f {}", quote_path(&staged)))).ok();
+        return Ok(status);
+    }
+
+    let output = execute_capture(ssh_remote(config, &install_command(&staged, destination, &name, options)))?;
+    if output.status.success() {
+        Ok(output.status)
+    } else {
+        Err(Error::tool("could not move the file into place", &String::from_utf8_lossy(&output.stderr)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::env;
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+    use std::process::Command;
+
+    #[test]
+    fn staging() {
+        assert_eq!(staging_path("/etc/app.conf", "app.conf", "42"), "/etc/.app.conf.pssh-42");
+        assert_eq!(staging_path("/etc/app/", "app.conf", "42"), "/etc/app/.app.conf.pssh-42");
+        assert_eq!(staging_path("/app.conf", "app.conf", "42"), "/.app.conf.pssh-42");
+        assert_eq!(staging_path("app.conf", "app.conf", "42"), ".app.conf.pssh-42");
+        assert_eq!(staging_path("~/my app/", "a b", "42"), "~/my app/.a b.pssh-42");
+    }
+
+    #[test]
+    fn install() {
+        let dir = env::temp_dir().join(format!("pssh-atomic-{}", std::process::id()));
+        fs::create_dir_all(dir.join("conf")).unwrap();
+        let run = |staged: &str, destination: &Path, options: AtomicOptions| {
+            fs::write(dir.join(staged), "new").unwrap();
+            let command = install_command(&dir.join(staged).to_string_lossy(), &destination.to_string_lossy(), "app.conf", options);
+            Command::new("sh").args(["-c", &command]).status().unwrap().success()
+        };
+
+        fs::write(dir.join("app.conf"), "old").unwrap();
+        assert!(run(".staged", &dir.join("app.conf"), AtomicOptions { sudo: false, backup: true }));
+        assert_eq!(fs::read_to_string(dir.join("app.conf")).unwrap(), "new");
+        assert_eq!(fs::read_to_string(dir.join("app.conf.bak")).unwrap(), "old");
+        assert!(!dir.join(".staged").exists());
+
+        assert!(run(".staged", &dir.join("conf"), AtomicOptions::default()));
+        assert_eq!(fs::read_to_string(dir.join("conf/app.conf")).unwrap(), "new");
+
+        // The staged file gets the mode of the replaced one
+        fs::set_permissions(dir.join("conf/app.conf"), fs::Permissions::from_mode(0o640)).unwrap();
+        assert!(run(".staged", &dir.join("conf/app.conf"), AtomicOptions::default()));
+        assert_eq!(fs::metadata(dir.join("conf/app.conf")).unwrap().permissions().mode() & 0o777, 0o640);
+
+        assert!(!run(".staged", &dir.join("missing/app.conf"), AtomicOptions::default()));
+        assert!(!dir.join(".staged").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
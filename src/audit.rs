@@ -0,0 +1,248 @@
+//! Audit log
+//!
+//! Operations touching machines are appended to `~/.pssh/audit.log`, one JSON
+//! object per line, separately from debug logging. Entries are never rewritten.
+
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, Duration, NaiveDate, Utc};
+use fs2::FileExt;
+use serde_json::{self, Value};
+
+use config::get_user_configuration_dir;
+use selection::glob_match;
+
+/// Audit log entry
+#[derive(Debug, Clone, PartialEq)]
+pub struct AuditEntry {
+    /// Operation time
+    pub time: DateTime<Utc>,
+    /// Local user running pssh
+    pub user: String,
+    /// Target machine
+    pub machine: String,
+    /// Operation name (`connect`, `exec`...)
+    pub operation: String,
+    /// Exact command or operation detail
+    pub command: String
+}
+
+impl AuditEntry {
+    /// Create an entry for the current user and time.
+    ///
+    /// # Arguments
+    ///
+    /// * `machine` - Target machine
+    /// * `operation` - Operation name
+    /// * `command` - Command or operation detail
+    ///
+    pub fn new(machine: &str, operation: &str, command: &str) -> AuditEntry {
+        AuditEntry {
+            time: Utc::now(),
+            user: env::var("USER").or_else(|_| env::var("LOGNAME")).unwrap_or_else(|_| "?".to_string()),
+            machine: machine.to_string(),
+            operation: operation.to_string(),
+            command: command.to_string()
+        }
+    }
+
+    /// Serialize the entry as a JSON line
+    pub fn to_json_line(&self) -> String {
+        json!({
+            "time": self.time.to_rfc3339(),
+            "user": self.user,
+            "machine": self.machine,
+            "operation": self.operation,
+            "command": self.command
+        }).to_string()
+    }
+
+    /// Parse an entry from a JSON line.
+    ///
+    /// # Arguments
+    ///
+    /// * `line` - JSON line
+    ///
+    pub fn from_json_line(line: &str) -> Option<AuditEntry> {
+        let value: Value = serde_json::from_str(line).ok()?;
+        let field = |key: &str| value[key].as_str().map(String::from);
+
+        Some(AuditEntry {
+            time: DateTime::parse_from_rfc3339(value["time"].as_str()?).ok()?.with_timezone(&Utc),
+            user: field("user")?,
+            machine: field("machine")?,
+            operation: field("operation")?,
+            command: field("command").unwrap_or_default()
+        })
+    }
+}
+
+/// Get the user audit log path (`~/.pssh/audit.log`)
+pub fn audit_log_path() -> PathBuf {
+    get_user_configuration_dir().join("audit.log")
+}
+
+/// Append an entry to the user audit log.
+///
+/// Failures are logged as warnings, they never stop the operation.
+///
+/// # Arguments
+///
+/// * `machine` - Target machine
+/// * `operation` - Operation name
+/// * `command` - Command or operation detail
+///
+pub fn record(machine: &str, operation: &str, command: &str) {
+    append_entry(&audit_log_path(), &AuditEntry::new(machine, operation, command));
+}
+
+/// Append an entry to an audit log.
+///
+/// The log is locked while the entry is written, so that entries of
+/// parallel operations never interleave.
+///
+/// # Arguments
+///
+/// * `path` - Audit log path
+/// * `entry` - Entry to append
+///
+pub fn append_entry(path: &Path, entry: &AuditEntry) {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).ok();
+    }
+
+    let line = format!("{}\n", entry.to_json_line());
+    let result = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .and_then(|mut f| {
+            f.lock_exclusive()?;
+            let result = f.write_all(line.as_bytes());
+            FileExt::unlock(&f).ok();
+            result
+        });
+
+    if let Err(error) = result {
+        warn!("Could not write audit log {}: {}", path.display(), error);
+    }
+}
+
+/// Read audit entries, skipping malformed lines.
+///
+/// # Arguments
+///
+/// * `path` - Audit log path
+///
+pub fn read_entries(path: &Path) -> Vec<AuditEntry> {
+    match File::open(path) {
+        Ok(f) => BufReader::new(f).lines()
+            .map_while(Result::ok)
+            .filter_map(|x| AuditEntry::from_json_line(&x))
+            .collect(),
+        Err(_) => vec![]
+    }
+}
+
+/// Filter audit entries.
+///
+/// # Arguments
+///
+/// * `entries` - Audit entries
+/// * `machine` - Machine name or glob pattern
+/// * `since` - Oldest entry time
+///
+pub fn filter_entries(entries: Vec<AuditEntry>, machine: Option<&str>, since: Option<DateTime<Utc>>) -> Vec<AuditEntry> {
+    entries.into_iter()
+        .filter(|x| machine.is_none_or(|pattern| glob_match(pattern, &x.machine)))
+        .filter(|x| since.is_none_or(|time| x.time >= time))
+        .collect()
+}
+
+/// Parse a `--since` value.
+///
+/// Accepts a relative age (`30m`, `12h`, `7d`, `2w`), a date (`2024-05-01`)
+/// or a RFC 3339 time.
+///
+/// # Arguments
+///
+/// * `value` - Value to parse
+/// * `now` - Current time
+///
+pub fn parse_since(value: &str, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+    if let Ok(time) = DateTime::parse_from_rfc3339(value) {
+        return Some(time.with_timezone(&Utc));
+    }
+    if let Ok(date) = NaiveDate::parse_from_str(value, "%Y-%m-%d") {
+        return Some(date.and_hms_opt(0, 0, 0)?.and_utc());
+    }
+
+    let (amount, unit) = value.split_at(value.len().checked_sub(1)?);
+    let amount: i64 = amount.parse().ok()?;
+    let age = match unit {
+        "s" => Duration::seconds(amount),
+        "m" => Duration::minutes(amount),
+        "h" => Duration::hours(amount),
+        "d" => Duration::days(amount),
+        "w" => Duration::weeks(amount),
+        _ => return None
+    };
+
+    Some(now - age)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::thread;
+
+    #[test]
+    fn json_lines() {
+        let entry = AuditEntry::new("prod:web1", "exec", "rm -rf /tmp/cache");
+        assert_eq!(AuditEntry::from_json_line(&entry.to_json_line()), Some(entry));
+        assert_eq!(AuditEntry::from_json_line("pouet"), None);
+    }
+
+    #[test]
+    fn filters() {
+        let now = Utc::now();
+        let mut old = AuditEntry::new("prod:web1", "connect", "");
+        old.time = now - Duration::days(3);
+        let recent = AuditEntry::new("staging:web1", "exec", "uptime");
+
+        let entries = vec![old, recent];
+        assert_eq!(filter_entries(entries.clone(), Some("prod:*"), None).len(), 1);
+        assert_eq!(filter_entries(entries.clone(), None, parse_since("1d", now)).len(), 1);
+        assert_eq!(filter_entries(entries, None, None).len(), 2);
+    }
+
+    #[test]
+    fn since() {
+        let now = Utc::now();
+        assert_eq!(parse_since("2h", now), Some(now - Duration::hours(2)));
+        assert_eq!(parse_since("2024-05-01", now).unwrap().to_rfc3339(), "2024-05-01T00:00:00+00:00");
+        assert!(parse_since("2024-05-01T10:00:00+02:00", now).is_some());
+        assert!(parse_since("pouet", now).is_none());
+        assert!(parse_since("", now).is_none());
+    }
+
+    #[test]
+    fn parallel_appends() {
+        let path = env::temp_dir().join(format!("pssh-audit-{}.log", std::process::id()));
+        let command = "x".repeat(64 * 1024);
+        let threads: Vec<_> = (0..8).map(|i| {
+            let (path, command) = (path.clone(), command.clone());
+            thread::spawn(move || append_entry(&path, &AuditEntry::new(&format!("web{}", i), "exec", &command)))
+        }).collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        assert_eq!(read_entries(&path).len(), 8);
+        fs::remove_file(&path).ok();
+    }
+}
@@ -0,0 +1,255 @@
+//! Ad-hoc backups
+//!
+//! `pssh backup` streams a compressed tar of a remote path, made by the remote
+//! `tar`, to `<dir>/<machine>_<name>_<YYYYMMDD-HHMMSS>.tar.gz`. Archives hold
+//! the path under its own name, so that `pssh restore` extracts them back
+//! into the parent directory. Older archives of the same path can be rotated
+//! away.
+
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use chrono::{DateTime, Local, NaiveDateTime};
+
+use config::MachineConfig;
+use error::Error;
+use export::host_alias;
+use quote::{quote, quote_path};
+use wrapper::ssh_remote;
+
+/// Timestamp format of archive names
+const TIMESTAMP_FORMAT: &str = "%Y%m%d-%H%M%S";
+
+/// Archive extension
+const EXTENSION: &str = ".tar.gz";
+
+/// Split a remote path into its parent directory and name.
+///
+/// # Arguments
+///
+/// * `remote_path` - Remote file or directory path
+///
+pub fn split_remote_path(remote_path: &str) -> (String, String) {
+    let trimmed = remote_path.trim_end_matches('/');
+    if trimmed.is_empty() {
+        return ("/".to_string(), ".".to_string());
+    }
+
+    match trimmed.rsplit_once('/') {
+        Some(("", name)) => ("/".to_string(), name.to_string()),
+        Some((parent, name)) => (parent.to_string(), name.to_string()),
+        None => (".".to_string(), trimmed.to_string())
+    }
+}
+
+/// Build the remote command writing the archive of a path to its output.
+///
+/// # Arguments
+///
+/// * `remote_path` - Remote file or directory path
+///
+pub fn backup_command(remote_path: &str) -> String {
+    let (parent, name) = split_remote_path(remote_path);
+    format!("tar -czf - -C {} {}", quote_path(&parent), quote(&name))
+}
+
+/// Build the remote command extracting an archive from its input.
+///
+/// # Arguments
+///
+/// * `remote_dir` - Remote directory to extract into
+///
+pub fn restore_command(remote_dir: &str) -> String {
+    format!("tar -xzf - -C {}", quote_path(remote_dir))
+}
+
+/// Get the archive name prefix of a remote path, before the timestamp
+fn archive_prefix(machine: &str, remote_path: &str) -> String {
+    let (_, name) = split_remote_path(remote_path);
+    let name = if name == "." { "root" } else { name.as_str() };
+    let name: String = name.chars().map(|x| if x.is_ascii_alphanumeric() || "-._".contains(x) { x } else { '-' }).collect();
+
+    format!("{}_{}_", host_alias(machine), name)
+}
+
+/// Get the archive path of a backup.
+///
+/// # Arguments
+///
+/// * `dir` - Local directory
+/// * `machine` - Machine name
+/// * `remote_path` - Remote file or directory path
+/// * `time` - Backup time
+///
+pub fn archive_path(dir: &Path, machine: &str, remote_path: &str, time: DateTime<Local>) -> PathBuf {
+    dir.join(format!("{}{}{}", archive_prefix(machine, remote_path), time.format(TIMESTAMP_FORMAT), EXTENSION))
+}
+
+/// List the archives of a remote path in a directory, oldest first.
+///
+/// # Arguments
+///
+/// * `dir` - Local directory
+/// * `machine` - Machine name
+/// * `remote_path` - Remote file or directory path
+///
+pub fn list_archives(dir: &Path, machine: &str, remote_path: &str) -> Vec<PathBuf> {
+    let prefix = archive_prefix(machine, remote_path);
+    let entries = match fs::read_dir(dir) {
+        Ok(x) => x,
+        Err(_) => return vec![]
+    };
+
+    // Only names ending with a timestamp match, so that `web` and `web_x` paths do not mix
+    let mut archives: Vec<PathBuf> = entries.filter_map(Result::ok)
+        .map(|x| x.path())
+        .filter(|x| {
+            let name = x.file_name().map(|x| x.to_string_lossy().into_owned()).unwrap_or_default();
+            name.strip_prefix(&prefix)
+                .and_then(|x| x.strip_suffix(EXTENSION))
+                .map(|x| NaiveDateTime::parse_from_str(x, TIMESTAMP_FORMAT).is_ok())
+                .unwrap_or(false)
+        })
+        .collect();
+
+    archives.sort();
+    archives
+}
+
+/// Remove the oldest archives of a remote path, keeping the latest ones.
+/// Returns the removed archives.
+///
+/// # Arguments
+///
+/// * `dir` - Local directory
+/// * `machine` - Machine name
+/// * `remote_path` - Remote file or directory path
+/// * `keep` - Number of archives kept
+///
+pub fn rotate_archives(dir: &Path, machine: &str, remote_path: &str, keep: usize) -> Result<Vec<PathBuf>, Error> {
+    let archives = list_archives(dir, machine, remote_path);
+    let count = archives.len().saturating_sub(keep);
+
+    archives.into_iter()
+        .take(count)
+        .map(|x| {
+            fs::remove_file(&x).map_err(|e| Error::io(format!("could not remove {}", x.display()), e))?;
+            Ok(x)
+        })
+        .collect()
+}
+
+/// Back up a remote path to a local archive, returning its size in bytes.
+///
+/// The archive is written under a temporary name first, so that an
+/// interrupted backup never looks complete.
+///
+/// # Arguments
+///
+/// * `config` - Machine configuration
+/// * `remote_path` - Remote file or directory path
+/// * `archive` - Local archive path
+///
+pub fn backup_path(config: &MachineConfig, remote_path: &str, archive: &Path) -> Result<u64, Error> {
+    config.check_values()?;
+    if let Some(parent) = archive.parent().filter(|x| !x.as_os_str().is_empty()) {
+        fs::create_dir_all(parent).map_err(|e| Error::io(format!("could not create {}", parent.display()), e))?;
+    }
+
+    let mut partial = archive.as_os_str().to_owned();
+    partial.push(".partial");
+    let partial = PathBuf::from(partial);
+    let file = File::create(&partial).map_err(|e| Error::io(format!("could not create {}", partial.display()), e))?;
+
+    let mut command: Command = ssh_remote(config, &backup_command(remote_path)).into();
+    let output = command.stdin(Stdio::null())
+        .stdout(file)
+        .stderr(Stdio::piped())
+        .output()
+        .map_err(|e| Error::io("failed to execute ssh", e));
+
+    let result = match output {
+        Ok(ref x) if x.status.success() => Ok(()),
+        Ok(x) => Err(Error::tool("could not archive the remote path", &String::from_utf8_lossy(&x.stderr))),
+        Err(error) => Err(error)
+    };
+    if let Err(error) = result {
+        fs::remove_file(&partial).ok();
+        return Err(error);
+    }
+
+    fs::rename(&partial, archive).map_err(|e| Error::io(format!("could not write {}", archive.display()), e))?;
+    fs::metadata(archive).map(|x| x.len()).map_err(|e| Error::io(format!("could not read {}", archive.display()), e))
+}
+
+/// Extract a local archive into a remote directory.
+///
+/// # Arguments
+///
+/// * `config` - Machine configuration
+/// * `archive` - Local archive path
+/// * `remote_dir` - Remote directory to extract into
+///
+pub fn restore_archive(config: &MachineConfig, archive: &Path, remote_dir: &str) -> Result<(), Error> {
+    config.check_values()?;
+    let file = File::open(archive).map_err(|e| Error::io(format!("could not open {}", archive.display()), e))?;
+
+    let mut command: Command = ssh_remote(config, &restore_command(remote_dir)).into();
+    let output = command.stdin(file)
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .output()
+        .map_err(|e| Error::io("failed to execute ssh", e))?;
+
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(Error::tool("could not extract the archive", &String::from_utf8_lossy(&output.stderr)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::env;
+
+    use chrono::TimeZone;
+
+    #[test]
+    fn commands() {
+        assert_eq!(split_remote_path("/etc/nginx/"), ("/etc".to_string(), "nginx".to_string()));
+        assert_eq!(split_remote_path("/srv"), ("/".to_string(), "srv".to_string()));
+        assert_eq!(split_remote_path("app"), (".".to_string(), "app".to_string()));
+        assert_eq!(split_remote_path("/"), ("/".to_string(), ".".to_string()));
+        assert_eq!(backup_command("~/my app"), "tar -czf - -C ~ 'my app'");
+        assert_eq!(backup_command("/etc/nginx"), "tar -czf - -C /etc nginx");
+        assert_eq!(restore_command("/etc"), "tar -xzf - -C /etc");
+    }
+
+    #[test]
+    fn rotation() {
+        let dir = env::temp_dir().join(format!("pssh-backup-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let time = Local.with_ymd_and_hms(2024, 1, 2, 3, 4, 5).unwrap();
+        let archive = archive_path(&dir, "web:one", "/etc/nginx/", time);
+        assert_eq!(archive.file_name().unwrap(), "web.one_nginx_20240102-030405.tar.gz");
+        assert_eq!(archive_path(&dir, "db", "/", time).file_name().unwrap(), "db_root_20240102-030405.tar.gz");
+
+        for day in 1..4 {
+            fs::write(archive_path(&dir, "web:one", "/etc/nginx", Local.with_ymd_and_hms(2024, 1, day, 0, 0, 0).unwrap()), "").unwrap();
+        }
+        fs::write(archive_path(&dir, "web:one", "/etc/nginx_x", time), "").unwrap();
+        fs::write(dir.join("web.one_nginx_notes.tar.gz"), "").unwrap();
+        assert_eq!(list_archives(&dir, "web:one", "/etc/nginx").len(), 3);
+
+        let removed = rotate_archives(&dir, "web:one", "/etc/nginx", 2).unwrap();
+        assert_eq!(removed, vec![dir.join("web.one_nginx_20240101-000000.tar.gz")]);
+        assert_eq!(list_archives(&dir, "web:one", "/etc/nginx").len(), 2);
+        assert_eq!(list_archives(&dir, "web:one", "/etc/nginx_x").len(), 1);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
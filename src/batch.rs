@@ -0,0 +1,348 @@
+//! Batch execution over several machines
+
+use std::process;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use config::ConfigMap;
+
+/// Default number of machines processed at once
+pub const DEFAULT_PARALLELISM: usize = 16;
+
+/// When to stop processing machines after failures
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FailurePolicy {
+    /// Process all machines, whatever the failures
+    Continue,
+    /// Start no more machines once this many have failed
+    MaxFailures(usize)
+}
+
+impl FailurePolicy {
+    /// Check if machines can still be started after `failures` failures
+    ///
+    /// # Arguments
+    ///
+    /// * `failures` - Failure count
+    ///
+    pub fn allows(&self, failures: usize) -> bool {
+        match *self {
+            FailurePolicy::Continue => true,
+            FailurePolicy::MaxFailures(max) => failures < max
+        }
+    }
+}
+
+/// Order in which batch runs process machines
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MachineOrder {
+    /// Machine name
+    Name,
+    /// Shuffled on each run
+    Random,
+    /// Ascending `priority`, machines without one last, then name
+    Priority
+}
+
+impl MachineOrder {
+    /// Parse an order name.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - Order name
+    ///
+    pub fn from_name(name: &str) -> Option<MachineOrder> {
+        match name {
+            "name" => Some(MachineOrder::Name),
+            "random" => Some(MachineOrder::Random),
+            "priority" => Some(MachineOrder::Priority),
+            _ => None
+        }
+    }
+}
+
+/// Order machines for a batch run.
+///
+/// # Arguments
+///
+/// * `names` - Machine names
+/// * `machines` - Machine configurations
+/// * `order` - Order
+///
+pub fn order_machines(names: &[String], machines: &ConfigMap, order: MachineOrder) -> Vec<String> {
+    let mut names = names.to_vec();
+    names.sort();
+
+    match order {
+        MachineOrder::Name => (),
+        MachineOrder::Priority => names.sort_by_key(|x| match machines.get(x).and_then(|x| x.priority) {
+            Some(priority) => (0, priority),
+            None => (1, 0)
+        }),
+        MachineOrder::Random => {
+            // A xorshift generator is enough to vary the order between runs
+            let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map(|x| x.subsec_nanos()).unwrap_or(0);
+            let mut state = (u64::from(nanos) << 32 | u64::from(process::id())) | 1;
+            for i in (1..names.len()).rev() {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                names.swap(i, (state % (i as u64 + 1)) as usize);
+            }
+        }
+    }
+
+    names
+}
+
+/// Run a function for each machine, with a bounded number of parallel workers.
+///
+/// Results are returned in the same order as the input names.
+///
+/// # Arguments
+///
+/// * `names` - Machine names
+/// * `concurrency` - Maximum number of machines processed at once (0 means unbounded)
+/// * `func` - Function to run for each machine name
+///
+pub fn run_parallel<T, F>(names: &[String], concurrency: usize, func: F) -> Vec<(String, T)>
+    where T: Send, F: Fn(&str) -> T + Sync
+{
+    run_parallel_checked(names, concurrency, FailurePolicy::Continue, func, |_| false)
+        .into_iter()
+        .map(|(name, result)| (name, result.unwrap()))
+        .collect()
+}
+
+/// Run a function for each machine like `run_parallel`, starting no more
+/// machines once the failure policy is exceeded. Machines already running
+/// are waited for.
+///
+/// Skipped machines have no result.
+///
+/// # Arguments
+///
+/// * `names` - Machine names
+/// * `concurrency` - Maximum number of machines processed at once (0 means unbounded)
+/// * `policy` - Failure policy
+/// * `func` - Function to run for each machine name
+/// * `failed` - Tell failed results
+///
+pub fn run_parallel_checked<T, F, P>(names: &[String], concurrency: usize, policy: FailurePolicy, func: F, failed: P) -> Vec<(String, Option<T>)>
+    where T: Send, F: Fn(&str) -> T + Sync, P: Fn(&T) -> bool + Sync
+{
+    let workers = if concurrency == 0 || concurrency > names.len() {
+        names.len()
+    } else {
+        concurrency
+    };
+
+    let next_index = Mutex::new(0);
+    let failures = AtomicUsize::new(0);
+    let results: Mutex<Vec<Option<T>>> = Mutex::new(names.iter().map(|_| None).collect());
+
+    thread::scope(|scope| {
+        for _ in 0..workers {
+            scope.spawn(|| {
+                loop {
+                    let index = {
+                        let mut next_index = next_index.lock().unwrap();
+                        let index = *next_index;
+                        *next_index += 1;
+                        index
+                    };
+
+                    if index >= names.len() || !policy.allows(failures.load(Ordering::SeqCst)) {
+                        break;
+                    }
+
+                    let result = func(&names[index]);
+                    if failed(&result) {
+                        failures.fetch_add(1, Ordering::SeqCst);
+                    }
+                    results.lock().unwrap()[index] = Some(result);
+                }
+            });
+        }
+    });
+
+    names.iter().cloned()
+        .zip(results.into_inner().unwrap())
+        .collect()
+}
+
+/// Run a function for each machine in waves of `size` machines: a wave
+/// starts `delay` after the previous one is done. Within a wave, machines run
+/// like with `run_parallel_checked`, and the failure policy spans all waves.
+///
+/// Skipped machines have no result.
+///
+/// # Arguments
+///
+/// * `names` - Machine names
+/// * `concurrency` - Maximum number of machines processed at once (0 means unbounded)
+/// * `size` - Wave size (0 means a single wave)
+/// * `delay` - Delay between waves
+/// * `policy` - Failure policy
+/// * `func` - Function to run for each machine name
+/// * `failed` - Tell failed results
+///
+pub fn run_in_waves<T, F, P>(names: &[String], concurrency: usize, size: usize, delay: Duration, policy: FailurePolicy, func: F, failed: P) -> Vec<(String, Option<T>)>
+    where T: Send, F: Fn(&str) -> T + Sync, P: Fn(&T) -> bool + Sync
+{
+    if size == 0 || size >= names.len() {
+        return run_parallel_checked(names, concurrency, policy, func, failed);
+    }
+
+    let waves: Vec<&[String]> = names.chunks(size).collect();
+    let mut results = vec![];
+    let mut failures = 0;
+
+    for (index, wave) in waves.iter().enumerate() {
+        if !policy.allows(failures) {
+            results.extend(wave.iter().map(|name| (name.clone(), None)));
+            continue;
+        }
+
+        if index > 0 && !delay.is_zero() {
+            println!("Waiting {}s before the next wave...", delay.as_secs_f64());
+            thread::sleep(delay);
+        }
+        println!("Wave {}/{}: {}", index + 1, waves.len(), wave.join(", "));
+
+        let remaining = match policy {
+            FailurePolicy::Continue => FailurePolicy::Continue,
+            FailurePolicy::MaxFailures(max) => FailurePolicy::MaxFailures(max - failures)
+        };
+        let wave_results = run_parallel_checked(wave, concurrency, remaining, &func, &failed);
+        failures += wave_results.iter().filter(|x| x.1.as_ref().map(&failed).unwrap_or(false)).count();
+        results.extend(wave_results);
+    }
+
+    results
+}
+
+/// Parse a duration such as `30s`, `2m`, `1h` or `500ms`; plain numbers are
+/// seconds.
+///
+/// # Arguments
+///
+/// * `value` - Value to parse
+///
+pub fn parse_duration(value: &str) -> Option<Duration> {
+    let split = value.find(|c: char| !c.is_ascii_digit()).unwrap_or(value.len());
+    let (amount, unit) = value.split_at(split);
+    let amount: u64 = amount.parse().ok()?;
+
+    match unit {
+        "ms" => Some(Duration::from_millis(amount)),
+        "" | "s" => Some(Duration::from_secs(amount)),
+        "m" => Some(Duration::from_secs(amount * 60)),
+        "h" => Some(Duration::from_secs(amount * 3600)),
+        _ => None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use config::MachineConfig;
+
+    #[test]
+    fn ordered_results() {
+        let names: Vec<String> = (0..20).map(|x| format!("machine{}", x)).collect();
+        let results = run_parallel(&names, 4, |name| name.len());
+
+        assert_eq!(results.len(), 20);
+        for (i, (name, length)) in results.iter().enumerate() {
+            assert_eq!(name, &names[i]);
+            assert_eq!(*length, names[i].len());
+        }
+    }
+
+    #[test]
+    fn bounded_concurrency() {
+        let names: Vec<String> = (0..12).map(|x| x.to_string()).collect();
+        let running = AtomicUsize::new(0);
+        let max_running = AtomicUsize::new(0);
+
+        run_parallel(&names, 3, |_| {
+            let current = running.fetch_add(1, Ordering::SeqCst) + 1;
+            max_running.fetch_max(current, Ordering::SeqCst);
+            thread::sleep(::std::time::Duration::from_millis(10));
+            running.fetch_sub(1, Ordering::SeqCst);
+        });
+
+        assert!(max_running.load(Ordering::SeqCst) <= 3);
+    }
+
+    #[test]
+    fn failure_policy() {
+        let names: Vec<String> = (0..10).map(|x| x.to_string()).collect();
+
+        let results = run_parallel_checked(&names, 1, FailurePolicy::MaxFailures(2), |name| name != "0" && name != "2", |x| !*x);
+        let done: Vec<&str> = results.iter().filter(|x| x.1.is_some()).map(|x| &x.0[..]).collect();
+        assert_eq!(done, vec!["0", "1", "2"]);
+
+        let results = run_parallel_checked(&names, 4, FailurePolicy::Continue, |_| false, |x| !*x);
+        assert!(results.iter().all(|x| x.1 == Some(false)));
+
+        assert!(FailurePolicy::MaxFailures(1).allows(0));
+        assert!(!FailurePolicy::MaxFailures(1).allows(1));
+    }
+
+    #[test]
+    fn waves() {
+        let names: Vec<String> = (0..5).map(|x| x.to_string()).collect();
+        let running = AtomicUsize::new(0);
+        let max_running = AtomicUsize::new(0);
+
+        let results = run_in_waves(&names, 0, 2, Duration::from_millis(1), FailurePolicy::Continue, |_| {
+            let current = running.fetch_add(1, Ordering::SeqCst) + 1;
+            max_running.fetch_max(current, Ordering::SeqCst);
+            thread::sleep(Duration::from_millis(10));
+            running.fetch_sub(1, Ordering::SeqCst);
+            true
+        }, |x| !*x);
+        assert_eq!(results.len(), 5);
+        assert!(results.iter().all(|x| x.1 == Some(true)));
+        assert!(max_running.load(Ordering::SeqCst) <= 2);
+
+        // Waves after the failure threshold are skipped
+        let results = run_in_waves(&names, 0, 2, Duration::from_millis(1), FailurePolicy::MaxFailures(1), |name| name != "1", |x| !*x);
+        let done: Vec<&str> = results.iter().filter(|x| x.1.is_some()).map(|x| &x.0[..]).collect();
+        assert_eq!(done, vec!["0", "1"]);
+    }
+
+    #[test]
+    fn orders() {
+        let names: Vec<String> = vec!["db:primary".to_string(), "db:standby".to_string(), "db:backup".to_string(), "db:archive".to_string()];
+        let machines: ConfigMap = vec![
+            ("db:primary".to_string(), MachineConfig { priority: Some(2), ..Default::default() }),
+            ("db:standby".to_string(), MachineConfig { priority: Some(1), ..Default::default() }),
+            ("db:backup".to_string(), MachineConfig::default()),
+            ("db:archive".to_string(), MachineConfig::default())
+        ].into_iter().collect();
+
+        assert_eq!(order_machines(&names, &machines, MachineOrder::Priority), vec!["db:standby", "db:primary", "db:archive", "db:backup"]);
+        assert_eq!(order_machines(&names, &machines, MachineOrder::Name), vec!["db:archive", "db:backup", "db:primary", "db:standby"]);
+
+        let mut shuffled = order_machines(&names, &machines, MachineOrder::Random);
+        shuffled.sort();
+        assert_eq!(shuffled, order_machines(&names, &machines, MachineOrder::Name));
+        assert_eq!(MachineOrder::from_name("size"), None);
+    }
+
+    #[test]
+    fn durations() {
+        assert_eq!(parse_duration("30s"), Some(Duration::from_secs(30)));
+        assert_eq!(parse_duration("2m"), Some(Duration::from_secs(120)));
+        assert_eq!(parse_duration("500ms"), Some(Duration::from_millis(500)));
+        assert_eq!(parse_duration("10"), Some(Duration::from_secs(10)));
+        assert_eq!(parse_duration("m"), None);
+        assert_eq!(parse_duration("3d"), None);
+    }
+}
@@ -0,0 +1,229 @@
+//! Reachability checks
+//!
+//! `pssh check` probes the SSH port of machines once, or at each interval
+//! with `--watch`, redrawing the results in place. A hook command can run
+//! on each state change. `pssh status --oneline` prints one parseable line
+//! per machine, for chat bots and monitoring scripts.
+
+use std::collections::{BTreeMap, HashMap};
+use std::net::ToSocketAddrs;
+use std::process::{Command, Stdio};
+use std::time::Duration;
+
+use ansi_term::Colour;
+use chrono::{DateTime, Local};
+
+use config::{ConfigMap, MachineConfig};
+use error::Error;
+use probe::{tcp_probe, Reachability};
+
+/// Default interval of `check --watch`
+pub const DEFAULT_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Connection timeout of checks
+pub const CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Clear the terminal, cursor at the top left
+pub const CLEAR_SCREEN: &str = "\x1b[H\x1b[2J";
+
+/// Result of a machine check
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Check {
+    /// Reachability
+    pub state: Reachability,
+    /// Connection time, when reachable
+    pub latency: Option<Duration>
+}
+
+/// Reachability change of a machine between two checks
+#[derive(Debug, Clone, PartialEq)]
+pub struct StateChange {
+    /// Machine name
+    pub machine: String,
+    /// Previous reachability
+    pub previous: Reachability,
+    /// New reachability
+    pub current: Reachability
+}
+
+/// Check a machine, telling unreachable machines from machines which cannot
+/// be resolved.
+///
+/// # Arguments
+///
+/// * `config` - Machine configuration
+/// * `timeout` - Connection timeout, per port
+///
+pub fn check_machine(config: &MachineConfig, timeout: Duration) -> Check {
+    let ip = match config.ip {
+        Some(ref ip) => ip,
+        None => return Check { state: Reachability::Unknown, latency: None }
+    };
+
+    match tcp_probe(config, timeout) {
+        Some(latency) => Check { state: Reachability::Up, latency: Some(latency) },
+        None if (ip.as_str(), config.first_port()).to_socket_addrs().is_err() => Check { state: Reachability::Unknown, latency: None },
+        None => Check { state: Reachability::Down, latency: None }
+    }
+}
+
+/// List the machines whose reachability changed between two checks.
+/// Machines missing from the previous check are not changes.
+///
+/// # Arguments
+///
+/// * `previous` - Previous checks
+/// * `current` - New checks, in display order
+///
+pub fn detect_changes(previous: &HashMap<String, Check>, current: &[(String, Check)]) -> Vec<StateChange> {
+    current.iter()
+        .filter_map(|(name, check)| {
+            let before = previous.get(name)?.state;
+            if before == check.state {
+                return None;
+            }
+            Some(StateChange { machine: name.clone(), previous: before, current: check.state })
+        })
+        .collect()
+}
+
+/// Render checks as a table.
+///
+/// # Arguments
+///
+/// * `rows` - Checks, per machine
+/// * `colored` - Color states
+///
+pub fn render_checks(rows: &[(String, Check)], colored: bool) -> String {
+    let width = rows.iter().map(|x| x.0.len()).chain(Some("MACHINE".len())).max().unwrap_or(0);
+
+    let mut output = format!("{:width$}  {:7}  LATENCY\n", "MACHINE", "STATE", width = width);
+    for (name, check) in rows {
+        let state = format!("{:7}", check.state.name());
+        let state = match check.state {
+            _ if !colored => state,
+            Reachability::Up => Colour::Green.paint(state).to_string(),
+            Reachability::Down => Colour::Red.paint(state).to_string(),
+            Reachability::Unknown => Colour::Yellow.paint(state).to_string()
+        };
+        let latency = check.latency.map(|x| format!("{:.1} ms", x.as_secs_f64() * 1000.0)).unwrap_or_else(|| "-".to_string());
+        output.push_str(&format!("{:width$}  {}  {}\n", name, state, latency, width = width));
+    }
+
+    output
+}
+
+/// Render checks as single lines of space-separated fields: machine, state,
+/// latency in milliseconds and address, as `prod:web1 up 12ms
+/// deploy@10.0.0.1:22`. Unknown values are `-`.
+///
+/// # Arguments
+///
+/// * `rows` - Machine names and checks
+/// * `machines` - Machine configurations
+///
+pub fn render_oneline(rows: &[(String, Check)], machines: &ConfigMap) -> String {
+    rows.iter()
+        .map(|(name, check)| {
+            let latency = check.latency.map(|x| format!("{}ms", x.as_millis())).unwrap_or_else(|| "-".to_string());
+            let address = machines.get(name).map(|x| x.address()).unwrap_or_else(|| "-".to_string());
+            format!("{} {} {} {}\n", name, check.state.name(), latency, address)
+        })
+        .collect()
+}
+
+/// Render a state change, as a log line.
+///
+/// # Arguments
+///
+/// * `change` - State change
+/// * `time` - Check time
+///
+pub fn render_change(change: &StateChange, time: DateTime<Local>) -> String {
+    format!("[{}] {}: {} -> {}", time.format("%H:%M:%S"), change.machine, change.previous.name(), change.current.name())
+}
+
+/// Run the hook command of a state change, through the local shell.
+///
+/// The machine and its states are given in the `PSSH_MACHINE`,
+/// `PSSH_PREVIOUS_STATE` and `PSSH_STATE` environment variables, and the
+/// machine variables in `PSSH_VAR_<KEY>` ones.
+///
+/// # Arguments
+///
+/// * `hook` - Shell command
+/// * `change` - State change
+/// * `vars` - Machine variables
+///
+pub fn run_hook(hook: &str, change: &StateChange, vars: &BTreeMap<String, String>) -> Result<(), Error> {
+    let status = Command::new("sh")
+        .args(["-c", hook])
+        .env("PSSH_MACHINE", &change.machine)
+        .env("PSSH_PREVIOUS_STATE", change.previous.name())
+        .env("PSSH_STATE", change.current.name())
+        .envs(vars.iter().map(|(k, v)| (format!("PSSH_VAR_{}", k.to_uppercase()), v)))
+        .stdin(Stdio::null())
+        .status()
+        .map_err(|e| Error::io("failed to execute the hook", e))?;
+
+    if status.success() {
+        Ok(())
+    } else {
+        Err(Error::tool(format!("hook `{}` failed", hook), &status.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::net::TcpListener;
+
+    use chrono::TimeZone;
+
+    fn check(state: Reachability) -> Check {
+        Check { state, latency: None }
+    }
+
+    #[test]
+    fn checks() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let config = MachineConfig {
+            ip: Some("127.0.0.1".to_string()),
+            port: Some(vec![listener.local_addr().unwrap().port()]),
+            ..Default::default()
+        };
+        let result = check_machine(&config, CHECK_TIMEOUT);
+        assert_eq!(result.state, Reachability::Up);
+        assert!(result.latency.is_some());
+        assert_eq!(check_machine(&MachineConfig::default(), CHECK_TIMEOUT).state, Reachability::Unknown);
+
+        let previous = hashmap!{
+            "web1".to_string() => check(Reachability::Up),
+            "web2".to_string() => check(Reachability::Up)
+        };
+        let current = vec![
+            ("web1".to_string(), check(Reachability::Up)),
+            ("web2".to_string(), check(Reachability::Down)),
+            ("web3".to_string(), check(Reachability::Down))
+        ];
+        let changes = detect_changes(&previous, &current);
+        assert_eq!(changes, vec![StateChange { machine: "web2".to_string(), previous: Reachability::Up, current: Reachability::Down }]);
+
+        let time = Local.with_ymd_and_hms(2024, 1, 2, 3, 4, 5).unwrap();
+        assert_eq!(render_change(&changes[0], time), "[03:04:05] web2: up -> down");
+
+        let rows = vec![("web1".to_string(), Check { state: Reachability::Up, latency: Some(Duration::from_micros(1340)) })];
+        assert_eq!(render_checks(&rows, false), "MACHINE  STATE    LATENCY\nweb1     up       1.3 ms\n");
+        let machines = hashmap!{
+            "web1".to_string() => MachineConfig { ip: Some("10.0.0.1".to_string()), user: Some("deploy".to_string()), ..Default::default() }
+        };
+        let rows = vec![rows[0].clone(), ("web2".to_string(), check(Reachability::Down))];
+        assert_eq!(render_oneline(&rows, &machines), "web1 up 1ms deploy@10.0.0.1:22\nweb2 down - -\n");
+
+        let hook_change = StateChange { machine: "web2".to_string(), previous: Reachability::Up, current: Reachability::Down };
+        let vars = vec![("env".to_string(), "prod".to_string())].into_iter().collect();
+        assert!(run_hook("test \"$PSSH_MACHINE:$PSSH_PREVIOUS_STATE:$PSSH_STATE:$PSSH_VAR_ENV\" = web2:up:down:prod", &hook_change, &vars).is_ok());
+        assert!(run_hook("exit 1", &hook_change, &vars).is_err());
+    }
+}
@@ -0,0 +1,54 @@
+//! Remote clipboard
+//!
+//! Small snippets are sent to a machine from the standard input, and read
+//! back to the standard output. They are kept in a file of the remote home
+//! directory, or in the remote X11 clipboard through `xclip`.
+
+use quote::quote_path;
+
+/// Default remote clipboard file
+pub const DEFAULT_CLIP_FILE: &str = "~/.pssh_clipboard";
+
+/// Remote clipboard storage
+#[derive(Debug, PartialEq)]
+pub enum ClipTarget {
+    /// Remote file
+    File(String),
+    /// Remote X11 clipboard, through `xclip`
+    X11
+}
+
+impl ClipTarget {
+    /// Get the remote command storing its standard input.
+    pub fn send_command(&self) -> String {
+        match *self {
+            ClipTarget::File(ref path) => format!("umask 077 && cat > {}", quote_path(path)),
+            ClipTarget::X11 => "xclip -selection clipboard -i".to_string()
+        }
+    }
+
+    /// Get the remote command printing the clipboard contents.
+    pub fn get_command(&self) -> String {
+        match *self {
+            ClipTarget::File(ref path) => format!("cat {}", quote_path(path)),
+            ClipTarget::X11 => "xclip -selection clipboard -o".to_string()
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn commands() {
+        let target = ClipTarget::File(DEFAULT_CLIP_FILE.to_string());
+        assert_eq!(target.send_command(), "umask 077 && cat > ~/.pssh_clipboard");
+        assert_eq!(target.get_command(), "cat ~/.pssh_clipboard");
+
+        let target = ClipTarget::File("/tmp/it's".to_string());
+        assert_eq!(target.get_command(), "cat '/tmp/it'\\''s'");
+
+        assert_eq!(ClipTarget::X11.get_command(), "xclip -selection clipboard -o");
+    }
+}
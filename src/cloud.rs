@@ -0,0 +1,477 @@
+//! Cloud inventory providers
+//!
+//! Providers list servers through their HTTP API (using `curl`) or their CLI,
+//! and synced servers are merged as machines into a namespace. Machines and
+//! values added by hand under it are kept across syncs.
+
+use std::env;
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+use serde_json::Value;
+use yaml_rust::Yaml;
+use yaml_rust::yaml::Hash;
+
+use config::{machine_values_to_yaml, MachineConfig};
+use error::Error;
+use names::sanitize_key;
+use wrapper::execute_capture;
+
+/// Maximum number of pages fetched from an API
+const MAX_PAGES: u32 = 100;
+
+/// Cloud inventory provider
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Provider {
+    /// DigitalOcean droplets
+    DigitalOcean,
+    /// Hetzner Cloud servers
+    Hetzner,
+    /// Linode instances
+    Linode,
+    /// Azure virtual machines, listed with the `az` CLI
+    Azure
+}
+
+/// Provider-specific sync options
+#[derive(Debug, Clone, Default)]
+pub struct SyncOptions {
+    /// API token (DigitalOcean, Hetzner, Linode)
+    pub token: Option<String>,
+    /// Subscription (Azure)
+    pub subscription: Option<String>,
+    /// Resource group (Azure)
+    pub resource_group: Option<String>
+}
+
+/// Server listed by a cloud provider
+#[derive(Debug, Clone, PartialEq)]
+pub struct CloudMachine {
+    /// Server name
+    pub name: String,
+    /// Public IPv4 address
+    pub public_ip: Option<String>,
+    /// Private IPv4 address
+    pub private_ip: Option<String>,
+    /// Administrator username, if known
+    pub user: Option<String>,
+    /// Tags and labels (`key=value` for labels)
+    pub tags: Vec<String>
+}
+
+impl Provider {
+    /// Parse a provider name.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - Provider name
+    ///
+    pub fn from_name(name: &str) -> Option<Provider> {
+        match name {
+            "do" | "digitalocean" => Some(Provider::DigitalOcean),
+            "hetzner" => Some(Provider::Hetzner),
+            "linode" => Some(Provider::Linode),
+            "azure" => Some(Provider::Azure),
+            _ => None
+        }
+    }
+
+    /// Provider short name, used as the default namespace
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Provider::DigitalOcean => "do",
+            Provider::Hetzner => "hetzner",
+            Provider::Linode => "linode",
+            Provider::Azure => "azure"
+        }
+    }
+
+    /// Environment variable holding the API token, for HTTP API providers
+    pub fn token_variable(&self) -> Option<&'static str> {
+        match *self {
+            Provider::DigitalOcean => Some("DIGITALOCEAN_TOKEN"),
+            Provider::Hetzner => Some("HCLOUD_TOKEN"),
+            Provider::Linode => Some("LINODE_TOKEN"),
+            Provider::Azure => None
+        }
+    }
+
+    /// Get the API token from the environment
+    pub fn token_from_env(&self) -> Option<String> {
+        env::var(self.token_variable()?).ok().filter(|x| !x.is_empty())
+    }
+
+    /// API URL listing servers, for a page (starting at 1)
+    ///
+    /// # Arguments
+    ///
+    /// * `page` - Page number
+    ///
+    fn page_url(&self, page: u32) -> String {
+        match *self {
+            Provider::DigitalOcean => format!("https://api.digitalocean.com/v2/droplets?per_page=200&page={}", page),
+            Provider::Hetzner => format!("https://api.hetzner.cloud/v1/servers?per_page=50&page={}", page),
+            Provider::Linode => format!("https://api.linode.com/v4/linode/instances?page_size=500&page={}", page),
+            Provider::Azure => unreachable!("Azure machines are listed with the az CLI")
+        }
+    }
+
+    /// Parse an API response page.
+    ///
+    /// Returns the listed servers, and whether a next page exists.
+    ///
+    /// # Arguments
+    ///
+    /// * `body` - JSON response
+    ///
+    pub fn parse_page(&self, body: &Value) -> (Vec<CloudMachine>, bool) {
+        match *self {
+            Provider::DigitalOcean => parse_digitalocean(body),
+            Provider::Hetzner => parse_hetzner(body),
+            Provider::Linode => parse_linode(body),
+            Provider::Azure => (parse_azure(body), false)
+        }
+    }
+}
+
+impl CloudMachine {
+    /// Convert to a machine configuration, preferring the public IP
+    pub fn to_machine_config(&self) -> MachineConfig {
+        MachineConfig {
+            ip: self.public_ip.clone().or_else(|| self.private_ip.clone()),
+            user: self.user.clone(),
+            tags: if self.tags.is_empty() { None } else { Some(self.tags.clone()) },
+            ..Default::default()
+        }
+    }
+}
+
+/// Fetch all servers from a provider.
+///
+/// The API token defaults to the provider environment variable.
+///
+/// # Arguments
+///
+/// * `provider` - Cloud provider
+/// * `options` - Sync options
+///
+pub fn fetch_machines(provider: Provider, options: &SyncOptions) -> Result<Vec<CloudMachine>, Error> {
+    if provider == Provider::Azure {
+        return fetch_azure_machines(options);
+    }
+
+    let token = match options.token.clone().or_else(|| provider.token_from_env()) {
+        Some(token) => token,
+        None => return Err(Error::Config(format!("no API token, use --token or set {}", provider.token_variable().unwrap())))
+    };
+
+    let mut machines = vec![];
+
+    for page in 1..=MAX_PAGES {
+        let body = http_get_json(&provider.page_url(page), &token)?;
+        let (page_machines, has_next) = provider.parse_page(&body);
+        machines.extend(page_machines);
+
+        if !has_next {
+            break;
+        }
+    }
+
+    Ok(machines)
+}
+
+/// Build the machine subtree for synced servers.
+///
+/// Server names are sanitized to be valid machine names.
+///
+/// # Arguments
+///
+/// * `machines` - Synced servers
+///
+pub fn machines_to_yaml(machines: &[CloudMachine]) -> Yaml {
+    let mut subtree = Hash::new();
+
+    for machine in machines {
+        let mut entry = Hash::new();
+        entry.insert(Yaml::String("$".to_string()), machine_values_to_yaml(&machine.to_machine_config()));
+        subtree.insert(Yaml::String(sanitize_key(&machine.name)), Yaml::Hash(entry));
+    }
+
+    Yaml::Hash(subtree)
+}
+
+fn http_get_json(url: &str, token: &str) -> Result<Value, Error> {
+    debug!("Fetching {}", url);
+    let mut child = Command::new("curl")
+        .args(["--silent", "--show-error", "--fail", "--location"])
+        // Read the authorization header from stdin, to keep the token out of the process list
+        .args(["--header", "@-"])
+        .arg(url)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| Error::io("failed to execute curl", e))?;
+
+    child.stdin.take().unwrap()
+        .write_all(format!("Authorization: Bearer {}\n", token).as_bytes())
+        .map_err(|e| Error::io("failed to write to curl", e))?;
+    let output = child.wait_with_output().map_err(|e| Error::io("failed to wait on curl", e))?;
+
+    if !output.status.success() {
+        return Err(Error::Transport(String::from_utf8_lossy(&output.stderr).trim().to_string()));
+    }
+
+    serde_json::from_slice(&output.stdout).map_err(|e| Error::Transport(format!("bad API response: {}", e)))
+}
+
+fn fetch_azure_machines(options: &SyncOptions) -> Result<Vec<CloudMachine>, Error> {
+    let mut command = Command::new("az");
+    command.args(["vm", "list", "--show-details", "--output", "json"]);
+    if let Some(ref subscription) = options.subscription {
+        command.args(["--subscription", subscription]);
+    }
+    if let Some(ref resource_group) = options.resource_group {
+        command.args(["--resource-group", resource_group]);
+    }
+
+    debug!("Executing {:?}", command);
+    let output = execute_capture(command)?;
+
+    if !output.status.success() {
+        return Err(Error::Transport(String::from_utf8_lossy(&output.stderr).trim().to_string()));
+    }
+
+    let body: Value = serde_json::from_slice(&output.stdout)
+        .map_err(|e| Error::Transport(format!("bad az output: {}", e)))?;
+    Ok(parse_azure(&body))
+}
+
+fn str_value(value: &Value) -> Option<String> {
+    value.as_str().map(String::from)
+}
+
+fn string_list(value: &Value) -> Vec<String> {
+    value.as_array()
+        .map(|x| x.iter().filter_map(str_value).collect())
+        .unwrap_or_default()
+}
+
+fn parse_digitalocean(body: &Value) -> (Vec<CloudMachine>, bool) {
+    let droplets = body["droplets"].as_array().cloned().unwrap_or_default();
+    let machines = droplets.iter().map(|droplet| {
+        let networks = droplet["networks"]["v4"].as_array().cloned().unwrap_or_default();
+        let address = |kind: &str| networks.iter()
+            .find(|x| x["type"] == kind)
+            .and_then(|x| str_value(&x["ip_address"]));
+
+        let mut tags = string_list(&droplet["tags"]);
+        if let Some(region) = droplet["region"]["slug"].as_str() {
+            tags.push(format!("region={}", region));
+        }
+
+        CloudMachine {
+            name: str_value(&droplet["name"]).unwrap_or_default(),
+            public_ip: address("public"),
+            private_ip: address("private"),
+            user: None,
+            tags
+        }
+    }).collect();
+
+    (machines, body["links"]["pages"]["next"].is_string())
+}
+
+fn parse_hetzner(body: &Value) -> (Vec<CloudMachine>, bool) {
+    let servers = body["servers"].as_array().cloned().unwrap_or_default();
+    let machines = servers.iter().map(|server| {
+        let mut tags: Vec<String> = server["labels"].as_object()
+            .map(|labels| labels.iter().map(|(key, value)| match value.as_str() {
+                Some(value) if !value.is_empty() => format!("{}={}", key, value),
+                _ => key.clone()
+            }).collect())
+            .unwrap_or_default();
+        tags.sort();
+        if let Some(location) = server["datacenter"]["location"]["name"].as_str() {
+            tags.push(format!("location={}", location));
+        }
+
+        CloudMachine {
+            name: str_value(&server["name"]).unwrap_or_default(),
+            public_ip: str_value(&server["public_net"]["ipv4"]["ip"]),
+            private_ip: server["private_net"].as_array()
+                .and_then(|x| x.first())
+                .and_then(|x| str_value(&x["ip"])),
+            user: None,
+            tags
+        }
+    }).collect();
+
+    (machines, body["meta"]["pagination"]["next_page"].is_number())
+}
+
+fn parse_linode(body: &Value) -> (Vec<CloudMachine>, bool) {
+    let instances = body["data"].as_array().cloned().unwrap_or_default();
+    let machines = instances.iter().map(|instance| {
+        let addresses = string_list(&instance["ipv4"]);
+        let is_private = |ip: &&String| ip.starts_with("192.168.");
+
+        let mut tags = string_list(&instance["tags"]);
+        if let Some(region) = instance["region"].as_str() {
+            tags.push(format!("region={}", region));
+        }
+
+        CloudMachine {
+            name: str_value(&instance["label"]).unwrap_or_default(),
+            public_ip: addresses.iter().find(|x| !is_private(x)).cloned(),
+            private_ip: addresses.iter().find(is_private).cloned(),
+            user: None,
+            tags
+        }
+    }).collect();
+
+    let page = body["page"].as_u64().unwrap_or(1);
+    let pages = body["pages"].as_u64().unwrap_or(1);
+    (machines, page < pages)
+}
+
+fn parse_azure(body: &Value) -> Vec<CloudMachine> {
+    let vms = body.as_array().cloned().unwrap_or_default();
+    vms.iter().map(|vm| {
+        // `--show-details` gives comma-separated address lists
+        let first_address = |key: &str| vm[key].as_str()
+            .and_then(|x| x.split(',').map(|ip| ip.trim()).find(|ip| !ip.is_empty()))
+            .map(String::from);
+
+        let mut tags: Vec<String> = vm["tags"].as_object()
+            .map(|tags| tags.iter().map(|(key, value)| match value.as_str() {
+                Some(value) if !value.is_empty() => format!("{}={}", key, value),
+                _ => key.clone()
+            }).collect())
+            .unwrap_or_default();
+        tags.sort();
+        if let Some(resource_group) = vm["resourceGroup"].as_str() {
+            tags.push(format!("resource_group={}", resource_group.to_lowercase()));
+        }
+        if let Some(location) = vm["location"].as_str() {
+            tags.push(format!("location={}", location));
+        }
+
+        CloudMachine {
+            name: str_value(&vm["name"]).unwrap_or_default(),
+            public_ip: first_address("publicIps"),
+            private_ip: first_address("privateIps"),
+            user: str_value(&vm["osProfile"]["adminUsername"]),
+            tags
+        }
+    }).collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn digitalocean() {
+        let body = json!({
+            "droplets": [{
+                "name": "web-1",
+                "tags": ["web"],
+                "region": {"slug": "fra1"},
+                "networks": {"v4": [
+                    {"ip_address": "10.110.0.2", "type": "private"},
+                    {"ip_address": "203.0.113.10", "type": "public"}
+                ]}
+            }],
+            "links": {"pages": {"next": "https://api.digitalocean.com/v2/droplets?page=2"}}
+        });
+
+        let (machines, has_next) = Provider::DigitalOcean.parse_page(&body);
+        assert!(has_next);
+        assert_eq!(machines, vec![CloudMachine {
+            name: "web-1".to_string(),
+            public_ip: Some("203.0.113.10".to_string()),
+            private_ip: Some("10.110.0.2".to_string()),
+            user: None,
+            tags: vec!["web".to_string(), "region=fra1".to_string()]
+        }]);
+    }
+
+    #[test]
+    fn hetzner() {
+        let body = json!({
+            "servers": [{
+                "name": "db 1",
+                "labels": {"role": "db", "backup": ""},
+                "datacenter": {"location": {"name": "nbg1"}},
+                "public_net": {"ipv4": {"ip": "198.51.100.7"}},
+                "private_net": [{"ip": "10.0.0.3"}]
+            }],
+            "meta": {"pagination": {"next_page": null}}
+        });
+
+        let (machines, has_next) = Provider::Hetzner.parse_page(&body);
+        assert!(!has_next);
+        assert_eq!(machines[0].tags, vec!["backup", "role=db", "location=nbg1"]);
+        assert_eq!(machines[0].private_ip, Some("10.0.0.3".to_string()));
+        assert_eq!(sanitize_key(&machines[0].name), "db-1");
+    }
+
+    #[test]
+    fn linode() {
+        let body = json!({
+            "data": [{
+                "label": "cache",
+                "tags": [],
+                "region": "eu-central",
+                "ipv4": ["192.168.130.4", "192.0.2.44"]
+            }],
+            "page": 1,
+            "pages": 1
+        });
+
+        let (machines, has_next) = Provider::Linode.parse_page(&body);
+        assert!(!has_next);
+        assert_eq!(machines[0].public_ip, Some("192.0.2.44".to_string()));
+        assert_eq!(machines[0].private_ip, Some("192.168.130.4".to_string()));
+    }
+
+    #[test]
+    fn azure() {
+        let body = json!([{
+            "name": "app-vm",
+            "resourceGroup": "PROD-RG",
+            "location": "westeurope",
+            "tags": {"env": "prod"},
+            "publicIps": "",
+            "privateIps": "10.1.0.4,10.1.0.5",
+            "osProfile": {"adminUsername": "azureuser"}
+        }]);
+
+        let (machines, has_next) = Provider::Azure.parse_page(&body);
+        assert!(!has_next);
+        assert_eq!(machines[0].public_ip, None);
+        assert_eq!(machines[0].private_ip, Some("10.1.0.4".to_string()));
+        assert_eq!(machines[0].user, Some("azureuser".to_string()));
+        assert_eq!(machines[0].tags, vec!["env=prod", "resource_group=prod-rg", "location=westeurope"]);
+
+        let config = machines[0].to_machine_config();
+        assert_eq!(config.ip, Some("10.1.0.4".to_string()));
+        assert_eq!(config.user, Some("azureuser".to_string()));
+    }
+
+    #[test]
+    fn yaml_subtree() {
+        let machines = vec![CloudMachine {
+            name: "web:1".to_string(),
+            public_ip: None,
+            private_ip: Some("10.0.0.1".to_string()),
+            user: None,
+            tags: vec![]
+        }];
+
+        let subtree = machines_to_yaml(&machines);
+        assert_eq!(subtree["web-1"]["$"]["ip"].as_str(), Some("10.0.0.1"));
+        assert!(subtree["web-1"]["$"]["tags"].is_badvalue());
+    }
+}
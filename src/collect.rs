@@ -0,0 +1,100 @@
+//! Collect a remote path from many machines
+//!
+//! Files are pulled into `<local-dir>/<machine>/<name>`, or into
+//! `<local-dir>/<machine>_<name>` when flattened. Existing destinations get a
+//! numeric suffix unless they are overwritten.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use config::MachineConfig;
+use error::Error;
+use wrapper::{execute_capture, scp_recursive, ScpDirection};
+
+/// Get the destination of a collected path.
+///
+/// # Arguments
+///
+/// * `local_dir` - Local directory
+/// * `machine` - Machine name
+/// * `remote_path` - Remote file or directory path
+/// * `flatten` - Put files directly in `local_dir`
+///
+pub fn collect_destination(local_dir: &Path, machine: &str, remote_path: &str, flatten: bool) -> PathBuf {
+    let trimmed = remote_path.trim_end_matches('/');
+    let name = trimmed.rsplit('/').next().filter(|x| !x.is_empty()).unwrap_or("root");
+
+    if flatten {
+        local_dir.join(format!("{}_{}", machine, name))
+    } else {
+        local_dir.join(machine).join(name)
+    }
+}
+
+/// Get a path which does not exist yet, adding `.1`, `.2`... to the path if
+/// needed.
+///
+/// # Arguments
+///
+/// * `path` - Wanted path
+///
+pub fn unused_path(path: &Path) -> PathBuf {
+    if !path.exists() {
+        return path.to_path_buf();
+    }
+
+    (1..).map(|i| {
+        let mut candidate = path.as_os_str().to_owned();
+        candidate.push(format!(".{}", i));
+        PathBuf::from(candidate)
+    })
+    .find(|x| !x.exists())
+    .unwrap()
+}
+
+/// Pull a remote path from a machine.
+///
+/// # Arguments
+///
+/// * `config` - Machine configuration
+/// * `remote_path` - Remote file or directory path
+/// * `destination` - Local destination
+///
+pub fn collect_path(config: &MachineConfig, remote_path: &str, destination: &Path) -> Result<(), Error> {
+    config.check_values()?;
+    if let Some(parent) = destination.parent() {
+        fs::create_dir_all(parent).map_err(|e| Error::io(format!("could not create {}", parent.display()), e))?;
+    }
+
+    let output = execute_capture(scp_recursive(config, remote_path, &destination.to_string_lossy(), ScpDirection::Pull))?;
+
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(Error::Transport(String::from_utf8_lossy(&output.stderr).trim().to_string()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::env;
+
+    #[test]
+    fn destinations() {
+        let dir = Path::new("/tmp/logs");
+        assert_eq!(collect_destination(dir, "prod:web1", "/var/log/syslog", false), dir.join("prod:web1/syslog"));
+        assert_eq!(collect_destination(dir, "prod:web1", "/etc/nginx/", true), dir.join("prod:web1_nginx"));
+        assert_eq!(collect_destination(dir, "db", "/", false), dir.join("db/root"));
+
+        let dir = env::temp_dir().join(format!("pssh-collect-{}", std::process::id()));
+        fs::create_dir_all(dir.join("web")).unwrap();
+        fs::write(dir.join("web/syslog"), "").unwrap();
+        fs::write(dir.join("web/syslog.1"), "").unwrap();
+
+        assert_eq!(unused_path(&dir.join("web/syslog")), dir.join("web/syslog.2"));
+        assert_eq!(unused_path(&dir.join("web/auth.log")), dir.join("web/auth.log"));
+        fs::remove_dir_all(&dir).ok();
+    }
+}
@@ -0,0 +1,326 @@
+//! File comparison
+//!
+//! `pssh cmp` compares a local file with a remote one by size and SHA-256
+//! digest. The remote digest is computed on the machine, with `sha256sum` or
+//! `shasum`, so that the content is never transferred.
+//!
+//! `pssh file-audit` reports the state of a file across machines: presence,
+//! size and modification time, and its digest or whether it contains a
+//! pattern, to spot configuration drift.
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::path::Path;
+
+use chrono::{DateTime, Local, TimeZone};
+use sha2::{Digest, Sha256};
+
+use config::MachineConfig;
+use error::Error;
+use quote::{quote, quote_path};
+use wrapper::{execute_capture, ssh_remote};
+
+/// Remote command printing the SHA-256 digest of `$f`
+const HASH_COMMAND: &str = "{ sha256sum \"$f\" 2>/dev/null || shasum -a 256 \"$f\"; } | cut -d ' ' -f 1";
+
+/// Size and digest of a file
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileDigest {
+    /// Size, in bytes
+    pub size: u64,
+    /// SHA-256 digest, in hexadecimal
+    pub sha256: String
+}
+
+/// Compute the digest of a local file.
+///
+/// # Arguments
+///
+/// * `path` - Local file
+///
+pub fn local_digest(path: &Path) -> Result<FileDigest, Error> {
+    let file = File::open(path).map_err(|e| Error::io(format!("could not open {}", path.display()), e))?;
+    let mut hasher = Sha256::new();
+    let size = io::copy(&mut BufReader::new(file), &mut hasher).map_err(|e| Error::io(format!("could not read {}", path.display()), e))?;
+
+    Ok(FileDigest { size, sha256: hasher.finalize().iter().map(|x| format!("{:02x}", x)).collect() })
+}
+
+/// Build the remote command printing the size and digest of a file, or
+/// `missing`.
+///
+/// # Arguments
+///
+/// * `remote_path` - Remote file
+///
+pub fn digest_command(remote_path: &str) -> String {
+    format!(
+        "f={}; if [ ! -f \"$f\" ]; then echo missing; exit 0; fi; wc -c < \"$f\"; {}",
+        quote_path(remote_path), HASH_COMMAND
+    )
+}
+
+/// Check that a line is a SHA-256 digest
+fn parse_sha256(line: Option<&str>) -> Option<String> {
+    line.filter(|x| x.len() == 64 && x.chars().all(|c| c.is_ascii_hexdigit())).map(|x| x.to_lowercase())
+}
+
+/// Parse the output of the digest command, none if the file is missing.
+///
+/// # Arguments
+///
+/// * `output` - Command output
+///
+pub fn parse_digest(output: &str) -> Result<Option<FileDigest>, Error> {
+    let mut lines = output.lines().map(str::trim);
+    let bad = || Error::Config(format!("unexpected digest output `{}`", output.trim()));
+
+    match lines.next() {
+        Some("missing") => Ok(None),
+        Some(size) => {
+            let size = size.parse().map_err(|_| bad())?;
+            let sha256 = parse_sha256(lines.next()).ok_or_else(bad)?;
+            Ok(Some(FileDigest { size, sha256 }))
+        },
+        None => Err(bad())
+    }
+}
+
+/// Get the digest of a remote file, none if it does not exist.
+///
+/// # Arguments
+///
+/// * `config` - Machine configuration
+/// * `remote_path` - Remote file
+///
+pub fn remote_digest(config: &MachineConfig, remote_path: &str) -> Result<Option<FileDigest>, Error> {
+    config.check_values()?;
+    let output = execute_capture(ssh_remote(config, &digest_command(remote_path)))?;
+    if !output.status.success() {
+        return Err(Error::tool("could not hash the remote file", &String::from_utf8_lossy(&output.stderr)));
+    }
+
+    parse_digest(&String::from_utf8_lossy(&output.stdout))
+}
+
+/// What a file audit checks, besides presence, size and modification time
+#[derive(Debug, Clone, PartialEq)]
+pub enum AuditProbe {
+    /// Nothing more
+    Presence,
+    /// SHA-256 digest
+    Hash,
+    /// Lines matching a `grep` pattern
+    Grep(String)
+}
+
+/// State of an audited file
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileState {
+    /// Size, in bytes
+    pub size: u64,
+    /// Modification time
+    pub modified: DateTime<Local>,
+    /// SHA-256 digest, for hash audits
+    pub sha256: Option<String>,
+    /// Does the file contain the pattern, for grep audits?
+    pub matches: Option<bool>
+}
+
+/// Build the remote command printing the state of a file, or `missing`.
+///
+/// # Arguments
+///
+/// * `remote_path` - Remote file
+/// * `probe` - What is checked
+///
+pub fn audit_command(remote_path: &str, probe: &AuditProbe) -> String {
+    let mut command = format!(
+        "f={}; if [ ! -f \"$f\" ]; then echo missing; exit 0; fi; wc -c < \"$f\"; stat -c %Y \"$f\" 2>/dev/null || stat -f %m \"$f\"",
+        quote_path(remote_path)
+    );
+
+    match *probe {
+        AuditProbe::Presence => (),
+        AuditProbe::Hash => command.push_str(&format!("; {}", HASH_COMMAND)),
+        AuditProbe::Grep(ref pattern) => command.push_str(&format!("; if grep -q -e {} \"$f\"; then echo match; else echo no; fi", quote(pattern)))
+    }
+
+    command
+}
+
+/// Parse the output of the audit command, none if the file is missing.
+///
+/// # Arguments
+///
+/// * `output` - Command output
+/// * `probe` - What was checked
+///
+pub fn parse_audit(output: &str, probe: &AuditProbe) -> Result<Option<FileState>, Error> {
+    let mut lines = output.lines().map(str::trim);
+    let bad = || Error::Config(format!("unexpected audit output `{}`", output.trim()));
+
+    let size = match lines.next() {
+        Some("missing") => return Ok(None),
+        Some(x) => x.parse().map_err(|_| bad())?,
+        None => return Err(bad())
+    };
+    let modified = lines.next().and_then(|x| x.parse().ok()).and_then(|x| Local.timestamp_opt(x, 0).single()).ok_or_else(bad)?;
+
+    let (sha256, matches) = match *probe {
+        AuditProbe::Presence => (None, None),
+        AuditProbe::Hash => (Some(parse_sha256(lines.next()).ok_or_else(bad)?), None),
+        AuditProbe::Grep(_) => match lines.next() {
+            Some("match") => (None, Some(true)),
+            Some("no") => (None, Some(false)),
+            _ => return Err(bad())
+        }
+    };
+
+    Ok(Some(FileState { size, modified, sha256, matches }))
+}
+
+/// Audit a remote file.
+///
+/// # Arguments
+///
+/// * `config` - Machine configuration
+/// * `remote_path` - Remote file
+/// * `probe` - What is checked
+///
+pub fn audit_file(config: &MachineConfig, remote_path: &str, probe: &AuditProbe) -> Result<Option<FileState>, Error> {
+    config.check_values()?;
+    let output = execute_capture(ssh_remote(config, &audit_command(remote_path, probe)))?;
+    if !output.status.success() {
+        return Err(Error::tool("could not audit the remote file", &String::from_utf8_lossy(&output.stderr)));
+    }
+
+    parse_audit(&String::from_utf8_lossy(&output.stdout), probe)
+}
+
+/// Render the audit of a file across machines, one line per machine.
+///
+/// # Arguments
+///
+/// * `rows` - Audit results, per machine
+///
+pub fn render_audit(rows: &[(String, Result<Option<FileState>, Error>)]) -> String {
+    let width = rows.iter().map(|x| x.0.len()).max().unwrap_or(0).max(7);
+    let mut lines = vec![format!("{:w$}  {:>10}  {:19}  DETAILS", "MACHINE", "SIZE", "MODIFIED", w = width)];
+
+    for (name, result) in rows {
+        lines.push(match result {
+            Ok(Some(state)) => {
+                let details = match (&state.sha256, state.matches) {
+                    (Some(sha256), _) => sha256.clone(),
+                    (_, Some(true)) => "matches".to_string(),
+                    (_, Some(false)) => "no match".to_string(),
+                    _ => String::new()
+                };
+                format!("{:w$}  {:>10}  {}  {}", name, state.size, state.modified.format("%Y-%m-%d %H:%M:%S"), details, w = width)
+            },
+            Ok(None) => format!("{:w$}  {:>10}  {:19}  missing", name, "-", "-", w = width),
+            Err(error) => format!("{:w$}  {:>10}  {:19}  error: {}", name, "-", "-", error, w = width)
+        });
+    }
+
+    lines.join("\n")
+}
+
+/// Summarize the versions of a file across machines: the most common digest
+/// first, then the other ones with their machines. Empty when all machines
+/// agree.
+///
+/// # Arguments
+///
+/// * `rows` - Hash audit results, per machine
+///
+pub fn render_versions(rows: &[(String, Result<Option<FileState>, Error>)]) -> String {
+    let mut versions: BTreeMap<String, Vec<&str>> = BTreeMap::new();
+    for (name, result) in rows {
+        let version = match result {
+            Ok(Some(state)) => state.sha256.clone().unwrap_or_default(),
+            Ok(None) => "missing".to_string(),
+            Err(_) => continue
+        };
+        versions.entry(version).or_default().push(name);
+    }
+
+    if versions.len() < 2 {
+        return String::new();
+    }
+
+    let mut versions: Vec<(String, Vec<&str>)> = versions.into_iter().collect();
+    versions.sort_by_key(|x| std::cmp::Reverse(x.1.len()));
+
+    let mut lines = vec![format!("{} versions:", versions.len())];
+    for (index, (version, names)) in versions.iter().enumerate() {
+        let version = if version == "missing" { version.clone() } else { version[..12].to_string() };
+        if index == 0 {
+            lines.push(format!("  {}  {} machine(s)", version, names.len()));
+        } else {
+            lines.push(format!("  {}  {}", version, names.join(", ")));
+        }
+    }
+
+    lines.join("\n")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::env;
+    use std::fs;
+    use std::process::Command;
+
+    #[test]
+    fn digests() {
+        let path = env::temp_dir().join(format!("pssh-compare-{}", std::process::id()));
+        fs::write(&path, "hello\n").unwrap();
+
+        let local = local_digest(&path).unwrap();
+        assert_eq!(local, FileDigest { size: 6, sha256: "5891b5b522d5df086d0ff0b110fbd9d21bb4fc7163af34d08286a2e846f6be03".to_string() });
+
+        let output = Command::new("sh").args(["-c", &digest_command(&path.to_string_lossy())]).output().unwrap();
+        assert_eq!(parse_digest(&String::from_utf8_lossy(&output.stdout)).unwrap(), Some(local));
+        let output = Command::new("sh").args(["-c", &digest_command("/nonexistent/file")]).output().unwrap();
+        assert_eq!(parse_digest(&String::from_utf8_lossy(&output.stdout)).unwrap(), None);
+        assert!(parse_digest("12\nnot a digest\n").is_err());
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn audits() {
+        let path = env::temp_dir().join(format!("pssh-audit-{}", std::process::id()));
+        fs::write(&path, "listen 80;\n").unwrap();
+        let run = |probe: &AuditProbe| {
+            let output = Command::new("sh").args(["-c", &audit_command(&path.to_string_lossy(), probe)]).output().unwrap();
+            parse_audit(&String::from_utf8_lossy(&output.stdout), probe).unwrap()
+        };
+
+        let state = run(&AuditProbe::Hash).unwrap();
+        assert_eq!(state.size, 11);
+        assert_eq!(state.sha256, Some(local_digest(&path).unwrap().sha256));
+        assert_eq!(run(&AuditProbe::Grep("listen [0-9]+".to_string())).unwrap().matches, Some(false));
+        assert_eq!(run(&AuditProbe::Grep("listen [0-9]*;".to_string())).unwrap().matches, Some(true));
+        assert_eq!(parse_audit("missing\n", &AuditProbe::Presence).unwrap(), None);
+        assert!(parse_audit("11\n", &AuditProbe::Presence).is_err());
+
+        let other = FileState { sha256: Some("f".repeat(64)), ..state.clone() };
+        let rows = vec![
+            ("web:one".to_string(), Ok(Some(state.clone()))),
+            ("web:two".to_string(), Ok(Some(state))),
+            ("web:three".to_string(), Ok(Some(other))),
+            ("web:four".to_string(), Ok(None))
+        ];
+        assert!(render_audit(&rows).ends_with("web:four            -  -                    missing"));
+        let versions = render_versions(&rows);
+        assert!(versions.starts_with("3 versions:\n"));
+        assert!(versions.ends_with("  ffffffffffff  web:three\n  missing  web:four") || versions.ends_with("  missing  web:four\n  ffffffffffff  web:three"));
+
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,128 @@
+//! Shell completion
+//!
+//! `pssh completion zsh|fish` prints a completion script. The scripts ask the
+//! hidden `pssh complete` subcommand for subcommands, machine names, and the
+//! remote paths of `push` destinations and `pull` sources.
+//!
+//! Remote paths are only completed through a warm master connection (see
+//! `pssh warm`), so that completing never waits on a handshake or prompts for
+//! a password.
+
+use config::MachineConfig;
+use control::active_control_path;
+use error::Error;
+use quote::quote;
+use wrapper::{execute_capture, ssh_remote};
+
+/// Zsh completion script
+pub const ZSH_SCRIPT: &str = r#"#compdef pssh
+
+_pssh_machines() {
+    compadd -- ${(f)"$(pssh complete machines 2>/dev/null)"}
+}
+
+_pssh_remote_paths() {
+    compadd -U -S '' -- ${(f)"$(pssh complete path ${(Q)words[3]} ${(Q)words[CURRENT]} 2>/dev/null)"}
+}
+
+_pssh() {
+    if (( CURRENT == 2 )); then
+        compadd -- ${(f)"$(pssh complete subcommands 2>/dev/null)"}
+        return
+    fi
+
+    case "$words[2]:$CURRENT" in
+        push:3|pull:3) _pssh_machines ;;
+        push:5|pull:4) _pssh_remote_paths ;;
+        push:4|pull:5) _files ;;
+        *:3) _pssh_machines ;;
+        *) _files ;;
+    esac
+}
+
+_pssh "$@"
+"#;
+
+/// Fish completion script
+pub const FISH_SCRIPT: &str = r#"function __pssh_argument_count
+    test (count (commandline -opc)) -eq $argv[1]
+end
+
+function __pssh_remote_paths
+    set -l tokens (commandline -opc)
+    pssh complete path $tokens[3] (commandline -ct) 2>/dev/null
+end
+
+complete -c pssh -f
+complete -c pssh -n '__pssh_argument_count 1' -a '(pssh complete subcommands 2>/dev/null)'
+complete -c pssh -n '__pssh_argument_count 2; and not __fish_seen_subcommand_from completion help' -a '(pssh complete machines 2>/dev/null)'
+complete -c pssh -n '__fish_seen_subcommand_from push; and __pssh_argument_count 3' -F
+complete -c pssh -n '__fish_seen_subcommand_from push; and __pssh_argument_count 4' -a '(__pssh_remote_paths)'
+complete -c pssh -n '__fish_seen_subcommand_from pull; and __pssh_argument_count 3' -a '(__pssh_remote_paths)'
+complete -c pssh -n '__fish_seen_subcommand_from pull; and __pssh_argument_count 4' -F
+"#;
+
+/// Build the remote command listing the paths starting with a prefix,
+/// directories ending with `/`.
+///
+/// # Arguments
+///
+/// * `prefix` - Path prefix, relative to the home directory or absolute
+///
+pub fn remote_path_command(prefix: &str) -> String {
+    format!("for f in {}*; do if [ -d \"$f\" ]; then echo \"$f/\"; elif [ -e \"$f\" ]; then echo \"$f\"; fi; done", quote(prefix))
+}
+
+/// Complete a remote path through the master connection of a machine.
+///
+/// Nothing is completed when no master connection is running.
+///
+/// # Arguments
+///
+/// * `config` - Machine configuration
+/// * `prefix` - Path prefix, as typed
+///
+pub fn complete_remote_paths(config: &MachineConfig, prefix: &str) -> Result<Vec<String>, Error> {
+    if config.ip.is_none() || active_control_path(config, None).is_none() {
+        return Ok(vec![]);
+    }
+
+    // Commands start in the home directory, where `~/` paths are relative
+    let (home, relative) = match prefix {
+        "~" => ("~/", ""),
+        _ => match prefix.strip_prefix("~/") {
+            Some(relative) => ("~/", relative),
+            None => ("", prefix)
+        }
+    };
+
+    let output = execute_capture(ssh_remote(config, &remote_path_command(relative)))?;
+    if !output.status.success() {
+        return Err(Error::tool("could not list remote paths", &String::from_utf8_lossy(&output.stderr)));
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout).lines().map(|x| format!("{}{}", home, x)).collect())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::env;
+    use std::fs;
+    use std::process::Command;
+
+    #[test]
+    fn remote_paths() {
+        assert_eq!(remote_path_command("/etc/ng"), "for f in /etc/ng*; do if [ -d \"$f\" ]; then echo \"$f/\"; elif [ -e \"$f\" ]; then echo \"$f\"; fi; done");
+        assert!(remote_path_command("my dir/").starts_with("for f in 'my dir/'*;"));
+        assert!(complete_remote_paths(&MachineConfig::default(), "/etc/").unwrap().is_empty());
+
+        let dir = env::temp_dir().join(format!("pssh-complete-{}", std::process::id()));
+        fs::create_dir_all(dir.join("app")).unwrap();
+        fs::write(dir.join("app.conf"), "").unwrap();
+        let output = Command::new("sh").args(["-c", &remote_path_command(&format!("{}/ap", dir.display()))]).output().unwrap();
+        assert_eq!(String::from_utf8_lossy(&output.stdout), format!("{0}/app/\n{0}/app.conf\n", dir.display()));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
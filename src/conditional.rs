@@ -0,0 +1,191 @@
+//! Conditional defaults
+//!
+//! The top-level `match` section holds blocks of values applied only when
+//! all their conditions hold, like the `Match` blocks of `ssh_config`:
+//!
+//! * `host` - glob matching the machine name
+//! * `target` - CIDR holding the machine IP, hostnames never match
+//! * `local` - CIDR holding the local address, detected when the
+//!   configuration is loaded
+//!
+//! A condition starting with `!` is negated, so that a block with
+//! `local: "!10.20.0.0/16"` only applies outside the office network.
+//! Matching blocks apply in order over the defaults and templates, as
+//! conditional defaults: the values set on the machine itself win.
+
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+
+use config::MachineConfig;
+use error::Error;
+use selection::glob_match;
+
+/// Values applied when conditions hold, from the `match` section
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MatchBlock {
+    /// Machine name glob
+    pub host: Option<String>,
+    /// CIDR holding the machine IP
+    pub target: Option<String>,
+    /// CIDR holding the local address
+    pub local: Option<String>,
+    /// Values applied when the conditions hold
+    pub values: MachineConfig
+}
+
+impl MatchBlock {
+    /// Get the conditions, as `(key, condition)`
+    pub fn conditions(&self) -> Vec<(&'static str, &str)> {
+        [("host", &self.host), ("target", &self.target), ("local", &self.local)].iter()
+            .filter_map(|&(key, value)| value.as_deref().map(|x| (key, x)))
+            .collect()
+    }
+
+    /// Describe the conditions, as `host web:* local !10.20.0.0/16`
+    pub fn describe(&self) -> String {
+        let conditions: Vec<String> = self.conditions().iter().map(|(key, value)| format!("{} {}", key, value)).collect();
+        if conditions.is_empty() {
+            return "always".to_string();
+        }
+
+        conditions.join(" ")
+    }
+
+    /// Check that the CIDR conditions are valid
+    pub fn check(&self) -> Result<(), Error> {
+        for (_, cidr) in self.conditions().into_iter().filter(|x| x.0 != "host") {
+            if parse_cidr(cidr.trim_start_matches('!')).is_none() {
+                return Err(Error::Config(format!("bad CIDR `{}`", cidr)));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Check if the block applies to a machine.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - Machine name
+    /// * `config` - Resolved machine configuration
+    /// * `local` - Local address, if known
+    ///
+    pub fn matches(&self, name: &str, config: &MachineConfig, local: Option<IpAddr>) -> bool {
+        let in_cidr = |address: Option<IpAddr>| move |cidr: &str| address.map(|x| cidr_contains(cidr, x)).unwrap_or(false);
+        let target = config.ip.as_ref().and_then(|x| x.parse().ok());
+
+        self.host.as_deref().map(|x| holds(x, |pattern| glob_match(pattern, name))).unwrap_or(true)
+            && self.target.as_deref().map(|x| holds(x, in_cidr(target))).unwrap_or(true)
+            && self.local.as_deref().map(|x| holds(x, in_cidr(local))).unwrap_or(true)
+    }
+}
+
+/// Evaluate a condition, negated by a leading `!`
+fn holds<F: Fn(&str) -> bool>(condition: &str, test: F) -> bool {
+    match condition.strip_prefix('!') {
+        Some(rest) => !test(rest),
+        None => test(condition)
+    }
+}
+
+/// Parse a CIDR, as `10.0.0.0/8` or `fd00::/8`. A lone address is a
+/// single-address network.
+///
+/// # Arguments
+///
+/// * `cidr` - CIDR
+///
+pub fn parse_cidr(cidr: &str) -> Option<(IpAddr, u8)> {
+    let (address, prefix) = match cidr.split_once('/') {
+        Some((address, prefix)) => (address.parse::<IpAddr>().ok()?, prefix.parse().ok()?),
+        None => {
+            let address: IpAddr = cidr.parse().ok()?;
+            (address, if address.is_ipv4() { 32 } else { 128 })
+        }
+    };
+
+    let bits = if address.is_ipv4() { 32 } else { 128 };
+    Some((address, prefix)).filter(|_| prefix <= bits)
+}
+
+/// Check if a CIDR holds an address. Invalid CIDRs hold nothing.
+///
+/// # Arguments
+///
+/// * `cidr` - CIDR
+/// * `address` - Address
+///
+pub fn cidr_contains(cidr: &str, address: IpAddr) -> bool {
+    let (network, prefix) = match parse_cidr(cidr) {
+        Some(x) => x,
+        None => return false
+    };
+
+    match (network, address) {
+        (IpAddr::V4(network), IpAddr::V4(address)) => {
+            let mask = u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0);
+            u32::from(network) & mask == u32::from(address) & mask
+        },
+        (IpAddr::V6(network), IpAddr::V6(address)) => {
+            let mask = u128::MAX.checked_shl(128 - prefix as u32).unwrap_or(0);
+            u128::from(network) & mask == u128::from(address) & mask
+        },
+        _ => false
+    }
+}
+
+/// Get the local address of the default route, none when offline
+pub fn local_address() -> Option<IpAddr> {
+    // No packet is sent, connecting only selects the outgoing interface
+    let route = |local: &str, remote: &str| -> Option<SocketAddr> {
+        let socket = UdpSocket::bind(local).ok()?;
+        socket.connect(remote).ok()?;
+        socket.local_addr().ok()
+    };
+
+    route("0.0.0.0:0", "192.0.2.1:9")
+        .or_else(|| route("[::]:0", "[2001:db8::1]:9"))
+        .map(|x| x.ip())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn cidrs() {
+        let address = |x: &str| x.parse::<IpAddr>().unwrap();
+        assert!(cidr_contains("10.20.0.0/16", address("10.20.3.4")));
+        assert!(!cidr_contains("10.20.0.0/16", address("10.21.0.1")));
+        assert!(cidr_contains("0.0.0.0/0", address("192.168.1.1")));
+        assert!(cidr_contains("192.168.1.5", address("192.168.1.5")));
+        assert!(cidr_contains("fd00::/8", address("fd12::1")));
+        assert!(!cidr_contains("fd00::/8", address("10.0.0.1")));
+        assert!(!cidr_contains("10.0.0.0/33", address("10.0.0.1")));
+        assert_eq!(parse_cidr("office"), None);
+    }
+
+    #[test]
+    fn blocks() {
+        let block = MatchBlock {
+            host: Some("prod:*".to_string()),
+            target: Some("10.1.0.0/16".to_string()),
+            local: Some("!10.20.0.0/16".to_string()),
+            ..Default::default()
+        };
+        let config = MachineConfig { ip: Some("10.1.2.3".to_string()), ..Default::default() };
+        let home = Some("192.168.1.10".parse().unwrap());
+        let office = Some("10.20.0.7".parse().unwrap());
+
+        assert!(block.matches("prod:web", &config, home));
+        assert!(block.matches("prod:web", &config, None));
+        assert!(!block.matches("prod:web", &config, office));
+        assert!(!block.matches("dev:web", &config, home));
+        assert!(!block.matches("prod:web", &MachineConfig { ip: Some("web.example.com".to_string()), ..Default::default() }, home));
+        assert_eq!(block.describe(), "host prod:* target 10.1.0.0/16 local !10.20.0.0/16");
+        assert!(block.check().is_ok());
+
+        let bad = MatchBlock { local: Some("!office".to_string()), ..Default::default() };
+        assert_eq!(bad.check().unwrap_err().to_string(), "bad CIDR `!office`");
+        assert!(MatchBlock::default().matches("any", &config, None));
+    }
+}
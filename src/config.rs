@@ -2,27 +2,208 @@
 
 use std::env;
 use std::fs::File;
-use std::path::PathBuf;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 use std::io::prelude::*;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicBool, Ordering};
 
+use chrono::NaiveDate;
 use yaml_rust::YamlLoader;
 use yaml_rust::Yaml;
+use yaml_rust::yaml::Hash;
+
+use batch::parse_duration;
+use conditional::{local_address, MatchBlock};
+use daemon;
+use error::Error;
+use expand::expand_placeholders;
+use inventory::InventorySource;
+use knock::{parse_knock, DEFAULT_DELAY as DEFAULT_KNOCK_DELAY};
+use location::{detect, locate, Network};
+use migrate::pending_migrations;
+use names::{check_names, validate_key};
+use notify::NotifyMode;
+use script::generate_machines;
+use title::DEFAULT_TITLE_FORMAT;
+use webhook::{Webhook, WebhookFormat};
+use wrapper::CompatPreset;
+
+/// Strict mode forced from the command line
+static STRICT_MODE: AtomicBool = AtomicBool::new(false);
 
 /// Contains a machine configuration
-#[derive(Debug, Clone, Default)]
+#[derive(Debug, Clone, Default, PartialEq)]
 pub struct MachineConfig {
     /// IP to use
     pub ip: Option<String>,
-    /// Port to use
-    pub port: Option<u16>,
+    /// IPs per network location, as `(location, ip)`, in order
+    pub location_ips: Option<Vec<(String, String)>>,
+    /// Candidate ports, tried in order
+    pub port: Option<Vec<u16>>,
     /// Username to use
     pub user: Option<String>,
     /// Password to use
     pub pass: Option<String>,
-    /// Identity key to use
-    pub identity: Option<String>
+    /// Identity keys to use, all offered
+    pub identity: Option<Vec<String>>,
+    /// Jump hosts, as `[user@]host[:port]`, tried in order until one is
+    /// reachable
+    pub via: Option<Vec<String>>,
+    /// Command connecting to the machine (`ProxyCommand`), with the `%h` and
+    /// `%p` tokens of `ssh` and placeholders like `{machine}`, used instead
+    /// of jump hosts
+    pub proxy_command: Option<String>,
+    /// Free-form tags
+    pub tags: Option<Vec<String>>,
+    /// Template name, from the `templates` section
+    pub template: Option<String>,
+    /// Require confirmations for operations on this machine
+    pub protected: Option<bool>,
+    /// Remote command forced by restricted connections
+    pub restricted_command: Option<String>,
+    /// Require explicit addresses and credentials
+    pub strict: Option<bool>,
+    /// Cipher list (`-o Ciphers=`)
+    pub ciphers: Option<String>,
+    /// MAC algorithm list (`-o MACs=`)
+    pub macs: Option<String>,
+    /// Key exchange algorithm list (`-o KexAlgorithms=`)
+    pub kex: Option<String>,
+    /// Host key algorithm list (`-o HostKeyAlgorithms=`)
+    pub host_key_algorithms: Option<String>,
+    /// Compatibility preset name, as `legacy`
+    pub compat: Option<String>,
+    /// Out-of-band console access
+    pub console: Option<ConsoleConfig>,
+    /// Terminal type for interactive sessions (`TERM`)
+    pub term: Option<String>,
+    /// Remote locale (`LC_ALL`)
+    pub locale: Option<String>,
+    /// Notice shown before connecting
+    pub banner: Option<String>,
+    /// Hide server banners
+    pub quiet: Option<bool>,
+    /// Add the identities to the SSH agent before connecting
+    pub auto_add_key: Option<bool>,
+    /// Socket of the SSH agent to use (`SSH_AUTH_SOCK`)
+    pub agent_socket: Option<String>,
+    /// Port knocking sequence sent before connecting, as `7000` or
+    /// `8000/udp`
+    pub knock: Option<Vec<String>>,
+    /// Delay after each knock, as `200ms`
+    pub knock_delay: Option<String>,
+    /// VPN reaching the machine, offered when it is unreachable
+    pub vpn: Option<VpnConfig>,
+    /// Date from which the entry is expired, as `2025-09-01`
+    pub expires: Option<String>,
+    /// Prompts answered after connecting, in order, for login menus and
+    /// secondary password prompts
+    pub expect: Option<Vec<ExpectStep>>,
+    /// Free-form variables, for command placeholders, hooks and plugins
+    pub vars: Option<BTreeMap<String, String>>,
+    /// Rank in batch runs, lower values first
+    pub priority: Option<i64>,
+    /// Role in its cluster, as `primary` or `replica`
+    pub role: Option<String>,
+    /// Person or team owning the machine
+    pub owner: Option<String>,
+    /// How to reach the owner, as an email address or a chat channel
+    pub contact: Option<String>,
+    /// Remote command run after successful pushes
+    pub on_push: Option<String>,
+    /// Keep modes and modification times in copies
+    pub preserve: Option<bool>,
+    /// Copy the targets of symbolic links, instead of the links
+    pub follow_symlinks: Option<bool>
+}
+
+/// Out-of-band console access, from the `console` machine value
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConsoleConfig {
+    /// IPMI serial-over-LAN, through the BMC address
+    Ipmi {
+        /// BMC address
+        host: String,
+        /// BMC user
+        user: Option<String>,
+        /// BMC password, asked by `ipmitool` when missing
+        pass: Option<String>
+    },
+    /// Console managed by a conserver server
+    Conserver {
+        /// Console name
+        name: String,
+        /// conserver master host, the client default when missing
+        master: Option<String>
+    }
+}
+
+/// Prompt answered after connecting, from the `expect` machine value
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExpectStep {
+    /// Text waited for in the session output
+    pub prompt: String,
+    /// Line sent when the prompt is seen, without line ending
+    pub response: String
+}
+
+/// VPN reaching a machine, from the `vpn` machine value
+#[derive(Debug, Clone, PartialEq)]
+pub struct VpnConfig {
+    /// Local command bringing the VPN up
+    pub up: String,
+    /// Local command succeeding when the VPN is up, the VPN being considered
+    /// down when missing
+    pub check: Option<String>
+}
+
+impl ConsoleConfig {
+    /// Describe the console, without password
+    pub fn describe(&self) -> String {
+        match *self {
+            ConsoleConfig::Ipmi { ref host, ref user, .. } => match *user {
+                Some(ref user) => format!("ipmi {}@{}", user, host),
+                None => format!("ipmi {}", host)
+            },
+            ConsoleConfig::Conserver { ref name, ref master } => match *master {
+                Some(ref master) => format!("conserver {}@{}", name, master),
+                None => format!("conserver {}", name)
+            }
+        }
+    }
+}
+
+/// Global settings, from the `settings` section
+#[derive(Debug, Clone)]
+pub struct Settings {
+    /// Machine count above which fleet operations must be confirmed
+    pub confirm_threshold: usize,
+    /// Notification mode for long operations
+    pub notify: NotifyMode,
+    /// Minimum operation duration for notifications, in seconds
+    pub notify_after: u64,
+    /// Failure count after which batch runs start no more machines
+    pub max_failures: Option<usize>,
+    /// Terminal title format of interactive sessions, empty to keep titles
+    pub title_format: String,
+    /// SSH agent socket of machines without `agent_socket`
+    pub identity_agent: Option<String>
+}
+
+impl Default for Settings {
+    fn default() -> Settings {
+        Settings {
+            confirm_threshold: 5,
+            notify: NotifyMode::Off,
+            notify_after: 10,
+            max_failures: None,
+            title_format: DEFAULT_TITLE_FORMAT.to_string(),
+            identity_agent: None
+        }
+    }
 }
 
 /// Configuration map
@@ -33,8 +214,26 @@ pub type ConfigMap = HashMap<String, MachineConfig>;
 pub struct ConfigResult {
     /// Default values for machines
     pub default_values: ConfigMap,
+    /// Named templates
+    pub templates: ConfigMap,
+    /// Global settings
+    pub settings: Settings,
+    /// Webhooks notified of fleet jobs
+    pub webhooks: Vec<Webhook>,
     /// Actual machine values
-    pub machine_values: ConfigMap
+    pub machine_values: ConfigMap,
+    /// Values set on the machines themselves, without defaults and templates
+    pub own_values: ConfigMap,
+    /// Fields filled from the executing environment, per machine
+    pub fallbacks: HashMap<String, Vec<&'static str>>,
+    /// Conditional values, from the `match` section
+    pub match_blocks: Vec<MatchBlock>,
+    /// Conditions of the blocks applied, per machine
+    pub matched: HashMap<String, Vec<String>>,
+    /// Network locations, from the `networks` section
+    pub networks: Vec<Network>,
+    /// Current network location, if detected
+    pub location: Option<String>
 }
 
 impl MachineConfig {
@@ -47,12 +246,19 @@ impl MachineConfig {
     pub fn merge(&self, other: &MachineConfig) -> MachineConfig {
         let mut config = self.clone();
         
+        // A single IP and IPs per location replace each other
         if other.ip.is_some() {
             config.ip = other.ip.clone();
+            config.location_ips = None;
+        }
+
+        if other.location_ips.is_some() {
+            config.ip = None;
+            config.location_ips = other.location_ips.clone();
         }
         
         if other.port.is_some() {
-            config.port = other.port;
+            config.port = other.port.clone();
         }
         
         if other.user.is_some() {
@@ -66,10 +272,351 @@ impl MachineConfig {
         if other.identity.is_some() {
             config.identity = other.identity.clone();
         }
+
+        if other.via.is_some() {
+            config.via = other.via.clone();
+        }
+
+        if other.proxy_command.is_some() {
+            config.proxy_command = other.proxy_command.clone();
+        }
+
+        if other.tags.is_some() {
+            config.tags = other.tags.clone();
+        }
+
+        if other.template.is_some() {
+            config.template = other.template.clone();
+        }
+
+        if other.protected.is_some() {
+            config.protected = other.protected;
+        }
+
+        if other.restricted_command.is_some() {
+            config.restricted_command = other.restricted_command.clone();
+        }
+
+        if other.strict.is_some() {
+            config.strict = other.strict;
+        }
+
+        if other.ciphers.is_some() {
+            config.ciphers = other.ciphers.clone();
+        }
+
+        if other.macs.is_some() {
+            config.macs = other.macs.clone();
+        }
+
+        if other.kex.is_some() {
+            config.kex = other.kex.clone();
+        }
+
+        if other.host_key_algorithms.is_some() {
+            config.host_key_algorithms = other.host_key_algorithms.clone();
+        }
+
+        if other.compat.is_some() {
+            config.compat = other.compat.clone();
+        }
+
+        if other.console.is_some() {
+            config.console = other.console.clone();
+        }
+
+        if other.term.is_some() {
+            config.term = other.term.clone();
+        }
+
+        if other.locale.is_some() {
+            config.locale = other.locale.clone();
+        }
+
+        if other.banner.is_some() {
+            config.banner = other.banner.clone();
+        }
+
+        if other.quiet.is_some() {
+            config.quiet = other.quiet;
+        }
+
+        if other.auto_add_key.is_some() {
+            config.auto_add_key = other.auto_add_key;
+        }
+
+        if other.agent_socket.is_some() {
+            config.agent_socket = other.agent_socket.clone();
+        }
+
+        if other.vpn.is_some() {
+            config.vpn = other.vpn.clone();
+        }
+
+        if other.knock.is_some() {
+            config.knock = other.knock.clone();
+        }
+
+        if other.knock_delay.is_some() {
+            config.knock_delay = other.knock_delay.clone();
+        }
+
+        if other.expires.is_some() {
+            config.expires = other.expires.clone();
+        }
+
+        if other.expect.is_some() {
+            config.expect = other.expect.clone();
+        }
+
+        if other.priority.is_some() {
+            config.priority = other.priority;
+        }
+
+        if other.role.is_some() {
+            config.role = other.role.clone();
+        }
+
+        if other.owner.is_some() {
+            config.owner = other.owner.clone();
+        }
+
+        if other.contact.is_some() {
+            config.contact = other.contact.clone();
+        }
+
+        if other.on_push.is_some() {
+            config.on_push = other.on_push.clone();
+        }
+
+        if other.preserve.is_some() {
+            config.preserve = other.preserve;
+        }
+
+        if other.follow_symlinks.is_some() {
+            config.follow_symlinks = other.follow_symlinks;
+        }
+
+        // Variables are merged one by one, unlike other values
+        if let Some(ref vars) = other.vars {
+            let mut merged = config.vars.take().unwrap_or_default();
+            merged.extend(vars.iter().map(|(k, v)| (k.clone(), v.clone())));
+            config.vars = Some(merged);
+        }
         
         config
     }
     
+    /// Check if the machine is protected
+    pub fn is_protected(&self) -> bool {
+        self.protected.unwrap_or(false)
+    }
+
+    /// Check if server banners are hidden
+    pub fn is_quiet(&self) -> bool {
+        self.quiet.unwrap_or(false)
+    }
+
+    /// Get the expiration date, if set and valid
+    pub fn expiration(&self) -> Option<NaiveDate> {
+        self.expires.as_ref().and_then(|x| NaiveDate::parse_from_str(x, "%Y-%m-%d").ok())
+    }
+
+    /// Check if the entry is expired on a day
+    ///
+    /// # Arguments
+    ///
+    /// * `today` - Current day
+    ///
+    pub fn is_expired(&self, today: NaiveDate) -> bool {
+        self.expiration().map(|x| x <= today).unwrap_or(false)
+    }
+
+    /// Get the SSH agent socket, with `~/` expanded
+    pub fn agent_socket_path(&self) -> Option<PathBuf> {
+        self.agent_socket.as_ref().map(|x| expand_home(x))
+    }
+
+    /// Get the port knocking sequence
+    pub fn knocks(&self) -> &[String] {
+        self.knock.as_ref().map(|x| &x[..]).unwrap_or(&[])
+    }
+
+    /// Get the delay after each knock
+    pub fn knock_delay(&self) -> Duration {
+        self.knock_delay.as_ref().and_then(|x| parse_duration(x)).unwrap_or(DEFAULT_KNOCK_DELAY)
+    }
+
+    /// Check if identities are added to the SSH agent before connecting
+    pub fn adds_keys(&self) -> bool {
+        self.auto_add_key.unwrap_or(false)
+    }
+
+    /// Check if copies keep modes and modification times
+    pub fn is_preserving(&self) -> bool {
+        self.preserve.unwrap_or(false)
+    }
+
+    /// Check if copies follow symbolic links, as `scp` does
+    pub fn follows_symlinks(&self) -> bool {
+        self.follow_symlinks.unwrap_or(true)
+    }
+
+    /// Check if the machine is in strict mode
+    pub fn is_strict(&self) -> bool {
+        self.strict.unwrap_or(false)
+    }
+
+    /// Get the candidate ports, defaulting to 22
+    pub fn ports(&self) -> Vec<u16> {
+        match self.port {
+            Some(ref x) if !x.is_empty() => x.clone(),
+            _ => vec![22]
+        }
+    }
+
+    /// Get the first candidate port, defaulting to 22
+    pub fn first_port(&self) -> u16 {
+        self.ports()[0]
+    }
+
+    /// Get the identity keys
+    pub fn identities(&self) -> &[String] {
+        self.identity.as_ref().map(|x| &x[..]).unwrap_or(&[])
+    }
+
+    /// Get the jump host candidates
+    pub fn jump_hosts(&self) -> &[String] {
+        self.via.as_ref().map(|x| &x[..]).unwrap_or(&[])
+    }
+
+    /// Check that the values needed to build commands are set: the IP, and
+    /// in strict mode the user and the identity (or password). The
+    /// compatibility preset must also be known, and the terminal type and
+    /// locale must be single words.
+    pub fn check_values(&self) -> Result<(), Error> {
+        if let Some(ref compat) = self.compat {
+            if CompatPreset::from_name(compat).is_none() {
+                return Err(Error::Config(format!("unknown compat preset `{}`", compat)));
+            }
+        }
+        for &(key, value) in &[("term", &self.term), ("locale", &self.locale)] {
+            if let Some(ref value) = *value {
+                if value.is_empty() || value.contains(|c: char| c.is_whitespace() || c == '"' || c == '\'') {
+                    return Err(Error::Config(format!("bad {} `{}`", key, value)));
+                }
+            }
+        }
+        if let Some(ref expires) = self.expires {
+            if self.expiration().is_none() {
+                return Err(Error::Config(format!("bad expiration date `{}`", expires)));
+            }
+        }
+        for jump in self.jump_hosts() {
+            if jump.is_empty() || jump.contains(char::is_whitespace) {
+                return Err(Error::Config(format!("bad jump host `{}`", jump)));
+            }
+        }
+        if let Some(knock) = self.knocks().iter().find(|x| parse_knock(x).is_none()) {
+            return Err(Error::Config(format!("bad knock `{}`", knock)));
+        }
+        if let Some(ref delay) = self.knock_delay {
+            if parse_duration(delay).is_none() {
+                return Err(Error::Config(format!("bad knock delay `{}`", delay)));
+            }
+        }
+
+        let mut missing = vec![];
+        if self.ip.is_none() {
+            missing.push("ip");
+        }
+        if self.is_strict() {
+            if self.user.is_none() {
+                missing.push("user");
+            }
+            if self.identity.is_none() && self.pass.is_none() {
+                missing.push("identity");
+            }
+        }
+
+        if missing.is_empty() {
+            Ok(())
+        } else if self.is_strict() {
+            Err(Error::Config(format!("missing {} (strict mode)", missing.join(", "))))
+        } else {
+            Err(Error::Config(format!("missing {}", missing.join(", "))))
+        }
+    }
+
+    /// Format the machine address as `user@ip:port`.
+    ///
+    /// Missing user is omitted, missing port defaults to 22. Only the first
+    /// candidate port is shown.
+    pub fn address(&self) -> String {
+        let ip = self.ip.as_ref().map(|x| &x[..]).unwrap_or("?");
+        let port = self.first_port();
+
+        match self.user {
+            Some(ref user) => format!("{}@{}:{}", user, ip, port),
+            None => format!("{}:{}", ip, port)
+        }
+    }
+
+    /// Parse an ad-hoc `[user@]host[:port]` address, as for machines which are
+    /// not in the configuration. IPv6 addresses with a port must be written
+    /// between brackets.
+    ///
+    /// To avoid confusion with machine names, the address must have a user,
+    /// a port, or a dotted or IPv6 host.
+    ///
+    /// # Arguments
+    ///
+    /// * `address` - Address
+    ///
+    pub fn from_address(address: &str) -> Option<MachineConfig> {
+        let (user, host_port) = match address.rfind('@') {
+            Some(index) => (Some(&address[..index]), &address[index + 1..]),
+            None => (None, address)
+        };
+        if user == Some("") {
+            return None;
+        }
+
+        let (host, port) = if host_port.starts_with('[') {
+            let end = host_port.find(']')?;
+            let port = match &host_port[end + 1..] {
+                "" => None,
+                rest => Some(rest.strip_prefix(':')?)
+            };
+            (&host_port[1..end], port)
+        } else if host_port.matches(':').count() > 1 {
+            (host_port, None)
+        } else {
+            match host_port.find(':') {
+                Some(index) => (&host_port[..index], Some(&host_port[index + 1..])),
+                None => (host_port, None)
+            }
+        };
+
+        let port = match port {
+            Some(x) => Some(x.parse::<u16>().ok()?),
+            None => None
+        };
+        let valid_host = !host.is_empty()
+            && host.chars().all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '-' || c == ':');
+        let explicit = user.is_some() || port.is_some() || host.contains('.') || host.contains(':');
+        if !valid_host || !explicit {
+            return None;
+        }
+
+        Some(MachineConfig {
+            ip: Some(host.to_string()),
+            port: port.map(|x| vec![x]),
+            user: user.map(String::from),
+            ..Default::default()
+        })
+    }
+
     /// Show machine information to stdout.
     ///
     /// # Arguments
@@ -79,156 +626,1072 @@ impl MachineConfig {
     pub fn show_info(&self, machine: &str) {
         println!("Configuration for `{}`:", machine);
     
-        self.ip.as_ref().map(|x| println!("  IP: {}", x));
-        self.port.as_ref().map(|x| println!("  Port: {}", x));
-        self.user.as_ref().map(|x| println!("  User: {}", x));
-        self.pass.as_ref().map(|_| println!("  Pass: *******"));
-        self.identity.as_ref().map(|x| println!("  Identity: {}", x));
+        if let Some(ref x) = self.ip {
+            println!("  IP: {}", x);
+        }
+        if let Some(ref x) = self.location_ips {
+            let ips: Vec<String> = x.iter().map(|(location, ip)| format!("{}={}", location, ip)).collect();
+            println!("  IP per location: {}", ips.join(", "));
+        }
+        if let Some(ref x) = self.port {
+            let ports: Vec<String> = x.iter().map(|x| x.to_string()).collect();
+            println!("  Port: {}", ports.join(", "));
+        }
+        if let Some(ref x) = self.user {
+            println!("  User: {}", x);
+        }
+        if self.pass.is_some() {
+            println!("  Pass: *******");
+        }
+        if let Some(ref x) = self.identity {
+            println!("  Identity: {}", x.join(", "));
+        }
+        if let Some(ref x) = self.via {
+            println!("  Via: {}", x.join(", "));
+        }
+        if let Some(ref x) = self.proxy_command {
+            println!("  Proxy command: {}", x);
+        }
+        if let Some(ref x) = self.tags {
+            println!("  Tags: {}", x.join(", "));
+        }
+        if let Some(ref x) = self.template {
+            println!("  Template: {}", x);
+        }
+        if self.is_protected() {
+            println!("  Protected: yes");
+        }
+        if let Some(ref x) = self.restricted_command {
+            println!("  Restricted command: {}", x);
+        }
+        if self.is_strict() {
+            println!("  Strict: yes");
+        }
+        if let Some(ref x) = self.ciphers {
+            println!("  Ciphers: {}", x);
+        }
+        if let Some(ref x) = self.macs {
+            println!("  MACs: {}", x);
+        }
+        if let Some(ref x) = self.kex {
+            println!("  KEX: {}", x);
+        }
+        if let Some(ref x) = self.host_key_algorithms {
+            println!("  Host key algorithms: {}", x);
+        }
+        if let Some(ref x) = self.compat {
+            println!("  Compat: {}", x);
+        }
+        if let Some(ref x) = self.console {
+            println!("  Console: {}", x.describe());
+        }
+        if let Some(ref x) = self.term {
+            println!("  Term: {}", x);
+        }
+        if let Some(ref x) = self.locale {
+            println!("  Locale: {}", x);
+        }
+        if let Some(ref x) = self.banner {
+            println!("  Banner: {}", x);
+        }
+        if self.is_quiet() {
+            println!("  Quiet: yes");
+        }
+        if self.adds_keys() {
+            println!("  Auto add key: yes");
+        }
+        if let Some(ref x) = self.agent_socket {
+            println!("  Agent socket: {}", x);
+        }
+        if let Some(ref x) = self.knock {
+            println!("  Knock: {}", x.join(", "));
+        }
+        if let Some(ref x) = self.vpn {
+            match x.check {
+                Some(ref check) => println!("  VPN: {} (checked with `{}`)", x.up, check),
+                None => println!("  VPN: {}", x.up)
+            }
+        }
+        if let Some(ref x) = self.expires {
+            println!("  Expires: {}", x);
+        }
+        if let Some(ref x) = self.expect {
+            // Responses may be passwords
+            let prompts: Vec<String> = x.iter().map(|x| format!("`{}`", x.prompt)).collect();
+            println!("  Expect: {}", prompts.join(", "));
+        }
+        if self.is_preserving() {
+            println!("  Preserve: yes");
+        }
+        if !self.follows_symlinks() {
+            println!("  Follow symlinks: no");
+        }
+        if let Some(x) = self.priority {
+            println!("  Priority: {}", x);
+        }
+        if let Some(ref x) = self.role {
+            println!("  Role: {}", x);
+        }
+        if let Some(ref x) = self.owner {
+            println!("  Owner: {}", x);
+        }
+        if let Some(ref x) = self.contact {
+            println!("  Contact: {}", x);
+        }
+        if let Some(ref x) = self.on_push {
+            println!("  On push: {}", x);
+        }
+        if let Some(ref x) = self.vars {
+            let vars: Vec<String> = x.iter().map(|(k, v)| format!("{}={}", k, v)).collect();
+            println!("  Vars: {}", vars.join(", "));
+        }
+    }
+}
+
+impl ConfigResult {
+    /// Fill missing users and identities with fallback values, recording the
+    /// filled fields in `fallbacks`. Strict machines are left untouched.
+    ///
+    /// # Arguments
+    ///
+    /// * `fallback` - Fallback values
+    ///
+    pub fn apply_fallbacks(&mut self, fallback: &MachineConfig) {
+        for (name, config) in self.machine_values.iter_mut().filter(|x| !x.1.is_strict()) {
+            let mut fields = vec![];
+            if config.user.is_none() && fallback.user.is_some() {
+                config.user = fallback.user.clone();
+                fields.push("user");
+            }
+            if config.identity.is_none() && fallback.identity.is_some() {
+                config.identity = fallback.identity.clone();
+                fields.push("identity");
+            }
+
+            if !fields.is_empty() {
+                self.fallbacks.insert(name.clone(), fields);
+            }
+        }
+    }
+
+    /// Select the IP of the current location for machines with IPs per
+    /// location, or their first one.
+    ///
+    /// # Arguments
+    ///
+    /// * `location` - Current network location, if detected
+    ///
+    pub fn apply_location(&mut self, location: Option<String>) {
+        for config in self.machine_values.values_mut() {
+            if let Some(ref ips) = config.location_ips {
+                let ip = ips.iter().find(|x| Some(&x.0) == location.as_ref()).or_else(|| ips.first());
+                config.ip = ip.map(|x| x.1.clone());
+            }
+        }
+        self.location = location;
+    }
+
+    /// Apply the matching conditional blocks over the machine values.
+    ///
+    /// Blocks are conditional defaults: their values override the defaults
+    /// and templates, and the values set on the machine itself override
+    /// them.
+    ///
+    /// # Arguments
+    ///
+    /// * `local` - Local address, if known
+    ///
+    pub fn apply_match_blocks(&mut self, local: Option<IpAddr>) {
+        for (name, config) in self.machine_values.iter_mut() {
+            // Conditions are evaluated on the values before any block applies
+            let resolved = config.clone();
+            let mut applied = false;
+            for block in self.match_blocks.iter().filter(|x| x.matches(name, &resolved, local)) {
+                *config = config.merge(&block.values);
+                self.matched.entry(name.clone()).or_default().push(block.describe());
+                applied = true;
+            }
+            if !applied {
+                continue;
+            }
+
+            // The IP of machines with IPs per location is already selected
+            let mut own = self.own_values.get(name).cloned().unwrap_or_default();
+            if own.location_ips.is_some() {
+                own.location_ips = None;
+                own.ip = resolved.ip.clone();
+            }
+            *config = config.merge(&own);
+        }
+    }
+
+    /// Expand the placeholders of proxy commands, once the machine values are
+    /// final.
+    pub fn expand_proxy_commands(&mut self) -> Result<(), Error> {
+        for (name, config) in self.machine_values.iter_mut() {
+            expand_proxy_command(name, config)?;
+        }
+
+        Ok(())
+    }
+
+    /// Add a transient machine for an ad-hoc `[user@]host[:port]` target,
+    /// unless the target is a configured machine. Global defaults apply.
+    ///
+    /// Returns `true` when the machine was added.
+    ///
+    /// # Arguments
+    ///
+    /// * `target` - Target address
+    ///
+    pub fn add_ad_hoc_machine(&mut self, target: &str) -> Result<bool, Error> {
+        if self.machine_values.contains_key(target) {
+            return Ok(false);
+        }
+
+        match MachineConfig::from_address(target) {
+            Some(config) => {
+                let mut config = self.default_values.get("").cloned().unwrap_or_default().merge(&config);
+                if STRICT_MODE.load(Ordering::Relaxed) {
+                    config.strict = Some(true);
+                }
+                expand_proxy_command(target, &mut config)?;
+                self.machine_values.insert(target.to_string(), config);
+                Ok(true)
+            },
+            None => Ok(false)
+        }
+    }
+}
+
+/// Expand the placeholders of a machine proxy command, if any. The `%h` and
+/// `%p` tokens are left to `ssh`.
+fn expand_proxy_command(name: &str, config: &mut MachineConfig) -> Result<(), Error> {
+    let command = match config.proxy_command {
+        Some(ref command) => command.clone(),
+        None => return Ok(())
+    };
+
+    let expanded = expand_placeholders(&command, name, config)
+        .map_err(|x| Error::Config(format!("could not expand the proxy command of `{}`: {}", name, x)))?;
+    config.proxy_command = Some(expanded);
+    Ok(())
+}
+
+/// Force strict mode on all machines loaded from configuration files
+pub fn enable_strict_mode() {
+    STRICT_MODE.store(true, Ordering::Relaxed);
+}
+
+/// Load a configuration from a file path.
+///
+/// If no path is given, the user configuration path will be used.
+///
+/// # Arguments
+///
+/// * `path_to_file` - Path to file (optional)
+///
+pub fn load_configuration_file(path_to_file: Option<&str>) -> Result<ConfigResult, Error> {
+    let path_to_file = get_configuration_path(path_to_file);
+    
+    let mut result = match daemon::resolve(&path_to_file) {
+        Some(result) => result,
+        None => {
+            debug!("Loading {}...", path_to_file);    
+            let mut contents = String::new();    
+            File::open(&path_to_file).and_then(|mut f| f.read_to_string(&mut contents))
+                .map_err(|e| Error::io(format!("could not read {}", path_to_file), e))?;
+
+            let errors = check_names(&contents, Some(&path_to_file));
+            if !errors.is_empty() {
+                return Err(Error::Names(errors));
+            }
+
+            load_configuration_string_in(&contents, configuration_dir(&path_to_file))?
+        }
+    };
+    // The local network may change between two runs, locations and blocks
+    // are applied per run
+    if result.machine_values.values().any(|x| x.location_ips.is_some()) {
+        let location = locate(&result.networks, &detect(&result.networks)).map(String::from);
+        result.apply_location(location);
+    }
+    if !result.match_blocks.is_empty() {
+        let local = if result.match_blocks.iter().any(|x| x.local.is_some()) { local_address() } else { None };
+        result.apply_match_blocks(local);
+    }
+    if STRICT_MODE.load(Ordering::Relaxed) {
+        for config in result.machine_values.values_mut() {
+            config.strict = Some(true);
+        }
+    }
+    result.apply_fallbacks(&environment_fallbacks());
+    result.expand_proxy_commands()?;
+    Ok(result)
+}
+
+/// Get the directory of a configuration file, which relative paths of the
+/// configuration are resolved against.
+///
+/// # Arguments
+///
+/// * `path` - Configuration file path
+///
+pub fn configuration_dir(path: &str) -> &Path {
+    Path::new(path).parent().unwrap_or_else(|| Path::new(""))
+}
+
+/// Load a configuration from a string, relative paths being resolved
+/// against the working directory.
+///
+/// # Arguments
+///
+/// * `contents` - Contents string
+///
+pub fn load_configuration_string(contents: &str) -> Result<ConfigResult, Error> {
+    load_configuration_string_in(contents, Path::new(""))
+}
+
+/// Load a configuration from a string, read from a file in a directory:
+/// relative generator paths are resolved against it.
+///
+/// # Arguments
+///
+/// * `contents` - Contents string
+/// * `dir` - Directory of the configuration file
+///
+pub fn load_configuration_string_in(contents: &str, dir: &Path) -> Result<ConfigResult, Error> {
+    let docs = YamlLoader::load_from_str(contents).map_err(|e| Error::Config(format!("bad YAML: {}", e)))?;
+    let doc = docs.first().ok_or_else(|| Error::Config("empty configuration".to_string()))?;
+    let pending = pending_migrations(doc)?;
+    if !pending.is_empty() {
+        let changes: Vec<&str> = pending.iter().map(|x| x.description).collect();
+        warn!("The configuration layout is outdated ({}), run `pssh config upgrade`.", changes.join(", "));
+    }
+    let doc = doc.as_hash().ok_or_else(|| Error::Config("the configuration must be a map".to_string()))?;
+        
+    let section = |key: &str| doc.get(&Yaml::from_str(key)).ok_or_else(|| Error::Config(format!("missing `{}` section", key)));
+    let default_values = section("defaults")?;
+    let machine_values = section("machines")?;
+    let template_values = doc.get(&Yaml::from_str("templates"));
+    let settings_values = doc.get(&Yaml::from_str("settings"));
+    let notification_values = doc.get(&Yaml::from_str("notifications"));
+    let match_values = doc.get(&Yaml::from_str("match"));
+    let network_values = doc.get(&Yaml::from_str("networks"));
+    
+    let mut default_map = extract_definition_keys("", default_values)?;
+    let template_map = template_values.map(extract_templates).transpose()?.unwrap_or_default();
+    let mut machine_map = extract_definition_keys("", machine_values)?;
+    if let Some(includes) = doc.get(&Yaml::from_str("include")) {
+        merge_included_inventories(&mut default_map, &mut machine_map, includes)?;
+    }
+    if let Some(generators) = doc.get(&Yaml::from_str("generators")) {
+        machine_map = merge_generated_machines(machine_map, generators, dir)?;
+    }
+    let own_map = machine_map.clone();
+    let mut machine_map = apply_machine_configurations(&machine_map, &default_map, &template_map)?;
+    let settings = settings_values.map(extract_settings).transpose()?.unwrap_or_default();
+    if let Some(ref socket) = settings.identity_agent {
+        for config in machine_map.values_mut().filter(|x| x.agent_socket.is_none()) {
+            config.agent_socket = Some(socket.clone());
+        }
+    }
+    
+    Ok(ConfigResult {
+        default_values: default_map,
+        templates: template_map,
+        settings,
+        webhooks: notification_values.map(extract_webhooks).transpose()?.unwrap_or_default(),
+        machine_values: machine_map,
+        own_values: own_map,
+        fallbacks: HashMap::new(),
+        match_blocks: match_values.map(extract_match_blocks).transpose()?.unwrap_or_default(),
+        matched: HashMap::new(),
+        networks: network_values.map(extract_networks).transpose()?.unwrap_or_default(),
+        location: None
+    })
+}
+
+/// Convert a loaded configuration to a resolved document, where `defaults`,
+/// `templates` and `machines` map full names to their final values, and `own`
+/// to the values set on the machines themselves. Settings, notifications and
+/// conditional blocks are copied from the original document, as well as
+/// network locations.
+///
+/// # Arguments
+///
+/// * `result` - Loaded configuration
+/// * `doc` - Original configuration document
+///
+pub fn resolved_configuration_to_yaml(result: &ConfigResult, doc: &Yaml) -> Yaml {
+    let section = |map: &ConfigMap| {
+        let mut names: Vec<&String> = map.keys().collect();
+        names.sort();
+        Yaml::Hash(names.into_iter().map(|x| (Yaml::String(x.clone()), machine_values_to_yaml(&map[x]))).collect())
+    };
+
+    let mut values = Hash::new();
+    for key in &["settings", "notifications", "match", "networks"] {
+        if !doc[*key].is_badvalue() {
+            values.insert(Yaml::from_str(key), doc[*key].clone());
+        }
+    }
+    values.insert(Yaml::from_str("defaults"), section(&result.default_values));
+    values.insert(Yaml::from_str("templates"), section(&result.templates));
+    values.insert(Yaml::from_str("machines"), section(&result.machine_values));
+    values.insert(Yaml::from_str("own"), section(&result.own_values));
+
+    Yaml::Hash(values)
+}
+
+/// Load a configuration from a resolved document, as built by
+/// `resolved_configuration_to_yaml`.
+///
+/// # Arguments
+///
+/// * `data` - Resolved document
+///
+pub fn load_resolved_configuration(data: &Yaml) -> Result<ConfigResult, Error> {
+    let section = |key: &str| -> ConfigMap {
+        data[key].as_hash()
+            .map(|x| x.iter().filter_map(|(k, v)| Some((k.as_str()?.to_string(), extract_machine_values(v)))).collect())
+            .unwrap_or_default()
+    };
+
+    Ok(ConfigResult {
+        default_values: section("defaults"),
+        templates: section("templates"),
+        settings: Some(&data["settings"]).filter(|x| !x.is_badvalue()).map(extract_settings).transpose()?.unwrap_or_default(),
+        webhooks: Some(&data["notifications"]).filter(|x| !x.is_badvalue()).map(extract_webhooks).transpose()?.unwrap_or_default(),
+        machine_values: section("machines"),
+        own_values: section("own"),
+        fallbacks: HashMap::new(),
+        match_blocks: Some(&data["match"]).filter(|x| !x.is_badvalue()).map(extract_match_blocks).transpose()?.unwrap_or_default(),
+        matched: HashMap::new(),
+        networks: Some(&data["networks"]).filter(|x| !x.is_badvalue()).map(extract_networks).transpose()?.unwrap_or_default(),
+        location: None
+    })
+}
+
+/// Get fallback values from the executing environment: the user from `$USER`
+/// (or `$LOGNAME`), and the first existing key of `~/.ssh/id_ed25519` and
+/// `~/.ssh/id_rsa`.
+pub fn environment_fallbacks() -> MachineConfig {
+    let user = env::var("USER").or_else(|_| env::var("LOGNAME")).ok().filter(|x| !x.is_empty());
+    let identity = ["~/.ssh/id_ed25519", "~/.ssh/id_rsa"].iter()
+        .find(|x| expand_home(x).is_file())
+        .map(|x| vec![x.to_string()]);
+
+    MachineConfig {
+        user,
+        identity,
+        ..Default::default()
+    }
+}
+
+/// Get the user configuration directory (`~/.pssh`)
+pub fn get_user_configuration_dir() -> PathBuf {
+    let home_path = match env::home_dir() {
+        Some(path) => path,
+        None => PathBuf::from("~")
+    };
+
+    home_path.join(".pssh")
+}
+
+/// Get the configuration path to use.
+///
+/// If no path is given, the user configuration path will be used.
+///
+/// # Arguments
+///
+/// * `path_to_file` - Path to file (optional)
+///
+pub fn get_configuration_path(path_to_file: Option<&str>) -> String {
+    match path_to_file {
+        Some(x) => x.to_string(),
+        None => get_user_configuration_path()
+    }
+}
+
+/// Get the user configuration path
+fn get_user_configuration_path() -> String {
+    let full_path = get_user_configuration_dir().join("config.yml");
+    full_path.into_os_string().into_string().unwrap()
+}
+
+/// Fetch default values for a machine name
+///
+/// # Arguments
+///
+/// * `name` - Machine name
+/// * `default_map` - Default values
+///
+fn fetch_default_values_for_name(name: &str, default_map: &ConfigMap) -> Option<MachineConfig> {
+    let default_keys: Vec<String> = default_map.keys().map(|x| x.to_string()).collect();
+    let split_parent_names: Vec<&str> = name.split(':').collect();
+    
+    let mut current_parent: String = "".to_string();
+    
+    // Fetch global default values
+    let mut current_values = if default_keys.contains(&"".to_string()) {
+        default_map.get(&"".to_string()).cloned()
+    } else {
+        None
+    };
+    
+    for parent in split_parent_names {
+        if current_parent.is_empty() {
+            current_parent = parent.to_string();
+        } else {
+            current_parent = format!("{}:{}", current_parent, parent);
+        }
+        
+        if default_keys.contains(&current_parent) {
+            let parent_values = default_map.get(&current_parent).unwrap().clone();
+            current_values = match current_values {
+                Some(x) => Some(x.merge(&parent_values)),
+                None => Some(parent_values)
+            };
+        }
+     }
+    
+    current_values
+}
+
+/// Apply machine configurations on machine map
+///
+/// Values come from the defaults, then from the machine template, then from
+/// the machine itself.
+///
+/// # Arguments
+///
+/// * `machine_map` - Machine configuration map
+/// * `default_map` - Default configuration map
+/// * `template_map` - Template map
+///
+fn apply_machine_configurations(machine_map: &ConfigMap, default_map: &ConfigMap, template_map: &ConfigMap) -> Result<ConfigMap, Error> {
+    let mut applied_machines: ConfigMap = HashMap::new();
+    
+    for k in machine_map.keys() {
+        let key = k.clone();
+        
+        let default_config = fetch_default_values_for_name(k, default_map);
+        let machine_config = machine_map.get(k).unwrap();
+        
+        let default_config = default_config.unwrap_or_default();
+
+        // The template may be set by the machine or inherited from defaults
+        let template = machine_config.template.as_ref().or(default_config.template.as_ref());
+        let machine_applied_config = match template {
+            Some(name) => match template_map.get(name) {
+                Some(template_config) => default_config.merge(template_config).merge(machine_config),
+                None => return Err(Error::Config(format!("unknown template `{}` for machine `{}`", name, k)))
+            },
+            None => default_config.merge(machine_config)
+        };
+        
+        applied_machines.insert(key, machine_applied_config);
+    }
+    
+    Ok(applied_machines)
+}
+
+/// Extract machine values from YAML
+///
+/// # Arguments
+///
+/// * `data` - YAML data
+///
+pub fn extract_machine_values(data: &Yaml) -> MachineConfig {
+    if data.as_hash().is_none() {
+        return Default::default();
+    }
+    
+    let dict_data = data.as_hash().unwrap();
+    // Algorithm lists are given as `ssh` strings or as YAML lists
+    let algorithms = |key: &str| dict_data.get(&Yaml::from_str(key))
+        .and_then(|x| scalar_or_list(x, |x| x.as_str().map(String::from)))
+        .map(|x| x.join(","));
+    
+    MachineConfig {
+        ip: dict_data.get(&Yaml::from_str("ip")).and_then(|x| x.as_str()).map(String::from),
+        // IPs per location are given as a map
+        location_ips: dict_data.get(&Yaml::from_str("ip")).and_then(|x| x.as_hash()).map(|x| {
+            x.iter().filter_map(|(k, v)| Some((k.as_str()?.to_string(), v.as_str()?.to_string()))).collect()
+        }),
+        port: dict_data.get(&Yaml::from_str("port")).and_then(|x| scalar_or_list(x, |x| x.as_i64().map(|x| x as u16))),
+        user: dict_data.get(&Yaml::from_str("user")).and_then(|x| x.as_str()).map(String::from),
+        pass: dict_data.get(&Yaml::from_str("pass")).and_then(|x| x.as_str()).map(String::from),
+        identity: dict_data.get(&Yaml::from_str("identity")).and_then(|x| scalar_or_list(x, |x| x.as_str().map(String::from))),
+        via: dict_data.get(&Yaml::from_str("via")).and_then(|x| scalar_or_list(x, |x| x.as_str().map(String::from))),
+        proxy_command: dict_data.get(&Yaml::from_str("proxy_command")).and_then(|x| x.as_str()).map(String::from),
+        tags: dict_data.get(&Yaml::from_str("tags")).and_then(|x| x.as_vec()).map(|x| {
+            x.iter().filter_map(|tag| tag.as_str()).map(String::from).collect()
+        }),
+        template: dict_data.get(&Yaml::from_str("template")).and_then(|x| x.as_str()).map(String::from),
+        protected: dict_data.get(&Yaml::from_str("protected")).and_then(|x| x.as_bool()),
+        restricted_command: dict_data.get(&Yaml::from_str("restricted_command")).and_then(|x| x.as_str()).map(String::from),
+        strict: dict_data.get(&Yaml::from_str("strict")).and_then(|x| x.as_bool()),
+        ciphers: algorithms("ciphers"),
+        macs: algorithms("macs"),
+        kex: algorithms("kex"),
+        host_key_algorithms: algorithms("host_key_algorithms"),
+        compat: dict_data.get(&Yaml::from_str("compat")).and_then(|x| x.as_str()).map(String::from),
+        console: dict_data.get(&Yaml::from_str("console")).and_then(extract_console),
+        term: dict_data.get(&Yaml::from_str("term")).and_then(|x| x.as_str()).map(String::from),
+        locale: dict_data.get(&Yaml::from_str("locale")).and_then(|x| x.as_str()).map(String::from),
+        banner: dict_data.get(&Yaml::from_str("banner")).and_then(|x| x.as_str()).map(String::from),
+        quiet: dict_data.get(&Yaml::from_str("quiet")).and_then(|x| x.as_bool()),
+        auto_add_key: dict_data.get(&Yaml::from_str("auto_add_key")).and_then(|x| x.as_bool()),
+        agent_socket: dict_data.get(&Yaml::from_str("agent_socket")).and_then(|x| x.as_str()).map(String::from),
+        // Knocks are TCP ports, or strings with a protocol
+        knock: dict_data.get(&Yaml::from_str("knock")).and_then(|x| scalar_or_list(x, |x| match *x {
+            Yaml::Integer(port) => Some(port.to_string()),
+            ref x => x.as_str().map(String::from)
+        })),
+        knock_delay: dict_data.get(&Yaml::from_str("knock_delay")).and_then(|x| x.as_str()).map(String::from),
+        vpn: dict_data.get(&Yaml::from_str("vpn")).and_then(extract_vpn),
+        expires: dict_data.get(&Yaml::from_str("expires")).and_then(|x| x.as_str()).map(String::from),
+        expect: dict_data.get(&Yaml::from_str("expect")).and_then(extract_expect),
+        vars: dict_data.get(&Yaml::from_str("vars")).and_then(extract_vars),
+        priority: dict_data.get(&Yaml::from_str("priority")).and_then(|x| x.as_i64()),
+        role: dict_data.get(&Yaml::from_str("role")).and_then(|x| x.as_str()).map(String::from),
+        owner: dict_data.get(&Yaml::from_str("owner")).and_then(|x| x.as_str()).map(String::from),
+        contact: dict_data.get(&Yaml::from_str("contact")).and_then(|x| x.as_str()).map(String::from),
+        on_push: dict_data.get(&Yaml::from_str("on_push")).and_then(|x| x.as_str()).map(String::from),
+        preserve: dict_data.get(&Yaml::from_str("preserve")).and_then(|x| x.as_bool()),
+        follow_symlinks: dict_data.get(&Yaml::from_str("follow_symlinks")).and_then(|x| x.as_bool())
+    }
+}
+
+/// Extract variables from YAML, as a map of scalar values
+///
+/// # Arguments
+///
+/// * `data` - YAML data
+///
+fn extract_vars(data: &Yaml) -> Option<BTreeMap<String, String>> {
+    let vars = data.as_hash()?.iter()
+        .filter_map(|(key, value)| {
+            let value = match *value {
+                Yaml::String(ref x) | Yaml::Real(ref x) => x.clone(),
+                Yaml::Integer(x) => x.to_string(),
+                Yaml::Boolean(x) => x.to_string(),
+                _ => return None
+            };
+            Some((key.as_str()?.to_string(), value))
+        })
+        .collect();
+
+    Some(vars)
+}
+
+/// Extract a VPN from YAML, as `{up: COMMAND, check: COMMAND}`, or as the
+/// command bringing it up
+///
+/// # Arguments
+///
+/// * `data` - YAML data
+///
+fn extract_vpn(data: &Yaml) -> Option<VpnConfig> {
+    match data.as_str() {
+        Some(up) => Some(VpnConfig { up: up.to_string(), check: None }),
+        None => data["up"].as_str().map(|up| VpnConfig { up: up.to_string(), check: data["check"].as_str().map(String::from) })
+    }
+}
+
+/// Extract console access from YAML, as `{ipmi: HOST, user: USER, pass: PASS}`
+/// or `{conserver: NAME, master: HOST}`
+///
+/// # Arguments
+///
+/// * `data` - YAML data
+///
+fn extract_console(data: &Yaml) -> Option<ConsoleConfig> {
+    let string = |key: &str| data[key].as_str().map(String::from);
+
+    match string("ipmi") {
+        Some(host) => Some(ConsoleConfig::Ipmi { host, user: string("user"), pass: string("pass") }),
+        None => string("conserver").map(|name| ConsoleConfig::Conserver { name, master: string("master") })
+    }
+}
+
+/// Extract expected prompts from YAML, as a list of
+/// `{prompt: TEXT, response: TEXT}`
+///
+/// # Arguments
+///
+/// * `data` - YAML data
+///
+fn extract_expect(data: &Yaml) -> Option<Vec<ExpectStep>> {
+    let steps = data.as_vec()?.iter()
+        .filter_map(|x| {
+            let string = |key: &str| match x[key] {
+                Yaml::Integer(ref value) => Some(value.to_string()),
+                ref value => value.as_str().map(String::from)
+            };
+            Some(ExpectStep { prompt: string("prompt")?, response: string("response")? })
+        })
+        .collect();
+
+    Some(steps)
+}
+
+/// Extract a value which may be given alone or as a list
+///
+/// # Arguments
+///
+/// * `data` - YAML data
+/// * `extract` - Extraction of a single value
+///
+fn scalar_or_list<T, F: Fn(&Yaml) -> Option<T>>(data: &Yaml, extract: F) -> Option<Vec<T>> {
+    match data.as_vec() {
+        Some(values) => Some(values.iter().filter_map(extract).collect::<Vec<T>>()).filter(|x| !x.is_empty()),
+        None => extract(data).map(|x| vec![x])
+    }
+}
+
+/// Convert a list of values to YAML, as a single value when alone
+///
+/// # Arguments
+///
+/// * `values` - Values
+/// * `convert` - Conversion of a single value
+///
+fn list_to_yaml<T, F: Fn(&T) -> Yaml>(values: &[T], convert: F) -> Yaml {
+    if values.len() == 1 {
+        convert(&values[0])
+    } else {
+        Yaml::Array(values.iter().map(convert).collect())
+    }
+}
+
+/// Extract global settings from YAML
+///
+/// # Arguments
+///
+/// * `data` - YAML data
+///
+fn extract_settings(data: &Yaml) -> Result<Settings, Error> {
+    let defaults = Settings::default();
+
+    let notify = data["notify"].as_str().map(|x| {
+        NotifyMode::from_name(x).ok_or_else(|| Error::Config(format!("bad notify mode `{}`", x)))
+    }).transpose()?;
+    let max_failures = match data["max_failures"] {
+        Yaml::BadValue => None,
+        Yaml::Integer(x) if x > 0 => Some(x as usize),
+        _ => return Err(Error::Config("`max_failures` must be a positive integer".to_string()))
+    };
+
+    Ok(Settings {
+        confirm_threshold: data["confirm_threshold"].as_i64().map(|x| x as usize).unwrap_or(defaults.confirm_threshold),
+        notify: notify.unwrap_or(defaults.notify),
+        notify_after: data["notify_after"].as_i64().map(|x| x as u64).unwrap_or(defaults.notify_after),
+        max_failures: max_failures.or(defaults.max_failures),
+        title_format: data["title_format"].as_str().map(String::from).unwrap_or(defaults.title_format),
+        identity_agent: data["identity_agent"].as_str().map(String::from).or(defaults.identity_agent)
+    })
+}
+
+/// Add defaults and machines from remote inventories
+///
+/// Values defined in the file take precedence over included ones. Inventories
+/// which cannot be loaded are skipped with a warning.
+///
+/// # Arguments
+///
+/// * `default_map` - Default configuration map
+/// * `machine_map` - Machine configuration map
+/// * `includes` - Inventory sources (YAML array)
+///
+fn merge_included_inventories(default_map: &mut ConfigMap, machine_map: &mut ConfigMap, includes: &Yaml) -> Result<(), Error> {
+    let sources = includes.as_vec().ok_or_else(|| Error::Config("`include` must be a list of inventories".to_string()))?;
+
+    for source in sources {
+        let source = InventorySource::from_yaml(source).map_err(|e| Error::Config(format!("bad include: {}", e)))?;
+        let inventory = match source.load() {
+            Ok(x) => x,
+            Err(error) => {
+                warn!("Could not load inventory {}: {}", source.url, error);
+                continue;
+            }
+        };
+
+        for (name, values) in extract_definition_keys("", &inventory["defaults"])? {
+            default_map.entry(name).or_insert(values);
+        }
+        for (name, values) in extract_definition_keys("", &inventory["machines"])? {
+            machine_map.entry(name).or_insert(values);
+        }
+    }
+
+    Ok(())
+}
+
+/// Add machines from generator scripts to the machine map
+///
+/// Machines defined in the file take precedence over generated ones.
+///
+/// # Arguments
+///
+/// * `machine_map` - Machine configuration map
+/// * `generators` - Generator script paths (YAML array)
+/// * `dir` - Directory relative paths are resolved against
+///
+fn merge_generated_machines(mut machine_map: ConfigMap, generators: &Yaml, dir: &Path) -> Result<ConfigMap, Error> {
+    let paths = generators.as_vec().ok_or_else(|| Error::Config("`generators` must be a list of script paths".to_string()))?;
+
+    for path in paths.iter().filter_map(|x| x.as_str()) {
+        let path = dir.join(expand_home(path));
+        debug!("Running generator {}...", path.display());
+        let generated = generate_machines(&path)
+            .map_err(|e| Error::Config(format!("generator {} failed: {}", path.display(), e)))?;
+
+        for (name, values) in generated {
+            machine_map.entry(name).or_insert(values);
+        }
     }
+
+    Ok(machine_map)
 }
 
-/// Load a configuration from a file path.
+/// Expand a leading `~/` to the home directory
 ///
-/// If no path is given, the user configuration path will be used.
+/// # Arguments
+///
+/// * `path` - Path
+///
+pub fn expand_home(path: &str) -> PathBuf {
+    match (path.strip_prefix("~/"), env::home_dir()) {
+        (Some(rest), Some(home)) => home.join(rest),
+        _ => PathBuf::from(path)
+    }
+}
+
+/// Extract webhooks from the `notifications` YAML section
 ///
 /// # Arguments
 ///
-/// * `path_to_file` - Path to file (optional)
+/// * `data` - YAML data
 ///
-pub fn load_configuration_file(path_to_file: Option<&str>) -> ConfigResult {
-    let path_to_file = match path_to_file {
-        Some(x) => x.to_string(),
-        None => get_user_configuration_path()
+fn extract_webhooks(data: &Yaml) -> Result<Vec<Webhook>, Error> {
+    let webhooks = match data["webhooks"].as_vec() {
+        Some(x) => x,
+        None => return Ok(vec![])
     };
-    
-    debug!("Loading {}...", path_to_file);    
-    let mut f = File::open(&path_to_file).expect(&format!("File {} not found.", path_to_file));
-    let mut contents = String::new();    
-    f.read_to_string(&mut contents).expect("Error while reading file.");
-    
-    load_configuration_string(&contents)
+
+    webhooks.iter().map(|values| {
+        let url = values["url"].as_str().ok_or_else(|| Error::Config(format!("missing webhook url: {:?}", values)))?;
+        let format = values["format"].as_str().unwrap_or("json");
+
+        Ok(Webhook {
+            url: url.to_string(),
+            format: WebhookFormat::from_name(format).ok_or_else(|| Error::Config(format!("bad webhook format `{}`", format)))?
+        })
+    }).collect()
 }
 
-/// Load a configuration from a string.
+/// Extract conditional blocks from YAML, as a list of conditions (`host`,
+/// `target`, `local`) with values under `$`
 ///
 /// # Arguments
 ///
-/// * `contents` - Contents string
+/// * `data` - YAML data
 ///
-pub fn load_configuration_string(contents: &str) -> ConfigResult {
-    let docs = YamlLoader::load_from_str(contents).unwrap();
-    let doc = &docs[0].as_hash().unwrap();
-        
-    let default_values = doc.get(&Yaml::from_str("defaults")).unwrap();
-    let machine_values = doc.get(&Yaml::from_str("machines")).unwrap();
-    
-    let default_map = extract_definition_keys("", default_values);
-    let machine_map = extract_definition_keys("", machine_values);
-    let machine_map = apply_machine_configurations(&machine_map, &default_map);
-    
-    ConfigResult {
-        default_values: default_map,
-        machine_values: machine_map
-    }
-}
+fn extract_match_blocks(data: &Yaml) -> Result<Vec<MatchBlock>, Error> {
+    data.as_vec().map(|x| &x[..]).unwrap_or(&[]).iter().map(|values| {
+        let string = |key: &str| values[key].as_str().map(String::from);
+        let block = MatchBlock {
+            host: string("host"),
+            target: string("target"),
+            local: string("local"),
+            values: extract_machine_values(&values["$"])
+        };
 
-/// Get the user configuration path
-fn get_user_configuration_path() -> String {
-    let home_path = match env::home_dir() {
-        Some(path) => path.into_os_string().into_string().unwrap(),
-        None => "~".to_string()
-    };
-    
-    let full_path: PathBuf = [&home_path, ".pssh", "config.yml"].iter().collect();
-    full_path.into_os_string().into_string().unwrap()
+        block.check().map_err(|e| Error::Config(format!("bad match block: {}", e)))?;
+        Ok(block)
+    }).collect()
 }
 
-/// Fetch default values for a machine name
+/// Extract network locations from YAML, as a map of location names to
+/// rules (`cidr`, `gateway_mac`, `ssid`)
 ///
 /// # Arguments
 ///
-/// * `name` - Machine name
-/// * `default_map` - Default values
+/// * `data` - YAML data
 ///
-fn fetch_default_values_for_name(name: &str, default_map: &ConfigMap) -> Option<MachineConfig> {
-    let default_keys: Vec<String> = default_map.keys().map(|x| x.to_string()).collect();
-    let split_parent_names: Vec<&str> = name.split(':').collect();
-    
-    let mut current_parent: String = "".to_string();
-    
-    // Fetch global default values
-    let mut current_values = if default_keys.contains(&"".to_string()) {
-        default_map.get(&"".to_string()).cloned()
-    } else {
-        None
+fn extract_networks(data: &Yaml) -> Result<Vec<Network>, Error> {
+    let networks = match data.as_hash() {
+        Some(x) => x,
+        None => return Ok(vec![])
     };
-    
-    for parent in split_parent_names {
-        if current_parent == "" {
-            current_parent = parent.to_string();
-        } else {
-            current_parent = format!("{}:{}", current_parent, parent);
-        }
-        
-        if default_keys.contains(&current_parent) {
-            let parent_values = default_map.get(&current_parent).unwrap().clone();
-            current_values = match current_values {
-                Some(x) => Some(x.merge(&parent_values)),
-                None => Some(parent_values)
-            };
-        }
-     }
-    
-    current_values
+
+    networks.iter().map(|(name, rules)| {
+        let name = name.as_str().ok_or_else(|| Error::Config(format!("bad network name: {:?}", name)))?;
+        let list = |key: &str| scalar_or_list(&rules[key], |x| x.as_str().map(String::from)).unwrap_or_default();
+        let network = Network {
+            name: name.to_string(),
+            cidr: list("cidr"),
+            gateway_mac: list("gateway_mac"),
+            ssid: list("ssid")
+        };
+
+        network.check().map_err(|e| Error::Config(format!("bad network `{}`: {}", name, e)))?;
+        Ok(network)
+    }).collect()
 }
 
-/// Apply machine configurations on machine map
+/// Extract templates from YAML
+///
+/// Templates are not hierarchical: each key of the `templates` section is a
+/// template name, holding machine values.
 ///
 /// # Arguments
 ///
-/// * `machine_map` - Machine configuration map
-/// * `default_map` - Default configuration map
+/// * `data` - YAML data
 ///
-fn apply_machine_configurations(machine_map: &ConfigMap, default_map: &ConfigMap) -> ConfigMap {
-    let mut applied_machines: ConfigMap = HashMap::new();
-    
-    for k in machine_map.keys() {
-        let key = k.clone();
-        
-        let default_config = fetch_default_values_for_name(k, default_map);
-        let machine_config = machine_map.get(k).unwrap();
-        
-        let machine_applied_config = match default_config {
-            Some(x) => x.merge(machine_config),
-            None => machine_config.clone()
-        };
-        
-        applied_machines.insert(key, machine_applied_config);
+fn extract_templates(data: &Yaml) -> Result<ConfigMap, Error> {
+    let mut templates: ConfigMap = HashMap::new();
+
+    if let Some(dict_data) = data.as_hash() {
+        for (name, values) in dict_data {
+            let name = name.as_str().ok_or_else(|| Error::Config(format!("bad template name: {:?}", name)))?;
+            let values = extract_machine_values(values);
+            if values.template.is_some() {
+                return Err(Error::Config(format!("template `{}` cannot use another template", name)));
+            }
+
+            templates.insert(name.to_string(), values);
+        }
     }
-    
-    applied_machines
+
+    Ok(templates)
 }
 
-/// Extract machine values from YAML
+/// Convert machine values to YAML, as found under a `$` key
 ///
 /// # Arguments
 ///
-/// * `data` - YAML data
+/// * `config` - Machine configuration
 ///
-fn extract_machine_values(data: &Yaml) -> MachineConfig {
-    if data.as_hash().is_none() {
-        return Default::default();
+pub fn machine_values_to_yaml(config: &MachineConfig) -> Yaml {
+    let mut values = Hash::new();
+    let mut insert = |key: &str, value: Yaml| {
+        values.insert(Yaml::String(key.to_string()), value);
+    };
+
+    if let Some(ref ips) = config.location_ips {
+        insert("ip", Yaml::Hash(ips.iter().map(|(k, v)| (Yaml::String(k.clone()), Yaml::String(v.clone()))).collect()));
+    } else if let Some(ref ip) = config.ip {
+        insert("ip", Yaml::String(ip.clone()));
     }
-    
-    let dict_data = data.as_hash().unwrap();
-    
-    MachineConfig {
-        ip: dict_data.get(&Yaml::from_str("ip")).and_then(|x| x.as_str()).map(String::from),
-        port: dict_data.get(&Yaml::from_str("port")).and_then(|x| x.as_i64()).map(|x| x as u16),
-        user: dict_data.get(&Yaml::from_str("user")).and_then(|x| x.as_str()).map(String::from),
-        pass: dict_data.get(&Yaml::from_str("pass")).and_then(|x| x.as_str()).map(String::from),
-        identity: dict_data.get(&Yaml::from_str("identity")).and_then(|x| x.as_str()).map(String::from)
+    if let Some(ref port) = config.port {
+        insert("port", list_to_yaml(port, |x| Yaml::Integer(i64::from(*x))));
+    }
+    if let Some(ref user) = config.user {
+        insert("user", Yaml::String(user.clone()));
+    }
+    if let Some(ref pass) = config.pass {
+        insert("pass", Yaml::String(pass.clone()));
+    }
+    if let Some(ref identity) = config.identity {
+        insert("identity", list_to_yaml(identity, |x| Yaml::String(x.clone())));
+    }
+    if let Some(ref via) = config.via {
+        insert("via", list_to_yaml(via, |x| Yaml::String(x.clone())));
+    }
+    if let Some(ref knock) = config.knock {
+        insert("knock", Yaml::Array(knock.iter().map(|x| x.parse().map(Yaml::Integer).unwrap_or_else(|_| Yaml::String(x.clone()))).collect()));
+    }
+    if let Some(ref tags) = config.tags {
+        insert("tags", Yaml::Array(tags.iter().cloned().map(Yaml::String).collect()));
+    }
+    if let Some(ref template) = config.template {
+        insert("template", Yaml::String(template.clone()));
+    }
+    if let Some(protected) = config.protected {
+        insert("protected", Yaml::Boolean(protected));
+    }
+    if let Some(ref restricted_command) = config.restricted_command {
+        insert("restricted_command", Yaml::String(restricted_command.clone()));
+    }
+    if let Some(strict) = config.strict {
+        insert("strict", Yaml::Boolean(strict));
+    }
+    if let Some(quiet) = config.quiet {
+        insert("quiet", Yaml::Boolean(quiet));
+    }
+    if let Some(auto_add_key) = config.auto_add_key {
+        insert("auto_add_key", Yaml::Boolean(auto_add_key));
+    }
+    if let Some(preserve) = config.preserve {
+        insert("preserve", Yaml::Boolean(preserve));
+    }
+    if let Some(follow_symlinks) = config.follow_symlinks {
+        insert("follow_symlinks", Yaml::Boolean(follow_symlinks));
+    }
+    if let Some(priority) = config.priority {
+        insert("priority", Yaml::Integer(priority));
+    }
+    if let Some(ref console) = config.console {
+        let mut console_values = Hash::new();
+        let mut insert_console = |key: &str, value: &Option<String>| {
+            if let Some(ref value) = *value {
+                console_values.insert(Yaml::String(key.to_string()), Yaml::String(value.clone()));
+            }
+        };
+        match *console {
+            ConsoleConfig::Ipmi { ref host, ref user, ref pass } => {
+                insert_console("ipmi", &Some(host.clone()));
+                insert_console("user", user);
+                insert_console("pass", pass);
+            },
+            ConsoleConfig::Conserver { ref name, ref master } => {
+                insert_console("conserver", &Some(name.clone()));
+                insert_console("master", master);
+            }
+        }
+        insert("console", Yaml::Hash(console_values));
+    }
+    if let Some(ref vpn) = config.vpn {
+        match vpn.check {
+            Some(ref check) => {
+                let mut vpn_values = Hash::new();
+                vpn_values.insert(Yaml::String("up".to_string()), Yaml::String(vpn.up.clone()));
+                vpn_values.insert(Yaml::String("check".to_string()), Yaml::String(check.clone()));
+                insert("vpn", Yaml::Hash(vpn_values));
+            },
+            None => insert("vpn", Yaml::String(vpn.up.clone()))
+        }
+    }
+    if let Some(ref expect) = config.expect {
+        insert("expect", Yaml::Array(expect.iter().map(|x| {
+            let mut step = Hash::new();
+            step.insert(Yaml::String("prompt".to_string()), Yaml::String(x.prompt.clone()));
+            step.insert(Yaml::String("response".to_string()), Yaml::String(x.response.clone()));
+            Yaml::Hash(step)
+        }).collect()));
+    }
+    if let Some(ref vars) = config.vars {
+        insert("vars", Yaml::Hash(vars.iter().map(|(k, v)| (Yaml::String(k.clone()), Yaml::String(v.clone()))).collect()));
     }
+    for &(key, value) in &[("ciphers", &config.ciphers), ("macs", &config.macs),
+                           ("kex", &config.kex), ("host_key_algorithms", &config.host_key_algorithms),
+                           ("compat", &config.compat), ("term", &config.term), ("locale", &config.locale),
+                           ("banner", &config.banner), ("role", &config.role),
+                           ("owner", &config.owner), ("contact", &config.contact),
+                           ("on_push", &config.on_push), ("agent_socket", &config.agent_socket),
+                           ("proxy_command", &config.proxy_command),
+                           ("knock_delay", &config.knock_delay),
+                           ("expires", &config.expires)] {
+        if let Some(ref value) = *value {
+            insert(key, Yaml::String(value.clone()));
+        }
+    }
+
+    Yaml::Hash(values)
 }
 
 /// Extract definition keys from YAML
@@ -241,13 +1704,15 @@ fn extract_machine_values(data: &Yaml) -> MachineConfig {
 /// * `parent_key` - Parent key
 /// * `current_yaml` - YAML data
 ///
-fn extract_definition_keys(parent_key: &str, current_yaml: &Yaml) -> ConfigMap {        
+fn extract_definition_keys(parent_key: &str, current_yaml: &Yaml) -> Result<ConfigMap, Error> {        
     if current_yaml.as_hash().is_none() {
-        return HashMap::new();
+        return Ok(HashMap::new());
     }
 
     let current_dict = current_yaml.as_hash().unwrap();
-    let keys = current_dict.keys().map(|x| x.as_str().unwrap()).collect::<Vec<&str>>();
+    let keys = current_dict.keys()
+        .map(|x| x.as_str().ok_or_else(|| Error::Config(format!("bad machine key: {:?}", x))))
+        .collect::<Result<Vec<&str>, Error>>()?;
     let mut result: ConfigMap = HashMap::new();
     
     if keys.contains(&"$") {
@@ -257,28 +1722,29 @@ fn extract_definition_keys(parent_key: &str, current_yaml: &Yaml) -> ConfigMap {
     
     for key in keys {
         if key != "$" {
-            if key.contains(':') {
-                panic!("Bad character ':' in key: {}", key);
-            } 
+            if let Err(reason) = validate_key(key) {
+                return Err(Error::Config(format!("bad machine key `{}`: {}", key, reason)));
+            }
             
-            let current_key = if parent_key == "" {                
+            let current_key = if parent_key.is_empty() {                
                 key.to_string()
             } else {
                 format!("{}:{}", parent_key, key)
             };
             
             let current_value = current_dict.get(&Yaml::from_str(key)).unwrap();
-            let local_results = extract_definition_keys(&current_key, current_value);
+            let local_results = extract_definition_keys(&current_key, current_value)?;
             for (k, v) in &local_results {
                 result.insert(k.clone(), v.clone());
             }
         }
     }
 
-    result
+    Ok(result)
 }
 
 #[cfg(test)]
+#[allow(clippy::bool_comparison, clippy::unnecessary_to_owned)]
 mod test {
     use super::*;
     
@@ -289,7 +1755,7 @@ mod test {
             machines:
         "#;
         
-        let config = load_configuration_string(str_content);
+        let config = load_configuration_string(str_content).unwrap();
         assert_eq!(config.default_values.keys().len(), 0);
         assert_eq!(config.machine_values.keys().len(), 0);
     }
@@ -316,7 +1782,7 @@ mod test {
                 four:
         "#;
         
-        let config = load_configuration_string(str_content);
+        let config = load_configuration_string(str_content).unwrap();
         assert_eq!(config.machine_values.keys().len(), 0);
         assert_eq!(config.default_values.keys().len(), 5);
         
@@ -329,7 +1795,6 @@ mod test {
     }
     
     #[test]
-    #[should_panic]
     fn defaults_error() {
         let str_content = r#"
             machines:
@@ -341,7 +1806,12 @@ mod test {
                         user: hello
         "#;
         
-        load_configuration_string(str_content);
+        let error = load_configuration_string(str_content).unwrap_err();
+        assert!(error.to_string().starts_with("bad machine key `one:pouet`"));
+        assert_eq!(error.exit_code(), 2);
+
+        let error = load_configuration_file(Some("/nonexistent/config.yml")).unwrap_err();
+        assert!(error.to_string().starts_with("could not read /nonexistent/config.yml"));
     }
     
     #[test]
@@ -349,40 +1819,40 @@ mod test {
         let values = hashmap!(
             "".to_string() => MachineConfig {
                 user: Some("hello".to_string()),
-                port: Some(22),
+                port: Some(vec![22]),
                 ..Default::default()
             },
             "coucou".to_string() => MachineConfig {
-                port: Some(23),
+                port: Some(vec![23]),
                 ..Default::default()
             },
             "coucou:hello".to_string() => MachineConfig {
-                port: Some(24),
+                port: Some(vec![24]),
                 ..Default::default()
             }
         );
         
         let config = fetch_default_values_for_name(&"toto".to_string(), &values).unwrap();        
         assert_eq!(config.user, Some("hello".to_string()));
-        assert_eq!(config.port, Some(22));
+        assert_eq!(config.port, Some(vec![22]));
         assert_eq!(config.identity, None);
         
         let config = fetch_default_values_for_name(&"coucou".to_string(), &values).unwrap();        
         assert_eq!(config.user, Some("hello".to_string()));
-        assert_eq!(config.port, Some(23));
+        assert_eq!(config.port, Some(vec![23]));
         assert_eq!(config.identity, None);
         
         let config = fetch_default_values_for_name(&"coucou:pouet".to_string(), &values).unwrap();
         assert_eq!(config.user, Some("hello".to_string()));
-        assert_eq!(config.port, Some(23));
+        assert_eq!(config.port, Some(vec![23]));
         
         let config = fetch_default_values_for_name(&"coucou:hello".to_string(), &values).unwrap();
         assert_eq!(config.user, Some("hello".to_string()));
-        assert_eq!(config.port, Some(24));
+        assert_eq!(config.port, Some(vec![24]));
         
         let config = fetch_default_values_for_name(&"coucou:hello:one".to_string(), &values).unwrap();
         assert_eq!(config.user, Some("hello".to_string()));
-        assert_eq!(config.port, Some(24));
+        assert_eq!(config.port, Some(vec![24]));
     }
     
     #[test]
@@ -393,14 +1863,14 @@ mod test {
                 ..Default::default()
             },
             "coucou".to_string() => MachineConfig {
-                port: Some(23),
+                port: Some(vec![23]),
                 ..Default::default()
             }
         );
         
         let machines = hashmap!(
             "coucou".to_string() => MachineConfig {
-                port: Some(22),
+                port: Some(vec![22]),
                 ..Default::default()
             },
             "coucou:hello".to_string() => MachineConfig {
@@ -409,15 +1879,563 @@ mod test {
             }
         );
         
-        let configured_machines = apply_machine_configurations(&machines, &defaults);
+        let configured_machines = apply_machine_configurations(&machines, &defaults, &HashMap::new()).unwrap();
         let m_coucou = configured_machines.get(&"coucou".to_string()).unwrap(); 
         let m_coucou_hello = configured_machines.get(&"coucou:hello".to_string()).unwrap(); 
         
-        assert_eq!(m_coucou.port, Some(22));
+        assert_eq!(m_coucou.port, Some(vec![22]));
         assert_eq!(m_coucou.user, Some("hello".to_string()));
         
         assert_eq!(m_coucou_hello.user, Some("hello".to_string()));
-        assert_eq!(m_coucou_hello.port, Some(23));
+        assert_eq!(m_coucou_hello.port, Some(vec![23]));
         assert_eq!(m_coucou_hello.ip, Some("127.0.0.1".to_string()));
     }
+
+    #[test]
+    fn machine_tags() {
+        let str_content = r#"
+            defaults:
+                web:
+                    $:
+                        tags: [web]
+            machines:
+                web:
+                    one:
+                        $:
+                            ip: 10.0.0.1
+                    two:
+                        $:
+                            ip: 10.0.0.2
+                            tags: [web, canary]
+        "#;
+
+        let config = load_configuration_string(str_content).unwrap();
+        assert_eq!(config.machine_values["web:one"].tags, Some(vec!["web".to_string()]));
+        assert_eq!(config.machine_values["web:two"].tags, Some(vec!["web".to_string(), "canary".to_string()]));
+    }
+
+    #[test]
+    fn machine_vars() {
+        let str_content = r#"
+            defaults:
+                $:
+                    vars: {env: prod, backup: true}
+                db:
+                    $:
+                        vars: {port: 5432}
+                        priority: 2
+                        role: primary
+                        owner: data team
+                        contact: data@example.com
+                        on_push: systemctl reload postgresql
+            machines:
+                db:
+                    one:
+                        $:
+                            ip: 10.0.0.1
+                            vars: {env: staging}
+        "#;
+
+        let config = load_configuration_string(str_content).unwrap();
+        let vars = config.machine_values["db:one"].vars.clone().unwrap();
+        assert_eq!(vars.into_iter().collect::<Vec<_>>(), vec![
+            ("backup".to_string(), "true".to_string()),
+            ("env".to_string(), "staging".to_string()),
+            ("port".to_string(), "5432".to_string())
+        ]);
+        assert_eq!(config.machine_values["db:one"].priority, Some(2));
+        assert_eq!(config.machine_values["db:one"].role.as_deref(), Some("primary"));
+        assert_eq!(config.machine_values["db:one"].owner.as_deref(), Some("data team"));
+        assert_eq!(config.machine_values["db:one"].contact.as_deref(), Some("data@example.com"));
+        assert_eq!(config.machine_values["db:one"].on_push.as_deref(), Some("systemctl reload postgresql"));
+        assert_eq!(extract_machine_values(&machine_values_to_yaml(&config.machine_values["db:one"])), config.machine_values["db:one"]);
+    }
+
+    #[test]
+    fn ad_hoc_addresses() {
+        let config = MachineConfig::from_address("root@1.2.3.4:2222").unwrap();
+        assert_eq!(config.user, Some("root".to_string()));
+        assert_eq!(config.ip, Some("1.2.3.4".to_string()));
+        assert_eq!(config.port, Some(vec![2222]));
+
+        let config = MachineConfig::from_address("host.example.com").unwrap();
+        assert_eq!(config.ip, Some("host.example.com".to_string()));
+        assert_eq!(config.port, None);
+
+        let config = MachineConfig::from_address("[fe80::1]:22").unwrap();
+        assert_eq!(config.ip, Some("fe80::1".to_string()));
+        assert_eq!(config.port, Some(vec![22]));
+        assert_eq!(MachineConfig::from_address("me@fe80::1").unwrap().ip, Some("fe80::1".to_string()));
+
+        assert!(MachineConfig::from_address("web").is_none());
+        assert!(MachineConfig::from_address("web:w1").is_none());
+        assert!(MachineConfig::from_address("@1.2.3.4").is_none());
+        assert!(MachineConfig::from_address("1.2.3.4:ssh").is_none());
+        assert!(MachineConfig::from_address("web:*").is_none());
+    }
+
+    #[test]
+    fn fallbacks() {
+        let mut config = load_configuration_string(r#"
+defaults:
+    web:
+        $:
+            identity: ~/.ssh/web
+machines:
+    web:
+        $:
+            ip: 10.0.0.1
+    db:
+        $:
+            ip: 10.0.1.1
+            user: postgres
+"#).unwrap();
+        config.apply_fallbacks(&MachineConfig {
+            user: Some("alice".to_string()),
+            identity: Some(vec!["~/.ssh/id_ed25519".to_string()]),
+            ..Default::default()
+        });
+
+        assert_eq!(config.machine_values["web"].user, Some("alice".to_string()));
+        assert_eq!(config.machine_values["web"].identity, Some(vec!["~/.ssh/web".to_string()]));
+        assert_eq!(config.fallbacks["web"], vec!["user"]);
+        assert_eq!(config.machine_values["db"].user, Some("postgres".to_string()));
+        assert_eq!(config.fallbacks["db"], vec!["identity"]);
+    }
+
+    #[test]
+    fn match_blocks() {
+        let contents = r#"
+defaults:
+    $:
+        user: deploy
+match:
+    - local: "!10.20.0.0/16"
+      target: 10.1.0.0/16
+      $:
+          via: bastion.example.com
+    - host: "prod:*"
+      $:
+          user: admin
+machines:
+    prod:
+        web:
+            $:
+                ip: 10.1.0.5
+        db:
+            $:
+                ip: 10.2.0.5
+                user: postgres
+"#;
+        let mut outside = load_configuration_string(contents).unwrap();
+        outside.apply_match_blocks(Some("192.168.1.10".parse().unwrap()));
+        assert_eq!(outside.machine_values["prod:web"].via, Some(vec!["bastion.example.com".to_string()]));
+        assert_eq!(outside.machine_values["prod:web"].user.as_deref(), Some("admin"));
+        assert_eq!(outside.machine_values["prod:db"].via, None);
+        // Values set on the machine itself override the blocks
+        assert_eq!(outside.machine_values["prod:db"].user.as_deref(), Some("postgres"));
+        assert_eq!(outside.matched["prod:web"], vec!["target 10.1.0.0/16 local !10.20.0.0/16", "host prod:*"]);
+
+        let mut office = load_configuration_string(contents).unwrap();
+        office.apply_match_blocks(Some("10.20.4.2".parse().unwrap()));
+        assert_eq!(office.machine_values["prod:web"].via, None);
+
+        let doc = &YamlLoader::load_from_str(contents).unwrap()[0];
+        let resolved = load_resolved_configuration(&resolved_configuration_to_yaml(&office, doc)).unwrap();
+        assert_eq!(resolved.match_blocks, office.match_blocks);
+        assert_eq!(resolved.own_values, office.own_values);
+
+        let bad = load_configuration_string("defaults:\nmachines:\nmatch:\n    - local: 10.0.0.0/33\n      $:\n          user: x\n");
+        assert_eq!(bad.unwrap_err().exit_code(), 2);
+    }
+
+    #[test]
+    fn network_locations() {
+        let contents = r#"
+defaults:
+    $:
+        user: deploy
+    lab:
+        $:
+            ip: {office: 10.20.0.9, remote: lab.example.com}
+networks:
+    office:
+        cidr: [10.20.0.0/16, 10.21.0.0/16]
+    home:
+        gateway_mac: a0:b1:c2:d3:e4:f5
+        ssid: Home
+    remote:
+machines:
+    nas:
+        $:
+            ip: {home: 192.168.1.5, remote: vpn.example.com}
+    lab:
+        one:
+            $:
+                ip: 10.30.0.1
+        two:
+            $:
+                port: 2222
+"#;
+        let mut result = load_configuration_string(contents).unwrap();
+        assert_eq!(result.networks.iter().map(|x| x.name.as_str()).collect::<Vec<_>>(), ["office", "home", "remote"]);
+        assert_eq!(result.networks[0].cidr, ["10.20.0.0/16", "10.21.0.0/16"]);
+        assert_eq!(result.networks[1].ssid, ["Home"]);
+
+        result.apply_location(Some("home".to_string()));
+        assert_eq!(result.machine_values["nas"].ip.as_deref(), Some("192.168.1.5"));
+        assert_eq!(result.machine_values["lab:one"].ip.as_deref(), Some("10.30.0.1"));
+        assert_eq!(result.machine_values["lab:one"].location_ips, None);
+        assert_eq!(result.machine_values["lab:two"].ip.as_deref(), Some("10.20.0.9"));
+
+        result.apply_location(Some("remote".to_string()));
+        assert_eq!(result.machine_values["nas"].ip.as_deref(), Some("vpn.example.com"));
+        assert_eq!(result.machine_values["lab:two"].ip.as_deref(), Some("lab.example.com"));
+        result.apply_location(None);
+        assert_eq!(result.machine_values["nas"].ip.as_deref(), Some("192.168.1.5"));
+
+        let yaml = machine_values_to_yaml(&result.machine_values["nas"]);
+        assert_eq!(extract_machine_values(&yaml).location_ips, result.machine_values["nas"].location_ips);
+        let doc = &YamlLoader::load_from_str(contents).unwrap()[0];
+        let resolved = load_resolved_configuration(&resolved_configuration_to_yaml(&result, doc)).unwrap();
+        assert_eq!(resolved.networks, result.networks);
+
+        let bad = load_configuration_string("defaults:\nmachines:\nnetworks:\n    home:\n        gateway_mac: a0:b1\n");
+        assert_eq!(bad.unwrap_err().to_string(), "bad network `home`: bad MAC address `a0:b1`");
+    }
+
+    #[test]
+    fn value_lists() {
+        let config = load_configuration_string(r#"
+defaults:
+    $:
+machines:
+    legacy:
+        $:
+            ip: 10.0.0.1
+            port: [2222, 22]
+            identity: [~/.ssh/new, ~/.ssh/old]
+            via: [admin@bastion-a, bastion-b:2222]
+            kex: [curve25519-sha256, diffie-hellman-group14-sha256]
+            ciphers: aes256-gcm@openssh.com
+            term: xterm
+            locale: C
+            banner: Legacy hardware
+            quiet: true
+            auto_add_key: true
+            expires: 2025-09-01
+            knock: [7000, 8000/udp, 9000]
+            knock_delay: 250ms
+            vpn: {up: wg-quick up office, check: ip link show office}
+            expect:
+                - prompt: "Select option:"
+                  response: 3
+                - prompt: "Password:"
+            preserve: true
+            follow_symlinks: false
+    plain:
+        $:
+            ip: 10.0.0.2
+            identity: ~/.ssh/new
+"#).unwrap();
+
+        let legacy = &config.machine_values["legacy"];
+        assert_eq!(legacy.ports(), vec![2222, 22]);
+        assert_eq!(legacy.first_port(), 2222);
+        assert_eq!(legacy.identities(), ["~/.ssh/new", "~/.ssh/old"]);
+        assert_eq!(legacy.jump_hosts(), ["admin@bastion-a", "bastion-b:2222"]);
+        assert_eq!(legacy.address(), "10.0.0.1:2222");
+        assert_eq!(legacy.kex, Some("curve25519-sha256,diffie-hellman-group14-sha256".to_string()));
+        assert_eq!(legacy.ciphers, Some("aes256-gcm@openssh.com".to_string()));
+
+        let plain = &config.machine_values["plain"];
+        assert_eq!(plain.ports(), vec![22]);
+        assert_eq!(plain.identity, Some(vec!["~/.ssh/new".to_string()]));
+
+        let yaml = machine_values_to_yaml(legacy);
+        assert_eq!(extract_machine_values(&yaml).port, legacy.port);
+        assert_eq!(extract_machine_values(&yaml).via, legacy.via);
+        assert!(plain.jump_hosts().is_empty());
+        let jump = MachineConfig { via: Some(vec!["bastion a".to_string()]), ..plain.clone() };
+        assert_eq!(jump.check_values().unwrap_err().to_string(), "bad jump host `bastion a`");
+        assert_eq!(machine_values_to_yaml(plain)["identity"].as_str(), Some("~/.ssh/new"));
+
+        let compat = MachineConfig { compat: Some("ancient".to_string()), ..plain.clone() };
+        assert_eq!(compat.check_values().unwrap_err().to_string(), "unknown compat preset `ancient`");
+        assert!(MachineConfig { compat: Some("legacy".to_string()), ..compat }.check_values().is_ok());
+
+        assert_eq!((legacy.term.as_deref(), legacy.locale.as_deref()), (Some("xterm"), Some("C")));
+        assert_eq!(extract_machine_values(&yaml).locale, legacy.locale);
+        assert_eq!(extract_machine_values(&yaml).banner.as_deref(), Some("Legacy hardware"));
+        assert!(legacy.is_quiet() && !plain.is_quiet());
+        assert!(legacy.adds_keys() && !plain.adds_keys());
+        assert_eq!(extract_machine_values(&yaml).auto_add_key, Some(true));
+        assert_eq!(extract_machine_values(&yaml).expires.as_deref(), Some("2025-09-01"));
+        assert!(legacy.is_expired(NaiveDate::from_ymd_opt(2025, 9, 1).unwrap()));
+        assert!(!legacy.is_expired(NaiveDate::from_ymd_opt(2025, 8, 31).unwrap()));
+        assert!(!plain.is_expired(NaiveDate::from_ymd_opt(2025, 9, 1).unwrap()));
+        let expires = MachineConfig { expires: Some("next week".to_string()), ..plain.clone() };
+        assert_eq!(expires.check_values().unwrap_err().to_string(), "bad expiration date `next week`");
+        assert_eq!(legacy.expect, Some(vec![ExpectStep { prompt: "Select option:".to_string(), response: "3".to_string() }]));
+        assert_eq!(extract_machine_values(&yaml).expect, legacy.expect);
+        assert_eq!(legacy.knocks(), ["7000", "8000/udp", "9000"]);
+        assert_eq!(legacy.knock_delay(), Duration::from_millis(250));
+        assert_eq!(extract_machine_values(&yaml).knock, legacy.knock);
+        assert_eq!(legacy.vpn, Some(VpnConfig { up: "wg-quick up office".to_string(), check: Some("ip link show office".to_string()) }));
+        assert_eq!(extract_machine_values(&yaml).vpn, legacy.vpn);
+        let vpn = MachineConfig { vpn: Some(VpnConfig { up: "nmcli con up office".to_string(), check: None }), ..plain.clone() };
+        assert_eq!(machine_values_to_yaml(&vpn)["vpn"].as_str(), Some("nmcli con up office"));
+        assert_eq!(extract_machine_values(&machine_values_to_yaml(&vpn)).vpn, vpn.vpn);
+        assert!(plain.knocks().is_empty());
+        let knock = MachineConfig { knock: Some(vec!["7000/icmp".to_string()]), ..plain.clone() };
+        assert_eq!(knock.check_values().unwrap_err().to_string(), "bad knock `7000/icmp`");
+        assert!(legacy.is_preserving() && !legacy.follows_symlinks());
+        assert!(!plain.is_preserving() && plain.follows_symlinks());
+        assert_eq!(extract_machine_values(&yaml).follow_symlinks, Some(false));
+        let locale = MachineConfig { locale: Some("en US".to_string()), ..plain.clone() };
+        assert_eq!(locale.check_values().unwrap_err().to_string(), "bad locale `en US`");
+    }
+
+    #[test]
+    fn consoles() {
+        let config = load_configuration_string(r#"
+defaults:
+    rack1:
+        $:
+            console:
+                conserver: rack1
+machines:
+    rack1:
+        sw1:
+            $:
+                ip: 10.0.0.1
+    db:
+        $:
+            ip: 10.0.0.2
+            console:
+                ipmi: 10.0.9.2
+                user: ADMIN
+                pass: secret
+"#).unwrap();
+
+        let sw1 = &config.machine_values["rack1:sw1"];
+        assert_eq!(sw1.console, Some(ConsoleConfig::Conserver { name: "rack1".to_string(), master: None }));
+
+        let db = &config.machine_values["db"];
+        assert_eq!(db.console.as_ref().unwrap().describe(), "ipmi ADMIN@10.0.9.2");
+        assert_eq!(extract_machine_values(&machine_values_to_yaml(db)).console, db.console);
+    }
+
+    #[test]
+    fn strict_mode() {
+        let mut config = load_configuration_string(r#"
+defaults:
+    strict:
+        $:
+            strict: true
+machines:
+    strict:
+        one:
+            $:
+                ip: 10.0.0.1
+                user: admin
+                identity: ~/.ssh/admin
+        two:
+            $:
+                ip: 10.0.0.2
+    loose:
+        $:
+            user: admin
+"#).unwrap();
+        config.apply_fallbacks(&MachineConfig { user: Some("alice".to_string()), ..Default::default() });
+
+        assert!(config.machine_values["strict:one"].check_values().is_ok());
+        assert_eq!(config.machine_values["strict:two"].check_values().unwrap_err().to_string(), "missing user, identity (strict mode)");
+        assert_eq!(config.machine_values["loose"].check_values().unwrap_err().to_string(), "missing ip");
+        assert!(!config.fallbacks.contains_key("strict:two"));
+    }
+
+    #[test]
+    fn ad_hoc_machines() {
+        let mut config = load_configuration_string(r#"
+defaults:
+    $:
+        user: admin
+        identity: ~/.ssh/admin
+        proxy_command: corkscrew proxy.example.com 8080 %h %p {machine}
+machines:
+    host.example.com:
+        $:
+            ip: 10.0.0.1
+    lab:
+        $:
+            ip: 10.0.0.2
+            proxy_command: ssh -W %h:%p {tag:gateway}
+"#).unwrap();
+        assert_eq!(config.expand_proxy_commands().unwrap_err().to_string(),
+                   "could not expand the proxy command of `lab`: machine `lab` has no `gateway` tag for `{tag:gateway}`");
+        config.machine_values.remove("lab");
+        config.expand_proxy_commands().unwrap();
+        assert_eq!(config.machine_values["host.example.com"].proxy_command.as_deref(), Some("corkscrew proxy.example.com 8080 %h %p host.example.com"));
+
+        assert!(config.add_ad_hoc_machine("1.2.3.4").unwrap());
+        assert_eq!(config.machine_values["1.2.3.4"].user, Some("admin".to_string()));
+        assert!(config.add_ad_hoc_machine("root@1.2.3.5:2222").unwrap());
+        assert_eq!(config.machine_values["root@1.2.3.5:2222"].user, Some("root".to_string()));
+        assert_eq!(config.machine_values["root@1.2.3.5:2222"].identity, Some(vec!["~/.ssh/admin".to_string()]));
+        assert_eq!(config.machine_values["root@1.2.3.5:2222"].proxy_command.as_deref(), Some("corkscrew proxy.example.com 8080 %h %p root@1.2.3.5:2222"));
+
+        assert!(!config.add_ad_hoc_machine("host.example.com").unwrap());
+        assert!(!config.add_ad_hoc_machine("web").unwrap());
+    }
+
+    #[test]
+    fn machine_templates() {
+        let str_content = r#"
+            defaults:
+                $:
+                    user: pouet
+                    port: 22
+                db:
+                    $:
+                        template: database
+            templates:
+                database:
+                    user: postgres
+                    port: 2222
+            machines:
+                web:
+                    $:
+                        ip: 10.0.0.1
+                        template: database
+                        port: 2233
+                db:
+                    one:
+                        $:
+                            ip: 10.0.0.2
+                plain:
+                    $:
+                        ip: 10.0.0.3
+        "#;
+
+        let config = load_configuration_string(str_content).unwrap();
+        let web = &config.machine_values["web"];
+        assert_eq!(web.user, Some("postgres".to_string()));
+        assert_eq!(web.port, Some(vec![2233]));
+
+        let one = &config.machine_values["db:one"];
+        assert_eq!(one.user, Some("postgres".to_string()));
+        assert_eq!(one.port, Some(vec![2222]));
+
+        assert_eq!(config.machine_values["plain"].user, Some("pouet".to_string()));
+    }
+
+    #[test]
+    fn unknown_template() {
+        let error = load_configuration_string(r#"
+            defaults:
+            machines:
+                web:
+                    $:
+                        template: pouet
+        "#).unwrap_err();
+        assert_eq!(error.to_string(), "unknown template `pouet` for machine `web`");
+    }
+
+    #[test]
+    fn settings_and_protection() {
+        let str_content = r#"
+            settings:
+                confirm_threshold: 2
+                notify: bell
+                title_format: "{user}@{name}"
+                identity_agent: ~/.1password/agent.sock
+            defaults:
+                prod:
+                    $:
+                        protected: true
+            machines:
+                prod:
+                    web:
+                        $:
+                            ip: 10.0.0.1
+                    sandbox:
+                        $:
+                            ip: 10.0.0.2
+                            protected: false
+                            agent_socket: /run/user/1000/corp-agent.sock
+        "#;
+
+        let config = load_configuration_string(str_content).unwrap();
+        assert_eq!(config.settings.confirm_threshold, 2);
+        assert_eq!(config.settings.notify, NotifyMode::Bell);
+        assert_eq!(config.settings.title_format, "{user}@{name}");
+        assert!(config.machine_values["prod:web"].is_protected());
+        assert!(!config.machine_values["prod:sandbox"].is_protected());
+        assert_eq!(config.machine_values["prod:web"].agent_socket.as_deref(), Some("~/.1password/agent.sock"));
+        assert_eq!(config.machine_values["prod:sandbox"].agent_socket.as_deref(), Some("/run/user/1000/corp-agent.sock"));
+
+        let config = load_configuration_string("defaults:\nmachines:\n").unwrap();
+        assert_eq!(config.settings.confirm_threshold, 5);
+        assert_eq!(config.settings.title_format, DEFAULT_TITLE_FORMAT);
+        assert!(config.webhooks.is_empty());
+
+        let config = load_configuration_string("settings:\n    max_failures: 3\ndefaults:\nmachines:\n").unwrap();
+        assert_eq!(config.settings.max_failures, Some(3));
+        for value in &["0", "-1", "two"] {
+            let bad = load_configuration_string(&format!("settings:\n    max_failures: {}\ndefaults:\nmachines:\n", value));
+            assert_eq!(bad.unwrap_err().to_string(), "`max_failures` must be a positive integer");
+        }
+    }
+
+    #[test]
+    fn webhooks() {
+        let str_content = r#"
+            notifications:
+                webhooks:
+                    - url: https://hooks.slack.com/services/T/B/X
+                      format: slack
+                    - url: https://example.com/hook
+            defaults:
+            machines:
+        "#;
+
+        let config = load_configuration_string(str_content).unwrap();
+        assert_eq!(config.webhooks.len(), 2);
+        assert_eq!(config.webhooks[0].format, WebhookFormat::Slack);
+        assert_eq!(config.webhooks[1].format, WebhookFormat::Json);
+    }
+
+    #[test]
+    fn generators() {
+        let path = env::temp_dir().join(format!("pssh-generator-{}.rhai", std::process::id()));
+        File::create(&path).unwrap().write_all(br#"
+            #{ "gen:one": #{ ip: "10.0.0.1" }, "web": #{ ip: "10.0.0.99" } }
+        "#).unwrap();
+
+        let str_content = format!(r#"
+            generators: [{:?}]
+            defaults:
+                gen:
+                    $:
+                        user: generated
+            machines:
+                web:
+                    $:
+                        ip: 10.0.0.2
+        "#, path.display().to_string());
+
+        let config = load_configuration_string(&str_content).unwrap();
+
+        assert_eq!(config.machine_values["gen:one"].user, Some("generated".to_string()));
+        assert_eq!(config.machine_values["web"].ip, Some("10.0.0.2".to_string()));
+
+        // Relative paths are relative to the configuration file
+        let name = path.file_name().unwrap().to_string_lossy().into_owned();
+        let relative = format!("generators: [{:?}]\ndefaults:\nmachines:\n", name);
+        let config = load_configuration_string_in(&relative, &env::temp_dir()).unwrap();
+        assert!(config.machine_values.contains_key("gen:one"));
+        assert_eq!(configuration_dir("/etc/pssh/config.yml"), Path::new("/etc/pssh"));
+        std::fs::remove_file(&path).ok();
+    }
 }
@@ -0,0 +1,115 @@
+//! Output consensus across machines
+//!
+//! `pssh consensus` runs the same command on machines and groups them by
+//! identical output. The largest group is the reference, and the other ones
+//! are shown as line diffs against it, which points at the few machines whose
+//! configuration or package versions diverge.
+
+use ansi_term::Colour;
+
+use envdiff::{diff_lines, DiffLine};
+
+/// Machines which printed the same output
+#[derive(Debug, Clone, PartialEq)]
+pub struct OutputGroup {
+    /// Output, with the exit status of failed commands
+    pub output: String,
+    /// Machine names
+    pub machines: Vec<String>
+}
+
+/// Group machines by output, the largest group first. Groups of the same
+/// size keep the order of their first machine.
+///
+/// # Arguments
+///
+/// * `outputs` - Machine names and outputs
+///
+pub fn group_outputs(outputs: &[(String, String)]) -> Vec<OutputGroup> {
+    let mut groups: Vec<OutputGroup> = vec![];
+    for (name, output) in outputs {
+        match groups.iter_mut().find(|x| &x.output == output) {
+            Some(group) => group.machines.push(name.clone()),
+            None => groups.push(OutputGroup { output: output.clone(), machines: vec![name.clone()] })
+        }
+    }
+
+    // The sort is stable
+    groups.sort_by_key(|x| std::cmp::Reverse(x.machines.len()));
+    groups
+}
+
+/// Render output groups: the reference output, then the diff of every other
+/// group against it.
+///
+/// # Arguments
+///
+/// * `groups` - Output groups, the reference first
+/// * `colored` - Color removed and added lines
+///
+pub fn render_groups(groups: &[OutputGroup], colored: bool) -> String {
+    let paint = |colour: Colour, text: String| if colored { colour.paint(text).to_string() } else { text };
+    let header = |group: &OutputGroup| format!("{} machine(s): {}", group.machines.len(), group.machines.join(", "));
+
+    let reference = match groups.first() {
+        Some(x) => x,
+        None => return String::new()
+    };
+    if groups.len() == 1 {
+        return format!("All {} machine(s) agree:\n{}", reference.machines.len(), reference.output.trim_end());
+    }
+
+    let mut lines = vec![format!("{} outputs.", groups.len()), String::new(), format!("== {}", header(reference))];
+    lines.extend(reference.output.lines().map(|x| format!("  {}", x)));
+
+    for group in &groups[1..] {
+        lines.push(String::new());
+        lines.push(format!("== {}", header(group)));
+        for line in diff_lines(&reference.output, &group.output) {
+            match line {
+                DiffLine::Same(_) => (),
+                DiffLine::Left(text) => lines.push(paint(Colour::Red, format!("- {}", text))),
+                DiffLine::Right(text) => lines.push(paint(Colour::Green, format!("+ {}", text))),
+                DiffLine::Changed(left, right) => {
+                    lines.push(paint(Colour::Red, format!("- {}", left)));
+                    lines.push(paint(Colour::Green, format!("+ {}", right)));
+                }
+            }
+        }
+    }
+
+    lines.join("\n")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn groups() {
+        let outputs: Vec<(String, String)> = vec![
+            ("web:one", "nginx 1.24\nopenssl 3.0\n"),
+            ("web:two", "nginx 1.22\nopenssl 3.0\n"),
+            ("web:three", "nginx 1.24\nopenssl 3.0\n"),
+            ("web:four", "nginx 1.24\n")
+        ].into_iter().map(|(a, b)| (a.to_string(), b.to_string())).collect();
+
+        let groups = group_outputs(&outputs);
+        assert_eq!(groups.iter().map(|x| x.machines.join(",")).collect::<Vec<_>>(), vec!["web:one,web:three", "web:two", "web:four"]);
+        assert_eq!(render_groups(&groups, false), "\
+3 outputs.
+
+== 2 machine(s): web:one, web:three
+  nginx 1.24
+  openssl 3.0
+
+== 1 machine(s): web:two
+- nginx 1.24
++ nginx 1.22
+
+== 1 machine(s): web:four
+- openssl 3.0");
+
+        assert_eq!(render_groups(&group_outputs(&outputs[..1]), false), "All 1 machine(s) agree:\nnginx 1.24\nopenssl 3.0");
+    }
+}
@@ -0,0 +1,192 @@
+//! Connection sharing
+//!
+//! `pssh warm` starts OpenSSH master connections, listening on control
+//! sockets in `~/.pssh/state/control/`. While the socket of a machine exists,
+//! the `ssh` and `scp` commands built for it go through the master connection,
+//! skipping the handshake.
+
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::time::Duration;
+
+use config::MachineConfig;
+use error::Error;
+use state::get_state_dir;
+use wrapper::{ssh_control, ssh_master};
+
+/// Default time master connections stay open after their last session
+pub const DEFAULT_PERSIST: Duration = Duration::from_secs(600);
+
+/// State of a master connection after `warm`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WarmOutcome {
+    /// The master connection was started
+    Started,
+    /// A master connection was already running
+    Running
+}
+
+/// Get the control socket directory (`~/.pssh/state/control`)
+pub fn get_control_dir() -> PathBuf {
+    get_state_dir().join("control")
+}
+
+/// Get the control socket path of a machine, as `user@ip:port`.
+///
+/// The first candidate port names the socket, whichever port the master
+/// connection uses.
+///
+/// # Arguments
+///
+/// * `config` - Machine configuration
+/// * `user` - Username (overrides the configuration user)
+///
+pub fn control_path(config: &MachineConfig, user: Option<&str>) -> PathBuf {
+    let ip = config.ip.as_deref().unwrap_or("");
+    let name = match user.or(config.user.as_deref()) {
+        Some(user) => format!("{}@{}:{}", user, ip, config.first_port()),
+        None => format!("{}:{}", ip, config.first_port())
+    };
+
+    get_control_dir().join(name)
+}
+
+/// Get the control socket path of a machine, if a master connection
+/// created it.
+///
+/// # Arguments
+///
+/// * `config` - Machine configuration
+/// * `user` - Username (overrides the configuration user)
+///
+pub fn active_control_path(config: &MachineConfig, user: Option<&str>) -> Option<PathBuf> {
+    let path = control_path(config, user);
+    if is_socket(&path) { Some(path) } else { None }
+}
+
+#[cfg(unix)]
+fn is_socket(path: &Path) -> bool {
+    use std::os::unix::fs::FileTypeExt;
+
+    fs::symlink_metadata(path).map(|x| x.file_type().is_socket()).unwrap_or(false)
+}
+
+#[cfg(not(unix))]
+fn is_socket(_path: &Path) -> bool {
+    false
+}
+
+/// Start a master connection to a machine, unless one is running.
+///
+/// # Arguments
+///
+/// * `config` - Machine configuration
+/// * `persist` - Time the connection stays open after its last session
+///
+pub fn warm(config: &MachineConfig, persist: Duration) -> Result<WarmOutcome, Error> {
+    let path = control_path(config, None);
+    if is_socket(&path) {
+        if is_running(config)? {
+            return Ok(WarmOutcome::Running);
+        }
+        // Left by a master which did not exit cleanly
+        fs::remove_file(&path).map_err(|e| Error::io(format!("could not remove {}", path.display()), e))?;
+    }
+
+    let dir = get_control_dir();
+    fs::create_dir_all(&dir).map_err(|e| Error::io(format!("could not create {}", dir.display()), e))?;
+    restrict_permissions(&dir)?;
+
+    // The master keeps running in the background: its error output goes to a
+    // file, as it would hold a pipe open
+    let log_path = path.with_file_name(format!("{}.log", path.file_name().unwrap().to_string_lossy()));
+    let log = File::create(&log_path).map_err(|e| Error::io(format!("could not create {}", log_path.display()), e))?;
+    let status = ssh_master(config, &path, persist).command()
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(log)
+        .status()
+        .map_err(|e| Error::io("failed to execute ssh", e));
+
+    let mut output = String::new();
+    File::open(&log_path).and_then(|mut f| f.read_to_string(&mut output)).ok();
+    fs::remove_file(&log_path).ok();
+
+    match status? {
+        status if status.success() => Ok(WarmOutcome::Started),
+        status => Err(Error::Transport(format!("ssh exited with {}: {}", status, output.trim())))
+    }
+}
+
+/// Stop the master connection of a machine, returning whether one was
+/// running.
+///
+/// # Arguments
+///
+/// * `config` - Machine configuration
+///
+pub fn stop(config: &MachineConfig) -> Result<bool, Error> {
+    if active_control_path(config, None).is_none() || !is_running(config)? {
+        return Ok(false);
+    }
+
+    ssh_control(config, "exit").command()
+        .stdin(Stdio::null())
+        .output()
+        .map_err(|e| Error::io("failed to execute ssh", e))
+        .map(|x| x.status.success())
+}
+
+fn is_running(config: &MachineConfig) -> Result<bool, Error> {
+    ssh_control(config, "check").command()
+        .stdin(Stdio::null())
+        .output()
+        .map_err(|e| Error::io("failed to execute ssh", e))
+        .map(|x| x.status.success())
+}
+
+#[cfg(unix)]
+fn restrict_permissions(dir: &Path) -> Result<(), Error> {
+    use std::os::unix::fs::PermissionsExt;
+
+    fs::set_permissions(dir, fs::Permissions::from_mode(0o700))
+        .map_err(|e| Error::io(format!("could not restrict {}", dir.display()), e))
+}
+
+#[cfg(not(unix))]
+fn restrict_permissions(_dir: &Path) -> Result<(), Error> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::env;
+    use std::os::unix::net::UnixListener;
+
+    #[test]
+    fn control_paths() {
+        let config = MachineConfig {
+            ip: Some("10.0.0.1".to_string()),
+            user: Some("deploy".to_string()),
+            port: Some(vec![2222, 22]),
+            ..Default::default()
+        };
+
+        assert!(control_path(&config, None).ends_with("control/deploy@10.0.0.1:2222"));
+        assert!(control_path(&config, Some("root")).ends_with("control/root@10.0.0.1:2222"));
+
+        let dir = env::temp_dir().join(format!("pssh-control-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let socket = dir.join("sock");
+        let _listener = UnixListener::bind(&socket).unwrap();
+        assert!(is_socket(&socket));
+        fs::write(dir.join("file"), "").unwrap();
+        assert!(!is_socket(&dir.join("file")));
+        assert!(!is_socket(&dir.join("missing")));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
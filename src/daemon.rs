@@ -0,0 +1,450 @@
+//! Background daemon
+//!
+//! `pssh daemon start` runs a long-lived process listening on
+//! `~/.pssh/state/daemon.sock`. It keeps configurations resolved in memory,
+//! with their includes and generators, and can keep shared connections of a
+//! selection warm. While it runs, other invocations ask it for their
+//! configuration instead of loading it themselves, and scheduled operations
+//! run when due.
+//!
+//! Requests and responses are single JSON lines.
+
+use std::collections::HashMap;
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{self, Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+use chrono::{Local, Timelike};
+use serde_json::{self, Value};
+use yaml_rust::{YamlEmitter, YamlLoader};
+
+use config::{configuration_dir, load_configuration_string_in, load_resolved_configuration, resolved_configuration_to_yaml, ConfigResult};
+use control::{self, DEFAULT_PERSIST};
+use error::Error;
+use names::check_names;
+use schedule::{run_due, schedules_path};
+use selection::select_machines;
+use state::get_state_dir;
+
+/// Environment variable disabling the delegation to the daemon
+pub const NO_DAEMON_ENV: &str = "PSSH_NO_DAEMON";
+
+/// Reload cached configurations after this long, for included inventories
+/// and generators to be refreshed
+const RELOAD_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Timeout of requests to the daemon
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Time waited for a starting daemon to listen
+const START_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Daemon status
+#[derive(Debug, Clone, PartialEq)]
+pub struct DaemonStatus {
+    /// Process identifier
+    pub pid: u32,
+    /// Running time
+    pub uptime: Duration,
+    /// Cached configuration paths
+    pub configurations: Vec<String>,
+    /// Selector of machines kept warm
+    pub warm: Option<String>
+}
+
+/// Configuration resolved by the daemon
+struct CachedConfiguration {
+    /// Modification time of the file when loaded
+    modified: SystemTime,
+    /// Loading time
+    loaded: Instant,
+    /// Resolved document
+    contents: String
+}
+
+/// Get the daemon socket path (`~/.pssh/state/daemon.sock`)
+pub fn get_socket_path() -> PathBuf {
+    get_state_dir().join("daemon.sock")
+}
+
+/// Get the daemon log path (`~/.pssh/state/daemon.log`)
+pub fn get_log_path() -> PathBuf {
+    get_state_dir().join("daemon.log")
+}
+
+/// Send a request to the daemon, returning its response.
+///
+/// # Arguments
+///
+/// * `request` - Request
+///
+fn send(request: &Value) -> Result<Value, Error> {
+    let path = get_socket_path();
+    let mut stream = connect(&path)
+        .map_err(|e| Error::io(format!("could not connect to {}", path.display()), e))?;
+    writeln!(stream, "{}", request).map_err(|e| Error::io("could not send the request", e))?;
+
+    let mut line = String::new();
+    BufReader::new(stream).read_line(&mut line).map_err(|e| Error::io("could not read the response", e))?;
+    let response: Value = serde_json::from_str(&line)
+        .map_err(|e| Error::Transport(format!("bad daemon response: {}", e)))?;
+
+    match response["error"].as_str() {
+        Some(error) => Err(Error::Transport(error.to_string())),
+        None => Ok(response)
+    }
+}
+
+/// Get the status of the running daemon, if any.
+pub fn status() -> Option<DaemonStatus> {
+    let response = send(&json!({"request": "status"})).ok()?;
+
+    Some(DaemonStatus {
+        pid: response["pid"].as_u64()? as u32,
+        uptime: Duration::from_secs(response["uptime"].as_u64().unwrap_or(0)),
+        configurations: response["configurations"].as_array()
+            .map(|x| x.iter().filter_map(|x| x.as_str().map(String::from)).collect())
+            .unwrap_or_default(),
+        warm: response["warm"].as_str().map(String::from)
+    })
+}
+
+/// Get a configuration from the daemon, if one runs and can load it.
+///
+/// Strict mode and environment fallbacks are left to the caller, as they
+/// depend on the calling process.
+///
+/// # Arguments
+///
+/// * `path` - Configuration file path
+///
+pub fn resolve(path: &str) -> Option<ConfigResult> {
+    if env::var_os(NO_DAEMON_ENV).is_some() || !get_socket_path().exists() {
+        return None;
+    }
+
+    let path = fs::canonicalize(path).ok()?;
+    let request = json!({"request": "resolve", "path": path.to_string_lossy()});
+    let response = match send(&request) {
+        Ok(x) => x,
+        Err(error) => {
+            debug!("Loading {} without the daemon: {}", path.display(), error);
+            return None;
+        }
+    };
+
+    let docs = YamlLoader::load_from_str(response["config"].as_str()?).ok()?;
+    debug!("Loaded {} from the daemon", path.display());
+    docs.first().and_then(|x| load_resolved_configuration(x).ok())
+}
+
+/// Start the daemon in the background.
+///
+/// # Arguments
+///
+/// * `config_file` - Configuration of the machines kept warm
+/// * `warm` - Selector of machines kept warm
+///
+pub fn start(config_file: Option<&str>, warm: Option<&str>) -> Result<u32, Error> {
+    if let Some(status) = status() {
+        return Err(Error::Config(format!("the daemon is already running (pid {})", status.pid)));
+    }
+
+    let state_dir = get_state_dir();
+    fs::create_dir_all(&state_dir).map_err(|e| Error::io(format!("could not create {}", state_dir.display()), e))?;
+    let log_path = get_log_path();
+    let log = File::create(&log_path).map_err(|e| Error::io(format!("could not create {}", log_path.display()), e))?;
+    let log_copy = log.try_clone().map_err(|e| Error::io(format!("could not open {}", log_path.display()), e))?;
+
+    let exe = env::current_exe().map_err(|e| Error::io("could not find the pssh executable", e))?;
+    let mut command = Command::new(exe);
+    if let Some(config_file) = config_file {
+        command.arg("-f").arg(config_file);
+    }
+    command.args(["daemon", "start", "--foreground"]);
+    if let Some(warm) = warm {
+        command.arg("--warm").arg(warm);
+    }
+
+    let mut child = command.env(NO_DAEMON_ENV, "1")
+        .stdin(Stdio::null())
+        .stdout(log)
+        .stderr(log_copy)
+        .spawn()
+        .map_err(|e| Error::io("could not start the daemon", e))?;
+
+    let started = Instant::now();
+    while started.elapsed() < START_TIMEOUT {
+        if let Ok(Some(status)) = child.try_wait() {
+            return Err(Error::Config(format!("the daemon exited with {}, see {}", status, log_path.display())));
+        }
+        if let Some(status) = status() {
+            return Ok(status.pid);
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+
+    Err(Error::Config(format!("the daemon did not start, see {}", log_path.display())))
+}
+
+/// Stop the running daemon, returning whether one was running.
+pub fn stop() -> Result<bool, Error> {
+    if status().is_none() {
+        return Ok(false);
+    }
+
+    send(&json!({"request": "stop"})).map(|_| true)
+}
+
+/// Run the daemon in the current process, until a `stop` request.
+///
+/// # Arguments
+///
+/// * `config_file` - Configuration of the machines kept warm
+/// * `warm` - Selector of machines kept warm
+///
+pub fn serve(config_file: Option<&str>, warm: Option<&str>) -> Result<(), Error> {
+    let path = get_socket_path();
+    if status().is_some() {
+        return Err(Error::Config("the daemon is already running".to_string()));
+    }
+    let state_dir = get_state_dir();
+    fs::create_dir_all(&state_dir).map_err(|e| Error::io(format!("could not create {}", state_dir.display()), e))?;
+    // Left by a daemon which did not exit cleanly
+    fs::remove_file(&path).ok();
+
+    let connections = listen(&path).map_err(|e| Error::io(format!("could not listen on {}", path.display()), e))?;
+    info!("Listening on {}", path.display());
+
+    if let Some(selector) = warm {
+        let (config_file, selector) = (config_file.map(String::from), selector.to_string());
+        thread::spawn(move || keep_warm(config_file.as_deref(), &selector));
+    }
+
+    thread::spawn(run_schedules);
+
+    let started = Instant::now();
+    let mut cache: HashMap<String, CachedConfiguration> = HashMap::new();
+    for stream in connections {
+        let mut stream = match stream {
+            Ok(x) => x,
+            Err(error) => {
+                warn!("Could not accept a connection: {}", error);
+                continue;
+            }
+        };
+
+        let mut line = String::new();
+        if BufReader::new(&mut stream).read_line(&mut line).is_err() {
+            continue;
+        }
+        let request: Value = serde_json::from_str(&line).unwrap_or(Value::Null);
+
+        let response = match request["request"].as_str() {
+            Some("status") => {
+                let mut paths: Vec<&String> = cache.keys().collect();
+                paths.sort();
+                json!({"pid": process::id(), "uptime": started.elapsed().as_secs(), "configurations": paths, "warm": warm})
+            },
+            Some("resolve") => match request["path"].as_str() {
+                Some(path) if Path::new(path).is_absolute() => match cached_configuration(&mut cache, path) {
+                    Ok(contents) => json!({"config": contents}),
+                    Err(error) => json!({"error": error.to_string()})
+                },
+                Some(_) => json!({"error": "the configuration path must be absolute"}),
+                None => json!({"error": "missing path"})
+            },
+            Some("stop") => json!({"stopping": true}),
+            _ => json!({"error": format!("bad request: {}", line.trim())})
+        };
+
+        if let Err(error) = writeln!(stream, "{}", response) {
+            warn!("Could not send a response: {}", error);
+        }
+        if request["request"].as_str() == Some("stop") {
+            break;
+        }
+    }
+
+    fs::remove_file(&path).ok();
+    info!("Stopped");
+    Ok(())
+}
+
+/// Connect to the daemon socket
+#[cfg(unix)]
+fn connect(path: &Path) -> io::Result<impl Read + Write> {
+    use std::os::unix::net::UnixStream;
+
+    let stream = UnixStream::connect(path)?;
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT)).ok();
+    Ok(stream)
+}
+
+#[cfg(not(unix))]
+fn connect(_path: &Path) -> io::Result<io::Empty> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "the daemon needs Unix sockets"))
+}
+
+/// Listen on the daemon socket, returning the incoming connections
+#[cfg(unix)]
+fn listen(path: &Path) -> io::Result<impl Iterator<Item = io::Result<impl Read + Write>>> {
+    use std::os::unix::net::UnixListener;
+
+    let listener = UnixListener::bind(path)?;
+    Ok(std::iter::from_fn(move || {
+        Some(listener.accept().map(|(stream, _)| {
+            stream.set_read_timeout(Some(REQUEST_TIMEOUT)).ok();
+            stream
+        }))
+    }))
+}
+
+#[cfg(not(unix))]
+fn listen(_path: &Path) -> io::Result<std::iter::Empty<io::Result<io::Empty>>> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "the daemon needs Unix sockets"))
+}
+
+/// Get a resolved configuration from the cache, loading it when it changed
+/// or got old.
+///
+/// # Arguments
+///
+/// * `cache` - Cached configurations, by path
+/// * `path` - Configuration file path
+///
+fn cached_configuration<'a>(cache: &'a mut HashMap<String, CachedConfiguration>, path: &str) -> Result<&'a str, Error> {
+    let modified = fs::metadata(path).and_then(|x| x.modified())
+        .map_err(|e| Error::io(format!("could not read {}", path), e))?;
+    let fresh = cache.get(path).map(|x| x.modified == modified && x.loaded.elapsed() < RELOAD_INTERVAL).unwrap_or(false);
+
+    if !fresh {
+        info!("Loading {}...", path);
+        let contents = fs::read_to_string(path).map_err(|e| Error::io(format!("could not read {}", path), e))?;
+        cache.insert(path.to_string(), CachedConfiguration { modified, loaded: Instant::now(), contents: resolve_contents(&contents, path)? });
+    }
+
+    Ok(&cache[path].contents)
+}
+
+/// Load a configuration and emit its resolved document.
+///
+/// # Arguments
+///
+/// * `contents` - Configuration contents
+/// * `path` - Configuration file path, for messages
+///
+fn resolve_contents(contents: &str, path: &str) -> Result<String, Error> {
+    let errors = check_names(contents, Some(path));
+    if !errors.is_empty() {
+        return Err(Error::Names(errors));
+    }
+
+    let result = load_configuration_string_in(contents, configuration_dir(path))?;
+    let doc = YamlLoader::load_from_str(contents).map_err(|e| Error::Config(e.to_string()))?;
+
+    let mut output = String::new();
+    YamlEmitter::new(&mut output).dump(&resolved_configuration_to_yaml(&result, &doc[0]))
+        .map_err(|e| Error::Config(format!("could not emit {}: {:?}", path, e)))?;
+    Ok(output)
+}
+
+/// Keep shared connections to selected machines open, restarting them
+/// before they expire.
+///
+/// # Arguments
+///
+/// * `config_file` - Configuration file path
+/// * `selector` - Machine selector
+///
+fn keep_warm(config_file: Option<&str>, selector: &str) {
+    loop {
+        match ::config::load_configuration_file(config_file) {
+            Ok(config_content) => {
+                let names = select_machines(selector, &config_content.machine_values).unwrap_or_else(|error| {
+                    warn!("{}", error);
+                    vec![]
+                });
+                for name in names {
+                    let config = &config_content.machine_values[&name];
+                    if config.ip.is_none() {
+                        continue;
+                    }
+                    if let Err(error) = control::warm(config, DEFAULT_PERSIST) {
+                        warn!("Could not warm {}: {}", name, error);
+                    }
+                }
+            },
+            Err(error) => warn!("Could not load the configuration to warm `{}`: {}", selector, error)
+        }
+
+        thread::sleep(DEFAULT_PERSIST / 2);
+    }
+}
+
+/// Run scheduled operations when due, at the start of each minute
+fn run_schedules() {
+    let program = match env::current_exe() {
+        Ok(x) => x.to_string_lossy().into_owned(),
+        Err(error) => {
+            warn!("Schedules are disabled, the pssh executable was not found: {}", error);
+            return;
+        }
+    };
+
+    loop {
+        let now = Local::now();
+        let elapsed = u64::from(now.second()) * 1000 + u64::from(now.timestamp_subsec_millis().min(999));
+        thread::sleep(Duration::from_millis(60_000 - elapsed));
+        run_due(&schedules_path(), &Local::now().naive_local(), &program);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use config::load_configuration_string;
+
+    #[test]
+    fn resolved_documents() {
+        let contents = "\
+defaults:
+    $:
+        user: admin
+    web:
+        $:
+            port: 2222
+templates:
+    db:
+        tags: [db]
+settings:
+    confirm_threshold: 3
+machines:
+    web:
+        front:
+            $:
+                ip: 10.0.0.1
+    data:
+        $:
+            ip: 10.0.0.2
+            template: db
+";
+        let resolved = resolve_contents(contents, "config.yml").unwrap();
+        let result = load_resolved_configuration(&YamlLoader::load_from_str(&resolved).unwrap()[0]).unwrap();
+        let expected = load_configuration_string(contents).unwrap();
+
+        assert_eq!(result.machine_values, expected.machine_values);
+        assert_eq!(result.default_values, expected.default_values);
+        assert_eq!(result.templates, expected.templates);
+        assert_eq!(result.settings.confirm_threshold, 3);
+        assert_eq!(result.machine_values["web:front"].port, Some(vec![2222]));
+
+        assert!(resolve_contents("machines:\n    bad key:\n        $:\n", "config.yml").unwrap_err().to_string()
+            .contains("bad machine key `bad key`"));
+    }
+}
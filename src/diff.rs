@@ -0,0 +1,186 @@
+//! Configuration diffs
+//!
+//! Configurations are compared at the resolved-machine level: defaults and
+//! templates are applied before comparing, so the diff shows what actually
+//! changes for each machine rather than how the file was edited.
+
+use ansi_term::Colour;
+
+use config::{ConfigMap, MachineConfig};
+
+/// Displayed instead of passwords
+const MASK: &str = "*******";
+
+/// Change to a machine between two configurations
+#[derive(Debug, PartialEq)]
+pub enum MachineDiff {
+    /// Machine only in the new configuration
+    Added(String),
+    /// Machine only in the old configuration
+    Removed(String),
+    /// Machine with changed fields, as `(field, old, new)`
+    Changed(String, Vec<(&'static str, Option<String>, Option<String>)>)
+}
+
+impl MachineDiff {
+    /// Render the change, in color if asked.
+    ///
+    /// # Arguments
+    ///
+    /// * `colored` - Use colors
+    ///
+    pub fn render(&self, colored: bool) -> String {
+        let paint = |colour: Colour, text: String| {
+            if colored { colour.paint(text).to_string() } else { text }
+        };
+
+        match *self {
+            MachineDiff::Added(ref name) => paint(Colour::Green, format!("+ {}", name)),
+            MachineDiff::Removed(ref name) => paint(Colour::Red, format!("- {}", name)),
+            MachineDiff::Changed(ref name, ref fields) => {
+                let mut lines = vec![paint(Colour::Yellow, format!("~ {}", name))];
+                for &(field, ref old, ref new) in fields {
+                    lines.push(format!("    {}: {} -> {}", field, display(old), display(new)));
+                }
+                lines.join("\n")
+            }
+        }
+    }
+}
+
+/// Compare resolved machines, sorted by machine name.
+///
+/// Changed passwords are reported, but masked.
+///
+/// # Arguments
+///
+/// * `old` - Old machines
+/// * `new` - New machines
+///
+pub fn diff_machines(old: &ConfigMap, new: &ConfigMap) -> Vec<MachineDiff> {
+    let mut names: Vec<&String> = old.keys().chain(new.keys().filter(|x| !old.contains_key(*x))).collect();
+    names.sort();
+
+    names.into_iter().filter_map(|name| {
+        match (old.get(name), new.get(name)) {
+            (Some(_), None) => Some(MachineDiff::Removed(name.clone())),
+            (None, Some(_)) => Some(MachineDiff::Added(name.clone())),
+            (Some(a), Some(b)) => {
+                let changes: Vec<_> = fields(a).into_iter().zip(fields(b))
+                    .filter(|x| (x.0).1 != (x.1).1)
+                    .map(|((field, a), (_, b))| match field {
+                        "pass" => (field, a.map(|_| MASK.to_string()), b.map(|_| MASK.to_string())),
+                        _ => (field, a, b)
+                    })
+                    .collect();
+
+                if changes.is_empty() { None } else { Some(MachineDiff::Changed(name.clone(), changes)) }
+            },
+            (None, None) => None
+        }
+    }).collect()
+}
+
+/// Comparable machine fields
+fn fields(config: &MachineConfig) -> Vec<(&'static str, Option<String>)> {
+    vec![
+        ("ip", config.location_ips.as_ref().map(|x| x.iter().map(|(k, v)| format!("{}={}", k, v)).collect::<Vec<_>>().join(", ")).or_else(|| config.ip.clone())),
+        ("port", config.port.as_ref().map(|x| x.iter().map(|x| x.to_string()).collect::<Vec<_>>().join(", "))),
+        ("user", config.user.clone()),
+        ("pass", config.pass.clone()),
+        ("identity", config.identity.as_ref().map(|x| x.join(", "))),
+        ("via", config.via.as_ref().map(|x| x.join(", "))),
+        ("proxy_command", config.proxy_command.clone()),
+        ("tags", config.tags.as_ref().map(|x| x.join(", "))),
+        ("template", config.template.clone()),
+        ("protected", config.protected.map(|x| x.to_string())),
+        ("restricted_command", config.restricted_command.clone()),
+        ("strict", config.strict.map(|x| x.to_string())),
+        ("ciphers", config.ciphers.clone()),
+        ("macs", config.macs.clone()),
+        ("kex", config.kex.clone()),
+        ("host_key_algorithms", config.host_key_algorithms.clone()),
+        ("compat", config.compat.clone()),
+        ("console", config.console.as_ref().map(|x| x.describe())),
+        ("term", config.term.clone()),
+        ("locale", config.locale.clone()),
+        ("banner", config.banner.clone()),
+        ("quiet", config.quiet.map(|x| x.to_string())),
+        ("auto_add_key", config.auto_add_key.map(|x| x.to_string())),
+        ("agent_socket", config.agent_socket.clone()),
+        ("knock", config.knock.as_ref().map(|x| x.join(", "))),
+        ("knock_delay", config.knock_delay.clone()),
+        ("vpn", config.vpn.as_ref().map(|x| x.up.clone())),
+        ("expires", config.expires.clone()),
+        ("expect", config.expect.as_ref().map(|x| x.iter().map(|x| x.prompt.clone()).collect::<Vec<_>>().join(", "))),
+        ("preserve", config.preserve.map(|x| x.to_string())),
+        ("follow_symlinks", config.follow_symlinks.map(|x| x.to_string())),
+        ("priority", config.priority.map(|x| x.to_string())),
+        ("role", config.role.clone()),
+        ("owner", config.owner.clone()),
+        ("contact", config.contact.clone()),
+        ("on_push", config.on_push.clone()),
+        ("vars", config.vars.as_ref().map(|x| x.iter().map(|(k, v)| format!("{}={}", k, v)).collect::<Vec<_>>().join(", ")))
+    ]
+}
+
+fn display(value: &Option<String>) -> &str {
+    value.as_ref().map(|x| &x[..]).unwrap_or("(none)")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use config::load_configuration_string;
+
+    #[test]
+    fn resolved_diff() {
+        let old = load_configuration_string(r#"
+defaults:
+    $:
+        user: admin
+machines:
+    web:
+        w1:
+            $:
+                ip: 10.0.0.1
+        w2:
+            $:
+                ip: 10.0.0.2
+    db:
+        $:
+            ip: 10.0.1.1
+"#).unwrap().machine_values;
+        let new = load_configuration_string(r#"
+defaults:
+    $:
+        user: deploy
+machines:
+    web:
+        w1:
+            $:
+                ip: 10.0.0.1
+                user: admin
+        w2:
+            $:
+                ip: 10.0.0.4
+    cache:
+        $:
+            ip: 10.0.2.1
+"#).unwrap().machine_values;
+
+        let diff = diff_machines(&old, &new);
+        assert_eq!(diff, vec![
+            MachineDiff::Added("cache".to_string()),
+            MachineDiff::Removed("db".to_string()),
+            MachineDiff::Changed("web:w2".to_string(), vec![
+                ("ip", Some("10.0.0.2".to_string()), Some("10.0.0.4".to_string())),
+                ("user", Some("admin".to_string()), Some("deploy".to_string()))
+            ])
+        ]);
+        assert_eq!(diff[2].render(false), "~ web:w2\n    ip: 10.0.0.2 -> 10.0.0.4\n    user: admin -> deploy");
+
+        assert!(diff_machines(&new, &new).is_empty());
+    }
+}
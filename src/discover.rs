@@ -0,0 +1,252 @@
+//! LAN discovery of SSH hosts
+
+use std::net::{Ipv4Addr, SocketAddr, UdpSocket};
+use std::process::Command;
+use std::time::Duration;
+
+use batch::run_parallel;
+use probe::probe_address;
+use wrapper::execute_capture;
+
+/// Smallest subnet prefix accepted for a scan (65536 addresses)
+const MIN_PREFIX: u32 = 16;
+
+/// Number of addresses probed at once
+const SCAN_PARALLELISM: usize = 128;
+
+/// Discovered host
+#[derive(Debug, Clone, PartialEq)]
+pub struct DiscoveredHost {
+    /// Host IP
+    pub ip: String,
+    /// SSH port
+    pub port: u16,
+    /// Host name, from reverse DNS or mDNS
+    pub hostname: Option<String>,
+    /// Found through mDNS announcements
+    pub mdns: bool
+}
+
+impl DiscoveredHost {
+    /// Suggest a machine name for this host.
+    ///
+    /// The first label of the host name is used, or the IP with dots replaced
+    /// by dashes.
+    pub fn suggested_name(&self) -> String {
+        match self.hostname {
+            Some(ref hostname) => hostname.split('.').next().unwrap_or(hostname).to_string(),
+            None => self.ip.replace('.', "-")
+        }
+    }
+}
+
+/// Parse a subnet in CIDR notation and return its host addresses.
+///
+/// # Arguments
+///
+/// * `subnet` - Subnet (`192.168.1.0/24`)
+///
+pub fn parse_subnet(subnet: &str) -> Option<Vec<Ipv4Addr>> {
+    let mut parts = subnet.splitn(2, '/');
+    let address: Ipv4Addr = parts.next()?.parse().ok()?;
+    let prefix: u32 = parts.next().unwrap_or("32").parse().ok()?;
+
+    if !(MIN_PREFIX..=32).contains(&prefix) {
+        return None;
+    }
+
+    let mask = u32::MAX << (32 - prefix);
+    let network = u32::from(address) & mask;
+    let broadcast = network | !mask;
+
+    // Network and broadcast addresses are not hosts, except for /31 and /32
+    let (first, last) = if prefix >= 31 {
+        (network, broadcast)
+    } else {
+        (network + 1, broadcast - 1)
+    };
+
+    Some((first..=last).map(Ipv4Addr::from).collect())
+}
+
+/// Guess the local /24 subnet from the address of the default route interface.
+pub fn local_subnet() -> Option<String> {
+    let socket = UdpSocket::bind("0.0.0.0:0").ok()?;
+    // No packet is sent, connecting only selects the outgoing interface
+    socket.connect("192.0.2.1:9").ok()?;
+
+    match socket.local_addr().ok()? {
+        SocketAddr::V4(address) => {
+            let octets = address.ip().octets();
+            Some(format!("{}.{}.{}.0/24", octets[0], octets[1], octets[2]))
+        },
+        SocketAddr::V6(_) => None
+    }
+}
+
+/// Probe addresses for an open SSH port.
+///
+/// # Arguments
+///
+/// * `addresses` - Addresses to probe
+/// * `port` - SSH port
+/// * `timeout` - Connection timeout for each address
+///
+pub fn scan(addresses: &[Ipv4Addr], port: u16, timeout: Duration) -> Vec<DiscoveredHost> {
+    let ips: Vec<String> = addresses.iter().map(|x| x.to_string()).collect();
+    let results = run_parallel(&ips, SCAN_PARALLELISM, |ip| {
+        let address = SocketAddr::new(ip.parse().unwrap(), port);
+        probe_address(&address, timeout).is_some()
+    });
+
+    results.into_iter()
+        .filter(|&(_, open)| open)
+        .map(|(ip, _)| DiscoveredHost {
+            hostname: reverse_dns(&ip),
+            ip,
+            port,
+            mdns: false
+        })
+        .collect()
+}
+
+/// List hosts announcing `_ssh._tcp` through mDNS, using `avahi-browse`.
+///
+/// Returns an empty list when `avahi-browse` is not available.
+pub fn browse_mdns() -> Vec<DiscoveredHost> {
+    let mut command = Command::new("avahi-browse");
+    command.args(["--resolve", "--terminate", "--parsable", "_ssh._tcp"]);
+
+    match execute_capture(command) {
+        Ok(output) => parse_avahi_output(&String::from_utf8_lossy(&output.stdout)),
+        Err(_) => {
+            debug!("avahi-browse is not available, skipping mDNS discovery");
+            vec![]
+        }
+    }
+}
+
+/// Parse `avahi-browse --parsable` resolved entries.
+///
+/// # Arguments
+///
+/// * `output` - Command output
+///
+pub fn parse_avahi_output(output: &str) -> Vec<DiscoveredHost> {
+    let mut hosts: Vec<DiscoveredHost> = vec![];
+
+    for line in output.lines() {
+        // =;interface;protocol;name;type;domain;hostname;address;port;txt
+        let fields: Vec<&str> = line.split(';').collect();
+        if fields.len() < 9 || fields[0] != "=" || fields[2] != "IPv4" {
+            continue;
+        }
+
+        let host = DiscoveredHost {
+            ip: fields[7].to_string(),
+            port: fields[8].parse().unwrap_or(22),
+            hostname: Some(fields[6].to_string()),
+            mdns: true
+        };
+
+        if !hosts.iter().any(|x| x.ip == host.ip) {
+            hosts.push(host);
+        }
+    }
+
+    hosts
+}
+
+/// Merge mDNS hosts into scanned hosts, sorted by IP.
+///
+/// # Arguments
+///
+/// * `scanned` - Hosts found by scanning
+/// * `announced` - Hosts found through mDNS
+///
+pub fn merge_hosts(scanned: Vec<DiscoveredHost>, announced: Vec<DiscoveredHost>) -> Vec<DiscoveredHost> {
+    let mut hosts = scanned;
+
+    for host in announced {
+        match hosts.iter_mut().find(|x| x.ip == host.ip) {
+            Some(existing) => {
+                existing.mdns = true;
+                if existing.hostname.is_none() {
+                    existing.hostname = host.hostname;
+                }
+            },
+            None => hosts.push(host)
+        }
+    }
+
+    hosts.sort_by_key(|x| x.ip.parse::<Ipv4Addr>().map(u32::from).unwrap_or(0));
+    hosts
+}
+
+/// Resolve an IP to a host name, using `getent`.
+///
+/// # Arguments
+///
+/// * `ip` - IP address
+///
+pub fn reverse_dns(ip: &str) -> Option<String> {
+    let mut command = Command::new("getent");
+    command.args(["hosts", ip]);
+    let output = execute_capture(command).ok()?;
+
+    String::from_utf8_lossy(&output.stdout)
+        .split_whitespace()
+        .nth(1)
+        .map(String::from)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn subnets() {
+        let hosts = parse_subnet("192.168.1.77/24").unwrap();
+        assert_eq!(hosts.len(), 254);
+        assert_eq!(hosts[0], Ipv4Addr::new(192, 168, 1, 1));
+        assert_eq!(hosts[253], Ipv4Addr::new(192, 168, 1, 254));
+
+        assert_eq!(parse_subnet("10.0.0.5").unwrap(), vec![Ipv4Addr::new(10, 0, 0, 5)]);
+        assert_eq!(parse_subnet("10.0.0.4/31").unwrap().len(), 2);
+        assert!(parse_subnet("10.0.0.0/8").is_none());
+        assert!(parse_subnet("pouet/24").is_none());
+    }
+
+    #[test]
+    fn avahi_output() {
+        let output = "+;eth0;IPv4;nas;_ssh._tcp;local\n\
+                      =;eth0;IPv4;nas;_ssh._tcp;local;nas.local;192.168.1.5;22;\n\
+                      =;eth0;IPv6;nas;_ssh._tcp;local;nas.local;fe80::1;22;\n\
+                      =;wlan0;IPv4;nas;_ssh._tcp;local;nas.local;192.168.1.5;22;\n\
+                      =;eth0;IPv4;pi;_ssh._tcp;local;pi.local;192.168.1.9;2222;\n";
+
+        let hosts = parse_avahi_output(output);
+        assert_eq!(hosts.len(), 2);
+        assert_eq!(hosts[0].hostname, Some("nas.local".to_string()));
+        assert_eq!(hosts[1].port, 2222);
+        assert_eq!(hosts[1].suggested_name(), "pi");
+    }
+
+    #[test]
+    fn merge() {
+        let scanned = vec![
+            DiscoveredHost { ip: "192.168.1.10".to_string(), port: 22, hostname: None, mdns: false },
+            DiscoveredHost { ip: "192.168.1.5".to_string(), port: 22, hostname: None, mdns: false }
+        ];
+        let announced = vec![
+            DiscoveredHost { ip: "192.168.1.5".to_string(), port: 22, hostname: Some("nas.local".to_string()), mdns: true }
+        ];
+
+        let hosts = merge_hosts(scanned, announced);
+        assert_eq!(hosts.len(), 2);
+        assert_eq!(hosts[0].ip, "192.168.1.5");
+        assert!(hosts[0].mdns);
+        assert_eq!(hosts[0].suggested_name(), "nas");
+        assert_eq!(hosts[1].suggested_name(), "192-168-1-10");
+    }
+}
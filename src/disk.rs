@@ -0,0 +1,222 @@
+//! Remote disk usage
+//!
+//! Filesystem usage (`df`) and the largest directories under a path (`du`)
+//! are gathered in one SSH round trip, then sorted and rendered locally.
+
+use config::MachineConfig;
+use error::Error;
+use exec::exec_command;
+use quote::quote;
+
+/// Separator between the `df` and `du` outputs
+const SEPARATOR: &str = "--- du";
+
+/// Filesystems which never hold user data
+const PSEUDO_FILESYSTEMS: &[&str] = &["tmpfs", "devtmpfs", "udev", "none", "shm", "overlay"];
+
+/// Mounted filesystem usage
+#[derive(Debug, Clone, PartialEq)]
+pub struct Filesystem {
+    /// Device
+    pub device: String,
+    /// Total size, in KiB
+    pub size: u64,
+    /// Used space, in KiB
+    pub used: u64,
+    /// Available space, in KiB
+    pub available: u64,
+    /// Mount point
+    pub mount: String
+}
+
+/// Disk usage of a machine
+#[derive(Debug, Clone, PartialEq)]
+pub struct DiskReport {
+    /// Filesystems, fullest first
+    pub filesystems: Vec<Filesystem>,
+    /// Directory sizes in KiB, largest first
+    pub directories: Vec<(u64, String)>
+}
+
+impl Filesystem {
+    /// Used space, in percent
+    pub fn used_percent(&self) -> f64 {
+        if self.used + self.available == 0 {
+            0.0
+        } else {
+            self.used as f64 * 100.0 / (self.used + self.available) as f64
+        }
+    }
+}
+
+impl DiskReport {
+    /// Render the report as text.
+    ///
+    /// # Arguments
+    ///
+    /// * `top` - Maximum number of directories
+    ///
+    pub fn render(&self, top: usize) -> String {
+        let mut rows: Vec<[String; 6]> = vec![
+            ["FILESYSTEM", "SIZE", "USED", "AVAIL", "USE%", "MOUNTED ON"].map(String::from)
+        ];
+        for fs in &self.filesystems {
+            rows.push([
+                fs.device.clone(),
+                format_size(fs.size),
+                format_size(fs.used),
+                format_size(fs.available),
+                format!("{:.0}%", fs.used_percent()),
+                fs.mount.clone()
+            ]);
+        }
+
+        let mut widths = [0; 6];
+        for row in &rows {
+            for (i, cell) in row.iter().enumerate() {
+                widths[i] = widths[i].max(cell.len());
+            }
+        }
+
+        let mut lines: Vec<String> = rows.iter().map(|row| {
+            format!("{:w0$}  {:>w1$}  {:>w2$}  {:>w3$}  {:>w4$}  {}", row[0], row[1], row[2], row[3], row[4], row[5],
+                w0 = widths[0], w1 = widths[1], w2 = widths[2], w3 = widths[3], w4 = widths[4])
+        }).collect();
+
+        if !self.directories.is_empty() {
+            lines.push(String::new());
+            lines.push("Largest directories:".to_string());
+            for &(size, ref path) in self.directories.iter().take(top) {
+                lines.push(format!("  {:>7}  {}", format_size(size), path));
+            }
+        }
+
+        lines.join("\n")
+    }
+}
+
+/// Gather the disk usage of a machine.
+///
+/// # Arguments
+///
+/// * `config` - Machine configuration
+/// * `path` - Directory whose subdirectories are measured
+///
+pub fn gather_disk_usage(config: &MachineConfig, path: &str) -> Result<DiskReport, Error> {
+    let result = exec_command(config, &disk_script(path))?;
+    if result.stdout.trim().is_empty() {
+        return Err(Error::Transport(format!("{}: {}", result.describe(), result.stderr.trim())));
+    }
+
+    parse_disk_report(&result.stdout)
+}
+
+/// Remote script printing `df` and `du` outputs, in KiB
+///
+/// # Arguments
+///
+/// * `path` - Directory whose subdirectories are measured
+///
+fn disk_script(path: &str) -> String {
+    format!("df -P -k; echo {}; du -x -k -d 1 {} 2>/dev/null", quote(SEPARATOR), quote(path))
+}
+
+/// Parse the output of the disk script
+///
+/// # Arguments
+///
+/// * `output` - Script output
+///
+pub fn parse_disk_report(output: &str) -> Result<DiskReport, Error> {
+    let mut sections = output.splitn(2, SEPARATOR);
+    let df = sections.next().unwrap_or("");
+    let du = sections.next().ok_or_else(|| Error::Transport("unexpected df output".to_string()))?;
+
+    let mut filesystems: Vec<Filesystem> = df.lines().skip(1).filter_map(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() < 6 || PSEUDO_FILESYSTEMS.contains(&fields[0]) {
+            return None;
+        }
+
+        Some(Filesystem {
+            device: fields[0].to_string(),
+            size: fields[1].parse().ok()?,
+            used: fields[2].parse().ok()?,
+            available: fields[3].parse().ok()?,
+            mount: fields[5..].join(" ")
+        })
+    }).collect();
+    filesystems.sort_by(|a, b| b.used_percent().partial_cmp(&a.used_percent()).unwrap());
+
+    let mut directories: Vec<(u64, String)> = du.lines().filter_map(|line| {
+        let mut fields = line.splitn(2, '\t');
+        let size = fields.next()?.trim().parse().ok()?;
+        Some((size, fields.next()?.to_string()))
+    }).collect();
+    directories.sort_by(|a, b| b.0.cmp(&a.0).then_with(|| a.1.cmp(&b.1)));
+
+    Ok(DiskReport { filesystems, directories })
+}
+
+/// Format a size in KiB, with a binary unit
+///
+/// # Arguments
+///
+/// * `size` - Size in KiB
+///
+pub fn format_size(size: u64) -> String {
+    let units = ["K", "M", "G", "T", "P"];
+    let mut value = size as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < units.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+
+    if value < 10.0 && unit > 0 {
+        format!("{:.1}{}", value, units[unit])
+    } else {
+        format!("{:.0}{}", value, units[unit])
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn sizes() {
+        assert_eq!(format_size(512), "512K");
+        assert_eq!(format_size(1536), "1.5M");
+        assert_eq!(format_size(50 * 1024 * 1024), "50G");
+        assert_eq!(disk_script("/it's"), "df -P -k; echo '--- du'; du -x -k -d 1 '/it'\\''s' 2>/dev/null");
+    }
+
+    #[test]
+    fn parse() {
+        let output = "\
+Filesystem     1024-blocks     Used Available Capacity Mounted on
+/dev/sda1         41152736 36000000   3039872      93% /
+tmpfs              1024000        0   1024000       0% /dev/shm
+/dev/sdb1        103081248 10000000  87822984      11% /srv/my data
+--- du
+1200\t/var
+5000000\t/home
+80\t/etc
+6000000\t/
+";
+
+        let report = parse_disk_report(output).unwrap();
+        assert_eq!(report.filesystems.len(), 2);
+        assert_eq!(report.filesystems[0].mount, "/");
+        assert_eq!(report.filesystems[1].mount, "/srv/my data");
+        assert_eq!(report.directories[0], (6000000, "/".to_string()));
+        assert_eq!(report.directories[1], (5000000, "/home".to_string()));
+
+        let text = report.render(2);
+        assert!(text.contains("/dev/sda1    39G   34G   2.9G   92%  /\n/dev/sdb1    98G  9.5G    84G   10%  /srv/my data"));
+        assert!(text.ends_with("Largest directories:\n     5.7G  /\n     4.8G  /home"));
+
+        assert!(parse_disk_report("garbage").is_err());
+    }
+}
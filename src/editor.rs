@@ -0,0 +1,473 @@
+//! Configuration file edition
+//!
+//! The configuration is rewritten from its parsed YAML form, so comments and
+//! formatting are not preserved: a backup of the previous file is written
+//! next to it (`config.yml.bak`) before saving, and `has_comments` tells
+//! callers when saving would drop comments, to warn before doing so.
+
+use std::fs::{self, File};
+use std::io::prelude::*;
+
+use yaml_rust::{Yaml, YamlEmitter, YamlLoader};
+use yaml_rust::yaml::Hash;
+
+use config::{extract_machine_values, get_configuration_path, machine_values_to_yaml, MachineConfig};
+use error::Error;
+use migrate::{upgrade, Migration, CONFIG_VERSION};
+use names::validate_name;
+
+/// Editable configuration file
+#[derive(Debug)]
+pub struct ConfigEditor {
+    path: String,
+    doc: Yaml,
+    comments: bool
+}
+
+impl ConfigEditor {
+    /// Open a configuration file for edition.
+    ///
+    /// If no path is given, the user configuration path will be used.
+    ///
+    /// # Arguments
+    ///
+    /// * `path_to_file` - Path to file (optional)
+    ///
+    pub fn open(path_to_file: Option<&str>) -> Result<ConfigEditor, Error> {
+        let path = get_configuration_path(path_to_file);
+        let mut contents = String::new();
+        File::open(&path)
+            .and_then(|mut f| f.read_to_string(&mut contents))
+            .map_err(|e| Error::io(format!("could not read {}", path), e))?;
+
+        let mut editor = ConfigEditor::from_string(&contents)?;
+        editor.path = path;
+        Ok(editor)
+    }
+
+    /// Load a configuration from a string, for edition.
+    ///
+    /// # Arguments
+    ///
+    /// * `contents` - Contents string
+    ///
+    pub fn from_string(contents: &str) -> Result<ConfigEditor, Error> {
+        let docs = YamlLoader::load_from_str(contents).map_err(|e| Error::Config(format!("bad YAML: {}", e)))?;
+        let doc = docs.into_iter().next().unwrap_or(Yaml::Null);
+
+        Ok(ConfigEditor { path: String::new(), doc, comments: contains_comments(contents) })
+    }
+
+    /// Configuration file path
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// Check if the loaded configuration has comments, which are lost when
+    /// it is saved.
+    pub fn has_comments(&self) -> bool {
+        self.comments
+    }
+
+    /// Check if a machine or namespace is defined under `machines`.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - Machine or namespace name (`a:b:c`)
+    ///
+    pub fn contains(&self, name: &str) -> bool {
+        contains_path(&self.doc["machines"], name)
+    }
+
+    /// Get the values of a machine as written in the file, without defaults.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - Machine name (`a:b:c`)
+    ///
+    pub fn get_machine(&self, name: &str) -> Option<MachineConfig> {
+        let mut current = &self.doc["machines"];
+        for part in name.split(':') {
+            current = &current[part];
+        }
+
+        let values = &current["$"];
+        if values.is_badvalue() {
+            None
+        } else {
+            Some(extract_machine_values(values))
+        }
+    }
+
+    /// Set the values of a machine, creating its namespaces if needed.
+    ///
+    /// Existing values of the machine are replaced, nested machines are kept.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - Machine name (`a:b:c`)
+    /// * `config` - Machine values
+    ///
+    pub fn set_machine(&mut self, name: &str, config: &MachineConfig) {
+        let mut current = entry(&mut self.doc, "machines");
+        for part in name.split(':') {
+            current = entry(current, part);
+        }
+
+        *entry(current, "$") = machine_values_to_yaml(config);
+    }
+
+    /// Replace a whole subtree under `machines`, creating its namespaces if needed.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - Namespace name (`a:b`)
+    /// * `subtree` - New subtree
+    ///
+    pub fn set_subtree(&mut self, name: &str, subtree: Yaml) {
+        set_path(entry(&mut self.doc, "machines"), name, subtree);
+    }
+
+    /// Merge a subtree into `machines`, creating its namespaces if needed.
+    ///
+    /// Hashes are merged key by key, other values are replaced: entries
+    /// missing from the subtree are kept.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - Namespace name (`a:b`)
+    /// * `subtree` - Subtree to merge
+    ///
+    pub fn merge_subtree(&mut self, name: &str, subtree: Yaml) {
+        let mut current = entry(&mut self.doc, "machines");
+        for part in name.split(':') {
+            current = entry(current, part);
+        }
+
+        merge(current, subtree);
+    }
+
+    /// Move a machine or a whole namespace under `machines` to a new name,
+    /// with its defaults.
+    ///
+    /// Namespaces left empty by the move are removed.
+    ///
+    /// # Arguments
+    ///
+    /// * `old_name` - Current machine or namespace name (`a:b`)
+    /// * `new_name` - New name (`c:b`)
+    ///
+    pub fn rename(&mut self, old_name: &str, new_name: &str) -> Result<(), Error> {
+        if !self.contains(old_name) {
+            return Err(Error::Selection(format!("`{}` does not exist", old_name)));
+        }
+        validate_name(new_name).map_err(Error::Selection)?;
+        if self.contains(new_name) {
+            return Err(Error::Selection(format!("`{}` already exists", new_name)));
+        }
+        if new_name.starts_with(&format!("{}:", old_name)) {
+            return Err(Error::Selection(format!("cannot move `{}` into itself", old_name)));
+        }
+        let moves_defaults = contains_path(&self.doc["defaults"], old_name);
+        if moves_defaults && contains_path(&self.doc["defaults"], new_name) {
+            return Err(Error::Selection(format!("defaults of `{}` already exist", new_name)));
+        }
+
+        let parts: Vec<&str> = old_name.split(':').collect();
+        let subtree = remove_path(entry(&mut self.doc, "machines"), &parts).unwrap();
+        self.set_subtree(new_name, subtree);
+        if moves_defaults {
+            let defaults = entry(&mut self.doc, "defaults");
+            let subtree = remove_path(defaults, &parts).unwrap();
+            set_path(defaults, new_name, subtree);
+        }
+        Ok(())
+    }
+
+    /// Upgrade the configuration to the current version, returning the
+    /// applied migrations, or `None` when it is already up to date.
+    pub fn upgrade(&mut self) -> Result<Option<Vec<&'static Migration>>, Error> {
+        if self.doc["version"].as_i64() == Some(CONFIG_VERSION) {
+            return Ok(None);
+        }
+
+        upgrade(&mut self.doc).map(Some)
+    }
+
+    /// Render the configuration as YAML.
+    pub fn to_yaml_string(&self) -> String {
+        let mut output = String::new();
+        YamlEmitter::new(&mut output).dump(&self.doc).unwrap();
+        output.push('\n');
+        output
+    }
+
+    /// Save the configuration, keeping a backup of the previous file.
+    pub fn save(&self) -> Result<(), Error> {
+        fs::copy(&self.path, format!("{}.bak", self.path)).ok();
+
+        File::create(&self.path)
+            .and_then(|mut f| f.write_all(self.to_yaml_string().as_bytes()))
+            .map_err(|e| Error::io(format!("could not write {}", self.path), e))
+    }
+}
+
+/// Check if YAML contents hold comments: a `#` starting a line or following
+/// a blank. Quoted values holding ` #` are reported too, erring on the side
+/// of a needless warning.
+///
+/// # Arguments
+///
+/// * `contents` - YAML contents
+///
+fn contains_comments(contents: &str) -> bool {
+    contents.lines().any(|line| {
+        let line = line.trim_start();
+        line.starts_with('#') || line.contains(" #") || line.contains("\t#")
+    })
+}
+
+/// Check if a nested hash entry exists
+///
+/// # Arguments
+///
+/// * `root` - Root YAML node
+/// * `name` - Key path (`a:b:c`)
+///
+fn contains_path(root: &Yaml, name: &str) -> bool {
+    let mut current = root;
+    for part in name.split(':') {
+        current = &current[part];
+        if current.is_badvalue() {
+            return false;
+        }
+    }
+
+    true
+}
+
+/// Replace a nested hash entry, creating its parents if needed
+///
+/// # Arguments
+///
+/// * `root` - Root YAML node
+/// * `name` - Key path (`a:b:c`)
+/// * `value` - New value
+///
+fn set_path(root: &mut Yaml, name: &str, value: Yaml) {
+    let mut current = root;
+    for part in name.split(':') {
+        current = entry(current, part);
+    }
+
+    *current = value;
+}
+
+/// Merge a YAML value into another, recursively for hashes
+///
+/// # Arguments
+///
+/// * `target` - Merged YAML node
+/// * `value` - Value to merge
+///
+fn merge(target: &mut Yaml, value: Yaml) {
+    match value {
+        Yaml::Hash(hash) => for (key, value) in hash {
+            if let Yaml::String(ref key) = key {
+                merge(entry(target, key), value);
+            }
+        },
+        value => *target = value
+    }
+}
+
+/// Get a mutable hash entry, turning the parent into a hash if needed
+///
+/// # Arguments
+///
+/// * `parent` - Parent YAML node
+/// * `key` - Entry key
+///
+fn entry<'a>(parent: &'a mut Yaml, key: &str) -> &'a mut Yaml {
+    if parent.as_hash().is_none() {
+        *parent = Yaml::Hash(Hash::new());
+    }
+
+    match *parent {
+        Yaml::Hash(ref mut hash) => hash.entry(Yaml::String(key.to_string())).or_insert(Yaml::Null),
+        _ => unreachable!()
+    }
+}
+
+/// Remove a nested hash entry, and the parent entries it leaves empty
+///
+/// # Arguments
+///
+/// * `parent` - Parent YAML node
+/// * `parts` - Key path
+///
+fn remove_path(parent: &mut Yaml, parts: &[&str]) -> Option<Yaml> {
+    let hash = match *parent {
+        Yaml::Hash(ref mut hash) => hash,
+        _ => return None
+    };
+
+    let key = Yaml::String(parts[0].to_string());
+    if parts.len() == 1 {
+        return hash.remove(&key);
+    }
+
+    let removed = remove_path(hash.get_mut(&key)?, &parts[1..]);
+    if hash[&key].as_hash().is_some_and(|x| x.is_empty()) {
+        hash.remove(&key);
+    }
+
+    removed
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use config::load_configuration_string;
+
+    #[test]
+    fn set_machines() {
+        let mut editor = ConfigEditor::from_string(r#"
+            defaults:
+                $:
+                    user: pouet
+            machines:
+                one:
+                    $:
+                        ip: 10.0.0.1
+        "#).unwrap();
+
+        assert!(editor.contains("one"));
+        assert!(!editor.contains("two:three"));
+
+        editor.set_machine("two:three", &MachineConfig {
+            ip: Some("10.0.0.3".to_string()),
+            port: Some(vec![2222]),
+            ..Default::default()
+        });
+        editor.set_machine("one", &MachineConfig {
+            ip: Some("10.0.0.11".to_string()),
+            ..Default::default()
+        });
+        assert!(editor.contains("two:three"));
+        assert_eq!(editor.get_machine("two:three").unwrap().port, Some(vec![2222]));
+        assert!(editor.get_machine("two").is_none());
+
+        let config = load_configuration_string(&editor.to_yaml_string()).unwrap();
+        let one = &config.machine_values["one"];
+        let three = &config.machine_values["two:three"];
+
+        assert_eq!(one.ip, Some("10.0.0.11".to_string()));
+        assert_eq!(three.ip, Some("10.0.0.3".to_string()));
+        assert_eq!(three.port, Some(vec![2222]));
+        assert_eq!(three.user, Some("pouet".to_string()));
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(ConfigEditor::from_string("machines: [").unwrap_err().exit_code(), 2);
+        let error = ConfigEditor::open(Some("/nonexistent/config.yml")).unwrap_err();
+        assert!(error.to_string().starts_with("could not read /nonexistent/config.yml"));
+    }
+
+    #[test]
+    fn comments() {
+        assert!(!ConfigEditor::from_string("machines:\n    one:\n        $:\n            ip: a#b\n").unwrap().has_comments());
+        assert!(ConfigEditor::from_string("# Machines\nmachines:\n").unwrap().has_comments());
+        assert!(ConfigEditor::from_string("machines:\n    # one:\n").unwrap().has_comments());
+        assert!(ConfigEditor::from_string("machines: # none yet\n").unwrap().has_comments());
+    }
+
+    #[test]
+    fn empty_machines() {
+        let mut editor = ConfigEditor::from_string("defaults:\nmachines:\n").unwrap();
+        editor.set_machine("one", &MachineConfig {
+            ip: Some("localhost".to_string()),
+            ..Default::default()
+        });
+
+        let config = load_configuration_string(&editor.to_yaml_string()).unwrap();
+        assert_eq!(config.machine_values.len(), 1);
+    }
+
+    #[test]
+    fn rename() {
+        let mut editor = ConfigEditor::from_string(r#"
+            defaults:
+                staging:
+                    web:
+                        $:
+                            user: www
+            machines:
+                staging:
+                    web:
+                        $:
+                            ip: 10.0.0.1
+                        api:
+                            $:
+                                ip: 10.0.0.2
+                prod:
+                    db:
+                        $:
+                            ip: 10.0.1.1
+        "#).unwrap();
+
+        assert!(editor.rename("staging:web", "prod:db").is_err());
+        assert!(editor.rename("staging:web", "staging:web:new").is_err());
+        assert!(editor.rename("staging:web", "prod:my web").is_err());
+        assert!(editor.rename("staging:pouet", "prod:pouet").is_err());
+        editor.rename("staging:web", "prod:web").unwrap();
+
+        assert!(!editor.contains("staging"));
+        let config = load_configuration_string(&editor.to_yaml_string()).unwrap();
+        assert_eq!(config.machine_values.len(), 3);
+        assert_eq!(config.machine_values["prod:web"].ip, Some("10.0.0.1".to_string()));
+        assert_eq!(config.machine_values["prod:web:api"].ip, Some("10.0.0.2".to_string()));
+
+        // Defaults follow the machines
+        assert!(editor.doc["defaults"]["staging"].is_badvalue());
+        assert_eq!(config.machine_values["prod:web"].user, Some("www".to_string()));
+        assert_eq!(config.machine_values["prod:web:api"].user, Some("www".to_string()));
+        assert_eq!(config.machine_values["prod:db"].user, None);
+    }
+
+    #[test]
+    fn merge_subtrees() {
+        let mut editor = ConfigEditor::from_string(r#"
+            defaults:
+            machines:
+                cloud:
+                    web:
+                        $:
+                            ip: 10.0.0.1
+                            identity: ~/.ssh/web
+                    manual:
+                        $:
+                            ip: 10.0.0.9
+        "#).unwrap();
+        let synced = ConfigEditor::from_string(r#"
+            web:
+                $:
+                    ip: 10.0.0.2
+                    tags: [web]
+            db:
+                $:
+                    ip: 10.0.0.3
+        "#).unwrap();
+        editor.merge_subtree("cloud", synced.doc);
+
+        let config = load_configuration_string(&editor.to_yaml_string()).unwrap();
+        assert_eq!(config.machine_values.len(), 3);
+        assert_eq!(config.machine_values["cloud:web"].ip, Some("10.0.0.2".to_string()));
+        assert_eq!(config.machine_values["cloud:web"].identity, Some(vec!["~/.ssh/web".to_string()]));
+        assert_eq!(config.machine_values["cloud:web"].tags, Some(vec!["web".to_string()]));
+        assert_eq!(config.machine_values["cloud:manual"].ip, Some("10.0.0.9".to_string()));
+        assert_eq!(config.machine_values["cloud:db"].ip, Some("10.0.0.3".to_string()));
+    }
+}
@@ -0,0 +1,265 @@
+//! Copy endpoints
+//!
+//! `pssh cp` takes scp-style paths: `web1:/var/log/syslog` is a path on the
+//! `web1` machine, any other path is local. Machine names may contain `:`
+//! themselves, so the longest configured name followed by `:` wins.
+//!
+//! Copies between two machines go through the local host: the files are
+//! pulled into a temporary directory, then pushed to the destination, each
+//! side with its own port, identities and jump hosts.
+//!
+//! `pssh transfer` streams files between two machines instead, without a
+//! local copy: a `tar` archive created on the source machine is piped
+//! through two `ssh` commands into a `tar` extracting it on the destination
+//! machine.
+
+use std::collections::hash_map::RandomState;
+use std::env;
+use std::fs;
+use std::hash::{BuildHasher, Hasher};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::{self, ExitStatus};
+
+use config::{ConfigMap, MachineConfig};
+use error::Error;
+use ignore::tar_pattern;
+use quote::{quote, quote_path};
+use wrapper::{copy, execute, ssh_remote, CommandSpec, CopyOptions, ScpDirection};
+
+/// Side of a copy
+#[derive(Debug, Clone, PartialEq)]
+pub enum Endpoint {
+    /// Local path
+    Local(String),
+    /// Machine name and remote path
+    Machine(String, String)
+}
+
+impl Endpoint {
+    /// Get the path, local or remote
+    pub fn path(&self) -> &str {
+        match *self {
+            Endpoint::Local(ref path) | Endpoint::Machine(_, ref path) => path
+        }
+    }
+
+    /// Get the machine name, none for local paths
+    pub fn machine(&self) -> Option<&str> {
+        match *self {
+            Endpoint::Local(_) => None,
+            Endpoint::Machine(ref name, _) => Some(name)
+        }
+    }
+}
+
+/// Parse a `MACHINE:PATH` or local path.
+///
+/// # Arguments
+///
+/// * `arg` - Path argument
+/// * `machines` - Machine configurations
+///
+pub fn parse_endpoint(arg: &str, machines: &ConfigMap) -> Endpoint {
+    let machine = machines.keys()
+        .filter(|name| arg.len() > name.len() && arg.starts_with(name.as_str()) && arg[name.len()..].starts_with(':'))
+        .max_by_key(|name| name.len());
+
+    match machine {
+        Some(name) => Endpoint::Machine(name.clone(), arg[name.len() + 1..].to_string()),
+        None => Endpoint::Local(arg.to_string())
+    }
+}
+
+/// Get the machine of copy sources, none when they are all local.
+///
+/// # Arguments
+///
+/// * `sources` - Copy sources
+///
+pub fn source_machine(sources: &[Endpoint]) -> Result<Option<&str>, Error> {
+    let first = sources.first().and_then(Endpoint::machine);
+    if sources.iter().any(|x| x.machine() != first) {
+        return Err(Error::Config("sources must all be local, or on the same machine".to_string()));
+    }
+
+    Ok(first)
+}
+
+/// Attempts at creating the temporary directory of a relay, each under a
+/// new random name
+const RELAY_DIR_ATTEMPTS: usize = 8;
+
+/// Create a temporary directory only the user can access, failing rather
+/// than reusing an existing one.
+fn create_relay_dir() -> Result<PathBuf, Error> {
+    for _ in 0..RELAY_DIR_ATTEMPTS {
+        let suffix = RandomState::new().build_hasher().finish();
+        let dir = env::temp_dir().join(format!("pssh-cp-{}-{:016x}", process::id(), suffix));
+        match create_private_dir(&dir) {
+            Ok(()) => return Ok(dir),
+            Err(ref error) if error.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(error) => return Err(Error::io(format!("could not create {}", dir.display()), error))
+        }
+    }
+
+    Err(Error::io("could not create a temporary directory", io::Error::from(io::ErrorKind::AlreadyExists)))
+}
+
+#[cfg(unix)]
+fn create_private_dir(dir: &Path) -> io::Result<()> {
+    use std::os::unix::fs::DirBuilderExt;
+
+    fs::DirBuilder::new().mode(0o700).create(dir)
+}
+
+#[cfg(not(unix))]
+fn create_private_dir(dir: &Path) -> io::Result<()> {
+    fs::DirBuilder::new().create(dir)
+}
+
+/// Copy files from a machine to another one, through a local temporary
+/// directory.
+///
+/// Returns the status of the failed copy, or of the push.
+///
+/// # Arguments
+///
+/// * `from` - Source machine configuration
+/// * `sources` - Remote source paths
+/// * `to` - Destination machine configuration
+/// * `destination` - Remote destination path
+/// * `options` - Copy options, of both copies
+///
+pub fn relay<S: AsRef<str>>(from: &MachineConfig, sources: &[S], to: &MachineConfig, destination: &str, options: &CopyOptions) -> Result<ExitStatus, Error> {
+    let dir = create_relay_dir()?;
+
+    let result = relay_through(&dir, from, sources, to, destination, options);
+    fs::remove_dir_all(&dir).ok();
+    result
+}
+
+fn relay_through<S: AsRef<str>>(dir: &Path, from: &MachineConfig, sources: &[S], to: &MachineConfig, destination: &str, options: &CopyOptions) -> Result<ExitStatus, Error> {
+    let status = execute(copy(from, sources, &format!("{}/", dir.display()), ScpDirection::Pull, options))?;
+    if !status.success() {
+        return Ok(status);
+    }
+
+    let mut files: Vec<String> = fs::read_dir(dir)
+        .map_err(|e| Error::io(format!("could not read {}", dir.display()), e))?
+        .filter_map(Result::ok)
+        .map(|x| x.path().to_string_lossy().into_owned())
+        .collect();
+    files.sort();
+
+    execute(copy(to, &files, destination, ScpDirection::Push, options))
+}
+
+/// Split a remote path into its parent directory and its name, for
+/// `tar -C`.
+///
+/// # Arguments
+///
+/// * `path` - Remote path
+///
+pub fn split_remote(path: &str) -> (&str, &str) {
+    let path = if path.len() > 1 { path.trim_end_matches('/') } else { path };
+    match path.rsplit_once('/') {
+        Some(("", name)) => ("/", name),
+        Some(x) => x,
+        None => (".", path)
+    }
+}
+
+/// Build the commands streaming files from a machine to a directory of
+/// another one, created if needed: the first one writes a `tar` archive on
+/// its output, which the second one reads.
+///
+/// # Arguments
+///
+/// * `from` - Source machine configuration
+/// * `sources` - Remote source paths
+/// * `to` - Destination machine configuration
+/// * `destination` - Remote destination directory
+/// * `options` - Copy options: `preserve`, `follow_symlinks` and `excludes`
+///   apply
+///
+pub fn transfer_commands<S: AsRef<str>>(from: &MachineConfig, sources: &[S], to: &MachineConfig, destination: &str, options: &CopyOptions) -> (CommandSpec, CommandSpec) {
+    let mut create = vec![if options.follow_symlinks { "tar -chf -" } else { "tar -cf -" }.to_string()];
+    create.extend(options.excludes.iter().map(|x| quote(&format!("--exclude={}", tar_pattern(x)))));
+    for source in sources {
+        let (parent, name) = split_remote(source.as_ref());
+        create.push(format!("-C {} {}", quote_path(parent), quote(name)));
+    }
+
+    let extract = if options.preserve { "-xp" } else { "-xm" };
+    let extract = format!("mkdir -p {0} && tar {1} -f - -C {0}", quote_path(destination), extract);
+
+    (ssh_remote(from, &create.join(" ")), ssh_remote(to, &extract))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use wrapper::execute_pipe;
+
+    #[test]
+    fn endpoints() {
+        let machines = hashmap!{
+            "web".to_string() => MachineConfig::default(),
+            "web:one".to_string() => MachineConfig::default()
+        };
+
+        assert_eq!(parse_endpoint("web:one:/var/log/syslog", &machines), Endpoint::Machine("web:one".to_string(), "/var/log/syslog".to_string()));
+        assert_eq!(parse_endpoint("web:/tmp/", &machines), Endpoint::Machine("web".to_string(), "/tmp/".to_string()));
+        assert_eq!(parse_endpoint("web:two:/tmp/", &machines), Endpoint::Machine("web".to_string(), "two:/tmp/".to_string()));
+        assert_eq!(parse_endpoint("./web:one", &machines), Endpoint::Local("./web:one".to_string()));
+        assert_eq!(parse_endpoint("web", &machines), Endpoint::Local("web".to_string()));
+
+        let local = Endpoint::Local("a".to_string());
+        let remote = |name: &str| Endpoint::Machine(name.to_string(), "a".to_string());
+        assert_eq!(source_machine(&[local.clone(), local.clone()]).unwrap(), None);
+        assert_eq!(source_machine(&[remote("web"), remote("web")]).unwrap(), Some("web"));
+        assert!(source_machine(&[remote("web"), local]).is_err());
+        assert!(source_machine(&[remote("web"), remote("web:one")]).is_err());
+    }
+
+    #[test]
+    fn relay_dirs() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let (first, second) = (create_relay_dir().unwrap(), create_relay_dir().unwrap());
+        assert_ne!(first, second);
+        assert_eq!(fs::metadata(&first).unwrap().permissions().mode() & 0o777, 0o700);
+        assert_eq!(create_private_dir(&first).unwrap_err().kind(), io::ErrorKind::AlreadyExists);
+        fs::remove_dir(&first).unwrap();
+        fs::remove_dir(&second).unwrap();
+    }
+
+    #[test]
+    fn transfer() {
+        assert_eq!(split_remote("/var/log/nginx/"), ("/var/log", "nginx"));
+        assert_eq!(split_remote("/data"), ("/", "data"));
+        assert_eq!(split_remote("~/app"), ("~", "app"));
+        assert_eq!(split_remote("app.conf"), (".", "app.conf"));
+
+        let from = MachineConfig { ip: Some("10.0.0.1".to_string()), agent_socket: Some("/tmp/a.sock".to_string()), ..Default::default() };
+        let to = MachineConfig { ip: Some("10.0.0.2".to_string()), port: Some(vec![2222]), ..Default::default() };
+        let options = CopyOptions { excludes: vec![".git/".to_string()], ..CopyOptions::default() };
+        let (source, sink) = transfer_commands(&from, &["/srv/my app/"], &to, "~/backup", &options);
+
+        assert_eq!(source.envs, vec![("SSH_AUTH_SOCK".to_string(), "/tmp/a.sock".to_string())]);
+        assert_eq!(source.args.last().unwrap(), "tar -chf - --exclude=.git -C /srv 'my app'");
+        assert!(sink.args.windows(2).any(|x| x == ["-p", "2222"]));
+        assert_eq!(sink.args.last().unwrap(), "mkdir -p ~/backup && tar -xm -f - -C ~/backup");
+
+        let mut source = CommandSpec::new("sh");
+        source.args(["-c", "echo data; exit 3"]);
+        let mut sink = CommandSpec::new("sh");
+        sink.args(["-c", "read x && [ \"$x\" = data ]"]);
+        assert_eq!(execute_pipe(source.clone(), sink.clone()).unwrap().code(), Some(3));
+        source.args = vec!["-c".to_string(), "echo data".to_string()];
+        assert!(execute_pipe(source, sink).unwrap().success());
+    }
+}
@@ -0,0 +1,166 @@
+//! Environment diffs between machines
+//!
+//! The same inspection command runs on two machines, and its outputs are
+//! compared line by line, then displayed side by side like `diff -y`.
+
+use ansi_term::Colour;
+
+/// Default inspection command, sorted as `env` output order is arbitrary
+pub const DEFAULT_COMMAND: &str = "env | sort";
+
+/// Default width of side-by-side diffs, when `COLUMNS` is not set
+pub const DEFAULT_WIDTH: usize = 120;
+
+/// Line of a diff between two outputs
+#[derive(Debug, PartialEq)]
+pub enum DiffLine {
+    /// Line in both outputs
+    Same(String),
+    /// Line only in the left output
+    Left(String),
+    /// Line only in the right output
+    Right(String),
+    /// Line replaced between outputs, as `(left, right)`
+    Changed(String, String)
+}
+
+impl DiffLine {
+    /// Is the line the same in both outputs?
+    pub fn is_same(&self) -> bool {
+        matches!(*self, DiffLine::Same(_))
+    }
+}
+
+/// Compare two outputs line by line, pairing removed and added lines as
+/// changes.
+///
+/// # Arguments
+///
+/// * `left` - Left output
+/// * `right` - Right output
+///
+pub fn diff_lines(left: &str, right: &str) -> Vec<DiffLine> {
+    let left: Vec<&str> = left.lines().collect();
+    let right: Vec<&str> = right.lines().collect();
+
+    // Longest common subsequence lengths of the line suffixes
+    let mut lengths = vec![vec![0usize; right.len() + 1]; left.len() + 1];
+    for i in (0..left.len()).rev() {
+        for j in (0..right.len()).rev() {
+            lengths[i][j] = if left[i] == right[j] {
+                lengths[i + 1][j + 1] + 1
+            } else {
+                lengths[i + 1][j].max(lengths[i][j + 1])
+            };
+        }
+    }
+
+    let mut lines = vec![];
+    let (mut removed, mut added) = (vec![], vec![]);
+    let (mut i, mut j) = (0, 0);
+    while i < left.len() || j < right.len() {
+        if i < left.len() && j < right.len() && left[i] == right[j] {
+            flush_changes(&mut lines, &mut removed, &mut added);
+            lines.push(DiffLine::Same(left[i].to_string()));
+            i += 1;
+            j += 1;
+        } else if j == right.len() || (i < left.len() && lengths[i + 1][j] >= lengths[i][j + 1]) {
+            removed.push(left[i].to_string());
+            i += 1;
+        } else {
+            added.push(right[j].to_string());
+            j += 1;
+        }
+    }
+    flush_changes(&mut lines, &mut removed, &mut added);
+
+    lines
+}
+
+fn flush_changes(lines: &mut Vec<DiffLine>, removed: &mut Vec<String>, added: &mut Vec<String>) {
+    let mut removed = removed.drain(..);
+    let mut added = added.drain(..);
+
+    loop {
+        match (removed.next(), added.next()) {
+            (Some(left), Some(right)) => lines.push(DiffLine::Changed(left, right)),
+            (Some(left), None) => lines.push(DiffLine::Left(left)),
+            (None, Some(right)) => lines.push(DiffLine::Right(right)),
+            (None, None) => break
+        }
+    }
+}
+
+/// Render a diff side by side, with `|`, `<` and `>` markers between columns.
+///
+/// # Arguments
+///
+/// * `lines` - Diff lines
+/// * `headers` - Column headers, usually machine names
+/// * `width` - Total width
+/// * `all` - Also show lines which are the same
+/// * `colored` - Use colors
+///
+pub fn render_side_by_side(lines: &[DiffLine], headers: (&str, &str), width: usize, all: bool, colored: bool) -> String {
+    let column = (width.saturating_sub(3) / 2).max(10);
+    let paint = |colour: Colour, text: String| {
+        if colored { colour.paint(text).to_string() } else { text }
+    };
+    let row = |left: &str, marker: char, right: &str| {
+        format!("{:cw$} {} {}", truncate(left, column), marker, truncate(right, column), cw = column)
+            .trim_end()
+            .to_string()
+    };
+
+    let mut rows = vec![row(headers.0, ' ', headers.1), "-".repeat(column * 2 + 3)];
+    for line in lines.iter().filter(|x| all || !x.is_same()) {
+        rows.push(match *line {
+            DiffLine::Same(ref text) => row(text, ' ', text),
+            DiffLine::Left(ref text) => paint(Colour::Red, row(text, '<', "")),
+            DiffLine::Right(ref text) => paint(Colour::Green, row("", '>', text)),
+            DiffLine::Changed(ref left, ref right) => paint(Colour::Yellow, row(left, '|', right))
+        });
+    }
+
+    rows.join("\n")
+}
+
+fn truncate(text: &str, width: usize) -> String {
+    if text.chars().count() <= width {
+        text.to_string()
+    } else {
+        let mut truncated: String = text.chars().take(width - 1).collect();
+        truncated.push('…');
+        truncated
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn line_diffs() {
+        let left = "HOME=/root\nLANG=C\nPATH=/usr/bin\nTERM=xterm\n";
+        let right = "HOME=/root\nLANG=en_US.UTF-8\nPATH=/usr/bin\nTZ=UTC\nTERM=xterm\nUSER=root\n";
+
+        let lines = diff_lines(left, right);
+        assert_eq!(lines, vec![
+            DiffLine::Same("HOME=/root".to_string()),
+            DiffLine::Changed("LANG=C".to_string(), "LANG=en_US.UTF-8".to_string()),
+            DiffLine::Same("PATH=/usr/bin".to_string()),
+            DiffLine::Right("TZ=UTC".to_string()),
+            DiffLine::Same("TERM=xterm".to_string()),
+            DiffLine::Right("USER=root".to_string())
+        ]);
+        assert!(diff_lines(left, left).iter().all(|x| x.is_same()));
+
+        let text = render_side_by_side(&lines, ("a", "b"), 23, false, false);
+        assert_eq!(text, "\
+a            b
+-----------------------
+LANG=C     | LANG=en_U…
+           > TZ=UTC
+           > USER=root");
+    }
+}
@@ -0,0 +1,97 @@
+//! Errors
+//!
+//! Library functions return `Error`, whose variants tell what failed: the
+//! configuration, the machine selection, the connection to a machine, or a
+//! local operation. The command line maps each variant to an exit code.
+
+use std::io;
+
+use thiserror::Error;
+
+use names::NameError;
+
+/// pssh error
+#[derive(Debug, Error)]
+pub enum Error {
+    /// Bad or incomplete configuration
+    #[error("{0}")]
+    Config(String),
+    /// Invalid machine names in a configuration, one per line
+    #[error("{}", .0.iter().map(|x| x.to_string()).collect::<Vec<_>>().join("\n"))]
+    Names(Vec<NameError>),
+    /// Bad machine selection, filter or name
+    #[error("{0}")]
+    Selection(String),
+    /// Failure to reach a machine, or unexpected remote output
+    #[error("{0}")]
+    Transport(String),
+    /// Local I/O failure, including local tools which failed
+    #[error("{context}: {source}")]
+    Io {
+        /// What was being done
+        context: String,
+        /// Cause
+        #[source]
+        source: io::Error
+    }
+}
+
+impl Error {
+    /// Build an I/O error.
+    ///
+    /// # Arguments
+    ///
+    /// * `context` - What was being done
+    /// * `source` - Cause
+    ///
+    pub fn io<S: Into<String>>(context: S, source: io::Error) -> Error {
+        Error::Io { context: context.into(), source }
+    }
+
+    /// Build an error for a local tool which failed, from its error output.
+    ///
+    /// # Arguments
+    ///
+    /// * `context` - What was being done
+    /// * `stderr` - Error output of the tool
+    ///
+    pub fn tool<S: Into<String>>(context: S, stderr: &str) -> Error {
+        Error::io(context, io::Error::other(stderr.trim().to_string()))
+    }
+
+    /// Get the process exit code of the command line for this error.
+    pub fn exit_code(&self) -> i32 {
+        match *self {
+            Error::Config(_) | Error::Names(_) => 2,
+            Error::Selection(_) => 3,
+            Error::Transport(_) => 4,
+            Error::Io { .. } => 5
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn messages() {
+        let error = Error::io("failed to execute ssh", io::Error::new(io::ErrorKind::NotFound, "not found"));
+        assert_eq!(error.to_string(), "failed to execute ssh: not found");
+        assert_eq!(error.exit_code(), 5);
+
+        assert_eq!(Error::tool("git pull failed", "fatal: no remote\n").to_string(), "git pull failed: fatal: no remote");
+        assert_eq!(Error::Config("missing `ip`".to_string()).exit_code(), 2);
+
+        let name_error = |key: &str, line| NameError {
+            file: Some("config.yml".to_string()),
+            location: Some((line, 5)),
+            key: key.to_string(),
+            name: key.to_string(),
+            reason: "bad character".to_string()
+        };
+        let error = Error::Names(vec![name_error("a b", 3), name_error("c/d", 7)]);
+        assert_eq!(error.to_string(), "config.yml:3:5: bad machine key `a b`: bad character\nconfig.yml:7:5: bad machine key `c/d`: bad character");
+        assert_eq!(error.exit_code(), 2);
+    }
+}
@@ -0,0 +1,361 @@
+//! Remote command execution
+
+use std::fs;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::Path;
+use std::process::{Child, ChildStderr, ChildStdout, Stdio};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, SecondsFormat, Utc};
+
+use config::{ConfigMap, MachineConfig};
+use error::Error;
+use quote::quote;
+use wrapper::{ssh_remote, ssh_tty};
+
+/// Delay between two checks of a running command with a timeout
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Grace period before the remote `timeout` command sends SIGKILL, in seconds
+const TIMEOUT_KILL_AFTER: u64 = 5;
+
+/// Time left to read outputs after a timeout, as processes spawned by `ssh`
+/// may keep them open
+const READ_GRACE: Duration = Duration::from_secs(1);
+
+/// Output of a remote command
+#[derive(Debug)]
+pub struct ExecResult {
+    /// Exit code, if the command was not killed by a signal
+    pub status: Option<i32>,
+    /// Standard output
+    pub stdout: String,
+    /// Standard error
+    pub stderr: String,
+    /// The command was killed after its timeout
+    pub timed_out: bool
+}
+
+/// Options of remote command runs
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ExecOptions<'a> {
+    /// Prefix output lines with the time they were received
+    pub timestamp: bool,
+    /// Kill the command after this duration
+    pub timeout: Option<Duration>,
+    /// Standard input of the command, none to detach it
+    pub input: Option<ExecInput<'a>>,
+    /// Attach the command to the terminal through a pseudo-terminal, for
+    /// interactive programs: outputs are not captured
+    pub tty: bool
+}
+
+/// Standard input of remote commands
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExecInput<'a> {
+    /// Data written to the command, for runs on several machines
+    Data(&'a [u8]),
+    /// Standard input of pssh, streamed to the command as it comes
+    Stream
+}
+
+impl ExecResult {
+    /// Check if the command succeeded
+    pub fn success(&self) -> bool {
+        self.status == Some(0) && !self.timed_out
+    }
+
+    /// Exit status summary
+    pub fn describe(&self) -> String {
+        if self.timed_out {
+            return "timed out".to_string();
+        }
+
+        match self.status {
+            Some(code) => format!("exit {}", code),
+            None => "killed".to_string()
+        }
+    }
+}
+
+/// Run a command on a machine, capturing its output.
+///
+/// # Arguments
+///
+/// * `config` - Machine configuration
+/// * `remote_command` - Command to run, interpreted by the remote shell
+///
+pub fn exec_command(config: &MachineConfig, remote_command: &str) -> Result<ExecResult, Error> {
+    exec_command_with(config, remote_command, &ExecOptions::default())
+}
+
+/// Run a command on a configured machine, capturing its output.
+///
+/// # Arguments
+///
+/// * `machines` - Resolved machines
+/// * `machine` - Machine name
+/// * `remote_command` - Command to run, interpreted by the remote shell
+///
+pub fn exec_on(machines: &ConfigMap, machine: &str, remote_command: &str) -> Result<ExecResult, Error> {
+    let config = machines.get(machine).ok_or_else(|| Error::Selection(format!("unknown machine `{}`", machine)))?;
+    exec_command(config, remote_command)
+}
+
+/// Run a command on a machine, capturing its output, with options.
+///
+/// With a timeout, the local `ssh` is killed when the timeout is exceeded,
+/// and the remote command runs under `timeout` when the machine has it, so
+/// that it does not outlive the connection. Commands ending after the local
+/// deadline are timed out, whatever their exit code.
+///
+/// # Arguments
+///
+/// * `config` - Machine configuration
+/// * `remote_command` - Command to run, interpreted by the remote shell
+/// * `options` - Run options
+///
+pub fn exec_command_with(config: &MachineConfig, remote_command: &str, options: &ExecOptions) -> Result<ExecResult, Error> {
+    config.check_values()?;
+    let remote_command = match options.timeout {
+        Some(timeout) => with_remote_timeout(remote_command, timeout),
+        None => remote_command.to_string()
+    };
+    let deadline = options.timeout.map(|x| Instant::now() + x);
+    if options.tty {
+        // The terminal is left to the remote `timeout` command
+        let status = ssh_tty(config, &remote_command).interactive_command().status().map_err(|e| Error::io("failed to execute ssh", e))?;
+        return Ok(ExecResult {
+            status: status.code(),
+            stdout: String::new(),
+            stderr: String::new(),
+            timed_out: deadline_passed(deadline)
+        });
+    }
+
+    let mut child = ssh_remote(config, &remote_command)
+        .command()
+        .stdin(match options.input {
+            Some(ExecInput::Data(_)) => Stdio::piped(),
+            Some(ExecInput::Stream) => Stdio::inherit(),
+            None => Stdio::null()
+        })
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| Error::io("failed to execute ssh", e))?;
+
+    // Commands not reading their input must not block the run
+    if let (Some(mut stdin), Some(ExecInput::Data(input))) = (child.stdin.take(), options.input) {
+        let input = input.to_vec();
+        thread::spawn(move || {
+            let _ = stdin.write_all(&input);
+        });
+    }
+
+    // Outputs are read by detached threads, so that they can be abandoned
+    let (sender, receiver) = mpsc::channel();
+    let streams = vec![ChildStream::Stdout(child.stdout.take().unwrap()), ChildStream::Stderr(child.stderr.take().unwrap())];
+    let outputs: Vec<Arc<Mutex<String>>> = streams.into_iter().map(|stream| {
+        let output = Arc::new(Mutex::new(String::new()));
+        let (thread_output, sender, timestamp) = (output.clone(), sender.clone(), options.timestamp);
+        thread::spawn(move || {
+            read_lines(stream, timestamp, &thread_output);
+            let _ = sender.send(());
+        });
+        output
+    }).collect();
+
+    let status = wait_until(&mut child, deadline).map_err(|e| Error::io("failed to wait on ssh", e))?;
+    let (status, timed_out) = match status {
+        Some(code) => (code, deadline_passed(deadline)),
+        None => (None, true)
+    };
+
+    let grace_end = Instant::now() + READ_GRACE;
+    for _ in 0..outputs.len() {
+        let received = if timed_out {
+            receiver.recv_timeout(grace_end.saturating_duration_since(Instant::now())).is_ok()
+        } else {
+            receiver.recv().is_ok()
+        };
+        if !received {
+            break;
+        }
+    }
+
+    let output = |index: usize| outputs[index].lock().unwrap().clone();
+    Ok(ExecResult {
+        status,
+        stdout: output(0),
+        stderr: output(1),
+        timed_out
+    })
+}
+
+/// Output stream of a child process
+enum ChildStream {
+    Stdout(ChildStdout),
+    Stderr(ChildStderr)
+}
+
+impl Read for ChildStream {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        match *self {
+            ChildStream::Stdout(ref mut x) => x.read(buffer),
+            ChildStream::Stderr(ref mut x) => x.read(buffer)
+        }
+    }
+}
+
+/// Wait for a child process, killing it after the deadline.
+///
+/// Returns the exit code, or `None` when the child was killed.
+fn wait_until(child: &mut Child, deadline: Option<Instant>) -> io::Result<Option<Option<i32>>> {
+    let deadline = match deadline {
+        Some(x) => x,
+        None => return child.wait().map(|x| Some(x.code()))
+    };
+
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(Some(status.code()));
+        }
+        if Instant::now() >= deadline {
+            child.kill()?;
+            child.wait()?;
+            return Ok(None);
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+/// Check if a deadline is set and passed
+fn deadline_passed(deadline: Option<Instant>) -> bool {
+    deadline.is_some_and(|x| Instant::now() >= x)
+}
+
+/// Wrap a remote command with the `timeout` utility, when available
+///
+/// # Arguments
+///
+/// * `remote_command` - Command to run, interpreted by the remote shell
+/// * `timeout` - Command timeout, rounded up to the second
+///
+fn with_remote_timeout(remote_command: &str, timeout: Duration) -> String {
+    let seconds = timeout.as_secs() + if timeout.subsec_nanos() > 0 { 1 } else { 0 };
+    let quoted = quote(remote_command);
+
+    format!("if command -v timeout >/dev/null 2>&1; then exec timeout -k {} {} \"${{SHELL:-sh}}\" -c {}; else exec \"${{SHELL:-sh}}\" -c {}; fi",
+        TIMEOUT_KILL_AFTER, seconds, quoted, quoted)
+}
+
+/// Read a stream line by line into `output`, optionally prefixing lines with
+/// their reception time
+///
+/// # Arguments
+///
+/// * `stream` - Stream to read
+/// * `timestamp` - Prefix lines with their reception time
+/// * `output` - Output buffer
+///
+fn read_lines<R: Read>(stream: R, timestamp: bool, output: &Mutex<String>) {
+    let mut reader = BufReader::new(stream);
+    let mut line = vec![];
+
+    while let Ok(length) = reader.read_until(b'\n', &mut line) {
+        if length == 0 {
+            break;
+        }
+
+        let text = String::from_utf8_lossy(&line);
+        let text = if timestamp {
+            timestamp_line(Utc::now(), text.trim_end_matches('\n'))
+        } else {
+            text.into_owned()
+        };
+        output.lock().unwrap().push_str(&text);
+        line.clear();
+    }
+}
+
+/// Prefix a line with a timestamp
+///
+/// # Arguments
+///
+/// * `time` - Line time
+/// * `line` - Line, without newline
+///
+pub fn timestamp_line(time: DateTime<Utc>, line: &str) -> String {
+    format!("{} {}\n", time.to_rfc3339_opts(SecondsFormat::Millis, true), line)
+}
+
+/// Save the output of a machine to `<dir>/<machine>.out` and
+/// `<dir>/<machine>.err`.
+///
+/// # Arguments
+///
+/// * `dir` - Output directory
+/// * `machine` - Machine name
+/// * `stdout` - Standard output
+/// * `stderr` - Standard error
+///
+pub fn save_output(dir: &Path, machine: &str, stdout: &str, stderr: &str) -> Result<(), Error> {
+    fs::create_dir_all(dir).map_err(|e| Error::io(format!("could not create {}", dir.display()), e))?;
+    for &(extension, contents) in &[("out", stdout), ("err", stderr)] {
+        let path = dir.join(format!("{}.{}", machine, extension));
+        fs::write(&path, contents).map_err(|e| Error::io(format!("could not write {}", path.display()), e))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::env;
+
+    use chrono::TimeZone;
+
+    #[test]
+    fn outputs() {
+        let time = Utc.with_ymd_and_hms(2024, 3, 1, 12, 30, 5).unwrap();
+        assert_eq!(timestamp_line(time, "hello"), "2024-03-01T12:30:05.000Z hello\n");
+        let output = Mutex::new(String::new());
+        read_lines("a\nb".as_bytes(), true, &output);
+        assert_eq!(output.lock().unwrap().lines().count(), 2);
+        assert!(output.lock().unwrap().ends_with("Z b\n"));
+
+        let output = Mutex::new(String::new());
+        read_lines("a\nb".as_bytes(), false, &output);
+        assert_eq!(*output.lock().unwrap(), "a\nb");
+
+        let dir = env::temp_dir().join(format!("pssh-exec-{}", std::process::id()));
+        save_output(&dir, "web:w1", "out\n", "").unwrap();
+        assert_eq!(fs::read_to_string(dir.join("web:w1.out")).unwrap(), "out\n");
+        assert_eq!(fs::read_to_string(dir.join("web:w1.err")).unwrap(), "");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn timeouts() {
+        assert_eq!(with_remote_timeout("echo 'hi'", Duration::from_millis(1500)),
+            "if command -v timeout >/dev/null 2>&1; then exec timeout -k 5 2 \"${SHELL:-sh}\" -c 'echo '\\''hi'\\'''; \
+             else exec \"${SHELL:-sh}\" -c 'echo '\\''hi'\\'''; fi");
+
+        let result = ExecResult { status: None, stdout: String::new(), stderr: String::new(), timed_out: true };
+        assert!(!result.success());
+        assert_eq!(result.describe(), "timed out");
+
+        assert!(!deadline_passed(None));
+        assert!(!deadline_passed(Some(Instant::now() + Duration::from_secs(60))));
+        assert!(deadline_passed(Some(Instant::now())));
+    }
+
+    #[test]
+    fn unknown_machines() {
+        assert_eq!(exec_on(&ConfigMap::new(), "web", "true").unwrap_err().to_string(), "unknown machine `web`");
+    }
+}
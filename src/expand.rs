@@ -0,0 +1,107 @@
+//! Command placeholders
+//!
+//! Commands run on machines can refer to the machine values, expanded before
+//! each run: `{machine}`, `{ip}`, `{user}`, `{port}`, `{tag:KEY}` for the
+//! value of a `KEY=value` tag, and `{var:KEY}` for a machine variable.
+//! Expanded values are quoted for the remote shell when needed. Other braces,
+//! and shell expansions like `${ip}`, are left as is.
+
+use config::MachineConfig;
+use error::Error;
+use quote::quote;
+
+/// Expand the placeholders of a command, for a machine.
+///
+/// # Arguments
+///
+/// * `command` - Command, with placeholders
+/// * `name` - Machine name
+/// * `config` - Machine configuration
+///
+pub fn expand_placeholders(command: &str, name: &str, config: &MachineConfig) -> Result<String, Error> {
+    let mut expanded = String::with_capacity(command.len());
+    let mut rest = command;
+
+    while let Some(start) = rest.find('{') {
+        let (before, after) = rest.split_at(start);
+        expanded.push_str(before);
+
+        let end = match after.find('}') {
+            Some(x) => x,
+            None => {
+                rest = after;
+                break;
+            }
+        };
+        let placeholder = &after[1..end];
+        let value = if before.ends_with('$') { None } else { placeholder_value(placeholder, name, config)? };
+        match value {
+            Some(value) => {
+                expanded.push_str(&quote(&value));
+                rest = &after[end + 1..];
+            },
+            None => {
+                expanded.push('{');
+                rest = &after[1..];
+            }
+        }
+    }
+
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+/// Get the value of a placeholder, none if it is not one
+fn placeholder_value(placeholder: &str, name: &str, config: &MachineConfig) -> Result<Option<String>, Error> {
+    let missing = |what: &str| Error::Config(format!("machine `{}` has no {} for `{{{}}}`", name, what, placeholder));
+
+    let value = match placeholder {
+        "machine" => name.to_string(),
+        "ip" => config.ip.clone().ok_or_else(|| missing("IP address"))?,
+        "user" => config.user.clone().ok_or_else(|| missing("user"))?,
+        "port" => config.first_port().to_string(),
+        _ => if let Some(key) = placeholder.strip_prefix("tag:") {
+            tag_value(config, key).ok_or_else(|| missing(&format!("`{}` tag", key)))?
+        } else if let Some(key) = placeholder.strip_prefix("var:") {
+            config.vars.as_ref().and_then(|x| x.get(key)).cloned().ok_or_else(|| missing(&format!("`{}` variable", key)))?
+        } else {
+            return Ok(None);
+        }
+    };
+
+    Ok(Some(value))
+}
+
+/// Get the value of a `KEY=value` tag
+fn tag_value(config: &MachineConfig, key: &str) -> Option<String> {
+    config.tags.as_ref()?.iter()
+        .filter_map(|x| x.split_once('='))
+        .find(|x| x.0 == key)
+        .map(|x| x.1.to_string())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn placeholders() {
+        let config = MachineConfig {
+            ip: Some("10.0.0.1".to_string()),
+            tags: Some(vec!["web".to_string(), "role=primary db".to_string()]),
+            vars: Some(vec![("database".to_string(), "mydb".to_string())].into_iter().collect()),
+            ..Default::default()
+        };
+
+        assert_eq!(expand_placeholders("pg_dump -f /backup/{machine}.sql mydb", "db:one", &config).unwrap(),
+                   "pg_dump -f /backup/db:one.sql mydb");
+        assert_eq!(expand_placeholders("echo {ip}:{port} {tag:role}", "db:one", &config).unwrap(),
+                   "echo 10.0.0.1:22 'primary db'");
+        assert_eq!(expand_placeholders("awk '{print $1}' ${ip} {", "db:one", &config).unwrap(),
+                   "awk '{print $1}' ${ip} {");
+        assert_eq!(expand_placeholders("pg_dump {var:database}", "db:one", &config).unwrap(), "pg_dump mydb");
+        assert!(expand_placeholders("echo {var:missing}", "db:one", &config).is_err());
+        assert!(expand_placeholders("echo {user}", "db:one", &config).is_err());
+        assert!(expand_placeholders("echo {tag:web}", "db:one", &config).is_err());
+    }
+}
@@ -0,0 +1,356 @@
+//! Login menus
+//!
+//! Some devices and appliances show a menu, or a second password prompt,
+//! before giving a shell. With an `expect` machine value, `pssh connect`
+//! runs `ssh` on a pseudo-terminal and answers the prompts in order, then
+//! hands the session over: the local terminal is relayed as is until the
+//! connection closes.
+
+use std::time::Duration;
+
+use config::ExpectStep;
+
+pub use self::session::run_expect;
+
+/// Time waited for each prompt before handing the session over
+pub const PROMPT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Delay between two checks of the session while no output comes
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Answers prompts, in order, as they show up in a session output
+#[derive(Debug)]
+pub struct Expecter<'a> {
+    steps: &'a [ExpectStep],
+    next: usize,
+    buffer: String
+}
+
+impl<'a> Expecter<'a> {
+    /// Create an expecter waiting for the first prompt.
+    ///
+    /// # Arguments
+    ///
+    /// * `steps` - Prompts and responses
+    ///
+    pub fn new(steps: &'a [ExpectStep]) -> Expecter<'a> {
+        Expecter { steps, next: 0, buffer: String::new() }
+    }
+
+    /// Get the prompt waited for, none when all were answered
+    pub fn waiting(&self) -> Option<&str> {
+        self.steps.get(self.next).map(|x| x.prompt.as_str())
+    }
+
+    /// Stop waiting for prompts
+    pub fn give_up(&mut self) {
+        self.next = self.steps.len();
+        self.buffer.clear();
+    }
+
+    /// Read session output, returning the responses to send, with their
+    /// line endings.
+    ///
+    /// # Arguments
+    ///
+    /// * `output` - Session output
+    ///
+    pub fn feed(&mut self, output: &str) -> Vec<String> {
+        let mut responses = vec![];
+        if self.waiting().is_none() {
+            return responses;
+        }
+
+        self.buffer.push_str(output);
+        while let Some(step) = self.steps.get(self.next) {
+            match self.buffer.find(&step.prompt) {
+                Some(index) => {
+                    self.buffer.drain(..index + step.prompt.len());
+                    responses.push(format!("{}\r", step.response));
+                    self.next += 1;
+                },
+                None => break
+            }
+        }
+
+        // Only the end of the output may hold the start of the next prompt
+        let keep = self.waiting().map(str::len).unwrap_or(0);
+        let mut start = self.buffer.len().saturating_sub(keep);
+        while !self.buffer.is_char_boundary(start) {
+            start -= 1;
+        }
+        self.buffer.drain(..start);
+
+        responses
+    }
+}
+
+/// Sessions on a pseudo-terminal
+#[cfg(unix)]
+mod session {
+    use std::fs::File;
+    use std::io::{self, Read, Write};
+    use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+    use std::os::unix::process::CommandExt;
+    use std::process::{ExitStatus, Stdio};
+    use std::ptr;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use libc;
+
+    use config::ExpectStep;
+    use error::Error;
+    use wrapper::CommandSpec;
+
+    use super::{Expecter, POLL_INTERVAL, PROMPT_TIMEOUT};
+
+    /// Set when the size of the local terminal changed
+    static RESIZED: AtomicBool = AtomicBool::new(false);
+
+    /// Local terminal switched to raw mode, restored when dropped
+    struct RawMode {
+        fd: RawFd,
+        original: libc::termios
+    }
+
+    impl RawMode {
+        /// Switch a terminal to raw mode, none when it is not a terminal
+        fn enable(fd: RawFd) -> Option<RawMode> {
+            unsafe {
+                if libc::isatty(fd) == 0 {
+                    return None;
+                }
+                let mut original: libc::termios = std::mem::zeroed();
+                if libc::tcgetattr(fd, &mut original) != 0 {
+                    return None;
+                }
+                let mut raw = original;
+                libc::cfmakeraw(&mut raw);
+                libc::tcsetattr(fd, libc::TCSANOW, &raw);
+
+                Some(RawMode { fd, original })
+            }
+        }
+    }
+
+    impl Drop for RawMode {
+        fn drop(&mut self) {
+            unsafe {
+                libc::tcsetattr(self.fd, libc::TCSANOW, &self.original);
+            }
+        }
+    }
+
+    extern "C" fn on_resize(_: libc::c_int) {
+        RESIZED.store(true, Ordering::Relaxed);
+    }
+
+    /// `SIGWINCH` handler, the previous one being restored when dropped
+    struct ResizeWatch {
+        previous: libc::sighandler_t
+    }
+
+    impl ResizeWatch {
+        /// Start watching size changes of the local terminal
+        fn start() -> ResizeWatch {
+            RESIZED.store(false, Ordering::Relaxed);
+            let handler = on_resize as extern "C" fn(libc::c_int);
+            let previous = unsafe { libc::signal(libc::SIGWINCH, handler as libc::sighandler_t) };
+
+            ResizeWatch { previous }
+        }
+
+        /// Check if the size changed since the last call
+        fn changed(&self) -> bool {
+            RESIZED.swap(false, Ordering::Relaxed)
+        }
+    }
+
+    impl Drop for ResizeWatch {
+        fn drop(&mut self) {
+            unsafe {
+                libc::signal(libc::SIGWINCH, self.previous);
+            }
+        }
+    }
+
+    /// Give a terminal the size of another one, the kernel signaling the
+    /// change to its processes
+    fn copy_size(from: RawFd, to: RawFd) {
+        unsafe {
+            let mut size: libc::winsize = std::mem::zeroed();
+            if libc::ioctl(from, libc::TIOCGWINSZ, &mut size) == 0 {
+                libc::ioctl(to, libc::TIOCSWINSZ, &size);
+            }
+        }
+    }
+
+    /// Open a pseudo-terminal with the size of the local terminal, returning
+    /// its master and slave sides
+    fn open() -> io::Result<(File, File)> {
+        unsafe {
+            let mut size: libc::winsize = std::mem::zeroed();
+            let sized = libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ, &mut size) == 0;
+
+            let (mut master, mut slave) = (0, 0);
+            let size = if sized { &mut size as *mut libc::winsize } else { ptr::null_mut() };
+            if libc::openpty(&mut master, &mut slave, ptr::null_mut(), ptr::null_mut(), size) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+
+            Ok((File::from_raw_fd(master), File::from_raw_fd(slave)))
+        }
+    }
+
+    /// Check if a file descriptor has data to read, waiting at most for the
+    /// timeout
+    fn wait_readable(fd: RawFd, timeout: Duration) -> bool {
+        let mut poll = libc::pollfd { fd, events: libc::POLLIN, revents: 0 };
+        unsafe { libc::poll(&mut poll, 1, timeout.as_millis() as libc::c_int) > 0 }
+    }
+
+    /// Run an interactive command on a pseudo-terminal, answering prompts
+    /// before relaying the local terminal.
+    ///
+    /// A prompt missing after `PROMPT_TIMEOUT` is reported, and the session is
+    /// handed over as is. Size changes of the local terminal are forwarded to
+    /// the pseudo-terminal.
+    ///
+    /// # Arguments
+    ///
+    /// * `command` - Interactive command, as `ssh`
+    /// * `steps` - Prompts and responses
+    ///
+    pub fn run_expect(command: &CommandSpec, steps: &[ExpectStep]) -> Result<ExitStatus, Error> {
+        let pty_error = |e| Error::io("could not open a pseudo-terminal", e);
+        let (master, slave) = open().map_err(pty_error)?;
+
+        let mut child = {
+            let mut child = command.interactive_command();
+            child.stdin(Stdio::from(slave.try_clone().map_err(pty_error)?))
+                .stdout(Stdio::from(slave.try_clone().map_err(pty_error)?))
+                .stderr(Stdio::from(slave));
+            // The pseudo-terminal becomes the controlling terminal of a new session
+            unsafe {
+                child.pre_exec(|| {
+                    if libc::setsid() == -1 || libc::ioctl(0, libc::TIOCSCTTY, 0) == -1 {
+                        return Err(io::Error::last_os_error());
+                    }
+                    Ok(())
+                });
+            }
+            child.spawn().map_err(|e| Error::io(format!("failed to execute {}", command.program), e))?
+        };
+
+        let raw_mode = RawMode::enable(libc::STDIN_FILENO);
+        let resizes = ResizeWatch::start();
+        let stop = Arc::new(AtomicBool::new(false));
+        let relay = {
+            let mut input = master.try_clone().map_err(pty_error)?;
+            let stop = stop.clone();
+            // Reads only start once input is there, for the relay to stop with
+            // the command instead of waiting for a last key
+            thread::spawn(move || {
+                let mut buffer = [0; 1024];
+                while !stop.load(Ordering::Relaxed) {
+                    if !wait_readable(libc::STDIN_FILENO, POLL_INTERVAL) {
+                        continue;
+                    }
+                    match io::stdin().read(&mut buffer) {
+                        Ok(count) if count > 0 && input.write_all(&buffer[..count]).is_ok() => (),
+                        _ => break
+                    }
+                }
+            })
+        };
+
+        let mut output = master;
+        let mut expecter = Expecter::new(steps);
+        let mut deadline = Instant::now() + PROMPT_TIMEOUT;
+        let mut buffer = [0; 4096];
+        loop {
+            if let Some(prompt) = expecter.waiting() {
+                if Instant::now() >= deadline {
+                    // Raw terminals do not return to the first column by themselves
+                    eprint!("\r\nPrompt `{}` not seen, handing the session over.\r\n", prompt);
+                    expecter.give_up();
+                }
+            }
+            if resizes.changed() {
+                copy_size(libc::STDOUT_FILENO, output.as_raw_fd());
+            }
+
+            if !wait_readable(output.as_raw_fd(), POLL_INTERVAL) {
+                match child.try_wait() {
+                    Ok(None) => continue,
+                    _ => break
+                }
+            }
+
+            // Reads fail once the command exited and closed the terminal
+            let count = match output.read(&mut buffer) {
+                Ok(0) | Err(_) => break,
+                Ok(count) => count
+            };
+            io::stdout().write_all(&buffer[..count]).and_then(|_| io::stdout().flush()).ok();
+            for response in expecter.feed(&String::from_utf8_lossy(&buffer[..count])) {
+                output.write_all(response.as_bytes()).ok();
+                deadline = Instant::now() + PROMPT_TIMEOUT;
+            }
+        }
+
+        let status = child.wait().map_err(|e| Error::io(format!("failed to wait on {}", command.program), e));
+        stop.store(true, Ordering::Relaxed);
+        relay.join().ok();
+        drop(resizes);
+        drop(raw_mode);
+        status
+    }
+}
+
+/// Sessions without pseudo-terminals
+#[cfg(not(unix))]
+mod session {
+    use std::process::ExitStatus;
+
+    use config::ExpectStep;
+    use error::Error;
+    use wrapper::CommandSpec;
+
+    /// Run an interactive command answering prompts, which needs a
+    /// pseudo-terminal
+    pub fn run_expect(_command: &CommandSpec, _steps: &[ExpectStep]) -> Result<ExitStatus, Error> {
+        Err(Error::Config("login prompts need a Unix pseudo-terminal".to_string()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn prompts() {
+        let steps = vec![
+            ExpectStep { prompt: "Select option:".to_string(), response: "3".to_string() },
+            ExpectStep { prompt: "Password:".to_string(), response: "s3cret".to_string() }
+        ];
+        let mut expecter = Expecter::new(&steps);
+
+        assert!(expecter.feed("Welcome\r\n1) Shell\r\n3) CLI\r\nSelect ").is_empty());
+        assert_eq!(expecter.feed("option: "), vec!["3\r"]);
+        assert_eq!(expecter.waiting(), Some("Password:"));
+        assert_eq!(expecter.feed("Password: Select option:"), vec!["s3cret\r"]);
+        assert_eq!(expecter.waiting(), None);
+        assert!(expecter.feed("Password:").is_empty());
+
+        let mut expecter = Expecter::new(&steps);
+        assert_eq!(expecter.feed("Select option: Password: "), vec!["3\r", "s3cret\r"]);
+
+        let mut expecter = Expecter::new(&steps);
+        expecter.give_up();
+        assert!(expecter.feed("Select option:").is_empty());
+    }
+}
@@ -0,0 +1,563 @@
+//! Inventory exports
+//!
+//! Machines are exported as an OpenSSH client configuration, so that native
+//! `ssh`, `scp` and IDE remote features know them too. The configuration is
+//! installed in `~/.ssh/config.d/pssh.conf`, included from `~/.ssh/config`.
+//!
+//! Resolved machines are also exported as flat JSON or CSV rows, for audits
+//! and spreadsheets, and the machine hierarchy as a Graphviz or D2 graph.
+
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde_json::Value;
+
+use atomic::BACKUP_SUFFIX;
+use config::{ConfigMap, MachineConfig};
+use error::Error;
+use state::write_atomic;
+use wrapper::{algorithm_options, locale_options};
+
+/// Path of the installed SSH configuration, relative to `~/.ssh`
+pub const SSH_CONFIG_INCLUDE: &str = "config.d/pssh.conf";
+
+/// Fields of inventory exports, other names being machine variables
+pub const EXPORT_FIELDS: &[&str] = &["name", "ip", "port", "user", "identity", "tags", "template", "protected", "strict", "compat", "banner", "role"];
+
+/// Default fields of inventory exports
+pub const DEFAULT_EXPORT_FIELDS: &str = "name,ip,port,user,tags";
+
+/// Graph file format
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GraphFormat {
+    /// Graphviz DOT
+    Dot,
+    /// D2 diagram
+    D2
+}
+
+impl GraphFormat {
+    /// Parse a graph format name.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - Format name
+    ///
+    pub fn from_name(name: &str) -> Option<GraphFormat> {
+        match name {
+            "dot" => Some(GraphFormat::Dot),
+            "d2" => Some(GraphFormat::D2),
+            _ => None
+        }
+    }
+}
+
+/// Get the SSH host alias of a machine: `:` separators become `.`, as `scp`
+/// would take them for a path separator.
+///
+/// # Arguments
+///
+/// * `name` - Machine name
+///
+pub fn host_alias(name: &str) -> String {
+    name.replace(':', ".")
+}
+
+/// Render machines as an OpenSSH client configuration.
+///
+/// Machines without IP are left out. Only the first candidate port is
+/// exported, and passwords never are.
+///
+/// # Arguments
+///
+/// * `machines` - Machines
+/// * `source` - Configuration file the machines come from
+///
+pub fn render_ssh_config(machines: &ConfigMap, source: &str) -> String {
+    let mut names: Vec<&String> = machines.keys().filter(|x| machines[*x].ip.is_some()).collect();
+    names.sort();
+
+    let mut blocks = vec![format!("# Generated by pssh from {}, do not edit.", source)];
+    for name in names {
+        blocks.push(render_host(&host_alias(name), &machines[name]));
+    }
+
+    blocks.join("\n\n") + "\n"
+}
+
+fn render_host(alias: &str, config: &MachineConfig) -> String {
+    let mut lines = vec![format!("Host {}", alias), format!("    HostName {}", config.ip.as_ref().unwrap())];
+
+    if let Some(ref user) = config.user {
+        lines.push(format!("    User {}", user));
+    }
+    lines.push(format!("    Port {}", config.first_port()));
+    for identity in config.identities() {
+        lines.push(format!("    IdentityFile {}", identity));
+    }
+    // As on the command line, a proxy command replaces the jump hosts, and
+    // `ssh_config` has no fallbacks: only the first jump host is kept
+    if let Some(ref proxy_command) = config.proxy_command {
+        lines.push(format!("    ProxyCommand {}", proxy_command));
+    } else if let Some(jump) = config.jump_hosts().first() {
+        lines.push(format!("    ProxyJump {}", jump));
+    }
+    if let Some(ref socket) = config.agent_socket {
+        lines.push(format!("    IdentityAgent {}", socket));
+    }
+
+    // `-o Option=value` pairs become `Option value` lines
+    for option in algorithm_options(config).iter().chain(&locale_options(config)).filter(|x| *x != "-o") {
+        if let Some((key, value)) = option.split_once('=') {
+            lines.push(format!("    {} {}", key, value));
+        }
+    }
+    if config.is_quiet() {
+        lines.push("    LogLevel ERROR".to_string());
+    }
+
+    lines.join("\n")
+}
+
+/// Tell if connecting to a machine needs options an `ssh://` URL cannot
+/// hold, as identities, jump hosts, proxy commands, agents or algorithms, so
+/// that tools must go through the exported SSH configuration.
+///
+/// # Arguments
+///
+/// * `config` - Machine configuration
+///
+pub fn needs_ssh_config(config: &MachineConfig) -> bool {
+    !config.identities().is_empty() || !config.jump_hosts().is_empty() || config.proxy_command.is_some()
+        || config.agent_socket.is_some() || !algorithm_options(config).is_empty()
+}
+
+/// Build the URL of a git repository on a machine: an `ssh://` URL, or a
+/// `host:path` URL going through the exported SSH configuration.
+///
+/// Relative paths are relative to the home directory of the user.
+///
+/// # Arguments
+///
+/// * `name` - Machine name
+/// * `config` - Machine configuration
+/// * `path` - Repository path on the machine
+/// * `ssh_config` - Go through the exported SSH configuration
+///
+pub fn git_url(name: &str, config: &MachineConfig, path: &str, ssh_config: bool) -> String {
+    if ssh_config {
+        return format!("{}:{}", host_alias(name), path);
+    }
+
+    let ip = config.ip.as_ref().unwrap();
+    let mut url = String::from("ssh://");
+    if let Some(ref user) = config.user {
+        url.push_str(&format!("{}@", user));
+    }
+    url.push_str(&if ip.contains(':') { format!("[{}]", ip) } else { ip.to_string() });
+    if config.first_port() != 22 {
+        url.push_str(&format!(":{}", config.first_port()));
+    }
+    if !path.starts_with('/') {
+        url.push_str("/~/");
+    }
+    url.push_str(path);
+    url
+}
+
+/// Build the rows of an inventory export, one JSON object per machine, in
+/// name order. Passwords are never exported.
+///
+/// # Arguments
+///
+/// * `machines` - Machines
+/// * `fields` - Exported fields, or machine variables
+///
+pub fn export_rows(machines: &ConfigMap, fields: &[&str]) -> Result<Vec<Value>, Error> {
+    for field in fields {
+        let known = EXPORT_FIELDS.contains(field)
+            || machines.values().any(|x| x.vars.as_ref().map(|x| x.contains_key(*field)).unwrap_or(false));
+        if !known {
+            return Err(Error::Config(format!("unknown field `{}`, expected one of {} or a machine variable", field, EXPORT_FIELDS.join(", "))));
+        }
+    }
+
+    let mut names: Vec<&String> = machines.keys().collect();
+    names.sort();
+
+    Ok(names.into_iter().map(|name| {
+        let config = &machines[name];
+        let row = fields.iter()
+            .map(|field| (field.to_string(), field_value(name, config, field)))
+            .collect();
+        Value::Object(row)
+    }).collect())
+}
+
+fn field_value(name: &str, config: &MachineConfig, field: &str) -> Value {
+    match field {
+        "name" => json!(name),
+        "ip" => json!(config.ip),
+        "port" => json!(config.first_port()),
+        "user" => json!(config.user),
+        "identity" => json!(config.identities()),
+        "tags" => json!(config.tags.clone().unwrap_or_default()),
+        "template" => json!(config.template),
+        "protected" => json!(config.is_protected()),
+        "strict" => json!(config.is_strict()),
+        "compat" => json!(config.compat),
+        "banner" => json!(config.banner),
+        "role" => json!(config.role),
+        _ => json!(config.vars.as_ref().and_then(|x| x.get(field)))
+    }
+}
+
+/// Render inventory export rows as CSV, with a header line. Lists are
+/// joined with `, `.
+///
+/// # Arguments
+///
+/// * `rows` - Export rows
+/// * `fields` - Exported fields, in column order
+///
+pub fn render_csv(rows: &[Value], fields: &[&str]) -> String {
+    let mut output = csv_line(fields.iter().map(|x| x.to_string()));
+    for row in rows {
+        output.push_str(&csv_line(fields.iter().map(|field| match row[*field] {
+            Value::Null => String::new(),
+            Value::String(ref x) => x.clone(),
+            Value::Array(ref x) => x.iter().map(|x| x.as_str().map(String::from).unwrap_or_else(|| x.to_string())).collect::<Vec<_>>().join(", "),
+            ref x => x.to_string()
+        })));
+    }
+
+    output
+}
+
+/// Render a CSV line, quoting cells when needed
+fn csv_line<I: Iterator<Item = String>>(cells: I) -> String {
+    let cells: Vec<String> = cells.map(|cell| {
+        if cell.contains([',', '"', '\n', '\r']) {
+            format!("\"{}\"", cell.replace('"', "\"\""))
+        } else {
+            cell
+        }
+    }).collect();
+
+    cells.join(",") + "\r\n"
+}
+
+/// Render the machine hierarchy as a graph: each namespace links to its
+/// children, and machines are labelled with their address. Dashed edges link
+/// machines to their jump hosts, which are nodes of their own when they are
+/// not machines.
+///
+/// # Arguments
+///
+/// * `machines` - Machines
+/// * `format` - Graph file format
+///
+pub fn render_graph(machines: &ConfigMap, format: GraphFormat) -> String {
+    let mut namespaces = BTreeSet::new();
+    for name in machines.keys() {
+        for (index, _) in name.match_indices(':') {
+            namespaces.insert(&name[..index]);
+        }
+    }
+    let mut names: Vec<&String> = machines.keys().filter(|x| !namespaces.contains(x.as_str())).collect();
+    names.sort();
+
+    let quoted = |x: &str| format!("\"{}\"", x.replace('\\', "\\\\").replace('"', "\\\""));
+    let label = |name: &str| quoted(name.rsplit(':').next().unwrap());
+    let machine_label = |name: &str| {
+        let short = name.rsplit(':').next().unwrap();
+        match machines[name].ip {
+            Some(_) => quoted(&format!("{}\n{}", short, machines[name].address())).replace("\n", "\\n"),
+            None => quoted(short)
+        }
+    };
+    let edges: Vec<(&str, &str)> = namespaces.iter().copied().chain(names.iter().map(|x| x.as_str()))
+        .filter_map(|name| name.rfind(':').map(|index| (&name[..index], name)))
+        .collect();
+    let jumps: Vec<(&str, &str)> = names.iter()
+        .flat_map(|name| machines[*name].jump_hosts().iter().map(move |jump| (name.as_str(), jump.as_str())))
+        .collect();
+    let hosts: BTreeSet<&str> = jumps.iter().map(|x| x.1).filter(|x| !machines.contains_key(*x)).collect();
+
+    let mut lines = vec![];
+    match format {
+        GraphFormat::Dot => {
+            lines.push("digraph pssh {".to_string());
+            lines.push("    rankdir=LR;".to_string());
+            lines.push("    node [shape=box];".to_string());
+            for namespace in &namespaces {
+                lines.push(format!("    {} [label={}, shape=folder];", quoted(namespace), label(namespace)));
+            }
+            for name in &names {
+                lines.push(format!("    {} [label={}];", quoted(name), machine_label(name)));
+            }
+            for host in &hosts {
+                lines.push(format!("    {} [shape=ellipse];", quoted(host)));
+            }
+            for (parent, child) in edges {
+                lines.push(format!("    {} -> {};", quoted(parent), quoted(child)));
+            }
+            for (name, jump) in jumps {
+                lines.push(format!("    {} -> {} [style=dashed, label=\"via\"];", quoted(name), quoted(jump)));
+            }
+            lines.push("}".to_string());
+        },
+        GraphFormat::D2 => {
+            lines.push("direction: right".to_string());
+            for namespace in &namespaces {
+                lines.push(format!("{}: {} {{shape: package}}", quoted(namespace), label(namespace)));
+            }
+            for name in &names {
+                lines.push(format!("{}: {}", quoted(name), machine_label(name)));
+            }
+            for host in &hosts {
+                lines.push(format!("{} {{shape: oval}}", quoted(host)));
+            }
+            for (parent, child) in edges {
+                lines.push(format!("{} -> {}", quoted(parent), quoted(child)));
+            }
+            for (name, jump) in jumps {
+                lines.push(format!("{} -> {}: via {{style.stroke-dash: 3}}", quoted(name), quoted(jump)));
+            }
+        }
+    }
+
+    lines.join("\n") + "\n"
+}
+
+/// Add the `Include` directive of the pssh configuration to an SSH client
+/// configuration, unless it is already there.
+///
+/// The directive goes first, as `Include` lines after a `Host` line only
+/// apply to that host.
+///
+/// # Arguments
+///
+/// * `contents` - SSH client configuration contents
+///
+pub fn add_include(contents: &str) -> Option<String> {
+    let included = contents.lines().any(|line| {
+        let mut words = line.split_whitespace();
+        words.next().map(|x| x.eq_ignore_ascii_case("include")).unwrap_or(false)
+            && words.any(|x| x.ends_with(SSH_CONFIG_INCLUDE))
+    });
+
+    if included {
+        None
+    } else {
+        Some(format!("Include {}\n\n{}", SSH_CONFIG_INCLUDE, contents))
+    }
+}
+
+/// Install an exported SSH configuration in an SSH directory, and include it
+/// from its `config` file if needed.
+///
+/// Files are replaced atomically, so that `ssh` never reads a partial one,
+/// and the previous `config` file is kept with a `.bak` suffix.
+///
+/// Returns the path of the installed file, and whether the include was added.
+///
+/// # Arguments
+///
+/// * `ssh_dir` - SSH directory, usually `~/.ssh`
+/// * `contents` - Exported configuration
+///
+pub fn install_ssh_config(ssh_dir: &Path, contents: &str) -> Result<(PathBuf, bool), Error> {
+    let path = ssh_dir.join(SSH_CONFIG_INCLUDE);
+    write_atomic(&path, contents).map_err(|e| Error::io(format!("could not write {}", path.display()), e))?;
+
+    let config_path = ssh_dir.join("config");
+    let current = fs::read_to_string(&config_path).unwrap_or_default();
+    match add_include(&current) {
+        Some(updated) => {
+            let write_error = |e| Error::io(format!("could not write {}", config_path.display()), e);
+            if config_path.exists() {
+                let mut backup = config_path.clone().into_os_string();
+                backup.push(BACKUP_SUFFIX);
+                fs::copy(&config_path, &backup).map_err(write_error)?;
+            }
+            write_atomic(&config_path, &updated).map_err(write_error)?;
+            Ok((path, true))
+        },
+        None => Ok((path, false))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::env;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    fn ssh_config() {
+        let machines = hashmap!(
+            "web:w1".to_string() => MachineConfig {
+                ip: Some("10.0.0.1".to_string()),
+                user: Some("deploy".to_string()),
+                pass: Some("secret".to_string()),
+                port: Some(vec![2222, 22]),
+                identity: Some(vec!["~/.ssh/a".to_string(), "~/.ssh/b".to_string()]),
+                ciphers: Some("aes256-ctr".to_string()),
+                locale: Some("C.UTF-8".to_string()),
+                ..Default::default()
+            },
+            "db".to_string() => MachineConfig {
+                ip: Some("10.0.1.1".to_string()),
+                ..Default::default()
+            },
+            "lab".to_string() => MachineConfig {
+                ip: Some("lab.internal".to_string()),
+                via: Some(vec!["bastion".to_string()]),
+                proxy_command: Some("cloudflared access ssh --hostname %h".to_string()),
+                ..Default::default()
+            },
+            "pending".to_string() => MachineConfig::default()
+        );
+
+        assert_eq!(render_ssh_config(&machines, "config.yml"), "\
+# Generated by pssh from config.yml, do not edit.
+
+Host db
+    HostName 10.0.1.1
+    Port 22
+
+Host lab
+    HostName lab.internal
+    Port 22
+    ProxyCommand cloudflared access ssh --hostname %h
+
+Host web.w1
+    HostName 10.0.0.1
+    User deploy
+    Port 2222
+    IdentityFile ~/.ssh/a
+    IdentityFile ~/.ssh/b
+    Ciphers aes256-ctr
+    SetEnv LC_ALL=C.UTF-8
+");
+    }
+
+    #[test]
+    fn git_urls() {
+        let mut config = MachineConfig {
+            ip: Some("10.0.0.1".to_string()),
+            user: Some("git".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(git_url("web:w1", &config, "/srv/app.git", false), "ssh://git@10.0.0.1/srv/app.git");
+        assert_eq!(git_url("web:w1", &config, "app.git", true), "web.w1:app.git");
+        assert!(!needs_ssh_config(&config));
+        assert!(needs_ssh_config(&MachineConfig { proxy_command: Some("nc %h %p".to_string()), ..config.clone() }));
+
+        config.ip = Some("fd00::1".to_string());
+        config.port = Some(vec![2222]);
+        config.identity = Some(vec!["~/.ssh/deploy".to_string()]);
+        assert_eq!(git_url("web:w1", &config, "app.git", false), "ssh://git@[fd00::1]:2222/~/app.git");
+        assert!(needs_ssh_config(&config));
+    }
+
+    #[test]
+    fn includes() {
+        let updated = add_include("Host old\n    User me\n").unwrap();
+        assert_eq!(updated, "Include config.d/pssh.conf\n\nHost old\n    User me\n");
+        assert_eq!(add_include(&updated), None);
+        assert_eq!(add_include("include ~/.ssh/config.d/pssh.conf\n"), None);
+
+        let dir = env::temp_dir().join(format!("pssh-export-{}", std::process::id()));
+        let (path, added) = install_ssh_config(&dir, "Host a\n").unwrap();
+        assert!(added);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "Host a\n");
+        assert!(!install_ssh_config(&dir, "Host b\n").unwrap().1);
+        assert_eq!(fs::read_to_string(dir.join("config")).unwrap(), "Include config.d/pssh.conf\n\n");
+        fs::remove_dir_all(&dir).unwrap();
+
+        // An existing configuration is backed up, and keeps its mode
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("config"), "Host old\n").unwrap();
+        fs::set_permissions(dir.join("config"), fs::Permissions::from_mode(0o600)).unwrap();
+        assert!(install_ssh_config(&dir, "Host a\n").unwrap().1);
+        assert_eq!(fs::read_to_string(dir.join("config.bak")).unwrap(), "Host old\n");
+        assert_eq!(fs::read_to_string(dir.join("config")).unwrap(), "Include config.d/pssh.conf\n\nHost old\n");
+        assert_eq!(fs::metadata(dir.join("config")).unwrap().permissions().mode() & 0o777, 0o600);
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 3);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn inventory() {
+        let machines = hashmap!(
+            "web:w1".to_string() => MachineConfig {
+                ip: Some("10.0.0.1".to_string()),
+                pass: Some("secret".to_string()),
+                tags: Some(vec!["web".to_string(), "region=fra1".to_string()]),
+                vars: Some(hashmap!("description".to_string() => "Front, \"main\"".to_string()).into_iter().collect()),
+                ..Default::default()
+            },
+            "db".to_string() => MachineConfig {
+                ip: Some("10.0.1.1".to_string()),
+                user: Some("postgres".to_string()),
+                ..Default::default()
+            }
+        );
+
+        let fields = vec!["name", "ip", "user", "tags", "description"];
+        let rows = export_rows(&machines, &fields).unwrap();
+        assert_eq!(rows[0], json!({"name": "db", "ip": "10.0.1.1", "user": "postgres", "tags": [], "description": null}));
+        assert_eq!(rows[1]["tags"], json!(["web", "region=fra1"]));
+        assert_eq!(render_csv(&rows, &fields), "\
+name,ip,user,tags,description\r
+db,10.0.1.1,postgres,,\r
+web:w1,10.0.0.1,,\"web, region=fra1\",\"Front, \"\"main\"\"\"\r
+");
+
+        assert!(export_rows(&machines, &["pass"]).is_err());
+    }
+
+    #[test]
+    fn graph() {
+        let machines = hashmap!(
+            "prod:web:w1".to_string() => MachineConfig {
+                ip: Some("10.0.0.1".to_string()),
+                via: Some(vec!["db".to_string(), "admin@bastion:2222".to_string()]),
+                ..Default::default()
+            },
+            "db".to_string() => MachineConfig::default()
+        );
+
+        assert_eq!(render_graph(&machines, GraphFormat::Dot), "\
+digraph pssh {
+    rankdir=LR;
+    node [shape=box];
+    \"prod\" [label=\"prod\", shape=folder];
+    \"prod:web\" [label=\"web\", shape=folder];
+    \"db\" [label=\"db\"];
+    \"prod:web:w1\" [label=\"w1\\n10.0.0.1:22\"];
+    \"admin@bastion:2222\" [shape=ellipse];
+    \"prod\" -> \"prod:web\";
+    \"prod:web\" -> \"prod:web:w1\";
+    \"prod:web:w1\" -> \"db\" [style=dashed, label=\"via\"];
+    \"prod:web:w1\" -> \"admin@bastion:2222\" [style=dashed, label=\"via\"];
+}
+");
+        assert_eq!(render_graph(&machines, GraphFormat::D2), "\
+direction: right
+\"prod\": \"prod\" {shape: package}
+\"prod:web\": \"web\" {shape: package}
+\"db\": \"db\"
+\"prod:web:w1\": \"w1\\n10.0.0.1:22\"
+\"admin@bastion:2222\" {shape: oval}
+\"prod\" -> \"prod:web\"
+\"prod:web\" -> \"prod:web:w1\"
+\"prod:web:w1\" -> \"db\": via {style.stroke-dash: 3}
+\"prod:web:w1\" -> \"admin@bastion:2222\": via {style.stroke-dash: 3}
+");
+    }
+}
@@ -0,0 +1,251 @@
+//! Prometheus exporter
+//!
+//! `pssh exporter` probes the SSH port of the configured machines at a fixed
+//! interval, and serves the results on `/metrics`, in the Prometheus text
+//! format:
+//!
+//! * `pssh_machine_up` - 1 when the SSH port accepts connections
+//! * `pssh_machine_connect_seconds` - connection time of the last probe
+//! * `pssh_machine_last_success_timestamp_seconds` - time the machine was
+//!   last seen up
+//!
+//! Probes are recorded in the machine state, so that the last success times
+//! survive restarts. They also count the connections and checks of `pssh`.
+
+use std::collections::HashMap;
+use std::io::BufReader;
+use std::net::TcpListener;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+
+use api::{read_request, write_response, READ_TIMEOUT};
+use batch::{run_parallel, DEFAULT_PARALLELISM};
+use config::{load_configuration_file, ConfigMap};
+use error::Error;
+use probe::tcp_probe;
+use selection::select_machines;
+use state::StateStore;
+
+/// Default listening address
+pub const DEFAULT_LISTEN: &str = "127.0.0.1:9721";
+
+/// Default interval between two probes of a machine
+pub const DEFAULT_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Connection timeout of probes
+const PROBE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Probe results of a machine
+#[derive(Debug, Clone, PartialEq)]
+pub struct MachineMetrics {
+    /// Probed address
+    pub address: String,
+    /// Connection time, if the SSH port accepted connections
+    pub latency: Option<Duration>,
+    /// Last successful probe
+    pub last_success: Option<DateTime<Utc>>
+}
+
+/// Probe machines, keeping the last success times of previous results.
+///
+/// # Arguments
+///
+/// * `machines` - Resolved machines
+/// * `names` - Machines to probe
+/// * `previous` - Previous results
+///
+pub fn probe_machines(machines: &ConfigMap, names: &[String], previous: &HashMap<String, MachineMetrics>) -> HashMap<String, MachineMetrics> {
+    let now = Utc::now();
+
+    run_parallel(names, DEFAULT_PARALLELISM, |name| {
+        let config = &machines[name];
+        let latency = tcp_probe(config, PROBE_TIMEOUT);
+        let last_success = if latency.is_some() {
+            Some(now)
+        } else {
+            previous.get(name).and_then(|x| x.last_success)
+        };
+
+        MachineMetrics { address: config.address(), latency, last_success }
+    }).into_iter().collect()
+}
+
+/// Render metrics in the Prometheus text format.
+///
+/// # Arguments
+///
+/// * `metrics` - Probe results, per machine
+///
+pub fn render_metrics(metrics: &HashMap<String, MachineMetrics>) -> String {
+    let mut names: Vec<&String> = metrics.keys().collect();
+    names.sort();
+    let labels = |name: &str| format!("machine=\"{}\",address=\"{}\"", escape_label(name), escape_label(&metrics[name].address));
+
+    let mut output = String::new();
+    output.push_str("# HELP pssh_machine_up Whether the SSH port of the machine accepts connections.\n");
+    output.push_str("# TYPE pssh_machine_up gauge\n");
+    for name in &names {
+        let up = if metrics[*name].latency.is_some() { 1 } else { 0 };
+        output.push_str(&format!("pssh_machine_up{{{}}} {}\n", labels(name), up));
+    }
+
+    output.push_str("# HELP pssh_machine_connect_seconds Connection time of the last successful probe.\n");
+    output.push_str("# TYPE pssh_machine_connect_seconds gauge\n");
+    for name in &names {
+        if let Some(latency) = metrics[*name].latency {
+            output.push_str(&format!("pssh_machine_connect_seconds{{{}}} {}\n", labels(name), latency.as_secs_f64()));
+        }
+    }
+
+    output.push_str("# HELP pssh_machine_last_success_timestamp_seconds Time the machine was last seen up, by a probe, a connection or a check.\n");
+    output.push_str("# TYPE pssh_machine_last_success_timestamp_seconds gauge\n");
+    for name in &names {
+        if let Some(time) = metrics[*name].last_success {
+            output.push_str(&format!("pssh_machine_last_success_timestamp_seconds{{{}}} {}\n", labels(name), time.timestamp()));
+        }
+    }
+
+    output
+}
+
+/// Escape a label value
+fn escape_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+/// Probe the selected machines at each interval, until the process is
+/// stopped.
+///
+/// # Arguments
+///
+/// * `config_file` - Configuration file path
+/// * `selector` - Machine selector
+/// * `interval` - Interval between two probes
+/// * `metrics` - Shared results
+///
+fn probe_forever(config_file: Option<&str>, selector: &str, interval: Duration, metrics: &Mutex<HashMap<String, MachineMetrics>>) {
+    loop {
+        // Reloaded each time, for configuration changes to be picked up
+        match load_configuration_file(config_file) {
+            Ok(config_content) => {
+                let machines = config_content.machine_values;
+                let names: Vec<String> = select_machines(selector, &machines)
+                    .unwrap_or_else(|error| {
+                        warn!("{}", error);
+                        vec![]
+                    })
+                    .into_iter()
+                    .filter(|x| machines[x].ip.is_some())
+                    .collect();
+
+                let previous = metrics.lock().unwrap().clone();
+                let results = probe_machines(&machines, &names, &previous);
+                StateStore::update_default(|state| {
+                    for (name, result) in &results {
+                        state.record_probe(name, result.latency.is_some());
+                    }
+                });
+                *metrics.lock().unwrap() = results;
+            },
+            Err(error) => warn!("Could not load the configuration ({}), keeping the last results", error)
+        }
+
+        thread::sleep(interval);
+    }
+}
+
+/// Serve metrics until the process is stopped.
+///
+/// # Arguments
+///
+/// * `address` - Listening address
+/// * `config_file` - Configuration file path
+/// * `selector` - Machine selector
+/// * `interval` - Interval between two probes
+///
+pub fn serve(address: &str, config_file: Option<&str>, selector: &str, interval: Duration) -> Result<(), Error> {
+    let listener = TcpListener::bind(address).map_err(|e| Error::io(format!("could not listen on {}", address), e))?;
+    info!("Listening on http://{}/metrics", address);
+
+    // Last success times survive restarts, through the probes recorded in
+    // the machine state
+    let state = StateStore::load_default();
+    let config_content = load_configuration_file(config_file)?;
+    let initial: HashMap<String, MachineMetrics> = select_machines(selector, &config_content.machine_values)?.into_iter()
+        .filter(|x| config_content.machine_values[x].ip.is_some())
+        .map(|name| {
+            let metrics = MachineMetrics {
+                address: config_content.machine_values[&name].address(),
+                latency: None,
+                last_success: state.get(&name).last_seen
+            };
+            (name, metrics)
+        })
+        .collect();
+    let metrics = Arc::new(Mutex::new(initial));
+
+    let (shared, config_file, selector) = (metrics.clone(), config_file.map(String::from), selector.to_string());
+    thread::spawn(move || probe_forever(config_file.as_deref(), &selector, interval, &shared));
+
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(x) => x,
+            Err(error) => {
+                warn!("Could not accept a connection: {}", error);
+                continue;
+            }
+        };
+        stream.set_read_timeout(Some(READ_TIMEOUT)).ok();
+
+        let response = match read_request(&mut BufReader::new(&stream)) {
+            Ok(ref request) if request.path == "/metrics" && request.method == "GET" =>
+                write_response(&stream, 200, "text/plain; version=0.0.4", &render_metrics(&metrics.lock().unwrap())),
+            Ok(_) => write_response(&stream, 404, "text/plain", "Not found, see /metrics\n"),
+            Err(error) => write_response(&stream, 400, "text/plain", &format!("{}\n", error))
+        };
+        if let Err(error) = response {
+            warn!("{}", error);
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use chrono::TimeZone;
+
+    #[test]
+    fn metrics() {
+        let metrics = hashmap!{
+            "web:w1".to_string() => MachineMetrics {
+                address: "10.0.0.1:22".to_string(),
+                latency: Some(Duration::from_millis(1500)),
+                last_success: Some(Utc.timestamp_opt(1700000000, 0).unwrap())
+            },
+            "web:\"w2\"".to_string() => MachineMetrics {
+                address: "10.0.0.2:22".to_string(),
+                latency: None,
+                last_success: None
+            }
+        };
+
+        assert_eq!(render_metrics(&metrics), "\
+# HELP pssh_machine_up Whether the SSH port of the machine accepts connections.
+# TYPE pssh_machine_up gauge
+pssh_machine_up{machine=\"web:\\\"w2\\\"\",address=\"10.0.0.2:22\"} 0
+pssh_machine_up{machine=\"web:w1\",address=\"10.0.0.1:22\"} 1
+# HELP pssh_machine_connect_seconds Connection time of the last successful probe.
+# TYPE pssh_machine_connect_seconds gauge
+pssh_machine_connect_seconds{machine=\"web:w1\",address=\"10.0.0.1:22\"} 1.5
+# HELP pssh_machine_last_success_timestamp_seconds Time the machine was last seen up, by a probe, a connection or a check.
+# TYPE pssh_machine_last_success_timestamp_seconds gauge
+pssh_machine_last_success_timestamp_seconds{machine=\"web:w1\",address=\"10.0.0.1:22\"} 1700000000
+");
+    }
+}
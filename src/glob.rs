@@ -0,0 +1,194 @@
+//! File globs of transfers
+//!
+//! `push` and `pull` accept several sources and `*`/`?` globs. Local globs
+//! are expanded by pssh, like a shell would, so that quoted patterns work and
+//! patterns matching nothing are reported instead of being copied as is.
+//! Remote globs are left to `scp`, after checking that they match something
+//! on the machine.
+
+use std::fs;
+use std::path::Path;
+
+use config::MachineConfig;
+use error::Error;
+use quote::quote;
+use selection::glob_match;
+use wrapper::{execute_capture, ssh_remote};
+
+/// Check if a path has glob characters.
+///
+/// # Arguments
+///
+/// * `path` - Path
+///
+pub fn has_glob(path: &str) -> bool {
+    path.contains(['*', '?'])
+}
+
+/// Expand local globs, sorted like a shell does. Other paths are kept as is.
+///
+/// # Arguments
+///
+/// * `patterns` - Paths and glob patterns
+///
+pub fn expand_local<S: AsRef<str>>(patterns: &[S]) -> Result<Vec<String>, Error> {
+    let mut paths = vec![];
+    for pattern in patterns.iter().map(|x| x.as_ref()) {
+        if !has_glob(pattern) {
+            paths.push(pattern.to_string());
+            continue;
+        }
+
+        let matches = expand_pattern(pattern);
+        if matches.is_empty() {
+            return Err(Error::Config(format!("no local file matches `{}`", pattern)));
+        }
+        paths.extend(matches);
+    }
+
+    Ok(paths)
+}
+
+/// Expand a local glob, one path component at a time
+fn expand_pattern(pattern: &str) -> Vec<String> {
+    let (mut paths, rest) = match pattern.strip_prefix('/') {
+        Some(rest) => (vec!["/".to_string()], rest),
+        None => (vec![String::new()], pattern)
+    };
+
+    for component in rest.split('/').filter(|x| !x.is_empty()) {
+        let join = |base: &str, name: &str| if base.is_empty() || base.ends_with('/') { format!("{}{}", base, name) } else { format!("{}/{}", base, name) };
+
+        if !has_glob(component) {
+            paths = paths.iter().map(|x| join(x, component)).filter(|x| Path::new(x).exists()).collect();
+            continue;
+        }
+
+        paths = paths.iter().flat_map(|base| {
+            let dir = if base.is_empty() { "." } else { base.as_str() };
+            let mut names: Vec<String> = fs::read_dir(dir).into_iter()
+                .flatten()
+                .filter_map(Result::ok)
+                .map(|x| x.file_name().to_string_lossy().into_owned())
+                // Like shells, hidden files only match patterns starting with a dot
+                .filter(|x| glob_match(component, x) && (!x.starts_with('.') || component.starts_with('.')))
+                .collect();
+            names.sort();
+            names.into_iter().map(|x| join(base, &x)).collect::<Vec<_>>()
+        })
+        .collect();
+    }
+
+    paths
+}
+
+/// Quote a remote glob for the remote shell, keeping `*`, `?` and a leading
+/// `~/` unquoted.
+///
+/// # Arguments
+///
+/// * `pattern` - Remote glob pattern
+///
+pub fn quote_glob(pattern: &str) -> String {
+    let (home, rest) = match pattern.strip_prefix("~/") {
+        Some(rest) => ("~/", rest),
+        None => ("", pattern)
+    };
+
+    let mut quoted = home.to_string();
+    let mut literal = String::new();
+    for c in rest.chars() {
+        if c == '*' || c == '?' {
+            if !literal.is_empty() {
+                quoted.push_str(&quote(&literal));
+                literal.clear();
+            }
+            quoted.push(c);
+        } else {
+            literal.push(c);
+        }
+    }
+    if !literal.is_empty() {
+        quoted.push_str(&quote(&literal));
+    }
+
+    quoted
+}
+
+/// Build the remote command printing the remote globs matching nothing, one
+/// per line.
+///
+/// # Arguments
+///
+/// * `patterns` - Remote glob patterns
+///
+pub fn unmatched_command<S: AsRef<str>>(patterns: &[S]) -> String {
+    patterns.iter()
+        .map(|x| format!("set -- {}; [ -e \"$1\" ] || echo {}", quote_glob(x.as_ref()), quote(x.as_ref())))
+        .collect::<Vec<_>>()
+        .join("; ")
+}
+
+/// Check that remote globs match files on a machine.
+///
+/// # Arguments
+///
+/// * `config` - Machine configuration
+/// * `patterns` - Remote paths and glob patterns, only globs are checked
+///
+pub fn check_remote<S: AsRef<str>>(config: &MachineConfig, patterns: &[S]) -> Result<(), Error> {
+    let globs: Vec<&str> = patterns.iter().map(|x| x.as_ref()).filter(|x| has_glob(x)).collect();
+    if globs.is_empty() {
+        return Ok(());
+    }
+
+    config.check_values()?;
+    let output = execute_capture(ssh_remote(config, &unmatched_command(&globs)))?;
+    if !output.status.success() {
+        return Err(Error::tool("could not list remote files", &String::from_utf8_lossy(&output.stderr)));
+    }
+
+    match String::from_utf8_lossy(&output.stdout).lines().next() {
+        Some(pattern) => Err(Error::Config(format!("no remote file matches `{}`", pattern))),
+        None => Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::env;
+    use std::process::Command;
+
+    #[test]
+    fn local() {
+        let dir = env::temp_dir().join(format!("pssh-glob-{}", std::process::id()));
+        fs::create_dir_all(dir.join("dist/js")).unwrap();
+        for name in &["b.js", "a.js", ".hidden.js", "app.css"] {
+            fs::write(dir.join("dist/js").join(name), "").unwrap();
+        }
+
+        let root = dir.to_string_lossy();
+        assert_eq!(expand_local(&[format!("{}/dist/*/?.js", root), "README".to_string()]).unwrap(),
+                   vec![format!("{}/dist/js/a.js", root), format!("{}/dist/js/b.js", root), "README".to_string()]);
+        assert_eq!(expand_local(&[format!("{}/dist/js/.*.js", root)]).unwrap(), vec![format!("{}/dist/js/.hidden.js", root)]);
+        assert!(expand_local(&[format!("{}/dist/*.png", root)]).unwrap_err().to_string().contains("no local file matches"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn remote() {
+        assert_eq!(quote_glob("/var/log/*.log"), "/var/log/*.log");
+        assert_eq!(quote_glob("~/my logs/app-?.log"), "~/'my logs/app-'?.log");
+
+        let dir = env::temp_dir().join(format!("pssh-glob-remote-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("app.log"), "").unwrap();
+        let patterns = vec![format!("{}/*.log", dir.display()), format!("{}/*.gz", dir.display())];
+        let output = Command::new("sh").args(["-c", &unmatched_command(&patterns)]).output().unwrap();
+        assert_eq!(String::from_utf8_lossy(&output.stdout), format!("{}/*.gz\n", dir.display()));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,228 @@
+//! Long-form help
+//!
+//! `pssh help TOPIC` shows the documentation of the configuration format,
+//! selectors and transports, through a pager on terminals. `cargo xtask man`
+//! writes man pages for pssh, its subcommands and these topics at build time,
+//! generated from the command line definitions so that they never drift from
+//! them.
+
+use std::env;
+use std::fs;
+use std::io::{self, IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use clap::{App, AppSettings, ErrorKind};
+
+use error::Error;
+
+/// Help topics: name, summary and contents
+pub const TOPICS: &[(&str, &str, &str)] = &[
+    ("configuration", "configuration file format", include_str!("../doc/config_file_format.md")),
+    ("selectors", "machine selectors and filters", include_str!("../doc/selectors.md")),
+    ("transports", "how machines are reached", include_str!("../doc/transports.md"))
+];
+
+/// Find a help topic.
+///
+/// # Arguments
+///
+/// * `name` - Topic name
+///
+pub fn find_topic(name: &str) -> Option<&'static str> {
+    TOPICS.iter().find(|x| x.0 == name).map(|x| x.2)
+}
+
+/// List help topics, one per line
+pub fn render_topics() -> String {
+    let mut output = String::from("Help topics, shown with `pssh help TOPIC`:\n\n");
+    for &(name, summary, _) in TOPICS {
+        output.push_str(&format!("    {:15} {}\n", name, summary));
+    }
+
+    output
+}
+
+/// Show a text through the pager (`PAGER`, `less` by default) when the
+/// output is a terminal, or print it.
+///
+/// # Arguments
+///
+/// * `text` - Text
+///
+pub fn page(text: &str) {
+    if io::stdout().is_terminal() {
+        let pager = env::var("PAGER").ok().filter(|x| !x.is_empty()).unwrap_or_else(|| "less".to_string());
+        let child = Command::new("sh")
+            .args(["-c", &pager])
+            .env("LESS", env::var("LESS").unwrap_or_else(|_| "FRX".to_string()))
+            .stdin(Stdio::piped())
+            .spawn();
+        if let Ok(mut child) = child {
+            // The pager may quit before reading everything
+            child.stdin.take().unwrap().write_all(text.as_bytes()).ok();
+            child.wait().ok();
+            return;
+        }
+    }
+
+    print!("{}", text);
+}
+
+/// Get the visible subcommands of a command line definition, as
+/// `(name, summary)`, read from its help. `help` itself is left out.
+///
+/// # Arguments
+///
+/// * `app` - Command line definition
+///
+pub fn subcommands(app: &App) -> Vec<(String, String)> {
+    let mut help = vec![];
+    // Without wrapping, each subcommand takes a line
+    app.clone().template("{subcommands}").set_term_width(0).write_help(&mut help).ok();
+
+    String::from_utf8_lossy(&help).lines()
+        .map(|x| x.trim())
+        .filter(|x| !x.is_empty())
+        .map(|x| match x.split_once(char::is_whitespace) {
+            Some((name, summary)) => (name.to_string(), summary.trim().to_string()),
+            None => (x.to_string(), String::new())
+        })
+        .filter(|x| x.0 != "help")
+        .collect()
+}
+
+/// Get the help of a subcommand, as printed by `pssh SUBCOMMAND --help`.
+///
+/// # Arguments
+///
+/// * `app` - Command line definition, with colors disabled
+/// * `name` - Subcommand name
+///
+fn subcommand_help(app: &App, name: &str) -> String {
+    match app.clone().get_matches_from_safe(["pssh", name, "--help"]) {
+        Err(ref error) if error.kind == ErrorKind::HelpDisplayed => error.message.clone(),
+        _ => String::new()
+    }
+}
+
+/// Escape text for roff
+fn escape_roff(text: &str) -> String {
+    text.lines()
+        .map(|line| {
+            let line = line.replace('\\', "\\e").replace('-', "\\-");
+            if line.starts_with('.') || line.starts_with('\'') { format!("\\&{}", line) } else { line }
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Render a man page.
+///
+/// # Arguments
+///
+/// * `name` - Page name
+/// * `section` - Manual section
+/// * `summary` - One-line summary
+/// * `version` - pssh version
+/// * `body` - Preformatted description
+/// * `see_also` - Related pages
+///
+pub fn render_man_page(name: &str, section: u8, summary: &str, version: &str, body: &str, see_also: &[String]) -> String {
+    let mut page = format!(".TH \"{}\" \"{}\" \"\" \"pssh {}\" \"pssh manual\"\n", name.to_uppercase(), section, version);
+    page.push_str(&format!(".SH NAME\n{} \\- {}\n", escape_roff(name), escape_roff(summary)));
+    page.push_str(&format!(".SH DESCRIPTION\n.nf\n{}\n.fi\n", escape_roff(body.trim_end())));
+    if !see_also.is_empty() {
+        page.push_str(&format!(".SH SEE ALSO\n{}\n", escape_roff(&see_also.join(", "))));
+    }
+
+    page
+}
+
+/// Write the man pages of pssh, its visible subcommands and the help topics
+/// to the `man1` and `man7` directories of a manual, returning the written
+/// paths.
+///
+/// # Arguments
+///
+/// * `app` - Command line definition
+/// * `version` - pssh version
+/// * `dir` - Manual directory
+///
+pub fn write_man_pages(app: &App, version: &str, dir: &Path) -> Result<Vec<PathBuf>, Error> {
+    // Pages do not depend on the terminal generating them
+    let app = app.clone().global_setting(AppSettings::ColorNever).set_term_width(80);
+    let subcommands = subcommands(&app);
+    let mut help = vec![];
+    app.write_help(&mut help).ok();
+
+    let mut pages = vec![];
+    let mut see_also: Vec<String> = subcommands.iter().map(|x| format!("pssh-{}(1)", x.0)).collect();
+    see_also.extend(TOPICS.iter().map(|x| format!("pssh-{}(7)", x.0)));
+    pages.push(("man1/pssh.1".to_string(), render_man_page("pssh", 1, "run commands and copy files on configured machines", version, &String::from_utf8_lossy(&help), &see_also)));
+
+    for (subcommand, summary) in &subcommands {
+        let name = format!("pssh-{}", subcommand);
+        pages.push((format!("man1/{}.1", name), render_man_page(&name, 1, summary, version, &subcommand_help(&app, subcommand), &["pssh(1)".to_string()])));
+    }
+    for &(topic, summary, contents) in TOPICS {
+        let name = format!("pssh-{}", topic);
+        pages.push((format!("man7/{}.7", name), render_man_page(&name, 7, summary, version, contents, &["pssh(1)".to_string()])));
+    }
+
+    for section in &["man1", "man7"] {
+        let path = dir.join(section);
+        fs::create_dir_all(&path).map_err(|e| Error::io(format!("could not create {}", path.display()), e))?;
+    }
+    pages.into_iter()
+        .map(|(file, contents)| {
+            let path = dir.join(file);
+            fs::write(&path, contents).map_err(|e| Error::io(format!("could not write {}", path.display()), e))?;
+            Ok(path)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use clap::{Arg, SubCommand};
+
+    #[test]
+    fn topics() {
+        assert!(find_topic("selectors").unwrap().contains("--filter"));
+        assert!(find_topic("missing").is_none());
+        assert!(render_topics().contains("    transports      how machines are reached\n"));
+    }
+
+    #[test]
+    fn man_pages() {
+        assert_eq!(render_man_page("pssh-push", 1, "push a file", "1.0.0", ".hidden\n-f \\n\n", &["pssh(1)".to_string()]), "\
+.TH \"PSSH-PUSH\" \"1\" \"\" \"pssh 1.0.0\" \"pssh manual\"
+.SH NAME
+pssh\\-push \\- push a file
+.SH DESCRIPTION
+.nf
+\\&.hidden
+\\-f \\en
+.fi
+.SH SEE ALSO
+pssh(1)
+");
+
+        let app = App::new("pssh")
+            .subcommand(SubCommand::with_name("push").about("push a file")
+                .arg(Arg::with_name("recursive").long("recursive").help("copy directories")))
+            .subcommand(SubCommand::with_name("secret").setting(AppSettings::Hidden));
+        assert_eq!(subcommands(&app), vec![("push".to_string(), "push a file".to_string())]);
+
+        let dir = env::temp_dir().join(format!("pssh-man-{}", std::process::id()));
+        let pages = write_man_pages(&app, "1.0.0", &dir).unwrap();
+        let names: Vec<String> = pages.iter().map(|x| x.strip_prefix(&dir).unwrap().to_string_lossy().into_owned()).collect();
+        assert_eq!(names, vec!["man1/pssh.1", "man1/pssh-push.1", "man7/pssh-configuration.7", "man7/pssh-selectors.7", "man7/pssh-transports.7"]);
+        assert!(fs::read_to_string(dir.join("man1/pssh.1")).unwrap().contains("pssh\\-push(1)"));
+        assert!(fs::read_to_string(dir.join("man1/pssh-push.1")).unwrap().contains("\\-\\-recursive    copy directories"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,111 @@
+//! Copy exclusions
+//!
+//! Recursive copies skip the files matching the patterns of `.psshignore`
+//! files, found at the root of pushed directories, and of `--exclude-from`
+//! files: VCS directories, build artifacts, secrets... Patterns are shell
+//! globs, one per line, matching file names at any depth (`.git/`,
+//! `target`, `*.pem`). Blank lines and `#` comments are skipped.
+//!
+//! The same patterns are given to `rsync` and `tar`, whichever copies the
+//! files.
+
+use std::fs;
+use std::path::Path;
+
+use error::Error;
+
+/// Name of the exclusion files of pushed directories
+pub const IGNORE_FILE: &str = ".psshignore";
+
+/// Parse exclusion patterns.
+///
+/// # Arguments
+///
+/// * `contents` - Exclusion file contents
+///
+pub fn parse_patterns(contents: &str) -> Vec<String> {
+    contents.lines()
+        .map(str::trim)
+        .filter(|x| !x.is_empty() && !x.starts_with('#'))
+        .filter(|x| {
+            // Neither `rsync --exclude` nor `tar --exclude` can re-include files
+            if x.starts_with('!') {
+                warn!("Negated exclusion `{}` is not supported, skipping it.", x);
+            }
+            !x.starts_with('!')
+        })
+        .map(String::from)
+        .collect()
+}
+
+/// Read the exclusion patterns of a file.
+///
+/// # Arguments
+///
+/// * `path` - Exclusion file
+///
+pub fn read_patterns(path: &Path) -> Result<Vec<String>, Error> {
+    fs::read_to_string(path)
+        .map(|x| parse_patterns(&x))
+        .map_err(|e| Error::io(format!("could not read {}", path.display()), e))
+}
+
+/// Gather the exclusion patterns of a copy: those of the `.psshignore` files
+/// of local source directories, then those of an exclusion file.
+///
+/// # Arguments
+///
+/// * `sources` - Local sources, empty for pulls
+/// * `exclude_from` - Exclusion file given on the command line
+///
+pub fn exclude_patterns<S: AsRef<str>>(sources: &[S], exclude_from: Option<&str>) -> Result<Vec<String>, Error> {
+    let mut patterns: Vec<String> = vec![];
+    let ignore_files = sources.iter().map(|x| Path::new(x.as_ref()).join(IGNORE_FILE)).filter(|x| x.is_file());
+
+    for path in ignore_files.chain(exclude_from.map(|x| Path::new(x).to_path_buf())) {
+        for pattern in read_patterns(&path)? {
+            if !patterns.contains(&pattern) {
+                patterns.push(pattern);
+            }
+        }
+    }
+
+    Ok(patterns)
+}
+
+/// Convert a pattern for `tar`, which matches member names without the
+/// trailing `/` of directories.
+///
+/// # Arguments
+///
+/// * `pattern` - Exclusion pattern
+///
+pub fn tar_pattern(pattern: &str) -> &str {
+    pattern.trim_matches('/')
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::env;
+
+    #[test]
+    fn patterns() {
+        assert_eq!(parse_patterns("# VCS\n.git/\n\n  target  \n!keep.pem\n*.pem\n"), vec![".git/", "target", "*.pem"]);
+        assert_eq!(tar_pattern(".git/"), ".git");
+        assert_eq!(tar_pattern("/build/"), "build");
+
+        let dir = env::temp_dir().join(format!("pssh-ignore-{}", std::process::id()));
+        fs::create_dir_all(dir.join("app")).unwrap();
+        fs::write(dir.join("app").join(IGNORE_FILE), ".git/\nnode_modules\n").unwrap();
+        fs::write(dir.join("extra"), "node_modules\n*.pem\n").unwrap();
+
+        let sources = [dir.join("app").to_string_lossy().into_owned(), dir.join("extra").to_string_lossy().into_owned()];
+        let extra = dir.join("extra");
+        assert_eq!(exclude_patterns(&sources, extra.to_str()).unwrap(), vec![".git/", "node_modules", "*.pem"]);
+        assert!(exclude_patterns(&sources, Some("/nonexistent/ignore")).is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
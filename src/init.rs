@@ -0,0 +1,205 @@
+//! Starter configuration generation
+//!
+//! Builds the commented `config.yml` written by `pssh init`, optionally with
+//! hosts imported from the OpenSSH client configuration (`~/.ssh/config`).
+
+use yaml_rust::{Yaml, YamlEmitter};
+
+use config::MachineConfig;
+use migrate::CONFIG_VERSION;
+use names::sanitize_key;
+
+/// Namespace receiving hosts imported from `~/.ssh/config`
+pub const SSH_CONFIG_NAMESPACE: &str = "ssh";
+
+/// Parse an OpenSSH client configuration, returning its concrete hosts.
+///
+/// Wildcard patterns and `Match` blocks are skipped, and a `Host` line with
+/// several aliases gives one machine per alias.
+///
+/// # Arguments
+///
+/// * `contents` - Configuration contents
+///
+pub fn parse_ssh_config(contents: &str) -> Vec<(String, MachineConfig)> {
+    let mut hosts: Vec<(String, MachineConfig)> = vec![];
+    let mut aliases: Vec<String> = vec![];
+    let mut current = MachineConfig::default();
+
+    let flush = |hosts: &mut Vec<(String, MachineConfig)>, aliases: &[String], current: &MachineConfig| {
+        for alias in aliases {
+            let mut config = current.clone();
+            if config.ip.is_none() {
+                config.ip = Some(alias.clone());
+            }
+            hosts.push((sanitize_key(alias), config));
+        }
+    };
+
+    for line in contents.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        // Keywords are case-insensitive, and separated from values by spaces or `=`
+        let mut parts = line.splitn(2, |c: char| c.is_whitespace() || c == '=');
+        let keyword = parts.next().unwrap_or("").to_lowercase();
+        let value = parts.next().unwrap_or("").trim_start_matches(|c: char| c.is_whitespace() || c == '=').trim();
+        let value = value.trim_matches('"').to_string();
+
+        match keyword.as_str() {
+            "host" | "match" => {
+                flush(&mut hosts, &aliases, &current);
+                current = MachineConfig::default();
+                aliases = if keyword == "host" {
+                    value.split_whitespace()
+                        .filter(|x| !x.contains(['*', '?', '!']))
+                        .map(String::from)
+                        .collect()
+                } else {
+                    vec![]
+                };
+            },
+            "hostname" => current.ip = Some(value),
+            "user" => current.user = Some(value),
+            "port" => current.port = value.parse().ok().map(|x| vec![x]),
+            "identityfile" => current.identity.get_or_insert_with(Vec::new).push(value),
+            _ => ()
+        }
+    }
+
+    flush(&mut hosts, &aliases, &current);
+    hosts
+}
+
+/// Render a commented starter configuration.
+///
+/// # Arguments
+///
+/// * `default_user` - User set in the root defaults
+/// * `machine` - First machine name and values
+/// * `imported` - Hosts imported from `~/.ssh/config`
+///
+pub fn starter_config(default_user: &str, machine: &(String, MachineConfig), imported: &[(String, MachineConfig)]) -> String {
+    let mut output = String::new();
+    output.push_str("# pssh configuration file\n");
+    output.push_str("# Format: https://github.com/Srynetix/pssh-rs/blob/master/doc/config_file_format.md\n\n");
+    output.push_str(&format!("version: {}\n\n", CONFIG_VERSION));
+
+    output.push_str("defaults:\n");
+    output.push_str("  # Values shared by every machine, overridable per namespace and machine\n");
+    output.push_str("  $:\n");
+    output.push_str(&format!("    user: {}\n", scalar(default_user)));
+    output.push_str("    port: 22\n");
+    output.push_str("    # identity: ~/.ssh/id_rsa\n");
+    output.push_str("  # Defaults for a namespace apply to all machines under it\n");
+    output.push_str("  # work:\n");
+    output.push_str("  #   $:\n");
+    output.push_str("  #     user: workuser\n\n");
+
+    output.push_str("machines:\n");
+    output.push_str("  # Machines can be grouped in namespaces, as `work:web01`\n");
+    write_machine(&mut output, "  ", &machine.0, &machine.1);
+
+    if !imported.is_empty() {
+        output.push_str("\n  # Imported from ~/.ssh/config\n");
+        output.push_str(&format!("  {}:\n", SSH_CONFIG_NAMESPACE));
+        for (name, config) in imported {
+            write_machine(&mut output, "    ", name, config);
+        }
+    }
+
+    output
+}
+
+fn write_machine(output: &mut String, indent: &str, name: &str, config: &MachineConfig) {
+    output.push_str(&format!("{}{}:\n", indent, scalar(name)));
+    output.push_str(&format!("{}  $:\n", indent));
+
+    let values = [
+        ("ip", config.ip.as_ref().map(|x| scalar(x))),
+        ("port", config.port.as_ref().map(|x| flow_list(x.iter().map(|x| x.to_string()).collect()))),
+        ("user", config.user.as_ref().map(|x| scalar(x))),
+        ("identity", config.identity.as_ref().map(|x| flow_list(x.iter().map(|x| scalar(x)).collect())))
+    ];
+    for &(key, ref value) in &values {
+        if let Some(ref value) = *value {
+            output.push_str(&format!("{}    {}: {}\n", indent, key, value));
+        }
+    }
+}
+
+/// Render a plain YAML scalar, quoted when needed
+fn scalar(value: &str) -> String {
+    let mut output = String::new();
+    YamlEmitter::new(&mut output).dump(&Yaml::String(value.to_string())).unwrap();
+    output.trim_start_matches("---").trim().to_string()
+}
+
+/// Format YAML values as a flow sequence, or as a single value when alone
+fn flow_list(values: Vec<String>) -> String {
+    if values.len() == 1 {
+        values[0].clone()
+    } else {
+        format!("[{}]", values.join(", "))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use config::load_configuration_string;
+
+    #[test]
+    fn ssh_config() {
+        let contents = "\
+            Host *\n\
+            \x20   ServerAliveInterval 30\n\
+            \n\
+            Host web web-alias\n\
+            \x20   HostName 10.0.0.5\n\
+            \x20   User deploy\n\
+            \x20   Port 2222\n\
+            \x20   IdentityFile ~/.ssh/web\n\
+            \x20   IdentityFile ~/.ssh/other\n\
+            \n\
+            Host bastion\n\
+            \x20   user=admin\n\
+            Match host *.internal\n\
+            \x20   User ignored\n";
+
+        let hosts = parse_ssh_config(contents);
+        assert_eq!(hosts.len(), 3);
+        assert_eq!(hosts[0].0, "web");
+        assert_eq!(hosts[1].0, "web-alias");
+        assert_eq!(hosts[0].1.ip, Some("10.0.0.5".to_string()));
+        assert_eq!(hosts[0].1.port, Some(vec![2222]));
+        assert_eq!(hosts[0].1.identity, Some(vec!["~/.ssh/web".to_string(), "~/.ssh/other".to_string()]));
+        assert_eq!(hosts[2].1.ip, Some("bastion".to_string()));
+        assert_eq!(hosts[2].1.user, Some("admin".to_string()));
+    }
+
+    #[test]
+    fn starter() {
+        let machine = ("first".to_string(), MachineConfig {
+            ip: Some("10.0.0.1".to_string()),
+            identity: Some(vec!["~/.ssh/id_ed25519".to_string()]),
+            ..Default::default()
+        });
+        let imported = parse_ssh_config("Host web\n  HostName 10.0.0.5\n  Port 2222\n  IdentityFile ~/.ssh/a\n  IdentityFile ~/.ssh/b\n");
+
+        let config = load_configuration_string(&starter_config("pouet", &machine, &imported)).unwrap();
+        assert_eq!(config.machine_values.len(), 2);
+
+        let first = &config.machine_values["first"];
+        assert_eq!(first.user, Some("pouet".to_string()));
+        assert_eq!(first.identity, Some(vec!["~/.ssh/id_ed25519".to_string()]));
+
+        let web = &config.machine_values["ssh:web"];
+        assert_eq!(web.port, Some(vec![2222]));
+        assert_eq!(web.identities(), ["~/.ssh/a", "~/.ssh/b"]);
+        assert_eq!(web.ip, Some("10.0.0.5".to_string()));
+    }
+}
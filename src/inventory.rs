@@ -0,0 +1,190 @@
+//! Remote inventory sources
+//!
+//! Configuration files can `include` inventories served over HTTPS. Fetched
+//! inventories are validated, then cached in `~/.pssh/state/inventory/` for
+//! their TTL; a stale cache is used when the server cannot be reached.
+
+use std::env;
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::time::{Duration, SystemTime};
+
+use yaml_rust::{Yaml, YamlLoader};
+
+use error::Error;
+use state::{get_state_dir, write_atomic, StateLock};
+
+/// Default cache TTL, in seconds
+pub const DEFAULT_TTL: u64 = 300;
+
+/// Remote inventory source
+#[derive(Debug, Clone, PartialEq)]
+pub struct InventorySource {
+    /// Inventory URL
+    pub url: String,
+    /// Environment variable holding a bearer token
+    pub token_env: Option<String>,
+    /// Cache TTL
+    pub ttl: Duration
+}
+
+impl InventorySource {
+    /// Parse an `include` entry, either an URL or a map with `url`,
+    /// `token_env` and `ttl` (in seconds) keys.
+    ///
+    /// # Arguments
+    ///
+    /// * `data` - YAML data
+    ///
+    pub fn from_yaml(data: &Yaml) -> Result<InventorySource, Error> {
+        let (url, token_env, ttl) = match *data {
+            Yaml::String(ref url) => (url.clone(), None, DEFAULT_TTL),
+            Yaml::Hash(_) => (
+                data["url"].as_str().ok_or_else(|| Error::Config("missing include url".to_string()))?.to_string(),
+                data["token_env"].as_str().map(String::from),
+                data["ttl"].as_i64().map(|x| x as u64).unwrap_or(DEFAULT_TTL)
+            ),
+            _ => return Err(Error::Config(format!("bad include entry: {:?}", data)))
+        };
+
+        if !url.starts_with("https://") {
+            return Err(Error::Config(format!("included inventories must use HTTPS: {}", url)));
+        }
+
+        Ok(InventorySource { url, token_env, ttl: Duration::from_secs(ttl) })
+    }
+
+    /// Cache file path
+    pub fn cache_path(&self) -> PathBuf {
+        let name: String = self.url.trim_start_matches("https://").chars()
+            .map(|c| if c.is_ascii_alphanumeric() || c == '-' { c } else { '_' })
+            .collect();
+
+        get_state_dir().join("inventory").join(format!("{}.yml", name))
+    }
+
+    /// Load the inventory, from cache when it is fresh enough.
+    pub fn load(&self) -> Result<Yaml, Error> {
+        let path = self.cache_path();
+        let _lock = StateLock::acquire(&path).map_err(|e| Error::io("could not lock cache", e))?;
+
+        let age = fs::metadata(&path).and_then(|x| x.modified()).ok()
+            .and_then(|x| SystemTime::now().duration_since(x).ok());
+        if age.is_some_and(|x| x < self.ttl) {
+            debug!("Using cached inventory {}", path.display());
+            return read_cache(&path).and_then(|x| validate_inventory(&x));
+        }
+
+        let fetched = self.fetch().and_then(|contents| {
+            let inventory = validate_inventory(&contents)?;
+            write_atomic(&path, &contents).map_err(|e| Error::io("could not write cache", e))?;
+            Ok(inventory)
+        });
+
+        match fetched {
+            Ok(inventory) => Ok(inventory),
+            Err(error) => match read_cache(&path).and_then(|x| validate_inventory(&x)) {
+                Ok(inventory) => {
+                    warn!("Could not refresh {} ({}), using cached copy", self.url, error);
+                    Ok(inventory)
+                },
+                Err(_) => Err(error)
+            }
+        }
+    }
+
+    fn fetch(&self) -> Result<String, Error> {
+        debug!("Fetching {}", self.url);
+        let token = match self.token_env {
+            Some(ref variable) => Some(env::var(variable).map_err(|_| Error::Config(format!("{} is not set", variable)))?),
+            None => None
+        };
+
+        let mut child = Command::new("curl")
+            .args(["--silent", "--show-error", "--fail", "--location", "--max-time", "30"])
+            // Read headers from stdin, to keep the token out of the process list
+            .args(["--header", "@-"])
+            .arg(&self.url)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| Error::io("failed to execute curl", e))?;
+
+        {
+            let mut stdin = child.stdin.take().unwrap();
+            if let Some(token) = token {
+                writeln!(stdin, "Authorization: Bearer {}", token).map_err(|e| Error::io("failed to write to curl", e))?;
+            }
+        }
+
+        let output = child.wait_with_output().map_err(|e| Error::io("failed to wait on curl", e))?;
+        if !output.status.success() {
+            return Err(Error::Transport(String::from_utf8_lossy(&output.stderr).trim().to_string()));
+        }
+
+        String::from_utf8(output.stdout).map_err(|_| Error::Transport("inventory is not valid UTF-8".to_string()))
+    }
+}
+
+/// Validate an inventory document, which must have a `machines` section and
+/// may have a `defaults` section.
+///
+/// # Arguments
+///
+/// * `contents` - Inventory contents
+///
+pub fn validate_inventory(contents: &str) -> Result<Yaml, Error> {
+    let docs = YamlLoader::load_from_str(contents).map_err(|e| Error::Config(format!("bad inventory: {}", e)))?;
+    let doc = docs.into_iter().next().ok_or_else(|| Error::Config("empty inventory".to_string()))?;
+
+    if doc.as_hash().is_none() || doc["machines"].is_badvalue() {
+        return Err(Error::Config("inventory has no `machines` section".to_string()));
+    }
+    for section in &["defaults", "machines"] {
+        let values = &doc[*section];
+        if !values.is_badvalue() && !values.is_null() && values.as_hash().is_none() {
+            return Err(Error::Config(format!("inventory `{}` section must be a map", section)));
+        }
+    }
+
+    Ok(doc)
+}
+
+fn read_cache(path: &PathBuf) -> Result<String, Error> {
+    let mut contents = String::new();
+    File::open(path)
+        .and_then(|mut f| f.read_to_string(&mut contents))
+        .map_err(|e| Error::io("could not read cache", e))?;
+    Ok(contents)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn sources() {
+        let source = InventorySource::from_yaml(&Yaml::String("https://example.com/inventory.yml".to_string())).unwrap();
+        assert_eq!(source.ttl, Duration::from_secs(DEFAULT_TTL));
+        assert!(source.cache_path().ends_with("inventory/example_com_inventory_yml.yml"));
+
+        let docs = YamlLoader::load_from_str("url: https://example.com/x\ntoken_env: TOKEN\nttl: 60").unwrap();
+        let source = InventorySource::from_yaml(&docs[0]).unwrap();
+        assert_eq!(source.token_env, Some("TOKEN".to_string()));
+        assert_eq!(source.ttl, Duration::from_secs(60));
+
+        assert!(InventorySource::from_yaml(&Yaml::String("http://example.com/x".to_string())).is_err());
+    }
+
+    #[test]
+    fn validation() {
+        assert!(validate_inventory("machines:\n  web:\n    $:\n      ip: 10.0.0.1\n").is_ok());
+        assert!(validate_inventory("defaults:\nmachines:\n").is_ok());
+        assert!(validate_inventory("pouet: 1\n").is_err());
+        assert!(validate_inventory("machines: [1, 2]\n").is_err());
+        assert!(validate_inventory("- 1\n").is_err());
+    }
+}
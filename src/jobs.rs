@@ -0,0 +1,414 @@
+//! Job history
+//!
+//! Fleet operations are recorded as jobs in `~/.pssh/state/jobs.jsonl`, one
+//! JSON object per line: the operation, its exact target machines, and the
+//! result on each machine. `pssh jobs rerun` runs a job again on the same
+//! machines. The oldest jobs are dropped past `MAX_JOBS`.
+
+use std::env;
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use serde_json::{self, Value};
+
+use atomic::AtomicOptions;
+use error::Error;
+use exec::ExecResult;
+use state::{get_state_dir, write_atomic, StateLock};
+use wrapper::CopyOptions;
+
+/// Number of jobs kept in the history
+pub const MAX_JOBS: usize = 500;
+
+/// Operation of a job
+#[derive(Debug, Clone, PartialEq)]
+pub enum JobOperation {
+    /// Remote command
+    Exec {
+        /// Command, interpreted by the remote shell
+        command: String,
+        /// Command timeout, as given on the command line
+        timeout: Option<String>
+    },
+    /// File copy to machines
+    Push {
+        /// Local sources, made absolute
+        sources: Vec<String>,
+        /// Remote destination
+        destination: String,
+        /// Atomic push options, none for a plain copy
+        atomic: Option<AtomicOptions>,
+        /// Copy options
+        copy: CopyOptions,
+        /// Remote command run after the copy, as given on the command line
+        then: Option<String>
+    }
+}
+
+/// Result of a job on a machine
+#[derive(Debug, Clone, PartialEq)]
+pub struct MachineResult {
+    /// Machine name
+    pub machine: String,
+    /// Did the operation succeed?
+    pub success: bool,
+    /// Outcome summary (`exit 0`, `skipped`, error message...)
+    pub outcome: String
+}
+
+/// Recorded fleet operation
+#[derive(Debug, Clone, PartialEq)]
+pub struct Job {
+    /// Identifier, increasing with each job
+    pub id: u64,
+    /// Start time
+    pub time: DateTime<Utc>,
+    /// Running time
+    pub duration: Duration,
+    /// Configuration file given on the command line
+    pub config_file: Option<String>,
+    /// Operation
+    pub operation: JobOperation,
+    /// Target machines, in order
+    pub targets: Vec<String>,
+    /// Results, per machine
+    pub results: Vec<MachineResult>
+}
+
+impl JobOperation {
+    /// Get the operation name
+    pub fn name(&self) -> &'static str {
+        match *self {
+            JobOperation::Exec { .. } => "exec",
+            JobOperation::Push { .. } => "push"
+        }
+    }
+
+    /// Describe the operation, on one line
+    pub fn describe(&self) -> String {
+        match *self {
+            JobOperation::Exec { ref command, .. } => format!("exec `{}`", command),
+            JobOperation::Push { ref sources, ref destination, .. } => format!("push {} to {}", sources.join(" "), destination)
+        }
+    }
+
+    /// Get the pssh arguments running the operation on machines.
+    ///
+    /// Command inputs are not recorded: commands run again without one.
+    ///
+    /// # Arguments
+    ///
+    /// * `targets` - Target machines
+    ///
+    pub fn arguments(&self, targets: &[String]) -> Vec<String> {
+        match *self {
+            JobOperation::Exec { ref command, ref timeout } => {
+                let mut arguments = vec!["exec".to_string(), targets.join(","), "--no-stdin".to_string()];
+                if let Some(ref timeout) = *timeout {
+                    arguments.push("--timeout".to_string());
+                    arguments.push(timeout.clone());
+                }
+                arguments.push("--".to_string());
+                arguments.push(command.clone());
+                arguments
+            },
+            JobOperation::Push { ref sources, ref destination, atomic, ref copy, ref then } => {
+                let mut arguments = vec!["push".to_string(), targets.join(",")];
+                arguments.extend(sources.iter().cloned());
+                arguments.push(destination.clone());
+                if let Some(atomic) = atomic {
+                    arguments.push("--atomic".to_string());
+                    if atomic.sudo {
+                        arguments.push("--become".to_string());
+                    }
+                    if atomic.backup {
+                        arguments.push("--backup".to_string());
+                    }
+                }
+                for &(flag, set) in &[("--recursive", copy.recursive), ("--preserve", copy.preserve), ("--no-follow", !copy.follow_symlinks)] {
+                    if set {
+                        arguments.push(flag.to_string());
+                    }
+                }
+                for pattern in &copy.excludes {
+                    arguments.push("--exclude".to_string());
+                    arguments.push(pattern.clone());
+                }
+                if let Some(ref then) = *then {
+                    arguments.push("--then".to_string());
+                    arguments.push(then.clone());
+                }
+                arguments
+            }
+        }
+    }
+}
+
+impl MachineResult {
+    /// Build the result of a remote command.
+    ///
+    /// # Arguments
+    ///
+    /// * `machine` - Machine name
+    /// * `result` - Command result, none if the machine was skipped
+    ///
+    pub fn from_exec(machine: &str, result: Option<&Result<ExecResult, Error>>) -> MachineResult {
+        let (success, outcome) = match result {
+            None => (false, "skipped".to_string()),
+            Some(Ok(output)) => (output.success(), output.describe()),
+            Some(Err(error)) => (false, error.to_string())
+        };
+
+        MachineResult { machine: machine.to_string(), success, outcome }
+    }
+}
+
+impl Job {
+    /// Create a job, before it is recorded.
+    ///
+    /// # Arguments
+    ///
+    /// * `config_file` - Configuration file given on the command line
+    /// * `operation` - Operation
+    /// * `targets` - Target machines
+    ///
+    pub fn new(config_file: Option<&str>, operation: JobOperation, targets: &[String]) -> Job {
+        // Reruns may start from another directory
+        let config_file = config_file.map(|x| fs::canonicalize(x).map(|x| x.to_string_lossy().into_owned()).unwrap_or_else(|_| x.to_string()));
+        let operation = match operation {
+            JobOperation::Push { sources, destination, atomic, copy, then } => {
+                let sources = sources.iter().map(|x| absolute_path(x)).collect();
+                JobOperation::Push { sources, destination, atomic, copy, then }
+            },
+            operation => operation
+        };
+
+        Job {
+            id: 0,
+            time: Utc::now(),
+            duration: Duration::from_secs(0),
+            config_file,
+            operation,
+            targets: targets.to_vec(),
+            results: vec![]
+        }
+    }
+
+    /// Get the machines where the job failed
+    pub fn failures(&self) -> Vec<String> {
+        self.results.iter().filter(|x| !x.success).map(|x| x.machine.clone()).collect()
+    }
+
+    /// Serialize the job as a JSON line
+    pub fn to_json_line(&self) -> String {
+        let operation = match self.operation {
+            JobOperation::Exec { ref command, ref timeout } => json!({"type": "exec", "command": command, "timeout": timeout}),
+            JobOperation::Push { ref sources, ref destination, atomic, ref copy, ref then } => json!({
+                "type": "push",
+                "sources": sources,
+                "destination": destination,
+                "atomic": atomic.map(|x| json!({"sudo": x.sudo, "backup": x.backup})),
+                "copy": {
+                    "recursive": copy.recursive,
+                    "preserve": copy.preserve,
+                    "follow_symlinks": copy.follow_symlinks,
+                    "excludes": copy.excludes
+                },
+                "then": then
+            })
+        };
+        let results: Vec<Value> = self.results.iter()
+            .map(|x| json!({"machine": x.machine, "success": x.success, "outcome": x.outcome}))
+            .collect();
+
+        json!({
+            "id": self.id,
+            "time": self.time.to_rfc3339(),
+            "duration": self.duration.as_secs_f64(),
+            "config": self.config_file,
+            "operation": operation,
+            "targets": self.targets,
+            "results": results
+        }).to_string()
+    }
+
+    /// Parse a job from a JSON line.
+    ///
+    /// # Arguments
+    ///
+    /// * `line` - JSON line
+    ///
+    pub fn from_json_line(line: &str) -> Option<Job> {
+        let value: Value = serde_json::from_str(line).ok()?;
+        let operation = &value["operation"];
+        let field = |key: &str| operation[key].as_str().map(String::from);
+        let operation = match operation["type"].as_str()? {
+            "exec" => JobOperation::Exec { command: field("command")?, timeout: field("timeout") },
+            "push" => JobOperation::Push {
+                // Jobs recorded before multiple sources have a single `source`
+                sources: match operation["sources"].as_array() {
+                    Some(x) => x.iter().filter_map(|x| x.as_str().map(String::from)).collect(),
+                    None => vec![field("source")?]
+                },
+                destination: field("destination")?,
+                atomic: operation["atomic"].as_object().map(|x| AtomicOptions {
+                    sudo: x.get("sudo").and_then(Value::as_bool).unwrap_or(false),
+                    backup: x.get("backup").and_then(Value::as_bool).unwrap_or(false)
+                }),
+                copy: CopyOptions {
+                    recursive: operation["copy"]["recursive"].as_bool().unwrap_or(false),
+                    preserve: operation["copy"]["preserve"].as_bool().unwrap_or(false),
+                    follow_symlinks: operation["copy"]["follow_symlinks"].as_bool().unwrap_or(true),
+                    excludes: operation["copy"]["excludes"].as_array()
+                        .map(|x| x.iter().filter_map(|x| x.as_str().map(String::from)).collect())
+                        .unwrap_or_default()
+                },
+                then: field("then")
+            },
+            _ => return None
+        };
+
+        let results = value["results"].as_array()?.iter()
+            .map(|x| Some(MachineResult {
+                machine: x["machine"].as_str()?.to_string(),
+                success: x["success"].as_bool()?,
+                outcome: x["outcome"].as_str().unwrap_or_default().to_string()
+            }))
+            .collect::<Option<Vec<_>>>()?;
+
+        Some(Job {
+            id: value["id"].as_u64()?,
+            time: DateTime::parse_from_rfc3339(value["time"].as_str()?).ok()?.with_timezone(&Utc),
+            duration: Duration::from_secs_f64(value["duration"].as_f64().unwrap_or(0.0).max(0.0)),
+            config_file: value["config"].as_str().map(String::from),
+            operation,
+            targets: value["targets"].as_array()?.iter().filter_map(|x| x.as_str().map(String::from)).collect(),
+            results
+        })
+    }
+}
+
+/// Make a local path absolute, for reruns started from another directory.
+/// Symbolic links are kept, as pushes may copy them as is.
+fn absolute_path(path: &str) -> String {
+    match env::current_dir() {
+        Ok(dir) => dir.join(path).to_string_lossy().into_owned(),
+        Err(_) => path.to_string()
+    }
+}
+
+/// Get the job history path (`~/.pssh/state/jobs.jsonl`)
+pub fn jobs_path() -> PathBuf {
+    get_state_dir().join("jobs.jsonl")
+}
+
+/// Read jobs, oldest first, skipping malformed lines.
+///
+/// # Arguments
+///
+/// * `path` - Job history path
+///
+pub fn read_jobs(path: &Path) -> Vec<Job> {
+    match File::open(path) {
+        Ok(f) => BufReader::new(f).lines()
+            .map_while(Result::ok)
+            .filter_map(|x| Job::from_json_line(&x))
+            .collect(),
+        Err(_) => vec![]
+    }
+}
+
+/// Find a job by identifier.
+///
+/// # Arguments
+///
+/// * `path` - Job history path
+/// * `id` - Job identifier
+///
+pub fn find_job(path: &Path, id: u64) -> Option<Job> {
+    read_jobs(path).into_iter().find(|x| x.id == id)
+}
+
+/// Record a job, returning its identifier.
+///
+/// # Arguments
+///
+/// * `path` - Job history path
+/// * `job` - Job, whose identifier is assigned
+///
+pub fn record_job(path: &Path, mut job: Job) -> Result<u64, Error> {
+    let _lock = StateLock::acquire(path).map_err(|e| Error::io(format!("could not lock {}", path.display()), e))?;
+
+    let mut jobs = read_jobs(path);
+    job.id = jobs.last().map(|x| x.id + 1).unwrap_or(1);
+    let id = job.id;
+
+    if jobs.len() < MAX_JOBS {
+        let mut file = fs::OpenOptions::new().create(true).append(true).open(path)
+            .map_err(|e| Error::io(format!("could not open {}", path.display()), e))?;
+        writeln!(file, "{}", job.to_json_line()).map_err(|e| Error::io(format!("could not write {}", path.display()), e))?;
+    } else {
+        jobs.push(job);
+        let start = jobs.len() - MAX_JOBS;
+        let contents: String = jobs[start..].iter().map(|x| format!("{}\n", x.to_json_line())).collect();
+        write_atomic(path, &contents).map_err(|e| Error::io(format!("could not write {}", path.display()), e))?;
+    }
+
+    Ok(id)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::env;
+
+    #[test]
+    fn history() {
+        let path = env::temp_dir().join(format!("pssh-jobs-{}.jsonl", std::process::id()));
+        let targets = vec!["web1".to_string(), "web2".to_string()];
+        let mut job = Job::new(None, JobOperation::Exec { command: "uptime".to_string(), timeout: Some("30s".to_string()) }, &targets);
+        job.results = vec![
+            MachineResult { machine: "web1".to_string(), success: true, outcome: "exit 0".to_string() },
+            MachineResult::from_exec("web2", None)
+        ];
+
+        assert_eq!(record_job(&path, job.clone()).unwrap(), 1);
+        assert_eq!(record_job(&path, job.clone()).unwrap(), 2);
+        let recorded = find_job(&path, 2).unwrap();
+        assert_eq!(recorded.results, job.results);
+        assert_eq!(recorded.time, job.time);
+        assert_eq!(recorded.failures(), vec!["web2"]);
+        assert_eq!(recorded.operation.arguments(&recorded.targets), vec!["exec", "web1,web2", "--no-stdin", "--timeout", "30s", "--", "uptime"]);
+        assert!(find_job(&path, 3).is_none());
+
+        let push = JobOperation::Push { sources: vec!["app.conf".to_string()], destination: "/etc/app.conf".to_string(), atomic: None, copy: CopyOptions::default(), then: None };
+        assert_eq!(push.arguments(&targets[..1]), vec!["push", "web1", "app.conf", "/etc/app.conf"]);
+        assert_eq!(push.describe(), "push app.conf to /etc/app.conf");
+
+        let atomic = Some(AtomicOptions { sudo: true, backup: false });
+        let then = Some("systemctl reload app".to_string());
+        let copy = CopyOptions { preserve: true, ..Default::default() };
+        let mut job = Job::new(None, JobOperation::Push { sources: vec!["app.conf".to_string()], destination: "/etc/app.conf".to_string(), atomic, copy, then }, &targets);
+        job.id = 3;
+        let parsed = Job::from_json_line(&job.to_json_line()).unwrap();
+        let source = env::current_dir().unwrap().join("app.conf").to_string_lossy().into_owned();
+        assert_eq!(parsed.operation.arguments(&targets[..1]),
+                   vec!["push", "web1", &source, "/etc/app.conf", "--atomic", "--become", "--preserve", "--then", "systemctl reload app"]);
+
+        let copy = CopyOptions { recursive: true, excludes: vec![".git/".to_string()], ..Default::default() };
+        job.operation = JobOperation::Push { sources: vec!["app".to_string()], destination: "/srv/".to_string(), atomic: None, copy, then: None };
+        let parsed = Job::from_json_line(&job.to_json_line()).unwrap();
+        assert_eq!(parsed.operation.arguments(&targets[..1]), vec!["push", "web1", "app", "/srv/", "--recursive", "--exclude", ".git/"]);
+        assert!(Job::from_json_line("{\"id\": 1}").is_none());
+
+        let legacy = r#"{"id": 4, "time": "2024-01-02T03:04:05Z", "operation": {"type": "push", "source": "a", "destination": "b"}, "targets": [], "results": []}"#;
+        assert_eq!(Job::from_json_line(legacy).unwrap().operation.describe(), "push a to b");
+
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(path.with_extension("jsonl.lock")).ok();
+    }
+}
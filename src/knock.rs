@@ -0,0 +1,158 @@
+//! Port knocking
+//!
+//! Machines protected by a port-knocking daemon only open their SSH port
+//! after a sequence of connection attempts on closed ports. With a `knock`
+//! machine value, as `[7000, 8000/udp, 9000]`, pssh sends the sequence from
+//! the local host before connecting, without an external knock client.
+//!
+//! Machines reached through jump hosts or a proxy command are not knocked:
+//! the local host does not connect to them directly.
+
+use std::collections::HashMap;
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs, UdpSocket};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use config::MachineConfig;
+
+/// Default delay after each knock
+pub const DEFAULT_DELAY: Duration = Duration::from_millis(100);
+
+/// Time given to each TCP knock, which only needs its first packet sent
+const TCP_KNOCK_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Time during which a sequence is not sent again to the same address, as
+/// daemons keep the port open for a while after a knock
+pub const KNOCK_TTL: Duration = Duration::from_secs(5);
+
+/// Time of the last sequence sent to an address, none before the first
+type LastKnock = Arc<Mutex<Option<Instant>>>;
+
+/// Addresses knocked by this process
+static KNOCKED: Mutex<Option<HashMap<String, LastKnock>>> = Mutex::new(None);
+
+/// Knock protocol
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Protocol {
+    /// TCP connection attempt
+    Tcp,
+    /// Empty UDP datagram
+    Udp
+}
+
+/// Parse a knock, as `7000`, `7000/tcp` or `7000/udp`.
+///
+/// # Arguments
+///
+/// * `knock` - Knock value
+///
+pub fn parse_knock(knock: &str) -> Option<(u16, Protocol)> {
+    let (port, protocol) = match knock.split_once('/') {
+        Some((port, "tcp")) => (port, Protocol::Tcp),
+        Some((port, "udp")) => (port, Protocol::Udp),
+        Some(_) => return None,
+        None => (knock, Protocol::Tcp)
+    };
+
+    port.parse().ok().filter(|x| *x > 0).map(|x| (x, protocol))
+}
+
+/// Send a knock, ignoring its outcome: closed ports are expected
+fn send_knock(address: &SocketAddr, protocol: Protocol) {
+    match protocol {
+        Protocol::Tcp => {
+            TcpStream::connect_timeout(address, TCP_KNOCK_TIMEOUT).ok();
+        },
+        Protocol::Udp => {
+            let local = if address.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+            if let Ok(socket) = UdpSocket::bind(local) {
+                socket.send_to(&[], address).ok();
+            }
+        }
+    }
+}
+
+/// Send the knock sequence of a machine, if it has one.
+///
+/// Called right before spawning each command. Commands spawned less than
+/// `KNOCK_TTL` after the last sequence sent to the same address do not send
+/// it again, and concurrent ones wait for it.
+///
+/// # Arguments
+///
+/// * `config` - Machine configuration
+///
+pub fn knock(config: &MachineConfig) {
+    let (ip, knocks) = match config.ip {
+        Some(ref ip) if !config.knocks().is_empty() => (ip, config.knocks()),
+        _ => return
+    };
+    if !config.jump_hosts().is_empty() || config.proxy_command.is_some() {
+        debug!("Not knocking `{}`, which is not connected to directly.", ip);
+        return;
+    }
+
+    let last = {
+        let mut knocked = KNOCKED.lock().unwrap_or_else(|x| x.into_inner());
+        knocked.get_or_insert_with(HashMap::new).entry(ip.clone()).or_default().clone()
+    };
+    let mut last = last.lock().unwrap_or_else(|x| x.into_inner());
+    if last.map(|x| x.elapsed() < KNOCK_TTL).unwrap_or(false) {
+        return;
+    }
+
+    let delay = config.knock_delay();
+    for (port, protocol) in knocks.iter().filter_map(|x| parse_knock(x)) {
+        match (ip.as_str(), port).to_socket_addrs().ok().and_then(|mut x| x.next()) {
+            Some(address) => send_knock(&address, protocol),
+            None => {
+                warn!("Could not resolve `{}` to knock.", ip);
+                return;
+            }
+        }
+        thread::sleep(delay);
+    }
+    *last = Some(Instant::now());
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::net::TcpListener;
+
+    #[test]
+    fn knocks() {
+        assert_eq!(parse_knock("7000"), Some((7000, Protocol::Tcp)));
+        assert_eq!(parse_knock("8000/udp"), Some((8000, Protocol::Udp)));
+        assert_eq!(parse_knock("9000/tcp"), Some((9000, Protocol::Tcp)));
+        assert_eq!(parse_knock("9000/icmp"), None);
+        assert_eq!(parse_knock("0"), None);
+        assert_eq!(parse_knock("70000"), None);
+
+        let tcp = TcpListener::bind("127.0.0.1:0").unwrap();
+        let udp = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let config = MachineConfig {
+            ip: Some("127.0.0.1".to_string()),
+            knock: Some(vec![tcp.local_addr().unwrap().port().to_string(), format!("{}/udp", udp.local_addr().unwrap().port())]),
+            knock_delay: Some("10ms".to_string()),
+            ..Default::default()
+        };
+        knock(&config);
+
+        assert!(tcp.accept().is_ok());
+        udp.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+        assert_eq!(udp.recv_from(&mut [0; 8]).unwrap().0, 0);
+
+        // Sent again only once the TTL has passed
+        knock(&config);
+        tcp.set_nonblocking(true).unwrap();
+        assert!(tcp.accept().is_err());
+
+        // Machines behind jump hosts are not knocked
+        let behind = MachineConfig { ip: Some("127.0.0.2".to_string()), via: Some(vec!["bastion".to_string()]), ..config };
+        knock(&behind);
+        assert!(tcp.accept().is_err());
+    }
+}
@@ -9,13 +9,81 @@ extern crate yaml_rust;
 extern crate log;
 extern crate fern;
 extern crate chrono;
+#[macro_use]
+extern crate serde_json;
+extern crate ansi_term;
+extern crate fs2;
+extern crate rhai;
+extern crate thiserror;
+extern crate sha2;
+extern crate libc;
 
 #[cfg(test)]
 #[macro_use]
 extern crate maplit;
+#[cfg(test)]
+extern crate proptest;
 
+pub mod agent;
+pub mod api;
+pub mod atomic;
+pub mod audit;
+pub mod backup;
+pub mod batch;
+pub mod check;
+pub mod cloud;
+pub mod clip;
+pub mod collect;
+pub mod compare;
+pub mod consensus;
+pub mod complete;
+pub mod conditional;
 pub mod config;
+pub mod control;
+pub mod daemon;
+pub mod diff;
+pub mod discover;
+pub mod disk;
+pub mod editor;
+pub mod endpoint;
+pub mod envdiff;
+pub mod error;
+pub mod exec;
+pub mod expand;
+pub mod expect;
+pub mod glob;
+pub mod export;
+pub mod exporter;
+pub mod help;
+pub mod ignore;
+pub mod init;
+pub mod inventory;
+pub mod jobs;
+pub mod knock;
+pub mod location;
+pub mod migrate;
+pub mod names;
+pub mod ping;
+pub mod notify;
+pub mod plugin;
+pub mod power;
+pub mod probe;
+pub mod process;
+pub mod prompt;
+pub mod quote;
+pub mod repo;
+pub mod report;
+pub mod schedule;
+pub mod script;
+pub mod selection;
 pub mod shell;
+pub mod speedtest;
+pub mod state;
+pub mod summary;
+pub mod title;
+pub mod update;
+pub mod vpn;
+pub mod webhook;
 pub mod wrapper;
 
 pub use shell::init_shell;
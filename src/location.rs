@@ -0,0 +1,252 @@
+//! Network locations
+//!
+//! The top-level `networks` section names the places the local host is used
+//! from, each detected by rules on the local network:
+//!
+//! * `cidr` - CIDR holding the local address
+//! * `gateway_mac` - MAC address of the default gateway
+//! * `ssid` - name of the Wi-Fi network
+//!
+//! A rule may list several values, any of them matching. A location holds
+//! when all its rules do, and the first one holding is the current location:
+//! a last location without rules catches all the others.
+//!
+//! Machines reachable through different addresses give them per location,
+//! as `ip: {home: 192.168.1.5, remote: vpn.example.com}`. The address of the
+//! current location is used, else the first one listed.
+
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr};
+
+use conditional::{cidr_contains, local_address, parse_cidr};
+use error::Error;
+use wrapper::{execute_capture, CommandSpec};
+
+/// Location detected by rules on the local network
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Network {
+    /// Location name
+    pub name: String,
+    /// CIDRs holding the local address
+    pub cidr: Vec<String>,
+    /// MAC addresses of the default gateway
+    pub gateway_mac: Vec<String>,
+    /// Wi-Fi network names
+    pub ssid: Vec<String>
+}
+
+/// Local network, as seen by the rules
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Surroundings {
+    /// Local address of the default route
+    pub address: Option<IpAddr>,
+    /// MAC address of the default gateway, normalized
+    pub gateway_mac: Option<String>,
+    /// Wi-Fi network name
+    pub ssid: Option<String>
+}
+
+impl Surroundings {
+    /// Detect the local network, only looking up the facts asked for.
+    ///
+    /// # Arguments
+    ///
+    /// * `address` - Look up the local address
+    /// * `gateway` - Look up the MAC address of the default gateway
+    /// * `wifi` - Look up the Wi-Fi network name
+    ///
+    pub fn detect(address: bool, gateway: bool, wifi: bool) -> Surroundings {
+        Surroundings {
+            address: if address { local_address() } else { None },
+            gateway_mac: if gateway { gateway_mac() } else { None },
+            ssid: if wifi { wifi_ssid() } else { None }
+        }
+    }
+}
+
+impl Network {
+    /// Check that the CIDRs and MAC addresses are valid
+    pub fn check(&self) -> Result<(), Error> {
+        if let Some(cidr) = self.cidr.iter().find(|x| parse_cidr(x).is_none()) {
+            return Err(Error::Config(format!("bad CIDR `{}`", cidr)));
+        }
+        if let Some(mac) = self.gateway_mac.iter().find(|x| normalize_mac(x).is_none()) {
+            return Err(Error::Config(format!("bad MAC address `{}`", mac)));
+        }
+
+        Ok(())
+    }
+
+    /// Check if all the rules hold.
+    ///
+    /// # Arguments
+    ///
+    /// * `surroundings` - Local network
+    ///
+    pub fn matches(&self, surroundings: &Surroundings) -> bool {
+        // Rules without values hold, rules on unknown facts do not
+        let holds = |values: &[String], test: &dyn Fn(&str) -> bool| values.is_empty() || values.iter().any(|x| test(x));
+
+        holds(&self.cidr, &|cidr| surroundings.address.map(|x| cidr_contains(cidr, x)).unwrap_or(false))
+            && holds(&self.gateway_mac, &|mac| surroundings.gateway_mac.is_some() && normalize_mac(mac) == surroundings.gateway_mac)
+            && holds(&self.ssid, &|ssid| surroundings.ssid.as_deref() == Some(ssid))
+    }
+}
+
+/// Get the first location holding, none when no location does.
+///
+/// # Arguments
+///
+/// * `networks` - Locations, in order
+/// * `surroundings` - Local network
+///
+pub fn locate<'a>(networks: &'a [Network], surroundings: &Surroundings) -> Option<&'a str> {
+    networks.iter().find(|x| x.matches(surroundings)).map(|x| x.name.as_str())
+}
+
+/// Detect the local network, only looking up what the rules use.
+///
+/// # Arguments
+///
+/// * `networks` - Locations
+///
+pub fn detect(networks: &[Network]) -> Surroundings {
+    let uses = |rule: fn(&Network) -> bool| networks.iter().any(rule);
+
+    Surroundings::detect(uses(|x| !x.cidr.is_empty()), uses(|x| !x.gateway_mac.is_empty()), uses(|x| !x.ssid.is_empty()))
+}
+
+/// Normalize a MAC address to lowercase, zero-padded octets, as
+/// `0a:1b:2c:3d:4e:5f`. BSD `arp` drops leading zeros.
+///
+/// # Arguments
+///
+/// * `mac` - MAC address, with `:` or `-` separators
+///
+pub fn normalize_mac(mac: &str) -> Option<String> {
+    let octets: Vec<u8> = mac.split([':', '-'])
+        .map(|x| if x.len() <= 2 { u8::from_str_radix(x, 16).ok() } else { None })
+        .collect::<Option<_>>()?;
+    if octets.len() != 6 {
+        return None;
+    }
+
+    Some(octets.iter().map(|x| format!("{:02x}", x)).collect::<Vec<_>>().join(":"))
+}
+
+/// Get the output of a command, none when it fails
+fn command_output(program: &str, args: &[&str]) -> Option<String> {
+    let mut command = CommandSpec::new(program);
+    command.args(args);
+
+    execute_capture(command).ok()
+        .filter(|x| x.status.success())
+        .map(|x| String::from_utf8_lossy(&x.stdout).into_owned())
+}
+
+/// Parse the default gateway of a Linux `/proc/net/route` table, whose
+/// addresses are hexadecimal in host byte order.
+///
+/// # Arguments
+///
+/// * `table` - Routing table
+///
+pub fn parse_proc_route(table: &str) -> Option<Ipv4Addr> {
+    table.lines().skip(1)
+        .map(|x| x.split_whitespace().collect::<Vec<_>>())
+        .find(|x| x.len() > 2 && x[1] == "00000000")
+        .and_then(|x| u32::from_str_radix(x[2], 16).ok())
+        .map(|x| Ipv4Addr::from(x.to_ne_bytes()))
+}
+
+/// Parse the MAC address of a neighbor in a Linux `/proc/net/arp` table, or
+/// in a BSD `arp -n` output.
+///
+/// # Arguments
+///
+/// * `table` - Neighbor table
+/// * `address` - Neighbor address
+///
+pub fn parse_neighbor_mac(table: &str, address: &str) -> Option<String> {
+    let bsd = format!("({})", address);
+    table.lines()
+        .map(|x| x.split_whitespace().collect::<Vec<_>>())
+        .find_map(|x| match x.iter().position(|&field| field == "at") {
+            Some(index) if x.get(1) == Some(&bsd.as_str()) => x.get(index + 1).and_then(|x| normalize_mac(x)),
+            _ if x.first() == Some(&address) => x.get(3).and_then(|x| normalize_mac(x)),
+            _ => None
+        })
+        // Incomplete entries have a null address
+        .filter(|x| x != "00:00:00:00:00:00")
+}
+
+/// Get the MAC address of the default gateway
+fn gateway_mac() -> Option<String> {
+    if cfg!(target_os = "macos") {
+        let route = command_output("route", &["-n", "get", "default"])?;
+        let gateway = route.lines().find_map(|x| x.trim().strip_prefix("gateway:"))?.trim().to_string();
+        parse_neighbor_mac(&command_output("arp", &["-n", &gateway])?, &gateway)
+    } else {
+        let gateway = parse_proc_route(&fs::read_to_string("/proc/net/route").ok()?)?;
+        parse_neighbor_mac(&fs::read_to_string("/proc/net/arp").ok()?, &gateway.to_string())
+    }
+}
+
+/// Get the name of the Wi-Fi network, from `networksetup` on macOS, and
+/// `iwgetid` or NetworkManager elsewhere
+fn wifi_ssid() -> Option<String> {
+    let ssid = if cfg!(target_os = "macos") {
+        command_output("networksetup", &["-getairportnetwork", "en0"])
+            .and_then(|x| x.split_once(": ").map(|x| x.1.to_string()))
+    } else {
+        command_output("iwgetid", &["-r"]).or_else(|| {
+            command_output("nmcli", &["-t", "-f", "active,ssid", "dev", "wifi"])?
+                .lines().find_map(|x| x.strip_prefix("yes:")).map(String::from)
+        })
+    };
+
+    ssid.map(|x| x.trim().to_string()).filter(|x| !x.is_empty())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn locations() {
+        let networks = vec![
+            Network { name: "office".to_string(), cidr: vec!["10.20.0.0/16".to_string()], ssid: vec!["Corp".to_string()], ..Default::default() },
+            Network { name: "home".to_string(), gateway_mac: vec!["A0:B1:C2:3:4:5".to_string()], ..Default::default() },
+            Network { name: "remote".to_string(), ..Default::default() }
+        ];
+        let office = Surroundings { address: Some("10.20.1.2".parse().unwrap()), ssid: Some("Corp".to_string()), ..Default::default() };
+        let home = Surroundings { address: Some("10.20.1.2".parse().unwrap()), gateway_mac: Some("a0:b1:c2:03:04:05".to_string()), ..Default::default() };
+
+        assert_eq!(locate(&networks, &office), Some("office"));
+        assert_eq!(locate(&networks, &home), Some("home"));
+        assert_eq!(locate(&networks, &Surroundings::default()), Some("remote"));
+        assert_eq!(locate(&networks[..2], &Surroundings::default()), None);
+
+        assert!(networks[1].check().is_ok());
+        let bad = Network { gateway_mac: vec!["a0:b1:c2".to_string()], ..Default::default() };
+        assert_eq!(bad.check().unwrap_err().to_string(), "bad MAC address `a0:b1:c2`");
+    }
+
+    #[test]
+    fn gateways() {
+        let route = "Iface\tDestination\tGateway \tFlags\tRefCnt\tUse\tMetric\tMask\n\
+                     wlan0\t0001A8C0\t00000000\t0001\t0\t0\t600\t00FFFFFF\n\
+                     wlan0\t00000000\t0101A8C0\t0003\t0\t0\t600\t00000000\n";
+        assert_eq!(parse_proc_route(route), Some(Ipv4Addr::from(u32::from_str_radix("0101A8C0", 16).unwrap().to_ne_bytes())));
+
+        let arp = "IP address       HW type     Flags       HW address            Mask     Device\n\
+                   192.168.1.1      0x1         0x2         a0:b1:c2:d3:e4:f5     *        wlan0\n\
+                   192.168.1.7      0x1         0x0         00:00:00:00:00:00     *        wlan0\n";
+        assert_eq!(parse_neighbor_mac(arp, "192.168.1.1").as_deref(), Some("a0:b1:c2:d3:e4:f5"));
+        assert_eq!(parse_neighbor_mac(arp, "192.168.1.7"), None);
+
+        let bsd = "? (192.168.1.1) at a0:b1:c2:d3:e4:5 on en0 ifscope [ethernet]\n";
+        assert_eq!(parse_neighbor_mac(bsd, "192.168.1.1").as_deref(), Some("a0:b1:c2:d3:e4:05"));
+        assert_eq!(parse_neighbor_mac(bsd, "192.168.1.2"), None);
+    }
+}
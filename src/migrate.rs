@@ -0,0 +1,142 @@
+//! Configuration versions
+//!
+//! Configuration files declare the version of their layout in a top-level
+//! `version` key, files without it using the first layout. Each layout change
+//! comes with a migration rewriting the previous layout, and
+//! `pssh config upgrade` applies the pending ones.
+
+use yaml_rust::Yaml;
+use yaml_rust::yaml::Hash;
+
+use error::Error;
+
+/// Current configuration version
+pub const CONFIG_VERSION: i64 = 1;
+
+/// Version of files without a `version` key
+const INITIAL_VERSION: i64 = 1;
+
+/// Layout change between two consecutive versions
+#[derive(Debug)]
+pub struct Migration {
+    /// Version the migration upgrades to
+    pub version: i64,
+    /// Description of the change, shown on upgrades
+    pub description: &'static str,
+    /// Rewrite of a document from the previous version
+    pub apply: fn(&mut Yaml)
+}
+
+/// Migrations, in version order
+const MIGRATIONS: &[Migration] = &[];
+
+/// Get the version of a configuration document.
+///
+/// # Arguments
+///
+/// * `doc` - Configuration document
+///
+pub fn config_version(doc: &Yaml) -> Result<i64, Error> {
+    match doc["version"] {
+        Yaml::BadValue | Yaml::Null => Ok(INITIAL_VERSION),
+        Yaml::Integer(version) if version >= INITIAL_VERSION => Ok(version),
+        ref value => Err(Error::Config(format!("bad configuration version: {:?}", value)))
+    }
+}
+
+/// Get the migrations a configuration document needs.
+///
+/// Documents from newer versions of pssh are refused, as their layout cannot
+/// be read.
+///
+/// # Arguments
+///
+/// * `doc` - Configuration document
+///
+pub fn pending_migrations(doc: &Yaml) -> Result<Vec<&'static Migration>, Error> {
+    pending(doc, MIGRATIONS, CONFIG_VERSION)
+}
+
+/// Upgrade a configuration document to the current version, returning the
+/// applied migrations.
+///
+/// # Arguments
+///
+/// * `doc` - Configuration document
+///
+pub fn upgrade(doc: &mut Yaml) -> Result<Vec<&'static Migration>, Error> {
+    upgrade_with(doc, MIGRATIONS, CONFIG_VERSION)
+}
+
+fn pending<'a>(doc: &Yaml, migrations: &'a [Migration], target: i64) -> Result<Vec<&'a Migration>, Error> {
+    let version = config_version(doc)?;
+    if version > target {
+        return Err(Error::Config(format!(
+            "configuration version {} is newer than the supported version {}, upgrade pssh", version, target)));
+    }
+
+    Ok(migrations.iter().filter(|x| x.version > version && x.version <= target).collect())
+}
+
+fn upgrade_with<'a>(doc: &mut Yaml, migrations: &'a [Migration], target: i64) -> Result<Vec<&'a Migration>, Error> {
+    let applied = pending(doc, migrations, target)?;
+    for migration in &applied {
+        (migration.apply)(doc);
+    }
+
+    set_version(doc, target);
+    Ok(applied)
+}
+
+/// Set the `version` key, first in the document
+fn set_version(doc: &mut Yaml, version: i64) {
+    let mut values = Hash::new();
+    values.insert(Yaml::String("version".to_string()), Yaml::Integer(version));
+
+    if let Yaml::Hash(ref current) = *doc {
+        for (key, value) in current {
+            if key.as_str() != Some("version") {
+                values.insert(key.clone(), value.clone());
+            }
+        }
+    }
+
+    *doc = Yaml::Hash(values);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use yaml_rust::YamlLoader;
+
+    fn rename_pass(doc: &mut Yaml) {
+        if let Yaml::Hash(ref mut values) = *doc {
+            if let Some(pass) = values.remove(&Yaml::from_str("pass")) {
+                values.insert(Yaml::from_str("secret"), pass);
+            }
+        }
+    }
+
+    #[test]
+    fn versions() {
+        let mut doc = YamlLoader::load_from_str("machines:\npass: hunter2\n").unwrap().remove(0);
+        assert_eq!(config_version(&doc).unwrap(), INITIAL_VERSION);
+        assert!(pending_migrations(&doc).unwrap().is_empty());
+
+        let migrations = [Migration { version: 2, description: "rename `pass` to `secret`", apply: rename_pass }];
+        assert_eq!(pending(&doc, &migrations, 2).unwrap().len(), 1);
+
+        let applied = upgrade_with(&mut doc, &migrations, 2).unwrap();
+        assert_eq!(applied[0].description, "rename `pass` to `secret`");
+        assert_eq!(doc["secret"].as_str(), Some("hunter2"));
+        assert_eq!(doc.as_hash().unwrap().keys().next().unwrap().as_str(), Some("version"));
+        assert_eq!(config_version(&doc).unwrap(), 2);
+        assert!(upgrade_with(&mut doc, &migrations, 2).unwrap().is_empty());
+
+        assert_eq!(pending(&doc, &migrations, 1).unwrap_err().to_string(),
+            "configuration version 2 is newer than the supported version 1, upgrade pssh");
+        let doc = YamlLoader::load_from_str("version: latest").unwrap().remove(0);
+        assert!(config_version(&doc).is_err());
+    }
+}
@@ -0,0 +1,233 @@
+//! Machine names
+//!
+//! A machine name is a list of keys separated by `:`, one per level of the
+//! `machines` and `defaults` trees. Each key is made of ASCII letters,
+//! digits, `-`, `_` and `.`, and does not start with `-`.
+//!
+//! Other characters are reserved: `:` separates levels, `,`, `*` and `?`
+//! are selector syntax, `@` separates users in addresses, a leading `-`
+//! reads as an option, and `$` holds the values of a level.
+
+use std::fmt;
+
+use yaml_rust::{Yaml, YamlLoader};
+
+/// Sanitized name of keys without any valid character
+const FALLBACK_KEY: &str = "machine";
+
+/// Invalid key in a configuration
+#[derive(Debug, Clone, PartialEq)]
+pub struct NameError {
+    /// Configuration file, if known
+    pub file: Option<String>,
+    /// Line and column of the key, starting at 1, if found
+    pub location: Option<(usize, usize)>,
+    /// Offending key
+    pub key: String,
+    /// Full name of the key, with its parents
+    pub name: String,
+    /// Broken rule
+    pub reason: String
+}
+
+impl fmt::Display for NameError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (self.file.as_ref(), self.location) {
+            (Some(file), Some((line, column))) => write!(f, "{}:{}:{}: ", file, line, column)?,
+            (Some(file), None) => write!(f, "{}: ", file)?,
+            (None, Some((line, column))) => write!(f, "line {}, column {}: ", line, column)?,
+            (None, None) => {}
+        }
+        write!(f, "bad machine key `{}`", self.key)?;
+        if self.name != self.key {
+            write!(f, " (in `{}`)", self.name)?;
+        }
+        write!(f, ": {}", self.reason)
+    }
+}
+
+/// Check a key against the name grammar.
+///
+/// # Arguments
+///
+/// * `key` - Key of one level
+///
+pub fn validate_key(key: &str) -> Result<(), String> {
+    if key.is_empty() {
+        return Err("empty key".to_string());
+    }
+    if key.starts_with('-') {
+        return Err("leading `-`".to_string());
+    }
+    match key.chars().find(|c| !is_key_char(*c)) {
+        Some(c) if c.is_whitespace() => Err("whitespace is not allowed".to_string()),
+        Some(c) => Err(format!("character `{}` is not allowed", c)),
+        None => Ok(())
+    }
+}
+
+/// Check a full machine name, key by key.
+///
+/// # Arguments
+///
+/// * `name` - Machine name (`a:b`)
+///
+pub fn validate_name(name: &str) -> Result<(), String> {
+    for key in name.split(':') {
+        validate_key(key).map_err(|reason| format!("bad key `{}` in `{}`: {}", key, name, reason))?;
+    }
+    Ok(())
+}
+
+fn is_key_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.'
+}
+
+/// Suggest a valid key: reserved characters become `-`, repeated and
+/// leading ones are dropped.
+///
+/// # Arguments
+///
+/// * `key` - Key of one level
+///
+pub fn sanitize_key(key: &str) -> String {
+    let mut sanitized = String::new();
+    for c in key.chars() {
+        if is_key_char(c) && c != '-' {
+            sanitized.push(c);
+        } else if !sanitized.is_empty() && !sanitized.ends_with('-') {
+            sanitized.push('-');
+        }
+    }
+
+    let sanitized = sanitized.trim_end_matches('-');
+    if sanitized.is_empty() {
+        FALLBACK_KEY.to_string()
+    } else {
+        sanitized.to_string()
+    }
+}
+
+/// Check the keys of the `machines` and `defaults` sections of a
+/// configuration.
+///
+/// # Arguments
+///
+/// * `contents` - Configuration contents
+/// * `file` - Configuration file, for messages
+///
+pub fn check_names(contents: &str, file: Option<&str>) -> Vec<NameError> {
+    let docs = match YamlLoader::load_from_str(contents) {
+        Ok(x) => x,
+        Err(_) => return vec![]
+    };
+
+    let mut errors = vec![];
+    if let Some(doc) = docs.first() {
+        for section in &["machines", "defaults"] {
+            check_level(&doc[*section], "", &mut errors);
+        }
+    }
+
+    let lines: Vec<&str> = contents.lines().collect();
+    let mut searched_from = 0;
+    for error in &mut errors {
+        error.file = file.map(String::from);
+        error.location = find_key(&lines, &error.key, searched_from);
+        if let Some((line, _)) = error.location {
+            searched_from = line;
+        }
+    }
+
+    errors
+}
+
+fn check_level(data: &Yaml, parent: &str, errors: &mut Vec<NameError>) {
+    let dict = match data.as_hash() {
+        Some(x) => x,
+        None => return
+    };
+
+    for (key, values) in dict {
+        let key = match *key {
+            Yaml::String(ref x) => x.clone(),
+            Yaml::Integer(x) => x.to_string(),
+            _ => continue
+        };
+        if key == "$" {
+            continue;
+        }
+
+        let name = if parent.is_empty() { key.clone() } else { format!("{}:{}", parent, key) };
+        if let Err(reason) = validate_key(&key) {
+            errors.push(NameError { file: None, location: None, key: key.clone(), name: name.clone(), reason });
+        }
+        check_level(values, &name, errors);
+    }
+}
+
+/// Find the line and column of a mapping key, from a line index on, as keys
+/// are checked in file order.
+fn find_key(lines: &[&str], key: &str, from: usize) -> Option<(usize, usize)> {
+    let candidates = [format!("{}:", key), format!("\"{}\":", key), format!("'{}':", key)];
+
+    for index in (from..lines.len()).chain(0..from.min(lines.len())) {
+        let line = lines[index];
+        let trimmed = line.trim_start();
+        if candidates.iter().any(|x| trimmed.starts_with(x.as_str())) {
+            return Some((index + 1, line.len() - trimmed.len() + 1));
+        }
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn keys() {
+        assert!(validate_key("web-01.example_com").is_ok());
+        assert_eq!(validate_key("-web").unwrap_err(), "leading `-`");
+        assert_eq!(validate_key("my web").unwrap_err(), "whitespace is not allowed");
+        assert_eq!(validate_key("root@web").unwrap_err(), "character `@` is not allowed");
+        assert_eq!(validate_key("a:b").unwrap_err(), "character `:` is not allowed");
+        assert!(validate_key("").is_err());
+
+        assert_eq!(sanitize_key("my web"), "my-web");
+        assert_eq!(sanitize_key("--root@@web,1"), "root-web-1");
+        assert_eq!(sanitize_key("db*"), "db");
+        assert_eq!(sanitize_key("@@"), FALLBACK_KEY);
+        assert!(validate_key(&sanitize_key(" -a b: c")).is_ok());
+
+        assert!(validate_name("prod:web-1").is_ok());
+        assert_eq!(validate_name("prod::web").unwrap_err(), "bad key `` in `prod::web`: empty key");
+    }
+
+    #[test]
+    fn configuration_names() {
+        let contents = "\
+machines:
+    web:
+        \"front 1\":
+            $:
+                ip: 10.0.0.1
+        w2:
+            $:
+                ip: 10.0.0.2
+defaults:
+    -ops:
+        $:
+            user: ops
+";
+        let errors = check_names(contents, Some("config.yml"));
+        assert_eq!(errors.len(), 2);
+        assert_eq!(errors[0].name, "web:front 1");
+        assert_eq!(errors[0].location, Some((3, 9)));
+        assert_eq!(errors[0].to_string(), "config.yml:3:9: bad machine key `front 1` (in `web:front 1`): whitespace is not allowed");
+        assert_eq!(errors[1].to_string(), "config.yml:10:5: bad machine key `-ops`: leading `-`");
+
+        assert!(check_names("machines:\n    web:\n        $:\n", None).is_empty());
+    }
+}
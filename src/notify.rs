@@ -0,0 +1,124 @@
+//! Completion notifications
+//!
+//! Desktop notifications go through `notify-send` (or `osascript` on macOS),
+//! the terminal bell is written to stderr.
+
+use std::io::{self, Write};
+use std::process::{Command, Stdio};
+
+/// Notification mode
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NotifyMode {
+    /// No notification
+    Off,
+    /// Terminal bell
+    Bell,
+    /// Desktop notification
+    Desktop,
+    /// Terminal bell and desktop notification
+    Both
+}
+
+impl NotifyMode {
+    /// Parse a notification mode name.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - Mode name
+    ///
+    pub fn from_name(name: &str) -> Option<NotifyMode> {
+        match name {
+            "off" => Some(NotifyMode::Off),
+            "bell" => Some(NotifyMode::Bell),
+            "desktop" => Some(NotifyMode::Desktop),
+            "both" => Some(NotifyMode::Both),
+            _ => None
+        }
+    }
+
+    /// Mode name
+    pub fn name(&self) -> &'static str {
+        match *self {
+            NotifyMode::Off => "off",
+            NotifyMode::Bell => "bell",
+            NotifyMode::Desktop => "desktop",
+            NotifyMode::Both => "both"
+        }
+    }
+}
+
+/// Notify the end of an operation.
+///
+/// # Arguments
+///
+/// * `mode` - Notification mode
+/// * `title` - Notification title
+/// * `message` - Notification body
+/// * `success` - Operation status
+///
+pub fn notify(mode: NotifyMode, title: &str, message: &str, success: bool) {
+    if mode == NotifyMode::Bell || mode == NotifyMode::Both {
+        eprint!("\x07");
+        io::stderr().flush().ok();
+    }
+
+    if mode == NotifyMode::Desktop || mode == NotifyMode::Both {
+        let title = format!("{} {}", if success { "✔" } else { "✘" }, title);
+        let result = desktop_command(&title, message)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status();
+
+        if let Err(error) = result {
+            debug!("Could not send desktop notification: {}", error);
+        }
+    }
+}
+
+fn desktop_command(title: &str, message: &str) -> Command {
+    if cfg!(target_os = "macos") {
+        let mut command = Command::new("osascript");
+        command.arg("-e").arg(format!(
+            "display notification {} with title {}",
+            applescript_string(message), applescript_string(title)
+        ));
+        command
+    } else {
+        // Texts starting with `-` are not options
+        let mut command = Command::new("notify-send");
+        command.args(["--app-name", "pssh", "--", title, message]);
+        command
+    }
+}
+
+/// Quote a text as an AppleScript string literal, where only backslashes
+/// and double quotes are escaped
+///
+/// # Arguments
+///
+/// * `text` - Text to quote
+///
+fn applescript_string(text: &str) -> String {
+    format!("\"{}\"", text.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn modes() {
+        for mode in &[NotifyMode::Off, NotifyMode::Bell, NotifyMode::Desktop, NotifyMode::Both] {
+            assert_eq!(NotifyMode::from_name(mode.name()), Some(*mode));
+        }
+        assert_eq!(NotifyMode::from_name("pouet"), None);
+    }
+
+    #[test]
+    fn applescript_strings() {
+        assert_eq!(applescript_string("done"), "\"done\"");
+        assert_eq!(applescript_string("`rm \"a b\"` failed\\"), "\"`rm \\\"a b\\\"` failed\\\\\"");
+        assert_eq!(applescript_string("✔ é\n"), "\"✔ é\n\"");
+    }
+}
@@ -0,0 +1,130 @@
+//! ICMP pings
+//!
+//! `pssh ping` pings the selected machines concurrently, then prints the
+//! round-trip time of each one and an up/down summary. Unlike `pssh check`,
+//! which probes the SSH port, it tells network problems from SSH ones.
+
+use std::time::Duration;
+
+use ansi_term::Colour;
+
+use config::MachineConfig;
+use probe::Reachability;
+use wrapper::{execute_capture, ping};
+
+/// Default number of echo requests sent to each machine
+pub const DEFAULT_COUNT: u32 = 3;
+
+/// Result of a machine ping
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Ping {
+    /// Reachability
+    pub state: Reachability,
+    /// Average round-trip time, when reachable
+    pub rtt: Option<Duration>
+}
+
+/// Parse the average round-trip time of a `ping` summary line, like
+/// `rtt min/avg/max/mdev = 0.045/0.052/0.061/0.007 ms` (Linux) or
+/// `round-trip min/avg/max/stddev = ...` (BSD, macOS, BusyBox).
+///
+/// # Arguments
+///
+/// * `output` - `ping` output
+///
+pub fn parse_rtt(output: &str) -> Option<Duration> {
+    let line = output.lines().find(|x| x.contains("min/avg/max"))?;
+    let average: f64 = line.split('=').nth(1)?.trim().split('/').nth(1)?.parse().ok()?;
+
+    Some(Duration::from_secs_f64(average / 1000.0))
+}
+
+/// Ping a machine. Machines without address, or whose address cannot be
+/// resolved, are `Unknown`.
+///
+/// # Arguments
+///
+/// * `config` - Machine configuration
+/// * `count` - Number of echo requests
+///
+pub fn ping_machine(config: &MachineConfig, count: u32) -> Ping {
+    let unknown = Ping { state: Reachability::Unknown, rtt: None };
+    let ip = match config.ip {
+        Some(ref ip) => ip,
+        None => return unknown
+    };
+
+    let output = match execute_capture(ping(ip, count)) {
+        Ok(x) => x,
+        Err(error) => {
+            warn!("{}", error);
+            return unknown;
+        }
+    };
+
+    // `ping` exits with 1 when no reply came back, and 2 on other errors
+    match output.status.code() {
+        Some(0) => Ping { state: Reachability::Up, rtt: parse_rtt(&String::from_utf8_lossy(&output.stdout)) },
+        Some(1) => Ping { state: Reachability::Down, rtt: None },
+        _ => unknown
+    }
+}
+
+/// Render pings as a table, then a summary line.
+///
+/// # Arguments
+///
+/// * `rows` - Pings, per machine
+/// * `colored` - Color states
+///
+pub fn render_pings(rows: &[(String, Ping)], colored: bool) -> String {
+    let width = rows.iter().map(|x| x.0.len()).chain(Some("MACHINE".len())).max().unwrap_or(0);
+
+    let mut output = format!("{:width$}  {:7}  RTT\n", "MACHINE", "STATE", width = width);
+    for (name, ping) in rows {
+        let state = format!("{:7}", ping.state.name());
+        let state = match ping.state {
+            _ if !colored => state,
+            Reachability::Up => Colour::Green.paint(state).to_string(),
+            Reachability::Down => Colour::Red.paint(state).to_string(),
+            Reachability::Unknown => Colour::Yellow.paint(state).to_string()
+        };
+        let rtt = ping.rtt.map(|x| format!("{:.1} ms", x.as_secs_f64() * 1000.0)).unwrap_or_else(|| "-".to_string());
+        output.push_str(&format!("{:width$}  {}  {}\n", name, state, rtt, width = width));
+    }
+
+    let count = |state: Reachability| rows.iter().filter(|x| x.1.state == state).count();
+    output.push_str(&format!("\n{} up, {} down", count(Reachability::Up), count(Reachability::Down)));
+    match count(Reachability::Unknown) {
+        0 => output.push_str(".\n"),
+        unknown => output.push_str(&format!(", {} unknown.\n", unknown))
+    }
+
+    output
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn rtt() {
+        let linux = "3 packets transmitted, 3 received, 0% packet loss, time 2003ms\nrtt min/avg/max/mdev = 0.045/12.250/0.061/0.007 ms\n";
+        assert_eq!(parse_rtt(linux), Some(Duration::from_micros(12250)));
+        let bsd = "round-trip min/avg/max/stddev = 1.1/2.5/3.2/0.4 ms\n";
+        assert_eq!(parse_rtt(bsd), Some(Duration::from_micros(2500)));
+        assert_eq!(parse_rtt("3 packets transmitted, 0 received, 100% packet loss\n"), None);
+    }
+
+    #[test]
+    fn render() {
+        let rows = vec![
+            ("web1".to_string(), Ping { state: Reachability::Up, rtt: Some(Duration::from_micros(1300)) }),
+            ("db".to_string(), Ping { state: Reachability::Down, rtt: None })
+        ];
+        assert_eq!(render_pings(&rows, false), "MACHINE  STATE    RTT\nweb1     up       1.3 ms\ndb       down     -\n\n1 up, 1 down.\n");
+
+        let rows = vec![("web2".to_string(), Ping { state: Reachability::Unknown, rtt: None })];
+        assert!(render_pings(&rows, false).ends_with("\n0 up, 0 down, 1 unknown.\n"));
+    }
+}
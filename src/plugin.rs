@@ -0,0 +1,155 @@
+//! External subcommands
+//!
+//! Unknown subcommands are dispatched git-style to `pssh-<name>` executables
+//! found on `PATH`. Plugins get the configuration through environment
+//! variables:
+//!
+//! * `PSSH_CONFIG` - configuration file path
+//! * `PSSH_MACHINES` - resolved machines, as a JSON object
+//! * `PSSH_MACHINE`, `PSSH_IP`, `PSSH_PORT`, `PSSH_USER`, `PSSH_IDENTITY` -
+//!   resolved values of the machine named by the first plugin argument, if any
+//! * `PSSH_VAR_<KEY>` - variables of that machine
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use serde_json::Value;
+
+use config::{ConfigMap, MachineConfig};
+
+/// Plugin executable prefix
+pub const PLUGIN_PREFIX: &str = "pssh-";
+
+/// Find a plugin executable on `PATH`.
+///
+/// # Arguments
+///
+/// * `name` - Subcommand name
+///
+pub fn find_plugin(name: &str) -> Option<PathBuf> {
+    let paths = env::var_os("PATH")?;
+    env::split_paths(&paths)
+        .map(|dir| dir.join(format!("{}{}", PLUGIN_PREFIX, name)))
+        .find(|path| is_executable(path))
+}
+
+/// Build the command running a plugin.
+///
+/// # Arguments
+///
+/// * `plugin` - Plugin executable path
+/// * `args` - Plugin arguments
+/// * `config_path` - Configuration file path
+/// * `machines` - Resolved machines, if the configuration could be loaded
+///
+pub fn plugin_command(plugin: &Path, args: &[&str], config_path: &str, machines: Option<&ConfigMap>) -> Command {
+    let mut command = Command::new(plugin);
+    command.args(args);
+    command.env("PSSH_CONFIG", config_path);
+
+    if let Some(machines) = machines {
+        command.env("PSSH_MACHINES", machines_to_json(machines).to_string());
+
+        let machine = args.first().and_then(|name| machines.get(*name).map(|x| (name, x)));
+        if let Some((name, config)) = machine {
+            command.env("PSSH_MACHINE", name);
+            let values = [
+                ("PSSH_IP", config.ip.clone()),
+                ("PSSH_PORT", Some(config.first_port().to_string())),
+                ("PSSH_USER", config.user.clone()),
+                ("PSSH_IDENTITY", config.identities().first().cloned())
+            ];
+            for &(key, ref value) in &values {
+                if let Some(ref value) = *value {
+                    command.env(key, value);
+                }
+            }
+            for (key, value) in config.vars.iter().flatten() {
+                command.env(format!("PSSH_VAR_{}", key.to_uppercase()), value);
+            }
+        }
+    }
+
+    debug!("Executing {:?}", command);
+    command
+}
+
+/// Convert resolved machines to JSON, keyed by machine name.
+///
+/// Passwords are not exported.
+///
+/// # Arguments
+///
+/// * `machines` - Resolved machines
+///
+pub fn machines_to_json(machines: &ConfigMap) -> Value {
+    let mut names: Vec<&String> = machines.keys().collect();
+    names.sort();
+
+    let entries = names.into_iter()
+        .map(|name| (name.clone(), machine_to_json(&machines[name])))
+        .collect();
+
+    Value::Object(entries)
+}
+
+fn machine_to_json(config: &MachineConfig) -> Value {
+    json!({
+        "ip": config.ip,
+        "port": config.first_port(),
+        "ports": config.ports(),
+        "user": config.user,
+        "identity": config.identities().first(),
+        "identities": config.identities(),
+        "tags": config.tags.clone().unwrap_or_default(),
+        "vars": config.vars.clone().unwrap_or_default()
+    })
+}
+
+#[cfg(unix)]
+fn is_executable(path: &Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+
+    fs::metadata(path).map(|x| x.is_file() && x.permissions().mode() & 0o111 != 0).unwrap_or(false)
+}
+
+#[cfg(not(unix))]
+fn is_executable(path: &Path) -> bool {
+    fs::metadata(path).map(|x| x.is_file()).unwrap_or(false)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn environment() {
+        let machines = hashmap!(
+            "web".to_string() => MachineConfig {
+                ip: Some("10.0.0.1".to_string()),
+                user: Some("deploy".to_string()),
+                pass: Some("secret".to_string()),
+                vars: Some(hashmap!("env".to_string() => "prod".to_string()).into_iter().collect()),
+                ..Default::default()
+            }
+        );
+
+        let json = machines_to_json(&machines);
+        assert_eq!(json["web"]["port"], 22);
+        assert!(json["web"].get("pass").is_none());
+        assert_eq!(json["web"]["vars"]["env"], "prod");
+
+        let command = plugin_command(Path::new("/bin/pssh-test"), &["web", "--flag"], "/tmp/config.yml", Some(&machines));
+        let envs: Vec<(String, String)> = command.get_envs()
+            .filter_map(|(k, v)| Some((k.to_string_lossy().into_owned(), v?.to_string_lossy().into_owned())))
+            .collect();
+
+        assert!(envs.contains(&("PSSH_MACHINE".to_string(), "web".to_string())));
+        assert!(envs.contains(&("PSSH_IP".to_string(), "10.0.0.1".to_string())));
+        assert!(envs.contains(&("PSSH_CONFIG".to_string(), "/tmp/config.yml".to_string())));
+        assert!(envs.contains(&("PSSH_VAR_ENV".to_string(), "prod".to_string())));
+        assert!(!envs.iter().any(|x| x.0 == "PSSH_IDENTITY"));
+    }
+}
@@ -0,0 +1,151 @@
+//! Reboot and shutdown orchestration
+
+use std::time::{Duration, Instant};
+
+use config::MachineConfig;
+use probe::wait_for_port;
+use wrapper::{execute_capture, ssh_remote};
+
+/// Maximum time for a machine to stop answering after the command
+const DOWN_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Maximum time for a machine to answer again after going down
+const UP_TIMEOUT: Duration = Duration::from_secs(600);
+
+/// SSH exit status when the connection is closed by the remote side
+const SSH_CONNECTION_CLOSED: i32 = 255;
+
+/// Power action to execute on a machine
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PowerAction {
+    /// Reboot the machine
+    Reboot,
+    /// Power off the machine
+    Shutdown
+}
+
+/// Power action outcome
+#[derive(Debug, PartialEq)]
+pub enum PowerOutcome {
+    /// Action was sent, without waiting
+    Sent,
+    /// Machine answered again after the given time
+    Back(Duration),
+    /// Machine stopped answering
+    Down,
+    /// Machine did not change state in time
+    Timeout,
+    /// Action could not be sent
+    Failed(String)
+}
+
+impl PowerAction {
+    /// Action name, as the subcommand
+    pub fn name(&self) -> &'static str {
+        match *self {
+            PowerAction::Reboot => "reboot",
+            PowerAction::Shutdown => "shutdown"
+        }
+    }
+
+    /// Action description, as a past participle
+    pub fn done(&self) -> &'static str {
+        match *self {
+            PowerAction::Reboot => "rebooted",
+            PowerAction::Shutdown => "shut down"
+        }
+    }
+
+    /// Remote command executing the action, with `sudo` for non-root users
+    pub fn remote_command(&self) -> String {
+        let flag = match *self {
+            PowerAction::Reboot => "-r",
+            PowerAction::Shutdown => "-h"
+        };
+
+        format!(
+            "if [ \"$(id -u)\" -eq 0 ]; then shutdown {0} now; else sudo -n shutdown {0} now; fi",
+            flag
+        )
+    }
+}
+
+impl PowerOutcome {
+    /// Outcome description
+    ///
+    /// # Arguments
+    ///
+    /// * `action` - Executed action
+    ///
+    pub fn describe(&self, action: PowerAction) -> String {
+        match *self {
+            PowerOutcome::Sent => action.done().to_string(),
+            PowerOutcome::Back(elapsed) => format!("back online after {}s", elapsed.as_secs()),
+            PowerOutcome::Down => "down".to_string(),
+            PowerOutcome::Timeout => "timed out while waiting".to_string(),
+            PowerOutcome::Failed(ref error) => format!("failed ({})", error)
+        }
+    }
+}
+
+/// Execute a power action on a machine.
+///
+/// When `wait` is set, the machine SSH port is polled until it goes down and,
+/// for reboots, until it is reachable again.
+///
+/// # Arguments
+///
+/// * `config` - Machine configuration
+/// * `action` - Action to execute
+/// * `wait` - Wait for the machine state change
+///
+pub fn execute_power_action(config: &MachineConfig, action: PowerAction, wait: bool) -> PowerOutcome {
+    if let Err(error) = config.check_values() {
+        return PowerOutcome::Failed(error.to_string());
+    }
+
+    let start = Instant::now();
+    match execute_capture(ssh_remote(config, &action.remote_command())) {
+        Err(error) => return PowerOutcome::Failed(error.to_string()),
+        Ok(ref output) if !output.status.success() && output.status.code() != Some(SSH_CONNECTION_CLOSED) => {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            return PowerOutcome::Failed(stderr.lines().last().unwrap_or("unknown error").to_string());
+        },
+        Ok(_) => ()
+    }
+
+    if !wait {
+        return PowerOutcome::Sent;
+    }
+
+    if !wait_for_port(config, false, DOWN_TIMEOUT) {
+        return PowerOutcome::Timeout;
+    }
+
+    match action {
+        PowerAction::Shutdown => PowerOutcome::Down,
+        PowerAction::Reboot => if wait_for_port(config, true, UP_TIMEOUT) {
+            PowerOutcome::Back(start.elapsed())
+        } else {
+            PowerOutcome::Timeout
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn remote_commands() {
+        assert!(PowerAction::Reboot.remote_command().contains("sudo -n shutdown -r now"));
+        assert!(PowerAction::Shutdown.remote_command().contains("sudo -n shutdown -h now"));
+    }
+
+    #[test]
+    fn descriptions() {
+        assert_eq!(PowerOutcome::Sent.describe(PowerAction::Shutdown), "shut down");
+        assert_eq!(PowerOutcome::Back(Duration::from_secs(42)).describe(PowerAction::Reboot), "back online after 42s");
+        assert_eq!(PowerOutcome::Failed("denied".to_string()).describe(PowerAction::Reboot), "failed (denied)");
+    }
+}
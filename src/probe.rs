@@ -0,0 +1,324 @@
+//! Network probes
+
+use std::collections::HashMap;
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use chrono::{self, Utc};
+
+use batch::{run_parallel, DEFAULT_PARALLELISM};
+use config::{ConfigMap, MachineConfig};
+use state::StateStore;
+
+/// Connection timeout of `list --status` probes
+pub const STATUS_TIMEOUT: Duration = Duration::from_millis(800);
+
+/// Reuse `list --status` probe results for this long, in seconds
+pub const STATUS_CACHE_TTL: i64 = 60;
+
+/// Delay between two probes while waiting for a state change
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Machine reachability, from a TCP probe of its SSH port
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Reachability {
+    /// The SSH port accepts connections
+    Up,
+    /// The SSH port cannot be reached
+    Down,
+    /// The machine has no address, or it cannot be resolved
+    Unknown
+}
+
+impl Reachability {
+    /// Get the reachability name
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Reachability::Up => "up",
+            Reachability::Down => "down",
+            Reachability::Unknown => "unknown"
+        }
+    }
+}
+
+/// Probe the machine SSH ports, telling unreachable machines from machines
+/// which cannot be resolved.
+///
+/// # Arguments
+///
+/// * `config` - Machine configuration
+/// * `timeout` - Connection timeout
+///
+pub fn probe_reachability(config: &MachineConfig, timeout: Duration) -> Reachability {
+    let ip = match config.ip {
+        Some(ref ip) => ip,
+        None => return Reachability::Unknown
+    };
+    let mut addresses: Vec<SocketAddr> = vec![];
+    for port in config.ports() {
+        match (ip.as_str(), port).to_socket_addrs() {
+            Ok(x) => addresses.extend(x),
+            Err(_) => return Reachability::Unknown
+        }
+    }
+
+    if addresses.iter().any(|address| probe_address(address, timeout).is_some()) {
+        Reachability::Up
+    } else {
+        Reachability::Down
+    }
+}
+
+/// Open a TCP connection to the machine SSH ports, in order.
+///
+/// Returns the connection time if a port is reachable.
+///
+/// # Arguments
+///
+/// * `config` - Machine configuration
+/// * `timeout` - Connection timeout
+///
+pub fn tcp_probe(config: &MachineConfig, timeout: Duration) -> Option<Duration> {
+    config.ports().into_iter().filter_map(|port| probe_port(config, port, timeout)).next()
+}
+
+/// Select the SSH port of a machine: the first candidate port accepting
+/// connections, or the first candidate when none does.
+///
+/// Machines with a single candidate port are not probed, nor machines
+/// behind jump hosts or a proxy command, which cannot be reached directly.
+///
+/// # Arguments
+///
+/// * `config` - Machine configuration
+/// * `timeout` - Connection timeout, per port
+///
+pub fn select_port(config: &MachineConfig, timeout: Duration) -> u16 {
+    let ports = config.ports();
+    if ports.len() == 1 || !config.jump_hosts().is_empty() || config.proxy_command.is_some() {
+        return ports[0];
+    }
+
+    ports.iter().cloned()
+        .find(|port| probe_port(config, *port, timeout).is_some())
+        .unwrap_or(ports[0])
+}
+
+/// Split a jump host into its host and port, the first hop of a chain
+/// (`bastion,inner`) being the one reached directly.
+///
+/// # Arguments
+///
+/// * `jump` - Jump host, as `[user@]host[:port]`
+///
+pub fn jump_address(jump: &str) -> (String, u16) {
+    let hop = jump.split(',').next().unwrap_or("");
+    let host_port = hop.rsplit_once('@').map(|x| x.1).unwrap_or(hop);
+
+    let (host, port) = match host_port.strip_prefix('[').and_then(|x| x.split_once(']')) {
+        Some((host, rest)) => (host, rest.strip_prefix(':')),
+        None if host_port.matches(':').count() == 1 => host_port.split_once(':').map(|(a, b)| (a, Some(b))).unwrap(),
+        None => (host_port, None)
+    };
+
+    (host.to_string(), port.and_then(|x| x.parse().ok()).unwrap_or(22))
+}
+
+/// Select the jump host of a machine: the first of its `via` hosts accepting
+/// connections, or the first one when none does.
+///
+/// A single jump host is not probed, nor the jump hosts of machines with a
+/// proxy command, which replaces them.
+///
+/// # Arguments
+///
+/// * `config` - Machine configuration
+/// * `timeout` - Connection timeout, per jump host
+///
+pub fn select_jump(config: &MachineConfig, timeout: Duration) -> Option<String> {
+    let jumps = config.jump_hosts();
+    if jumps.len() < 2 || config.proxy_command.is_some() {
+        return jumps.first().cloned();
+    }
+
+    let reachable = jumps.iter().find(|jump| {
+        let (host, port) = jump_address(jump);
+        let addresses = (host.as_str(), port).to_socket_addrs().map(|x| x.collect::<Vec<_>>()).unwrap_or_default();
+        addresses.iter().any(|address| probe_address(address, timeout).is_some())
+    });
+
+    match reachable {
+        Some(jump) => {
+            if jump != &jumps[0] {
+                warn!("Jump host `{}` is unreachable, going through `{}`.", jumps[0], jump);
+            }
+            Some(jump.clone())
+        },
+        None => Some(jumps[0].clone())
+    }
+}
+
+/// Open a TCP connection to a machine port.
+///
+/// # Arguments
+///
+/// * `config` - Machine configuration
+/// * `port` - Port
+/// * `timeout` - Connection timeout
+///
+fn probe_port(config: &MachineConfig, port: u16, timeout: Duration) -> Option<Duration> {
+    let ip = config.ip.as_ref()?;
+    let addresses = (ip.as_str(), port).to_socket_addrs().ok()?;
+
+    addresses.filter_map(|address| probe_address(&address, timeout)).next()
+}
+
+/// Open a TCP connection to an address.
+///
+/// Returns the connection time if the address is reachable.
+///
+/// # Arguments
+///
+/// * `address` - Socket address
+/// * `timeout` - Connection timeout
+///
+pub fn probe_address(address: &SocketAddr, timeout: Duration) -> Option<Duration> {
+    let start = Instant::now();
+    TcpStream::connect_timeout(address, timeout).ok()?;
+    Some(start.elapsed())
+}
+
+/// Poll the machine SSH port until its reachability matches `reachable`.
+///
+/// Returns `false` if the state did not change before `max_wait`.
+///
+/// # Arguments
+///
+/// * `config` - Machine configuration
+/// * `reachable` - Expected reachability
+/// * `max_wait` - Maximum waiting time
+///
+pub fn wait_for_port(config: &MachineConfig, reachable: bool, max_wait: Duration) -> bool {
+    let start = Instant::now();
+
+    loop {
+        if tcp_probe(config, POLL_INTERVAL).is_some() == reachable {
+            return true;
+        }
+
+        if start.elapsed() >= max_wait {
+            return false;
+        }
+
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+/// Probe the SSH port of machines in parallel, reusing recent probe results.
+///
+/// # Arguments
+///
+/// * `machines` - Resolved machines
+/// * `machine_names` - Machines to probe
+///
+pub fn probe_machines(machines: &ConfigMap, machine_names: &[String]) -> HashMap<String, Reachability> {
+    let state = StateStore::load_default();
+    let now = Utc::now();
+
+    let mut reachability = HashMap::new();
+    let mut stale = vec![];
+    for name in machine_names {
+        match state.get(name).cached_probe(now, chrono::Duration::seconds(STATUS_CACHE_TTL)) {
+            Some(true) => { reachability.insert(name.clone(), Reachability::Up); },
+            Some(false) => { reachability.insert(name.clone(), Reachability::Down); },
+            None => stale.push(name.clone())
+        }
+    }
+
+    let probes = run_parallel(&stale, DEFAULT_PARALLELISM, |name| {
+        probe_reachability(&machines[name], STATUS_TIMEOUT)
+    });
+    if !probes.is_empty() {
+        StateStore::update_default(|state| {
+            for (name, result) in &probes {
+                if *result != Reachability::Unknown {
+                    state.record_probe(name, *result == Reachability::Up);
+                }
+            }
+        });
+    }
+
+    reachability.extend(probes);
+    reachability
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::net::TcpListener;
+
+    #[test]
+    fn probes() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let config = MachineConfig {
+            ip: Some("127.0.0.1".to_string()),
+            port: Some(vec![listener.local_addr().unwrap().port()]),
+            ..Default::default()
+        };
+
+        assert!(tcp_probe(&config, Duration::from_secs(1)).is_some());
+        assert!(wait_for_port(&config, true, Duration::from_secs(0)));
+        assert_eq!(probe_reachability(&config, Duration::from_secs(1)), Reachability::Up);
+
+        drop(listener);
+        assert!(tcp_probe(&config, Duration::from_secs(1)).is_none());
+        assert_eq!(probe_reachability(&config, Duration::from_secs(1)), Reachability::Down);
+        assert_eq!(probe_reachability(&MachineConfig::default(), Duration::from_secs(1)), Reachability::Unknown);
+        assert!(tcp_probe(&MachineConfig::default(), Duration::from_secs(1)).is_none());
+    }
+
+    #[test]
+    fn port_candidates() {
+        let closed = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let open = listener.local_addr().unwrap().port();
+        let config = MachineConfig {
+            ip: Some("127.0.0.1".to_string()),
+            port: Some(vec![closed, open]),
+            ..Default::default()
+        };
+
+        assert_eq!(select_port(&config, Duration::from_secs(1)), open);
+        assert_eq!(probe_reachability(&config, Duration::from_secs(1)), Reachability::Up);
+
+        drop(listener);
+        assert_eq!(select_port(&config, Duration::from_secs(1)), closed);
+        assert_eq!(select_port(&MachineConfig::default(), Duration::from_secs(1)), 22);
+    }
+
+    #[test]
+    fn jump_hosts() {
+        assert_eq!(jump_address("admin@bastion"), ("bastion".to_string(), 22));
+        assert_eq!(jump_address("bastion:2222,inner"), ("bastion".to_string(), 2222));
+        assert_eq!(jump_address("admin@[fd00::1]:2200"), ("fd00::1".to_string(), 2200));
+        assert_eq!(jump_address("fd00::1"), ("fd00::1".to_string(), 22));
+
+        let closed = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let open = listener.local_addr().unwrap().port();
+        let config = MachineConfig {
+            ip: Some("10.0.0.1".to_string()),
+            port: Some(vec![2222, 22]),
+            via: Some(vec![format!("a@127.0.0.1:{}", closed), format!("b@127.0.0.1:{}", open)]),
+            ..Default::default()
+        };
+
+        assert_eq!(select_jump(&config, Duration::from_secs(1)), Some(format!("b@127.0.0.1:{}", open)));
+        assert_eq!(select_port(&config, Duration::from_secs(1)), 2222);
+        drop(listener);
+        assert_eq!(select_jump(&config, Duration::from_secs(1)), Some(format!("a@127.0.0.1:{}", closed)));
+        assert_eq!(select_jump(&MachineConfig::default(), Duration::from_secs(1)), None);
+    }
+}
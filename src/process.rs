@@ -0,0 +1,170 @@
+//! Remote processes
+//!
+//! Processes are listed with `ps` over SSH, then matched locally, so that the
+//! user can review the processes a `kill` will signal.
+
+use config::MachineConfig;
+use error::Error;
+use exec::{exec_command, ExecResult};
+
+/// Remote command listing processes
+const PS_COMMAND: &str = "ps -eo pid=,user=,pcpu=,pmem=,args=";
+
+/// Default signal sent by `kill`
+pub const DEFAULT_SIGNAL: &str = "TERM";
+
+/// Remote process
+#[derive(Debug, Clone, PartialEq)]
+pub struct Process {
+    /// Process ID
+    pub pid: u32,
+    /// Owner
+    pub user: String,
+    /// CPU usage, in percent
+    pub cpu: f64,
+    /// Memory usage, in percent
+    pub memory: f64,
+    /// Command line
+    pub command: String
+}
+
+/// List the processes of a machine.
+///
+/// # Arguments
+///
+/// * `config` - Machine configuration
+///
+pub fn list_processes(config: &MachineConfig) -> Result<Vec<Process>, Error> {
+    let result = exec_command(config, PS_COMMAND)?;
+    if !result.success() {
+        return Err(Error::Transport(format!("{}: {}", result.describe(), result.stderr.trim())));
+    }
+
+    Ok(parse_processes(&result.stdout))
+}
+
+/// Parse `ps` output, leaving out the `ps` command itself
+///
+/// # Arguments
+///
+/// * `output` - `ps` output
+///
+pub fn parse_processes(output: &str) -> Vec<Process> {
+    output.lines().filter_map(|line| {
+        let mut fields = line.split_whitespace();
+        let pid = fields.next()?.parse().ok()?;
+        let user = fields.next()?.to_string();
+        let cpu = fields.next()?.parse().ok()?;
+        let memory = fields.next()?.parse().ok()?;
+        let command = fields.collect::<Vec<&str>>().join(" ");
+
+        if command.contains(PS_COMMAND) {
+            return None;
+        }
+        Some(Process { pid, user, cpu, memory, command })
+    }).collect()
+}
+
+/// Keep the processes matching a pattern: a PID, or a substring of the
+/// command line.
+///
+/// # Arguments
+///
+/// * `processes` - Processes
+/// * `pattern` - PID or command substring
+///
+pub fn match_processes(processes: Vec<Process>, pattern: &str) -> Vec<Process> {
+    match pattern.parse::<u32>() {
+        Ok(pid) => processes.into_iter().filter(|x| x.pid == pid).collect(),
+        Err(_) => processes.into_iter().filter(|x| x.command.contains(pattern)).collect()
+    }
+}
+
+/// Normalize a signal name (`term`, `SIGTERM`, `9`...) to a `kill -s` value.
+///
+/// # Arguments
+///
+/// * `signal` - Signal name or number
+///
+pub fn parse_signal(signal: &str) -> Option<String> {
+    let signal = signal.to_uppercase();
+    let signal = signal.trim_start_matches("SIG");
+
+    if !signal.is_empty() && signal.chars().all(|c| c.is_ascii_alphanumeric()) {
+        Some(signal.to_string())
+    } else {
+        None
+    }
+}
+
+/// Signal processes of a machine.
+///
+/// # Arguments
+///
+/// * `config` - Machine configuration
+/// * `pids` - Process IDs
+/// * `signal` - Signal, as returned by `parse_signal`
+///
+pub fn kill_processes(config: &MachineConfig, pids: &[u32], signal: &str) -> Result<ExecResult, Error> {
+    let pids: Vec<String> = pids.iter().map(|x| x.to_string()).collect();
+    exec_command(config, &format!("kill -s {} {}", signal, pids.join(" ")))
+}
+
+/// Render processes as a table.
+///
+/// # Arguments
+///
+/// * `processes` - Processes
+///
+pub fn render_processes(processes: &[Process]) -> String {
+    let user_width = processes.iter().map(|x| x.user.len()).max().unwrap_or(0).max(4);
+
+    let mut lines = vec![format!("{:>7}  {:uw$}  {:>5}  {:>5}  COMMAND", "PID", "USER", "%CPU", "%MEM", uw = user_width)];
+    for process in processes {
+        lines.push(format!("{:>7}  {:uw$}  {:>5.1}  {:>5.1}  {}",
+            process.pid, process.user, process.cpu, process.memory, process.command, uw = user_width));
+    }
+
+    lines.join("\n")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn processes() {
+        let output = format!("\
+    1 root       0.0  0.1 /sbin/init splash
+  812 www-data   2.5  1.2 nginx: worker process
+  813 www-data   0.0  1.1 nginx: worker process
+ 4242 deploy     0.0  0.0 sh -c {}
+", PS_COMMAND);
+
+        let processes = parse_processes(&output);
+        assert_eq!(processes.len(), 3);
+        assert_eq!(processes[1], Process {
+            pid: 812,
+            user: "www-data".to_string(),
+            cpu: 2.5,
+            memory: 1.2,
+            command: "nginx: worker process".to_string()
+        });
+
+        assert_eq!(match_processes(processes.clone(), "nginx").len(), 2);
+        assert_eq!(match_processes(processes.clone(), "813")[0].pid, 813);
+        assert!(match_processes(processes.clone(), "postgres").is_empty());
+
+        let text = render_processes(&processes[..1]);
+        assert_eq!(text, "    PID  USER   %CPU   %MEM  COMMAND\n      1  root    0.0    0.1  /sbin/init splash");
+    }
+
+    #[test]
+    fn signals() {
+        assert_eq!(parse_signal("term"), Some("TERM".to_string()));
+        assert_eq!(parse_signal("SIGKILL"), Some("KILL".to_string()));
+        assert_eq!(parse_signal("9"), Some("9".to_string()));
+        assert_eq!(parse_signal("TERM; reboot"), None);
+        assert_eq!(parse_signal(""), None);
+    }
+}
@@ -0,0 +1,93 @@
+//! Interactive prompts
+
+use std::io::{self, BufRead, Write};
+
+/// Ask a yes/no question on stdin, defaulting to "no".
+///
+/// # Arguments
+///
+/// * `question` - Question to display
+///
+pub fn confirm(question: &str) -> bool {
+    let stdin = io::stdin();
+    let mut input = stdin.lock();
+    confirm_from(&mut input, question)
+}
+
+/// Ask a yes/no question, reading the answer from a reader.
+///
+/// # Arguments
+///
+/// * `input` - Answer source
+/// * `question` - Question to display
+///
+pub fn confirm_from<R: BufRead>(input: &mut R, question: &str) -> bool {
+    print!("{} [y/N] ", question);
+    io::stdout().flush().ok();
+
+    let mut answer = String::new();
+    if input.read_line(&mut answer).is_err() {
+        return false;
+    }
+
+    matches!(answer.trim().to_lowercase().as_str(), "y" | "yes")
+}
+
+/// Ask a question on stdin, with a default answer.
+///
+/// # Arguments
+///
+/// * `question` - Question to display
+/// * `default` - Answer used when the input is empty
+///
+pub fn ask(question: &str, default: &str) -> String {
+    let stdin = io::stdin();
+    let mut input = stdin.lock();
+    ask_from(&mut input, question, default)
+}
+
+/// Ask a question with a default answer, reading the answer from a reader.
+///
+/// # Arguments
+///
+/// * `input` - Answer source
+/// * `question` - Question to display
+/// * `default` - Answer used when the input is empty
+///
+pub fn ask_from<R: BufRead>(input: &mut R, question: &str, default: &str) -> String {
+    if default.is_empty() {
+        print!("{}: ", question);
+    } else {
+        print!("{} [{}]: ", question, default);
+    }
+    io::stdout().flush().ok();
+
+    let mut answer = String::new();
+    input.read_line(&mut answer).ok();
+
+    match answer.trim() {
+        "" => default.to_string(),
+        x => x.to_string()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn answers() {
+        assert!(confirm_from(&mut "y\n".as_bytes(), "Continue?"));
+        assert!(confirm_from(&mut "Yes\n".as_bytes(), "Continue?"));
+        assert!(!confirm_from(&mut "\n".as_bytes(), "Continue?"));
+        assert!(!confirm_from(&mut "nope\n".as_bytes(), "Continue?"));
+        assert!(!confirm_from(&mut "".as_bytes(), "Continue?"));
+    }
+
+    #[test]
+    fn questions() {
+        assert_eq!(ask_from(&mut "web1\n".as_bytes(), "Name?", "host"), "web1");
+        assert_eq!(ask_from(&mut "  \n".as_bytes(), "Name?", "host"), "host");
+        assert_eq!(ask_from(&mut "".as_bytes(), "Name?", ""), "");
+    }
+}
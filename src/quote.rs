@@ -0,0 +1,87 @@
+//! Shell quoting
+//!
+//! Remote commands are strings interpreted by the remote shell. Every value
+//! pssh puts into such a string, as a path or a nested command, goes through
+//! this module, so that spaces, quotes and glob characters are kept literal.
+
+/// Characters which never need quoting
+fn is_safe(c: char) -> bool {
+    c.is_ascii_alphanumeric() || "-_./=:,+@%".contains(c)
+}
+
+/// Quote a word for a POSIX shell.
+///
+/// Words made of safe characters are left as is, others are put between
+/// single quotes, with single quotes inside written as `'\''`.
+///
+/// # Arguments
+///
+/// * `word` - Word to quote
+///
+pub fn quote(word: &str) -> String {
+    if !word.is_empty() && word.chars().all(is_safe) {
+        return word.to_string();
+    }
+
+    format!("'{}'", word.replace('\'', "'\\''"))
+}
+
+/// Quote a remote path, keeping a leading `~/` unquoted so that the remote
+/// shell still expands it to the home directory.
+///
+/// # Arguments
+///
+/// * `path` - Remote path
+///
+pub fn quote_path(path: &str) -> String {
+    match path.strip_prefix("~/") {
+        Some(rest) if !rest.is_empty() => format!("~/{}", quote(rest)),
+        _ if path == "~" || path == "~/" => path.to_string(),
+        _ => quote(path)
+    }
+}
+
+/// Quote words and join them with spaces, as a command line.
+///
+/// # Arguments
+///
+/// * `words` - Program and arguments
+///
+pub fn join<S: AsRef<str>>(words: &[S]) -> String {
+    words.iter().map(|x| quote(x.as_ref())).collect::<Vec<_>>().join(" ")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::process::Command;
+
+    #[test]
+    fn words() {
+        assert_eq!(quote("/var/log"), "/var/log");
+        assert_eq!(quote(""), "''");
+        assert_eq!(quote("my file"), "'my file'");
+        assert_eq!(quote("it's"), "'it'\\''s'");
+        assert_eq!(quote("*.log"), "'*.log'");
+        assert_eq!(quote("$(reboot)"), "'$(reboot)'");
+        assert_eq!(quote("a;b"), "'a;b'");
+
+        assert_eq!(quote_path("~/.pssh_clipboard"), "~/.pssh_clipboard");
+        assert_eq!(quote_path("~/my notes"), "~/'my notes'");
+        assert_eq!(quote_path("~"), "~");
+        assert_eq!(quote_path("/tmp/~/x y"), "'/tmp/~/x y'");
+
+        assert_eq!(join(&["du", "-k", "my dir"]), "du -k 'my dir'");
+    }
+
+    #[test]
+    fn shell_round_trip() {
+        let words = ["plain", "two words", "it's", "\"double\"", "*", "?[a]", "$HOME", "`id`", "a\\b", "new\nline", ""];
+        let script = format!("printf '%s\\0' {}", join(&words));
+
+        let output = Command::new("sh").args(["-c", &script]).output().unwrap();
+        let printed: Vec<_> = output.stdout.split(|x| *x == 0).map(|x| String::from_utf8_lossy(x).into_owned()).collect();
+        assert_eq!(&printed[..words.len()], &words[..]);
+    }
+}
@@ -0,0 +1,247 @@
+//! Git-backed configuration directory
+//!
+//! The user configuration directory (`~/.pssh`) can be managed as a git
+//! repository, to share and version the inventory of a team. Local state
+//! (`state/`, audit log, backups) is kept out of the repository.
+
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::process::Command;
+
+use error::Error;
+use wrapper::execute_capture;
+
+/// Ignored local files
+const GITIGNORE_ENTRIES: &[&str] = &["state/", "audit.log", "*.bak"];
+
+/// Default commit message for local edits
+pub const DEFAULT_COMMIT_MESSAGE: &str = "Update pssh configuration";
+
+/// Check if a directory is a git repository.
+///
+/// # Arguments
+///
+/// * `dir` - Directory
+///
+pub fn is_repository(dir: &Path) -> bool {
+    dir.join(".git").exists()
+}
+
+/// Initialize the configuration directory as a git repository.
+///
+/// With an URL, the team repository is cloned (or pulled, when the directory
+/// already exists).
+///
+/// # Arguments
+///
+/// * `dir` - Configuration directory
+/// * `url` - Team repository URL
+///
+pub fn init(dir: &Path, url: Option<&str>) -> Result<(), Error> {
+    if is_repository(dir) {
+        return Err(Error::Config(format!("{} is already a git repository", dir.display())));
+    }
+
+    let is_empty = fs::read_dir(dir).map(|mut x| x.next().is_none()).unwrap_or(true);
+    match url {
+        Some(url) if is_empty => {
+            let parent = dir.parent().ok_or_else(|| Error::Config("bad configuration directory".to_string()))?;
+            fs::create_dir_all(parent).map_err(|e| Error::io(format!("could not create {}", parent.display()), e))?;
+            git(parent, &["clone", url, &dir.to_string_lossy()])?;
+        },
+        Some(url) => {
+            git(dir, &["init"])?;
+            git(dir, &["remote", "add", "origin", url])?;
+            // Fails without touching local files if they conflict with the repository
+            git(dir, &["pull", "origin", "HEAD"])?;
+        },
+        None => {
+            fs::create_dir_all(dir).map_err(|e| Error::io(format!("could not create {}", dir.display()), e))?;
+            git(dir, &["init"])?;
+        }
+    }
+
+    write_gitignore(dir)?;
+    commit(dir, "Initialize pssh configuration")?;
+    Ok(())
+}
+
+/// Pull remote changes, rebasing local commits.
+///
+/// # Arguments
+///
+/// * `dir` - Configuration directory
+///
+pub fn pull(dir: &Path) -> Result<(), Error> {
+    check_repository(dir)?;
+    git(dir, &["pull", "--rebase", "--autostash", "origin", "HEAD"])?;
+    Ok(())
+}
+
+/// Commit local edits, then push them after pulling remote changes.
+///
+/// # Arguments
+///
+/// * `dir` - Configuration directory
+/// * `message` - Commit message
+///
+pub fn push(dir: &Path, message: &str) -> Result<(), Error> {
+    check_repository(dir)?;
+    commit(dir, message)?;
+    pull(dir)?;
+    git(dir, &["push", "origin", "HEAD"])?;
+    Ok(())
+}
+
+/// Read the committed version of a file.
+///
+/// # Arguments
+///
+/// * `dir` - Configuration directory
+/// * `path` - File path, inside the configuration directory
+///
+pub fn committed_file(dir: &Path, path: &Path) -> Result<String, Error> {
+    check_repository(dir)?;
+    let relative = path.strip_prefix(dir)
+        .map_err(|_| Error::Config(format!("{} is not in {}", path.display(), dir.display())))?;
+    git(dir, &["show", &format!("HEAD:{}", relative.to_string_lossy())])
+}
+
+/// Commit all local edits, if any.
+///
+/// Returns `true` when a commit was created.
+///
+/// # Arguments
+///
+/// * `dir` - Configuration directory
+/// * `message` - Commit message
+///
+pub fn commit(dir: &Path, message: &str) -> Result<bool, Error> {
+    git(dir, &["add", "--all"])?;
+    if git(dir, &["status", "--porcelain"])?.trim().is_empty() {
+        return Ok(false);
+    }
+
+    git(dir, &["commit", "--quiet", "--message", message])?;
+    Ok(true)
+}
+
+/// Add a remote to a repository, or update its URL when it already exists.
+///
+/// Returns `true` when the remote was added.
+///
+/// # Arguments
+///
+/// * `dir` - Repository directory
+/// * `name` - Remote name
+/// * `url` - Remote URL
+///
+pub fn set_remote(dir: &Path, name: &str, url: &str) -> Result<bool, Error> {
+    if git(dir, &["remote", "get-url", name]).is_ok() {
+        git(dir, &["remote", "set-url", name, url])?;
+        Ok(false)
+    } else {
+        git(dir, &["remote", "add", name, url])?;
+        Ok(true)
+    }
+}
+
+fn check_repository(dir: &Path) -> Result<(), Error> {
+    if is_repository(dir) {
+        Ok(())
+    } else {
+        Err(Error::Config(format!("{} is not a git repository, use `pssh repo init`", dir.display())))
+    }
+}
+
+fn write_gitignore(dir: &Path) -> Result<(), Error> {
+    let path = dir.join(".gitignore");
+    let existing = fs::read_to_string(&path).unwrap_or_default();
+    let missing: Vec<&&str> = GITIGNORE_ENTRIES.iter()
+        .filter(|entry| !existing.lines().any(|line| line.trim() == **entry))
+        .collect();
+
+    if missing.is_empty() {
+        return Ok(());
+    }
+
+    let write_error = |e| Error::io(format!("could not write {}", path.display()), e);
+    let mut f = OpenOptions::new().create(true).append(true).open(&path).map_err(write_error)?;
+    if !existing.is_empty() && !existing.ends_with('\n') {
+        writeln!(f).map_err(write_error)?;
+    }
+    for entry in missing {
+        writeln!(f, "{}", entry).map_err(write_error)?;
+    }
+
+    Ok(())
+}
+
+/// Run a git command in a directory, returning its output
+///
+/// # Arguments
+///
+/// * `dir` - Working directory
+/// * `args` - Git arguments
+///
+fn git(dir: &Path, args: &[&str]) -> Result<String, Error> {
+    let mut command = Command::new("git");
+    command.arg("-C").arg(dir).args(args);
+    // Tests run without a git identity configured
+    #[cfg(test)]
+    command.envs(test::IDENTITY.iter().cloned());
+
+    debug!("Executing {:?}", command);
+    let output = execute_capture(command)?;
+
+    if output.status.success() {
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    } else {
+        Err(Error::tool(format!("git {} failed", args[0]), &String::from_utf8_lossy(&output.stderr)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::env;
+
+    /// Git identity of test commits
+    pub const IDENTITY: &[(&str, &str)] = &[("GIT_AUTHOR_NAME", "pssh"), ("GIT_AUTHOR_EMAIL", "pssh@localhost"),
+                                            ("GIT_COMMITTER_NAME", "pssh"), ("GIT_COMMITTER_EMAIL", "pssh@localhost")];
+
+    #[test]
+    fn shared_repository() {
+        let root = env::temp_dir().join(format!("pssh-repo-{}", std::process::id()));
+        let remote = root.join("remote.git");
+        let first = root.join("first");
+        let second = root.join("second");
+        fs::create_dir_all(&root).unwrap();
+        git(&root, &["init", "--bare", "--quiet", &remote.to_string_lossy()]).unwrap();
+
+        // Share a local configuration
+        fs::create_dir_all(&first).unwrap();
+        fs::write(first.join("config.yml"), "defaults:\nmachines:\n").unwrap();
+        fs::write(first.join("config.yml.bak"), "").unwrap();
+        init(&first, None).unwrap();
+        assert!(init(&first, None).is_err());
+        git(&first, &["remote", "add", "origin", &remote.to_string_lossy()]).unwrap();
+        git(&first, &["push", "--quiet", "origin", "HEAD"]).unwrap();
+
+        // Clone it, then exchange an edit
+        init(&second, Some(&remote.to_string_lossy())).unwrap();
+        assert!(second.join("config.yml").exists());
+        assert!(!second.join("config.yml.bak").exists());
+
+        fs::write(second.join("config.yml"), "defaults:\nmachines:\n  web:\n").unwrap();
+        assert_eq!(committed_file(&second, &second.join("config.yml")).unwrap(), "defaults:\nmachines:\n");
+        assert!(committed_file(&second, &root.join("config.yml")).is_err());
+        push(&second, DEFAULT_COMMIT_MESSAGE).unwrap();
+        pull(&first).unwrap();
+        assert!(fs::read_to_string(first.join("config.yml")).unwrap().contains("web"));
+
+        fs::remove_dir_all(&root).ok();
+    }
+}
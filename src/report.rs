@@ -0,0 +1,403 @@
+//! Machine health reports
+
+use std::cmp::Ordering;
+
+use ansi_term::Colour;
+use serde_json::Value;
+
+use config::MachineConfig;
+use error::Error;
+use wrapper::{execute_capture, ssh_remote};
+
+/// Remote script printing health values, one `key values...` line each
+const REPORT_SCRIPT: &str = "\
+echo uptime $(cut -d' ' -f1 /proc/uptime); \
+echo load $(cut -d' ' -f1 /proc/loadavg); \
+echo cpus $(nproc 2>/dev/null || grep -c ^processor /proc/cpuinfo); \
+awk '/^MemTotal:/ {t=$2} /^MemAvailable:/ {a=$2} END {print \"memory\", t, a}' /proc/meminfo; \
+df -P / | awk 'NR==2 {print \"disk\", $2, $3}'";
+
+/// Machine health values
+#[derive(Debug, Clone, PartialEq)]
+pub struct HealthReport {
+    /// Uptime, in seconds
+    pub uptime: u64,
+    /// Load average over the last minute
+    pub load: f64,
+    /// Number of CPUs
+    pub cpus: u32,
+    /// Used memory, in percent
+    pub memory: f64,
+    /// Used disk space on `/`, in percent
+    pub disk: f64
+}
+
+/// Health result for a machine: a report, or an error message
+pub type HealthResult = Result<HealthReport, Error>;
+
+/// Report column to sort on
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReportSort {
+    /// Machine name, ascending
+    Name,
+    /// Uptime, descending
+    Uptime,
+    /// Load per CPU, descending
+    Load,
+    /// Used memory, descending
+    Memory,
+    /// Used disk space, descending
+    Disk
+}
+
+/// Threshold level of a value
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Level {
+    /// Value is fine
+    Normal,
+    /// Value is above the warning threshold
+    Warning,
+    /// Value is above the critical threshold
+    Critical
+}
+
+impl ReportSort {
+    /// Parse a sort column name.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - Column name
+    ///
+    pub fn from_name(name: &str) -> Option<ReportSort> {
+        match name {
+            "name" => Some(ReportSort::Name),
+            "uptime" => Some(ReportSort::Uptime),
+            "load" => Some(ReportSort::Load),
+            "memory" => Some(ReportSort::Memory),
+            "disk" => Some(ReportSort::Disk),
+            _ => None
+        }
+    }
+}
+
+impl HealthReport {
+    /// Load average divided by the CPU count
+    pub fn load_per_cpu(&self) -> f64 {
+        self.load / f64::from(self.cpus.max(1))
+    }
+
+    /// Threshold level of the load per CPU
+    pub fn load_level(&self) -> Level {
+        level(self.load_per_cpu(), 0.7, 1.0)
+    }
+
+    /// Threshold level of the used memory
+    pub fn memory_level(&self) -> Level {
+        level(self.memory, 75.0, 90.0)
+    }
+
+    /// Threshold level of the used disk space
+    pub fn disk_level(&self) -> Level {
+        level(self.disk, 80.0, 90.0)
+    }
+}
+
+/// Gather health values from a machine.
+///
+/// # Arguments
+///
+/// * `config` - Machine configuration
+///
+pub fn gather(config: &MachineConfig) -> HealthResult {
+    config.check_values()?;
+    let output = execute_capture(ssh_remote(config, REPORT_SCRIPT))?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(Error::Transport(stderr.lines().last().unwrap_or("unreachable").to_string()));
+    }
+
+    parse_report(&String::from_utf8_lossy(&output.stdout))
+        .ok_or_else(|| Error::Transport("unexpected report output".to_string()))
+}
+
+/// Parse the output of the report script.
+///
+/// # Arguments
+///
+/// * `output` - Script output
+///
+pub fn parse_report(output: &str) -> Option<HealthReport> {
+    let mut uptime = None;
+    let mut load = None;
+    let mut cpus = None;
+    let mut memory = None;
+    let mut disk = None;
+
+    for line in output.lines() {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        match fields.as_slice() {
+            ["uptime", value] => uptime = value.parse::<f64>().ok().map(|x| x as u64),
+            ["load", value] => load = value.parse().ok(),
+            ["cpus", value] => cpus = value.parse().ok(),
+            ["memory", total, available] => memory = used_percent(total, available, true),
+            ["disk", total, used] => disk = used_percent(total, used, false),
+            _ => ()
+        }
+    }
+
+    Some(HealthReport {
+        uptime: uptime?,
+        load: load?,
+        cpus: cpus.unwrap_or(1),
+        memory: memory?,
+        disk: disk?
+    })
+}
+
+/// Sort report rows in place.
+///
+/// Numeric columns are sorted in descending order, and unreachable machines
+/// are always listed last.
+///
+/// # Arguments
+///
+/// * `rows` - Report rows
+/// * `sort` - Column to sort on
+///
+pub fn sort_rows(rows: &mut [(String, HealthResult)], sort: ReportSort) {
+    rows.sort_by(|(name_a, a), (name_b, b)| {
+        let ordering = match (a, b) {
+            (Ok(a), Ok(b)) => match sort {
+                ReportSort::Name => Ordering::Equal,
+                ReportSort::Uptime => b.uptime.cmp(&a.uptime),
+                ReportSort::Load => compare_f64(b.load_per_cpu(), a.load_per_cpu()),
+                ReportSort::Memory => compare_f64(b.memory, a.memory),
+                ReportSort::Disk => compare_f64(b.disk, a.disk)
+            },
+            (Ok(_), Err(_)) => Ordering::Less,
+            (Err(_), Ok(_)) => Ordering::Greater,
+            (Err(_), Err(_)) => Ordering::Equal
+        };
+
+        ordering.then_with(|| name_a.cmp(name_b))
+    });
+}
+
+/// Render report rows as a table.
+///
+/// # Arguments
+///
+/// * `rows` - Report rows
+/// * `colored` - Color values depending on their threshold level
+///
+pub fn render_table(rows: &[(String, HealthResult)], colored: bool) -> String {
+    let header = ["MACHINE", "UPTIME", "LOAD", "MEMORY", "DISK"];
+    let cells: Vec<Vec<(String, Level)>> = rows.iter().map(|(name, result)| {
+        let mut line = vec![(name.clone(), Level::Normal)];
+        match *result {
+            Ok(ref report) => {
+                line.push((format_uptime(report.uptime), Level::Normal));
+                line.push((format!("{:.2}", report.load), report.load_level()));
+                line.push((format!("{:.0}%", report.memory), report.memory_level()));
+                line.push((format!("{:.0}%", report.disk), report.disk_level()));
+            },
+            Err(ref error) => {
+                line.push((format!("unreachable ({})", error), Level::Critical));
+            }
+        }
+        line
+    }).collect();
+
+    let mut widths: Vec<usize> = header.iter().map(|x| x.len()).collect();
+    for line in &cells {
+        // Error messages span the remaining columns
+        if line.len() == header.len() {
+            for (i, (text, _)) in line.iter().enumerate() {
+                widths[i] = widths[i].max(text.len());
+            }
+        } else {
+            widths[0] = widths[0].max(line[0].0.len());
+        }
+    }
+
+    let mut output = String::new();
+    let header_line: Vec<String> = header.iter().enumerate()
+        .map(|(i, x)| format!("{:width$}", x, width = widths[i]))
+        .collect();
+    output.push_str(header_line.join("  ").trim_end());
+    output.push('\n');
+
+    for line in &cells {
+        let texts: Vec<String> = line.iter().enumerate().map(|(i, (text, level))| {
+            let padded = if i + 1 < line.len() {
+                format!("{:width$}", text, width = widths[i])
+            } else {
+                text.clone()
+            };
+            paint(padded, *level, colored)
+        }).collect();
+        output.push_str(&texts.join("  "));
+        output.push('\n');
+    }
+
+    output
+}
+
+/// Convert report rows to JSON.
+///
+/// # Arguments
+///
+/// * `rows` - Report rows
+///
+pub fn to_json(rows: &[(String, HealthResult)]) -> Value {
+    Value::Array(rows.iter().map(|(name, result)| match *result {
+        Ok(ref report) => json!({
+            "machine": name,
+            "uptime": report.uptime,
+            "load": report.load,
+            "cpus": report.cpus,
+            "memory": report.memory,
+            "disk": report.disk
+        }),
+        Err(ref error) => json!({
+            "machine": name,
+            "error": error.to_string()
+        })
+    }).collect())
+}
+
+/// Format an uptime in a compact way
+///
+/// # Arguments
+///
+/// * `seconds` - Uptime, in seconds
+///
+pub fn format_uptime(seconds: u64) -> String {
+    let days = seconds / 86400;
+    let hours = (seconds % 86400) / 3600;
+    let minutes = (seconds % 3600) / 60;
+
+    if days > 0 {
+        format!("{}d {}h", days, hours)
+    } else if hours > 0 {
+        format!("{}h {}m", hours, minutes)
+    } else {
+        format!("{}m", minutes)
+    }
+}
+
+fn level(value: f64, warning: f64, critical: f64) -> Level {
+    if value >= critical {
+        Level::Critical
+    } else if value >= warning {
+        Level::Warning
+    } else {
+        Level::Normal
+    }
+}
+
+fn paint(text: String, level: Level, colored: bool) -> String {
+    if !colored {
+        return text;
+    }
+
+    match level {
+        Level::Normal => text,
+        Level::Warning => Colour::Yellow.paint(text).to_string(),
+        Level::Critical => Colour::Red.paint(text).to_string()
+    }
+}
+
+fn used_percent(total: &str, value: &str, value_is_free: bool) -> Option<f64> {
+    let total: f64 = total.parse().ok()?;
+    let value: f64 = value.parse().ok()?;
+    if total <= 0.0 {
+        return None;
+    }
+
+    let used = if value_is_free { total - value } else { value };
+    Some(used * 100.0 / total)
+}
+
+fn compare_f64(a: f64, b: f64) -> Ordering {
+    a.partial_cmp(&b).unwrap_or(Ordering::Equal)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn report(uptime: u64, load: f64, memory: f64, disk: f64) -> HealthReport {
+        HealthReport { uptime, load, cpus: 2, memory, disk }
+    }
+
+    #[test]
+    fn parse() {
+        let output = "uptime 90061.52\nload 0.42\ncpus 4\nmemory 8000000 2000000\ndisk 1000 950\n";
+        let report = parse_report(output).unwrap();
+
+        assert_eq!(report.uptime, 90061);
+        assert_eq!(report.load, 0.42);
+        assert_eq!(report.cpus, 4);
+        assert_eq!(report.memory, 75.0);
+        assert_eq!(report.disk, 95.0);
+
+        assert!(parse_report("uptime 12\nload 0.1\n").is_none());
+    }
+
+    #[test]
+    fn levels() {
+        let r = report(0, 1.0, 50.0, 85.0);
+        assert_eq!(r.load_level(), Level::Normal);
+        assert_eq!(r.memory_level(), Level::Normal);
+        assert_eq!(r.disk_level(), Level::Warning);
+
+        let r = report(0, 2.5, 95.0, 99.0);
+        assert_eq!(r.load_level(), Level::Critical);
+        assert_eq!(r.memory_level(), Level::Critical);
+        assert_eq!(r.disk_level(), Level::Critical);
+    }
+
+    #[test]
+    fn sorting() {
+        let mut rows = vec![
+            ("a".to_string(), Ok(report(10, 0.1, 10.0, 50.0))),
+            ("b".to_string(), Err(Error::Transport("timeout".to_string()))),
+            ("c".to_string(), Ok(report(20, 0.2, 30.0, 20.0)))
+        ];
+
+        sort_rows(&mut rows, ReportSort::Disk);
+        let names: Vec<&str> = rows.iter().map(|x| x.0.as_str()).collect();
+        assert_eq!(names, vec!["a", "c", "b"]);
+
+        sort_rows(&mut rows, ReportSort::Memory);
+        let names: Vec<&str> = rows.iter().map(|x| x.0.as_str()).collect();
+        assert_eq!(names, vec!["c", "a", "b"]);
+
+        sort_rows(&mut rows, ReportSort::Name);
+        let names: Vec<&str> = rows.iter().map(|x| x.0.as_str()).collect();
+        assert_eq!(names, vec!["a", "c", "b"]);
+    }
+
+    #[test]
+    fn uptimes() {
+        assert_eq!(format_uptime(59), "0m");
+        assert_eq!(format_uptime(3720), "1h 2m");
+        assert_eq!(format_uptime(90061), "1d 1h");
+    }
+
+    #[test]
+    fn table() {
+        let rows = vec![
+            ("web1".to_string(), Ok(report(3720, 0.5, 40.0, 50.0))),
+            ("web2".to_string(), Err(Error::Transport("timeout".to_string())))
+        ];
+
+        let table = render_table(&rows, false);
+        let lines: Vec<&str> = table.lines().collect();
+        assert_eq!(lines[0], "MACHINE  UPTIME  LOAD  MEMORY  DISK");
+        assert_eq!(lines[1], "web1     1h 2m   0.50  40%     50%");
+        assert_eq!(lines[2], "web2     unreachable (timeout)");
+    }
+}
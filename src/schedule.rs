@@ -0,0 +1,395 @@
+//! Scheduled jobs
+//!
+//! Recurring fleet operations are kept in `~/.pssh/state/schedules.jsonl`,
+//! each with a cron expression and the pssh arguments to run. The daemon
+//! runs them when due, and `pssh schedule crontab` renders them as crontab
+//! entries instead. Their runs are recorded in the job history.
+//!
+//! Cron expressions have five fields (minute, hour, day of month, month, day
+//! of week), each made of `*`, values, ranges (`1-5`) and steps (`*/15`),
+//! separated by commas. `@hourly`, `@daily`, `@weekly` and `@monthly` are
+//! accepted too.
+
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::thread;
+
+use chrono::{Datelike, Duration, NaiveDateTime, Timelike};
+use serde_json::{self, Value};
+
+use error::Error;
+use quote;
+use state::{get_state_dir, write_atomic, StateLock};
+
+/// Operations which can be scheduled, as they are recorded as jobs
+pub const SCHEDULED_OPERATIONS: &[&str] = &["exec", "push"];
+
+/// Parsed cron expression
+#[derive(Debug, Clone, PartialEq)]
+pub struct CronSchedule {
+    minutes: Vec<u32>,
+    hours: Vec<u32>,
+    days: Vec<u32>,
+    months: Vec<u32>,
+    weekdays: Vec<u32>,
+    /// Day of month and day of week were both restricted: either matches
+    either_day: bool
+}
+
+/// Scheduled operation
+#[derive(Debug, Clone, PartialEq)]
+pub struct Schedule {
+    /// Identifier
+    pub id: u64,
+    /// Cron expression
+    pub cron: String,
+    /// Configuration file given on the command line
+    pub config_file: Option<String>,
+    /// pssh arguments, starting with the operation
+    pub arguments: Vec<String>
+}
+
+impl CronSchedule {
+    /// Parse a cron expression.
+    ///
+    /// # Arguments
+    ///
+    /// * `expression` - Cron expression
+    ///
+    pub fn parse(expression: &str) -> Result<CronSchedule, Error> {
+        let expression = match expression.trim() {
+            "@hourly" => "0 * * * *",
+            "@daily" => "0 0 * * *",
+            "@weekly" => "0 0 * * 0",
+            "@monthly" => "0 0 1 * *",
+            x => x
+        };
+
+        let fields: Vec<&str> = expression.split_whitespace().collect();
+        if fields.len() != 5 {
+            return Err(Error::Config(format!("bad cron expression `{}`: 5 fields expected", expression)));
+        }
+
+        // Sunday is both 0 and 7
+        let mut weekdays = parse_field(fields[4], 0, 7)?;
+        if weekdays.contains(&7) {
+            weekdays.retain(|x| *x != 7);
+            if !weekdays.contains(&0) {
+                weekdays.insert(0, 0);
+            }
+        }
+
+        Ok(CronSchedule {
+            minutes: parse_field(fields[0], 0, 59)?,
+            hours: parse_field(fields[1], 0, 23)?,
+            days: parse_field(fields[2], 1, 31)?,
+            months: parse_field(fields[3], 1, 12)?,
+            weekdays,
+            either_day: !fields[2].starts_with('*') && !fields[4].starts_with('*')
+        })
+    }
+
+    /// Check if a time matches the schedule, to the minute.
+    ///
+    /// # Arguments
+    ///
+    /// * `time` - Local time
+    ///
+    pub fn matches(&self, time: &NaiveDateTime) -> bool {
+        let day = self.days.contains(&time.day());
+        let weekday = self.weekdays.contains(&time.weekday().num_days_from_sunday());
+        let day_matches = if self.either_day { day || weekday } else { day && weekday };
+
+        self.minutes.contains(&time.minute()) && self.hours.contains(&time.hour())
+            && self.months.contains(&time.month()) && day_matches
+    }
+
+    /// Get the first matching minute after a time, within 5 years.
+    ///
+    /// # Arguments
+    ///
+    /// * `time` - Local time
+    ///
+    pub fn next_after(&self, time: &NaiveDateTime) -> Option<NaiveDateTime> {
+        let mut next = time.with_second(0)?.with_nanosecond(0)? + Duration::minutes(1);
+        let limit = next + Duration::days(5 * 366);
+
+        while next < limit {
+            if !self.months.contains(&next.month()) || !self.hours.contains(&next.hour()) {
+                // Skip to the next hour
+                next = next.with_minute(0)? + Duration::hours(1);
+                continue;
+            }
+            if self.matches(&next) {
+                return Some(next);
+            }
+            next += Duration::minutes(1);
+        }
+
+        None
+    }
+}
+
+/// Parse a cron field into its sorted values.
+///
+/// # Arguments
+///
+/// * `field` - Field
+/// * `min` - Smallest value
+/// * `max` - Largest value
+///
+fn parse_field(field: &str, min: u32, max: u32) -> Result<Vec<u32>, Error> {
+    let bad_field = |detail: &str| Error::Config(format!("bad cron field `{}`: {}", field, detail));
+    let parse_value = |x: &str| -> Result<u32, Error> {
+        match x.parse() {
+            Ok(value) if value >= min && value <= max => Ok(value),
+            _ => Err(bad_field(&format!("values go from {} to {}", min, max)))
+        }
+    };
+
+    let mut values = vec![];
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => match step.parse::<u32>() {
+                Ok(step) if step > 0 => (range, step),
+                _ => return Err(bad_field("bad step"))
+            },
+            None => (part, 1)
+        };
+        let (start, end) = match range {
+            "*" => (min, max),
+            _ => match range.split_once('-') {
+                Some((start, end)) => (parse_value(start)?, parse_value(end)?),
+                None if step > 1 => (parse_value(range)?, max),
+                None => (parse_value(range)?, parse_value(range)?)
+            }
+        };
+        if start > end {
+            return Err(bad_field("reversed range"));
+        }
+
+        values.extend((start..=end).step_by(step as usize));
+    }
+
+    values.sort_unstable();
+    values.dedup();
+    Ok(values)
+}
+
+impl Schedule {
+    /// Get the command line of the scheduled operation.
+    ///
+    /// # Arguments
+    ///
+    /// * `program` - pssh executable
+    ///
+    pub fn command_line(&self, program: &str) -> Vec<String> {
+        let mut words = vec![program.to_string()];
+        if let Some(ref config_file) = self.config_file {
+            words.push("-f".to_string());
+            words.push(config_file.clone());
+        }
+        words.extend(self.arguments.iter().cloned());
+        words
+    }
+
+    fn to_json_line(&self) -> String {
+        json!({"id": self.id, "cron": self.cron, "config": self.config_file, "arguments": self.arguments}).to_string()
+    }
+
+    fn from_json_line(line: &str) -> Option<Schedule> {
+        let value: Value = serde_json::from_str(line).ok()?;
+
+        Some(Schedule {
+            id: value["id"].as_u64()?,
+            cron: value["cron"].as_str()?.to_string(),
+            config_file: value["config"].as_str().map(String::from),
+            arguments: value["arguments"].as_array()?.iter().filter_map(|x| x.as_str().map(String::from)).collect()
+        })
+    }
+}
+
+/// Get the schedules path (`~/.pssh/state/schedules.jsonl`)
+pub fn schedules_path() -> PathBuf {
+    get_state_dir().join("schedules.jsonl")
+}
+
+/// Read schedules, skipping malformed lines.
+///
+/// # Arguments
+///
+/// * `path` - Schedules path
+///
+pub fn read_schedules(path: &Path) -> Vec<Schedule> {
+    match File::open(path) {
+        Ok(f) => BufReader::new(f).lines()
+            .map_while(Result::ok)
+            .filter_map(|x| Schedule::from_json_line(&x))
+            .collect(),
+        Err(_) => vec![]
+    }
+}
+
+fn write_schedules(path: &Path, schedules: &[Schedule]) -> Result<(), Error> {
+    let contents: String = schedules.iter().map(|x| format!("{}\n", x.to_json_line())).collect();
+    write_atomic(path, &contents).map_err(|e| Error::io(format!("could not write {}", path.display()), e))
+}
+
+/// Add a schedule, returning its identifier.
+///
+/// # Arguments
+///
+/// * `path` - Schedules path
+/// * `cron` - Cron expression
+/// * `config_file` - Configuration file
+/// * `arguments` - pssh arguments, starting with the operation
+///
+pub fn add_schedule(path: &Path, cron: &str, config_file: Option<&str>, arguments: &[String]) -> Result<u64, Error> {
+    CronSchedule::parse(cron)?;
+    match arguments.first() {
+        Some(operation) if SCHEDULED_OPERATIONS.contains(&operation.as_str()) => {},
+        _ => return Err(Error::Config(format!("scheduled operations are {}", SCHEDULED_OPERATIONS.join(", "))))
+    }
+
+    let _lock = StateLock::acquire(path).map_err(|e| Error::io(format!("could not lock {}", path.display()), e))?;
+    let mut schedules = read_schedules(path);
+    let id = schedules.iter().map(|x| x.id).max().unwrap_or(0) + 1;
+    schedules.push(Schedule { id, cron: cron.to_string(), config_file: config_file.map(String::from), arguments: arguments.to_vec() });
+    write_schedules(path, &schedules)?;
+
+    Ok(id)
+}
+
+/// Remove a schedule, returning whether it existed.
+///
+/// # Arguments
+///
+/// * `path` - Schedules path
+/// * `id` - Schedule identifier
+///
+pub fn remove_schedule(path: &Path, id: u64) -> Result<bool, Error> {
+    let _lock = StateLock::acquire(path).map_err(|e| Error::io(format!("could not lock {}", path.display()), e))?;
+    let mut schedules = read_schedules(path);
+    let count = schedules.len();
+    schedules.retain(|x| x.id != id);
+    if schedules.len() == count {
+        return Ok(false);
+    }
+
+    write_schedules(path, &schedules)?;
+    Ok(true)
+}
+
+/// Render schedules as crontab entries.
+///
+/// # Arguments
+///
+/// * `schedules` - Schedules
+/// * `program` - pssh executable
+///
+pub fn render_crontab(schedules: &[Schedule], program: &str) -> String {
+    schedules.iter()
+        .map(|x| {
+            // `%` ends crontab commands
+            let command = quote::join(&x.command_line(program)).replace('%', "\\%");
+            format!("# pssh schedule {}\n{} {}\n", x.id, x.cron, command)
+        })
+        .collect()
+}
+
+/// Start the schedules due at a time, in the background. Their output goes
+/// to the output of the current process.
+///
+/// # Arguments
+///
+/// * `path` - Schedules path
+/// * `time` - Local time
+/// * `program` - pssh executable
+///
+pub fn run_due(path: &Path, time: &NaiveDateTime, program: &str) {
+    for schedule in read_schedules(path) {
+        match CronSchedule::parse(&schedule.cron) {
+            Ok(ref cron) if cron.matches(time) => {},
+            Ok(_) => continue,
+            Err(error) => {
+                warn!("Schedule {}: {}", schedule.id, error);
+                continue;
+            }
+        }
+
+        let words = schedule.command_line(program);
+        info!("Schedule {}: running {}", schedule.id, quote::join(&words));
+        match Command::new(&words[0]).args(&words[1..]).stdin(Stdio::null()).spawn() {
+            Ok(mut child) => {
+                let id = schedule.id;
+                thread::spawn(move || match child.wait() {
+                    Ok(status) => info!("Schedule {}: {}", id, status),
+                    Err(error) => warn!("Schedule {}: {}", id, error)
+                });
+            },
+            Err(error) => warn!("Schedule {}: could not start pssh: {}", schedule.id, error)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::env;
+    use std::fs;
+
+    use chrono::NaiveDate;
+
+    fn time(day: u32, hour: u32, minute: u32) -> NaiveDateTime {
+        // 2024-01-01 is a Monday
+        NaiveDate::from_ymd_opt(2024, 1, day).unwrap().and_hms_opt(hour, minute, 0).unwrap()
+    }
+
+    #[test]
+    fn cron_expressions() {
+        let cron = CronSchedule::parse("*/15 9-17 * * 1-5").unwrap();
+        assert!(cron.matches(&time(1, 9, 45)));
+        assert!(!cron.matches(&time(1, 9, 50)));
+        assert!(!cron.matches(&time(6, 10, 0)));
+        assert_eq!(cron.next_after(&time(5, 17, 45)), Some(time(8, 9, 0)));
+
+        let cron = CronSchedule::parse("30 2 1,15 * 7").unwrap();
+        assert!(cron.matches(&time(7, 2, 30)));
+        assert!(cron.matches(&time(15, 2, 30)));
+        assert!(!cron.matches(&time(8, 2, 30)));
+
+        assert_eq!(CronSchedule::parse("@daily").unwrap(), CronSchedule::parse("0 0 * * *").unwrap());
+        assert_eq!(CronSchedule::parse("5/20 * * * *").unwrap().minutes, vec![5, 25, 45]);
+        assert!(CronSchedule::parse("* * * *").is_err());
+        assert!(CronSchedule::parse("60 * * * *").is_err());
+        assert!(CronSchedule::parse("5-1 * * * *").is_err());
+        assert!(CronSchedule::parse("*/0 * * * *").is_err());
+        assert_eq!(CronSchedule::parse("0 0 31 2 *").unwrap().next_after(&time(1, 0, 0)), None);
+    }
+
+    #[test]
+    fn schedules() {
+        let path = env::temp_dir().join(format!("pssh-schedules-{}.jsonl", std::process::id()));
+        let arguments: Vec<String> = ["exec", "web:*", "--yes", "--", "rm -rf /tmp/cache/%Y"].iter().map(|x| x.to_string()).collect();
+
+        assert_eq!(add_schedule(&path, "0 3 * * *", Some("/etc/pssh.yml"), &arguments).unwrap(), 1);
+        assert_eq!(add_schedule(&path, "@hourly", None, &arguments).unwrap(), 2);
+        assert!(add_schedule(&path, "0 3 * *", None, &arguments).is_err());
+        assert!(add_schedule(&path, "0 3 * * *", None, &["connect".to_string()]).is_err());
+
+        let schedules = read_schedules(&path);
+        assert_eq!(schedules.len(), 2);
+        assert_eq!(render_crontab(&schedules[..1], "/usr/bin/pssh"),
+            "# pssh schedule 1\n0 3 * * * /usr/bin/pssh -f /etc/pssh.yml exec 'web:*' --yes -- 'rm -rf /tmp/cache/\\%Y'\n");
+
+        assert!(remove_schedule(&path, 1).unwrap());
+        assert!(!remove_schedule(&path, 1).unwrap());
+        assert_eq!(read_schedules(&path)[0].id, 2);
+
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(path.with_extension("jsonl.lock")).ok();
+    }
+}
@@ -0,0 +1,252 @@
+//! Rhai scripting
+//!
+//! Scripts can generate machine entries when the configuration is loaded
+//! (files listed under the `generators` key), and define custom subcommands
+//! (`~/.pssh/scripts/<name>.rhai`).
+//!
+//! Both kinds of scripts can use `env(name)` to read environment variables.
+//! Subcommand scripts also get an `args` array, a `machines` map of resolved
+//! machines, and the `select(selector)`, `exec(machine, command)` and
+//! `exec(machine, command, timeout_seconds)` functions. Commands can hold
+//! machine placeholders, like `pssh exec`.
+
+use std::env;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use rhai::{Array, Dynamic, Engine, EvalAltResult, Map, Scope};
+
+use config::{get_user_configuration_dir, ConfigMap, MachineConfig, VpnConfig};
+use error::Error;
+use expand::expand_placeholders;
+use exec::{exec_command_with, ExecOptions};
+use selection::select_machines;
+
+/// Script file extension
+pub const SCRIPT_EXTENSION: &str = "rhai";
+
+/// Get the subcommand scripts directory (`~/.pssh/scripts`)
+pub fn get_scripts_dir() -> PathBuf {
+    get_user_configuration_dir().join("scripts")
+}
+
+/// Find the script defining a subcommand.
+///
+/// # Arguments
+///
+/// * `name` - Subcommand name
+///
+pub fn find_script(name: &str) -> Option<PathBuf> {
+    let path = get_scripts_dir().join(name).with_extension(SCRIPT_EXTENSION);
+    if path.is_file() { Some(path) } else { None }
+}
+
+/// Run a generator script, returning the machines it defines.
+///
+/// The script must evaluate to a map of machine names (`a:b:c`) to machine
+/// values, as found under `$` keys in the configuration file.
+///
+/// # Arguments
+///
+/// * `path` - Script path
+///
+pub fn generate_machines(path: &Path) -> Result<ConfigMap, Error> {
+    let engine = base_engine();
+    let result: Dynamic = engine.eval_file(path.to_path_buf()).map_err(|e| Error::Config(e.to_string()))?;
+    machines_from_dynamic(result).map_err(Error::Config)
+}
+
+/// Run a subcommand script.
+///
+/// Returns the script result as an exit code, when it evaluates to an integer.
+///
+/// # Arguments
+///
+/// * `path` - Script path
+/// * `args` - Subcommand arguments
+/// * `machines` - Resolved machines
+///
+pub fn run_script(path: &Path, args: &[&str], machines: &ConfigMap) -> Result<i32, Error> {
+    let mut engine = base_engine();
+
+    let selection_machines = machines.clone();
+    engine.register_fn("select", move |selector: &str| -> Result<Array, Box<EvalAltResult>> {
+        let names = select_machines(selector, &selection_machines).map_err(|e| e.to_string())?;
+        Ok(names.into_iter().map(Dynamic::from).collect())
+    });
+
+    let exec_machines = machines.clone();
+    engine.register_fn("exec", move |name: &str, command: &str| -> Result<Map, Box<EvalAltResult>> {
+        script_exec(&exec_machines, name, command, None)
+    });
+    let exec_machines = machines.clone();
+    engine.register_fn("exec", move |name: &str, command: &str, timeout: i64| -> Result<Map, Box<EvalAltResult>> {
+        script_exec(&exec_machines, name, command, Some(Duration::from_secs(timeout.max(1) as u64)))
+    });
+
+    let mut scope = Scope::new();
+    let args: Array = args.iter().map(|x| Dynamic::from(x.to_string())).collect();
+    scope.push("args", args);
+    scope.push("machines", machines_to_dynamic(machines));
+
+    let result: Dynamic = engine.eval_file_with_scope(&mut scope, path.to_path_buf()).map_err(|e| Error::Config(e.to_string()))?;
+    Ok(result.as_int().map(|x| x as i32).unwrap_or(0))
+}
+
+/// Run a command for a script, returning its status, outputs and whether it
+/// timed out
+fn script_exec(machines: &ConfigMap, name: &str, command: &str, timeout: Option<Duration>) -> Result<Map, Box<EvalAltResult>> {
+    let config = machines.get(name).ok_or_else(|| format!("unknown machine `{}`", name))?;
+    let result = expand_placeholders(command, name, config)
+        .and_then(|command| exec_command_with(config, &command, &ExecOptions { timeout, ..Default::default() }))
+        .map_err(|e| e.to_string())?;
+
+    let mut map = Map::new();
+    map.insert("status".into(), Dynamic::from(i64::from(result.status.unwrap_or(-1))));
+    map.insert("stdout".into(), Dynamic::from(result.stdout));
+    map.insert("stderr".into(), Dynamic::from(result.stderr));
+    map.insert("timed_out".into(), Dynamic::from(result.timed_out));
+    Ok(map)
+}
+
+fn base_engine() -> Engine {
+    let mut engine = Engine::new();
+    engine.register_fn("env", |name: &str| env::var(name).unwrap_or_default());
+    engine
+}
+
+/// Convert a script result to machines
+///
+/// # Arguments
+///
+/// * `value` - Script result
+///
+fn machines_from_dynamic(value: Dynamic) -> Result<ConfigMap, String> {
+    let entries = value.try_cast::<Map>().ok_or("a generator must return a map of machines")?;
+    let mut machines = ConfigMap::new();
+
+    for (name, values) in entries {
+        if name.is_empty() || name.split(':').any(|x| x.is_empty() || x == "$") {
+            return Err(format!("bad machine name `{}`", name));
+        }
+
+        let values = values.try_cast::<Map>().ok_or_else(|| format!("values of `{}` must be a map", name))?;
+        let string = |key: &str| values.get(key).and_then(|x| x.clone().into_string().ok());
+        // Ports and identities are given alone or as arrays
+        let list = |key: &str| values.get(key).map(|x| match x.clone().try_cast::<Array>() {
+            Some(items) => items,
+            None => vec![x.clone()]
+        });
+
+        machines.insert(name.to_string(), MachineConfig {
+            ip: string("ip"),
+            // IPs per location are only read from configuration files
+            location_ips: None,
+            port: list("port").map(|x| x.into_iter().filter_map(|x| x.as_int().ok()).map(|x| x as u16).collect()),
+            user: string("user"),
+            pass: string("pass"),
+            identity: list("identity").map(|x| x.into_iter().filter_map(|x| x.into_string().ok()).collect()),
+            via: list("via").map(|x| x.into_iter().filter_map(|x| x.into_string().ok()).collect()),
+            proxy_command: string("proxy_command"),
+            tags: values.get("tags").and_then(|x| x.clone().try_cast::<Array>()).map(|tags| {
+                tags.into_iter().filter_map(|x| x.into_string().ok()).collect()
+            }),
+            template: string("template"),
+            protected: values.get("protected").and_then(|x| x.as_bool().ok()),
+            restricted_command: string("restricted_command"),
+            strict: values.get("strict").and_then(|x| x.as_bool().ok()),
+            ciphers: string("ciphers"),
+            macs: string("macs"),
+            kex: string("kex"),
+            host_key_algorithms: string("host_key_algorithms"),
+            compat: string("compat"),
+            // Console access is only read from configuration files
+            console: None,
+            term: string("term"),
+            locale: string("locale"),
+            banner: string("banner"),
+            quiet: values.get("quiet").and_then(|x| x.as_bool().ok()),
+            auto_add_key: values.get("auto_add_key").and_then(|x| x.as_bool().ok()),
+            agent_socket: string("agent_socket"),
+            knock: list("knock").map(|x| x.into_iter().map(|x| x.to_string()).collect()),
+            knock_delay: string("knock_delay"),
+            vpn: string("vpn").map(|up| VpnConfig { up, check: None }),
+            expires: string("expires"),
+            // Like console access, expected prompts are only read from configuration files
+            expect: None,
+            preserve: values.get("preserve").and_then(|x| x.as_bool().ok()),
+            follow_symlinks: values.get("follow_symlinks").and_then(|x| x.as_bool().ok()),
+            priority: values.get("priority").and_then(|x| x.as_int().ok()),
+            role: string("role"),
+            owner: string("owner"),
+            contact: string("contact"),
+            on_push: string("on_push"),
+            vars: values.get("vars").and_then(|x| x.clone().try_cast::<Map>()).map(|vars| {
+                vars.into_iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+            })
+        });
+    }
+
+    Ok(machines)
+}
+
+fn machines_to_dynamic(machines: &ConfigMap) -> Map {
+    machines.iter().map(|(name, config)| {
+        let mut values = Map::new();
+        let mut insert = |key: &str, value: Option<String>| {
+            if let Some(value) = value {
+                values.insert(key.into(), Dynamic::from(value));
+            }
+        };
+
+        insert("ip", config.ip.clone());
+        insert("user", config.user.clone());
+        insert("identity", config.identities().first().cloned());
+        values.insert("port".into(), Dynamic::from(i64::from(config.first_port())));
+        let tags: Array = config.tags.clone().unwrap_or_default().into_iter().map(Dynamic::from).collect();
+        values.insert("tags".into(), Dynamic::from(tags));
+        let vars: Map = config.vars.clone().unwrap_or_default().into_iter().map(|(k, v)| (k.into(), Dynamic::from(v))).collect();
+        values.insert("vars".into(), Dynamic::from(vars));
+
+        (name.as_str().into(), Dynamic::from(values))
+    }).collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn generated_machines() {
+        let engine = base_engine();
+        let result: Dynamic = engine.eval(r#"
+            let machines = #{};
+            for i in 1..=3 {
+                machines["web:web" + i] = #{ ip: "10.0.0." + i, port: 2200 + i, tags: ["web"] };
+            }
+            machines
+        "#).unwrap();
+
+        let machines = machines_from_dynamic(result).unwrap();
+        assert_eq!(machines.len(), 3);
+        assert_eq!(machines["web:web2"].ip, Some("10.0.0.2".to_string()));
+        assert_eq!(machines["web:web2"].port, Some(vec![2202]));
+        assert_eq!(machines["web:web2"].tags, Some(vec!["web".to_string()]));
+
+        let result: Dynamic = engine.eval(r#"#{ "web::one": #{} }"#).unwrap();
+        assert!(machines_from_dynamic(result).is_err());
+        assert!(machines_from_dynamic(Dynamic::from(42_i64)).is_err());
+    }
+
+    #[test]
+    fn resolved_machines() {
+        let machines = hashmap!(
+            "web".to_string() => MachineConfig { ip: Some("10.0.0.1".to_string()), ..Default::default() }
+        );
+
+        let map = machines_to_dynamic(&machines);
+        let web = map["web"].clone().try_cast::<Map>().unwrap();
+        assert_eq!(web["port"].as_int().unwrap(), 22);
+        assert_eq!(web["ip"].clone().into_string().unwrap(), "10.0.0.1");
+    }
+}
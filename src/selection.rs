@@ -0,0 +1,513 @@
+//! Machine selection
+//!
+//! Machines are selected by name patterns, then optionally narrowed with
+//! filter expressions over their values, such as
+//! `tag=web and (user=deploy or not port=22)`. Selectors can hold
+//! `field=value` terms after the patterns, as `db:* role=replica`.
+//!
+//! Machines with the `primary` role are left out of batch operations unless
+//! the selector names them, or selects on `role`.
+
+use std::io::{self, BufRead};
+
+use config::{ConfigMap, MachineConfig};
+use error::Error;
+
+/// Selector reading patterns from stdin, one per line
+pub const STDIN_SELECTOR: &str = "-";
+
+/// Role of machines left out of batch operations unless explicitly selected
+pub const PRIMARY_ROLE: &str = "primary";
+
+/// Select machine names matching a selector.
+///
+/// A selector is a comma-separated list of patterns. Each pattern is either an
+/// exact machine name or a glob, where `*` matches any sequence of characters
+/// (including `:`) and `?` matches a single character.
+///
+/// The `-` selector reads patterns from stdin instead, one per line.
+///
+/// Terms containing `=` after the patterns, separated by spaces, are filter
+/// comparisons which all must match.
+///
+/// Returned names are sorted and deduplicated.
+///
+/// # Arguments
+///
+/// * `selector` - Selector string
+/// * `machines` - Machine configuration map
+///
+pub fn select_machines(selector: &str, machines: &ConfigMap) -> Result<Vec<String>, Error> {
+    let (patterns, terms) = split_selector(selector);
+    let patterns: Vec<String> = if patterns == [STDIN_SELECTOR] {
+        let stdin = io::stdin();
+        let mut input = stdin.lock();
+        read_patterns(&mut input)
+    } else {
+        patterns
+    };
+
+    let mut names: Vec<String> = machines.keys()
+        .filter(|name| patterns.iter().any(|pattern| glob_match(pattern, name)))
+        .cloned()
+        .collect();
+
+    if !terms.is_empty() {
+        let filter = Filter::parse(&terms.join(" and "))
+            .map_err(|e| Error::Selection(format!("bad selector `{}`: {}", selector, e)))?;
+        names.retain(|name| filter.matches(name, &machines[name]));
+    }
+
+    names.sort();
+    Ok(names)
+}
+
+/// Split a selector into its name patterns and its `field=value` terms.
+///
+/// # Arguments
+///
+/// * `selector` - Selector string
+///
+pub fn split_selector(selector: &str) -> (Vec<String>, Vec<String>) {
+    let (terms, patterns): (Vec<&str>, Vec<&str>) = selector.split_whitespace().partition(|x| x.contains('='));
+    let patterns = patterns.join(",").split(',')
+        .filter(|x| !x.is_empty())
+        .map(String::from)
+        .collect();
+
+    (patterns, terms.into_iter().map(String::from).collect())
+}
+
+/// Split the primary machines out of a batch selection, unless the selector
+/// names them or has a `role` term. Returns the kept and the excluded
+/// machines.
+///
+/// # Arguments
+///
+/// * `selector` - Selector string
+/// * `names` - Selected machine names
+/// * `machines` - Machine configuration map
+///
+pub fn exclude_primaries(selector: &str, names: Vec<String>, machines: &ConfigMap) -> (Vec<String>, Vec<String>) {
+    let (patterns, terms) = split_selector(selector);
+    if terms.iter().any(|x| x.starts_with("role=") || x.starts_with("role!=")) {
+        return (names, vec![]);
+    }
+
+    names.into_iter().partition(|name| {
+        machines.get(name).and_then(|x| x.role.as_deref()) != Some(PRIMARY_ROLE) || patterns.contains(name)
+    })
+}
+
+/// Check if a machine is under a namespace prefix.
+///
+/// `prod` and `prod:` both match `prod` and all machines under `prod:`.
+///
+/// # Arguments
+///
+/// * `prefix` - Namespace prefix
+/// * `name` - Machine name
+///
+pub fn prefix_match(prefix: &str, name: &str) -> bool {
+    let prefix = prefix.trim_end_matches(':');
+    prefix.is_empty() || name == prefix || (name.starts_with(prefix) && name[prefix.len()..].starts_with(':'))
+}
+
+/// Collapse a machine name to its first `depth` levels.
+///
+/// Returns `None` when the name is not deeper than `depth`.
+///
+/// # Arguments
+///
+/// * `name` - Machine name
+/// * `depth` - Number of kept levels
+///
+pub fn collapse_name(name: &str, depth: usize) -> Option<String> {
+    let levels: Vec<&str> = name.split(':').collect();
+    if depth == 0 || levels.len() <= depth {
+        None
+    } else {
+        Some(format!("{}:", levels[..depth].join(":")))
+    }
+}
+
+/// Filter expression over machine values
+#[derive(Debug, Clone, PartialEq)]
+pub enum Filter {
+    /// `field=glob` (or `field!=glob` when negated)
+    Compare(String, String, bool),
+    /// Both filters match
+    And(Box<Filter>, Box<Filter>),
+    /// Any filter matches
+    Or(Box<Filter>, Box<Filter>),
+    /// The filter does not match
+    Not(Box<Filter>)
+}
+
+/// Fields usable in filter expressions
+const FILTER_FIELDS: &[&str] = &["name", "ip", "port", "user", "identity", "tag", "template", "protected", "strict", "role"];
+
+impl Filter {
+    /// Parse a filter expression.
+    ///
+    /// Comparisons are `field=value` or `field!=value`, where the value is a
+    /// glob pattern; they can be combined with `and`, `or`, `not` and
+    /// parentheses. `tag=value` matches machines having any matching tag.
+    ///
+    /// # Arguments
+    ///
+    /// * `expression` - Filter expression
+    ///
+    pub fn parse(expression: &str) -> Result<Filter, Error> {
+        let tokens = tokenize(expression).map_err(Error::Selection)?;
+        let mut position = 0;
+        let filter = parse_or(&tokens, &mut position).map_err(Error::Selection)?;
+
+        match tokens.get(position) {
+            None => Ok(filter),
+            Some(token) => Err(Error::Selection(format!("unexpected `{}` in filter", token)))
+        }
+    }
+
+    /// Check if a machine matches the filter.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - Machine name
+    /// * `config` - Machine configuration
+    ///
+    pub fn matches(&self, name: &str, config: &MachineConfig) -> bool {
+        match *self {
+            Filter::Compare(ref field, ref pattern, negated) => {
+                let values: Vec<String> = match field.as_str() {
+                    "name" => vec![name.to_string()],
+                    "ip" => config.ip.iter().cloned().collect(),
+                    "port" => config.ports().iter().map(|x| x.to_string()).collect(),
+                    "user" => config.user.iter().cloned().collect(),
+                    "identity" => config.identities().to_vec(),
+                    "tag" => config.tags.clone().unwrap_or_default(),
+                    "template" => config.template.iter().cloned().collect(),
+                    "protected" => vec![config.is_protected().to_string()],
+                    "strict" => vec![config.is_strict().to_string()],
+                    "role" => config.role.iter().cloned().collect(),
+                    _ => vec![]
+                };
+
+                values.iter().any(|value| glob_match(pattern, value)) != negated
+            },
+            Filter::And(ref a, ref b) => a.matches(name, config) && b.matches(name, config),
+            Filter::Or(ref a, ref b) => a.matches(name, config) || b.matches(name, config),
+            Filter::Not(ref a) => !a.matches(name, config)
+        }
+    }
+}
+
+/// Keep the machines matching a filter.
+///
+/// # Arguments
+///
+/// * `names` - Machine names
+/// * `filter` - Filter
+/// * `machines` - Machine configuration map
+///
+pub fn filter_machines(names: Vec<String>, filter: &Filter, machines: &ConfigMap) -> Vec<String> {
+    names.into_iter()
+        .filter(|name| machines.get(name).is_some_and(|config| filter.matches(name, config)))
+        .collect()
+}
+
+fn tokenize(expression: &str) -> Result<Vec<String>, String> {
+    let mut tokens = vec![];
+    let mut current = String::new();
+    let mut quoted = false;
+
+    for c in expression.chars() {
+        match c {
+            '"' => quoted = !quoted,
+            _ if quoted => current.push(c),
+            '(' | ')' => {
+                if !current.is_empty() {
+                    tokens.push(current.clone());
+                    current.clear();
+                }
+                tokens.push(c.to_string());
+            },
+            _ if c.is_whitespace() => {
+                if !current.is_empty() {
+                    tokens.push(current.clone());
+                    current.clear();
+                }
+            },
+            _ => current.push(c)
+        }
+    }
+
+    if quoted {
+        return Err("unterminated quote in filter".to_string());
+    }
+    if !current.is_empty() {
+        tokens.push(current);
+    }
+    Ok(tokens)
+}
+
+fn parse_or(tokens: &[String], position: &mut usize) -> Result<Filter, String> {
+    let mut filter = parse_and(tokens, position)?;
+    while tokens.get(*position).map(|x| x.as_str()) == Some("or") {
+        *position += 1;
+        filter = Filter::Or(Box::new(filter), Box::new(parse_and(tokens, position)?));
+    }
+    Ok(filter)
+}
+
+fn parse_and(tokens: &[String], position: &mut usize) -> Result<Filter, String> {
+    let mut filter = parse_not(tokens, position)?;
+    while tokens.get(*position).map(|x| x.as_str()) == Some("and") {
+        *position += 1;
+        filter = Filter::And(Box::new(filter), Box::new(parse_not(tokens, position)?));
+    }
+    Ok(filter)
+}
+
+fn parse_not(tokens: &[String], position: &mut usize) -> Result<Filter, String> {
+    let token = tokens.get(*position).ok_or("incomplete filter")?;
+    *position += 1;
+
+    match token.as_str() {
+        "not" => Ok(Filter::Not(Box::new(parse_not(tokens, position)?))),
+        "(" => {
+            let filter = parse_or(tokens, position)?;
+            if tokens.get(*position).map(|x| x.as_str()) != Some(")") {
+                return Err("missing `)` in filter".to_string());
+            }
+            *position += 1;
+            Ok(filter)
+        },
+        _ => parse_comparison(token)
+    }
+}
+
+fn parse_comparison(token: &str) -> Result<Filter, String> {
+    let index = token.find('=').ok_or_else(|| format!("expected `field=value`, got `{}`", token))?;
+    let (field, negated) = match token[..index].strip_suffix('!') {
+        Some(field) => (field, true),
+        None => (&token[..index], false)
+    };
+
+    if !FILTER_FIELDS.contains(&field) {
+        return Err(format!("unknown filter field `{}` (expected one of: {})", field, FILTER_FIELDS.join(", ")));
+    }
+    Ok(Filter::Compare(field.to_string(), token[index + 1..].to_string(), negated))
+}
+
+/// Read patterns from a reader, one per line.
+///
+/// Empty lines and `#` comments are skipped.
+///
+/// # Arguments
+///
+/// * `input` - Pattern source
+///
+pub fn read_patterns<R: BufRead>(input: &mut R) -> Vec<String> {
+    input.lines()
+        .map_while(Result::ok)
+        .map(|x| x.trim().to_string())
+        .filter(|x| !x.is_empty() && !x.starts_with('#'))
+        .collect()
+}
+
+/// Split selected machines into canaries, run first, and the remaining
+/// machines.
+///
+/// Canaries are given as a percentage of the selection (`10%`, at least one
+/// machine), or as comma-separated names or globs among the selection.
+///
+/// # Arguments
+///
+/// * `names` - Selected machine names
+/// * `canary` - Canary specification
+///
+pub fn split_canaries(names: &[String], canary: &str) -> Result<(Vec<String>, Vec<String>), Error> {
+    let canaries: Vec<String> = match canary.strip_suffix('%') {
+        Some(percentage) => {
+            let percentage: usize = match percentage.parse() {
+                Ok(x) if x > 0 && x <= 100 => x,
+                _ => return Err(Error::Selection(format!("bad canary percentage `{}`", canary)))
+            };
+            let count = (names.len() * percentage).div_ceil(100).max(1).min(names.len());
+            names[..count].to_vec()
+        },
+        None => {
+            let patterns: Vec<&str> = canary.split(',').map(|x| x.trim()).collect();
+            let canaries: Vec<String> = names.iter()
+                .filter(|name| patterns.iter().any(|x| glob_match(x, name)))
+                .cloned()
+                .collect();
+            if canaries.is_empty() {
+                return Err(Error::Selection(format!("canary `{}` matches no selected machine", canary)));
+            }
+            canaries
+        }
+    };
+
+    let remaining = names.iter().filter(|x| !canaries.contains(x)).cloned().collect();
+    Ok((canaries, remaining))
+}
+
+/// Match a name against a glob pattern
+///
+/// # Arguments
+///
+/// * `pattern` - Glob pattern
+/// * `name` - Name to match
+///
+pub fn glob_match(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+
+    let (mut p, mut n) = (0, 0);
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while n < name.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == name[n]) {
+            p += 1;
+            n += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            backtrack = Some((p, n));
+            p += 1;
+        } else if let Some((star_p, star_n)) = backtrack {
+            p = star_p + 1;
+            n = star_n + 1;
+            backtrack = Some((star_p, star_n + 1));
+        } else {
+            return false;
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use config::MachineConfig;
+
+    #[test]
+    fn globs() {
+        assert!(glob_match("prod:web1", "prod:web1"));
+        assert!(glob_match("prod:*", "prod:web1"));
+        assert!(glob_match("prod:*", "prod:db:one"));
+        assert!(glob_match("*:web?", "staging:web2"));
+        assert!(glob_match("*", "localhost"));
+        assert!(!glob_match("prod:*", "staging:web1"));
+        assert!(!glob_match("prod:web?", "prod:web10"));
+        assert!(!glob_match("prod", "prod:web1"));
+    }
+
+    #[test]
+    fn selection() {
+        let machines = hashmap!(
+            "localhost".to_string() => MachineConfig::default(),
+            "prod:web1".to_string() => MachineConfig::default(),
+            "prod:web2".to_string() => MachineConfig::default(),
+            "prod:db".to_string() => MachineConfig::default()
+        );
+
+        assert_eq!(select_machines("prod:web*", &machines).unwrap(), vec!["prod:web1", "prod:web2"]);
+        assert_eq!(select_machines("localhost, prod:db", &machines).unwrap(), vec!["localhost", "prod:db"]);
+        assert_eq!(select_machines("prod:*,prod:db", &machines).unwrap().len(), 3);
+        assert!(select_machines("staging:*", &machines).unwrap().is_empty());
+    }
+
+    #[test]
+    fn canaries() {
+        let names: Vec<String> = (1..=12).map(|x| format!("web{}", x)).collect();
+
+        let (canaries, remaining) = split_canaries(&names, "10%").unwrap();
+        assert_eq!(canaries, vec!["web1", "web2"]);
+        assert_eq!(remaining.len(), 10);
+        assert_eq!(split_canaries(&names[..3], "1%").unwrap().0, vec!["web1"]);
+        assert_eq!(split_canaries(&names, "100%").unwrap().1.len(), 0);
+
+        let (canaries, remaining) = split_canaries(&names, "web5,web1?").unwrap();
+        assert_eq!(canaries, vec!["web5", "web10", "web11", "web12"]);
+        assert_eq!(remaining[0], "web1");
+
+        assert!(split_canaries(&names, "db1").is_err());
+        assert!(split_canaries(&names, "0%").is_err());
+        assert!(split_canaries(&names, "x%").is_err());
+    }
+
+    #[test]
+    fn prefixes() {
+        assert!(prefix_match("prod:", "prod:web1"));
+        assert!(prefix_match("prod", "prod:web1"));
+        assert!(prefix_match("prod", "prod"));
+        assert!(!prefix_match("prod", "production:web1"));
+        assert!(prefix_match("", "localhost"));
+
+        assert_eq!(collapse_name("prod:eu:web1", 1), Some("prod:".to_string()));
+        assert_eq!(collapse_name("prod:eu:web1", 2), Some("prod:eu:".to_string()));
+        assert_eq!(collapse_name("prod:eu:web1", 3), None);
+        assert_eq!(collapse_name("localhost", 1), None);
+    }
+
+    #[test]
+    fn filters() {
+        let web = MachineConfig {
+            user: Some("deploy".to_string()),
+            tags: Some(vec!["web".to_string(), "region=fra1".to_string()]),
+            ..Default::default()
+        };
+        let db = MachineConfig {
+            user: Some("postgres".to_string()),
+            port: Some(vec![2222]),
+            ..Default::default()
+        };
+
+        let filter = Filter::parse("tag=web and user=deploy").unwrap();
+        assert!(filter.matches("prod:web1", &web));
+        assert!(!filter.matches("prod:db", &db));
+
+        let filter = Filter::parse("not port=22 or tag=region=fra*").unwrap();
+        assert!(filter.matches("prod:web1", &web));
+        assert!(filter.matches("prod:db", &db));
+
+        let filter = Filter::parse("(name=prod:* and user!=deploy) or tag=\"x y\"").unwrap();
+        assert!(!filter.matches("prod:web1", &web));
+        assert!(filter.matches("prod:db", &db));
+
+        assert!(Filter::parse("tag=web and").is_err());
+        assert!(Filter::parse("(tag=web").is_err());
+        assert!(Filter::parse("color=red").is_err());
+        assert!(Filter::parse("web").is_err());
+        assert!(Filter::parse("tag=web user=deploy").is_err());
+    }
+
+    #[test]
+    fn roles() {
+        let role = |role: &str| MachineConfig { role: Some(role.to_string()), ..Default::default() };
+        let machines: ConfigMap = vec![
+            ("db:one".to_string(), role("primary")),
+            ("db:two".to_string(), role("replica")),
+            ("db:three".to_string(), role("replica")),
+            ("web".to_string(), MachineConfig::default())
+        ].into_iter().collect();
+
+        assert_eq!(select_machines("db:* role=replica", &machines).unwrap(), vec!["db:three", "db:two"]);
+        assert_eq!(select_machines("db:*, web role!=replica", &machines).unwrap(), vec!["db:one", "web"]);
+        assert_eq!(select_machines("db:* color=red", &machines).unwrap_err().exit_code(), 3);
+        assert_eq!(split_selector("a,b  c tag=x"), (vec!["a".to_string(), "b".to_string(), "c".to_string()], vec!["tag=x".to_string()]));
+
+        let names = select_machines("*", &machines).unwrap();
+        assert_eq!(exclude_primaries("*", names.clone(), &machines).1, vec!["db:one"]);
+        assert!(exclude_primaries("db:one,web", names.clone(), &machines).1.is_empty());
+        assert!(exclude_primaries("* role=primary", names, &machines).1.is_empty());
+    }
+
+    #[test]
+    fn patterns() {
+        let mut input = "prod:web1\n\n  # comment\nprod:db  \n".as_bytes();
+        assert_eq!(read_patterns(&mut input), vec!["prod:web1", "prod:db"]);
+    }
+}
@@ -1,18 +1,88 @@
 //! Shell
 
+use std::collections::HashMap;
 use std::env;
-use std::io;
+use std::fs::{self, File};
+use std::io::{self, IsTerminal, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{self, Command, ExitStatus};
+use std::thread;
+use std::time::{Duration, Instant};
 
 use log;
-use chrono;
+use chrono::{self, Local, NaiveDate, Utc};
 use fern;
+use serde_json;
 
-use clap::{Arg, SubCommand, App};
+use ansi_term::Colour;
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
 
-use config::load_configuration_file;
-use wrapper::{ping, ssh, scp, execute, ScpDirection};
+use config::{enable_strict_mode, environment_fallbacks, get_configuration_path, get_user_configuration_dir, load_configuration_file, load_configuration_string, ConfigMap, ConfigResult, Settings};
+use wrapper::{console, copy, vscode, ssh, ssh_remote, ssh_restricted, ssh_tty, execute, execute_command, execute_pipe, CommandSpec, CopyOptions, ScpDirection, TOP_COMMAND};
+use speedtest::speedtest;
+use selection::{collapse_name, exclude_primaries, filter_machines, prefix_match, select_machines, split_canaries, Filter};
+use batch::{order_machines, parse_duration, run_in_waves, run_parallel, FailurePolicy, MachineOrder, DEFAULT_PARALLELISM};
+use report::{gather, sort_rows, render_table, to_json, ReportSort};
+use power::{execute_power_action, PowerAction, PowerOutcome};
+use prompt::{ask, confirm};
+use update::{update_packages, UpdateOutcome, UpdateResult};
+use state::{format_age, format_time, StateStore};
+use discover::{browse_mdns, local_subnet, merge_hosts, parse_subnet, scan};
+use editor::ConfigEditor;
+use cloud::{fetch_machines, machines_to_yaml, Provider, SyncOptions};
+use config::MachineConfig;
+use api::{self, ServerOptions, DEFAULT_LISTEN};
+use atomic::{push_atomic, AtomicOptions};
+use audit::{self, filter_entries, parse_since, read_entries, audit_log_path};
+use backup::{archive_path, backup_path, restore_archive, rotate_archives};
+use error::Error;
+use expand::expand_placeholders;
+use expect::run_expect;
+use glob::{check_remote, expand_local};
+use endpoint::{parse_endpoint, relay, source_machine, transfer_commands, Endpoint};
+use ignore::exclude_patterns;
+use help::{find_topic, page, render_topics, subcommands};
+use exec::{exec_command, exec_command_with, save_output, ExecInput, ExecOptions, ExecResult};
+use jobs::{find_job, jobs_path, read_jobs, record_job, Job, JobOperation, MachineResult};
+use location::{locate, Surroundings};
+use vpn::{bring_up, is_reachable, vpn_is_up};
+use notify::{notify, NotifyMode};
+use webhook::{post_summary, JobSummary};
+use plugin::{find_plugin, plugin_command, PLUGIN_PREFIX};
+use schedule::{add_schedule, read_schedules, remove_schedule, render_crontab, schedules_path, CronSchedule};
+use script::{find_script, run_script};
+use quote;
+use repo;
+use disk::{format_size, gather_disk_usage};
+use complete::{complete_remote_paths, FISH_SCRIPT, ZSH_SCRIPT};
+use consensus::{group_outputs, render_groups};
+use compare::{audit_file, local_digest, remote_digest, render_audit, render_versions, AuditProbe};
+use collect::{collect_destination, collect_path, unused_path};
+use check::{check_machine, detect_changes, render_change, render_checks, render_oneline, run_hook, Check, CHECK_TIMEOUT, CLEAR_SCREEN, DEFAULT_INTERVAL as CHECK_INTERVAL};
+use clip::{ClipTarget, DEFAULT_CLIP_FILE};
+use process::{kill_processes, list_processes, match_processes, parse_signal, render_processes, DEFAULT_SIGNAL};
+use summary::Summary;
+use probe::{probe_machines, Reachability};
+use ping::{ping_machine, render_pings, DEFAULT_COUNT as DEFAULT_PING_COUNT};
+use diff::diff_machines;
+use control::{self, WarmOutcome, DEFAULT_PERSIST};
+use daemon;
+use migrate::CONFIG_VERSION;
+use title::{render_title, set_title, RESTORE_TITLE};
+use names::{check_names, sanitize_key, validate_key, validate_name};
+use exporter::{self, DEFAULT_INTERVAL as EXPORTER_INTERVAL, DEFAULT_LISTEN as EXPORTER_LISTEN};
+use export::{export_rows, git_url, host_alias, install_ssh_config, needs_ssh_config, render_csv, render_graph, render_ssh_config, GraphFormat, DEFAULT_EXPORT_FIELDS};
+use envdiff::{diff_lines, render_side_by_side, DEFAULT_COMMAND as ENVDIFF_COMMAND, DEFAULT_WIDTH as ENVDIFF_WIDTH};
+use init::{parse_ssh_config, starter_config, SSH_CONFIG_NAMESPACE};
 
-const VERSION: &str = "1.0.0";
+/// pssh version
+pub const VERSION: &str = "1.0.0";
+
+/// State changes shown by `check --watch`
+const CHECK_HISTORY: usize = 10;
+
+/// Interval between configuration checks of `export ssh-config --watch`
+const EXPORT_WATCH_INTERVAL: Duration = Duration::from_secs(1);
 
 /// Initialize logger
 ///
@@ -39,12 +109,578 @@ fn init_logger(level: log::LogLevelFilter) -> Result<(), log::SetLoggerError> {
         .apply()
 }
 
-/// Initialize shell
-pub fn init_shell() {
-    let mut app = App::new("pssh")
+/// Build a clipboard subcommand
+///
+/// # Arguments
+///
+/// * `name` - Subcommand name
+/// * `about` - Subcommand description
+///
+fn clip_subcommand(name: &'static str, about: &'static str) -> App<'static, 'static> {
+    SubCommand::with_name(name)
+        .about(about)
+        .arg(Arg::with_name("machine")
+            .value_name("MACHINE")
+            .help("machine name")
+            .required(true)
+            .takes_value(true))
+        .arg(Arg::with_name("file")
+            .value_name("PATH")
+            .long("file")
+            .help("remote clipboard file")
+            .default_value(DEFAULT_CLIP_FILE)
+            .takes_value(true))
+        .arg(Arg::with_name("x11")
+            .long("x11")
+            .help("use the remote X11 clipboard, through xclip")
+            .conflicts_with("file"))
+}
+
+/// Build an inventory export subcommand
+fn inventory_subcommand(name: &'static str, about: &'static str) -> App<'static, 'static> {
+    SubCommand::with_name(name)
+        .about(about)
+        .arg(Arg::with_name("selector")
+            .value_name("SELECTOR")
+            .help("machine names or glob patterns, comma-separated, all machines by default")
+            .takes_value(true))
+        .arg(Arg::with_name("fields")
+            .long("fields")
+            .value_name("FIELDS")
+            .help("exported fields, comma-separated, among name, ip, port, user, identity, tags, template, protected, strict, compat, banner, role and machine variables")
+            .default_value(DEFAULT_EXPORT_FIELDS)
+            .takes_value(true))
+}
+
+/// Build a power action subcommand
+///
+/// # Arguments
+///
+/// * `name` - Subcommand name
+/// * `about` - Subcommand description
+///
+fn power_subcommand(name: &'static str, about: &'static str) -> App<'static, 'static> {
+    SubCommand::with_name(name)
+        .about(about)
+        .arg(Arg::with_name("selector")
+            .value_name("SELECTOR")
+            .help("machine names or glob patterns, comma-separated")
+            .required(true)
+            .takes_value(true))
+        .arg(Arg::with_name("wait")
+            .long("wait")
+            .short("w")
+            .help("wait until machines are back online (or down, for shutdown)"))
+        .arg(Arg::with_name("rolling")
+            .value_name("COUNT")
+            .long("rolling")
+            .short("r")
+            .help("maximum number of machines processed at once")
+            .takes_value(true))
+        .args(&failure_args())
+            .args(&wave_args())
+        .arg(yes_arg())
+}
+
+fn failure_args() -> Vec<Arg<'static, 'static>> {
+    vec![
+        Arg::with_name("fail-fast")
+            .long("fail-fast")
+            .help("start no more machines after the first failure")
+            .conflicts_with_all(&["max-failures", "continue-on-error"]),
+        Arg::with_name("max-failures")
+            .value_name("COUNT")
+            .long("max-failures")
+            .help("start no more machines after COUNT failures")
+            .takes_value(true)
+            .conflicts_with("continue-on-error"),
+        Arg::with_name("continue-on-error")
+            .long("continue-on-error")
+            .help("process all machines whatever the failures (ignores the `max_failures` setting)")
+    ]
+}
+
+/// Get the failure policy of a batch run, from the command line or from the
+/// `max_failures` setting.
+///
+/// # Arguments
+///
+/// * `args` - Command arguments
+/// * `settings` - Global settings
+///
+fn failure_policy(args: &ArgMatches, settings: &Settings) -> Option<FailurePolicy> {
+    if args.is_present("continue-on-error") {
+        return Some(FailurePolicy::Continue);
+    }
+    if args.is_present("fail-fast") {
+        return Some(FailurePolicy::MaxFailures(1));
+    }
+
+    match args.value_of("max-failures").map(|x| x.parse::<usize>()) {
+        Some(Ok(x)) if x > 0 => Some(FailurePolicy::MaxFailures(x)),
+        Some(_) => {
+            println!("Bad failure count: `{}`.", args.value_of("max-failures").unwrap());
+            None
+        },
+        None => Some(settings.max_failures.map(FailurePolicy::MaxFailures).unwrap_or(FailurePolicy::Continue))
+    }
+}
+
+fn wave_args() -> Vec<Arg<'static, 'static>> {
+    vec![
+        Arg::with_name("batch-size")
+            .value_name("COUNT")
+            .long("batch-size")
+            .help("process machines in waves of COUNT machines")
+            .takes_value(true),
+        Arg::with_name("batch-delay")
+            .value_name("DURATION")
+            .long("batch-delay")
+            .help("delay between waves (`30s`, `2m`...)")
+            .requires("batch-size")
+            .takes_value(true),
+        Arg::with_name("order")
+            .value_name("ORDER")
+            .long("order")
+            .help("order of the machines: by `priority` (lower first, the default), `name` or `random`")
+            .possible_values(&["priority", "name", "random"])
+            .default_value("priority")
+            .takes_value(true)
+    ]
+}
+
+/// Leave the primary machines out of a batch run, unless the selector names
+/// them or selects on `role`.
+///
+/// # Arguments
+///
+/// * `selector` - Selector string
+/// * `config_content` - Loaded configuration
+/// * `machine_names` - Selected machines
+///
+fn exclude_primaries_or_report(selector: &str, config_content: &ConfigResult, machine_names: Vec<String>) -> Vec<String> {
+    let (kept, excluded) = exclude_primaries(selector, machine_names, &config_content.machine_values);
+    if !excluded.is_empty() {
+        println!("Leaving out primary machines: {} (name them, or add `role=primary` to the selector).", excluded.join(", "));
+    }
+
+    kept
+}
+
+/// Leave out the machines in maintenance, telling which ones.
+///
+/// # Arguments
+///
+/// * `machine_names` - Selected machines
+///
+fn exclude_maintenance_or_report(machine_names: Vec<String>) -> Vec<String> {
+    let state = StateStore::load_default();
+    let (excluded, kept): (Vec<String>, Vec<String>) = machine_names.into_iter().partition(|x| state.get(x).in_maintenance());
+    if !excluded.is_empty() {
+        eprintln!("Skipping machines in maintenance: {}.", excluded.join(", "));
+    }
+
+    kept
+}
+
+/// Order the selected machines of a batch run, as given by `--order`.
+///
+/// # Arguments
+///
+/// * `args` - Command arguments
+/// * `config_content` - Loaded configuration
+/// * `machine_names` - Selected machines
+///
+fn order_selection(args: &ArgMatches, config_content: &ConfigResult, machine_names: Vec<String>) -> Vec<String> {
+    let order = MachineOrder::from_name(args.value_of("order").unwrap_or("priority")).unwrap_or(MachineOrder::Priority);
+    order_machines(&machine_names, &config_content.machine_values, order)
+}
+
+/// Get the wave size and delay of a batch run.
+///
+/// # Arguments
+///
+/// * `args` - Command arguments
+///
+fn wave_values(args: &ArgMatches) -> Option<(usize, Duration)> {
+    let size = match args.value_of("batch-size").map(|x| x.parse::<usize>()) {
+        None => 0,
+        Some(Ok(x)) if x > 0 => x,
+        Some(_) => {
+            println!("Bad batch size: `{}`.", args.value_of("batch-size").unwrap());
+            return None;
+        }
+    };
+    let delay = match args.value_of("batch-delay").map(parse_duration) {
+        None => Duration::from_secs(0),
+        Some(Some(x)) => x,
+        Some(None) => {
+            println!("Bad batch delay: `{}`.", args.value_of("batch-delay").unwrap());
+            return None;
+        }
+    };
+
+    Some((size, delay))
+}
+
+/// Print the machines skipped by a batch run, and tell their count.
+///
+/// # Arguments
+///
+/// * `names` - Skipped machine names
+///
+fn report_skipped(names: &[String]) -> usize {
+    if !names.is_empty() {
+        println!("Skipped {} machines: {}", names.len(), names.join(", "));
+    }
+    names.len()
+}
+
+fn force_arg() -> Arg<'static, 'static> {
+    Arg::with_name("force")
+        .long("force")
+        .help("allow the operation on protected machines")
+}
+
+/// Refuse an operation on protected machines, unless forced.
+///
+/// # Arguments
+///
+/// * `config_content` - Configuration
+/// * `machine_names` - Target machines
+/// * `force` - Allow the operation
+///
+fn check_protected(config_content: &ConfigResult, machine_names: &[String], force: bool) -> bool {
+    let protected: Vec<&str> = machine_names.iter()
+        .filter(|x| config_content.machine_values[*x].is_protected())
+        .map(|x| x.as_str())
+        .collect();
+
+    if force || protected.is_empty() {
+        return true;
+    }
+
+    println!("Protected machines: {}. Use --force to proceed.", protected.join(", "));
+    false
+}
+
+fn notify_arg() -> Arg<'static, 'static> {
+    Arg::with_name("notify")
+        .long("notify")
+        .help("notify when the operation finishes (desktop notification and bell)")
+}
+
+/// Notify the end of an operation, when forced or when it took longer than
+/// the configured duration.
+///
+/// # Arguments
+///
+/// * `settings` - Global settings
+/// * `forced` - Notify whatever the duration (`--notify`)
+/// * `started` - Operation start
+/// * `title` - Notification title
+/// * `message` - Notification body
+/// * `success` - Operation status
+///
+fn notify_done(settings: &Settings, forced: bool, started: Instant, title: &str, message: &str, success: bool) {
+    let mode = if forced && settings.notify == NotifyMode::Off {
+        NotifyMode::Both
+    } else {
+        settings.notify
+    };
+
+    if forced || started.elapsed() >= Duration::from_secs(settings.notify_after) {
+        notify(mode, title, message, success);
+    }
+}
+
+/// Print an error, then exit with the code of its kind
+///
+/// # Arguments
+///
+/// * `error` - Error
+///
+fn exit_with(error: &Error) -> ! {
+    match *error {
+        Error::Names(ref errors) => {
+            for name_error in errors {
+                eprintln!("{}", name_error);
+            }
+            eprintln!("Run `pssh lint --fix-names` for suggested names.");
+        },
+        _ => eprintln!("{}", error)
+    }
+    process::exit(error.exit_code());
+}
+
+/// Load a configuration file, exiting when it cannot be loaded
+///
+/// # Arguments
+///
+/// * `config_file` - Configuration file path (optional)
+///
+fn load_configuration_or_exit(config_file: Option<&str>) -> ConfigResult {
+    load_configuration_file(config_file).unwrap_or_else(|error| exit_with(&error))
+}
+
+/// Execute a command, exiting when it cannot be run
+///
+/// # Arguments
+///
+/// * `command` - Command to execute
+///
+fn execute_or_exit(command: CommandSpec) -> ExitStatus {
+    execute(command).unwrap_or_else(|error| exit_with(&error))
+}
+
+/// Check that a machine has the values needed to build commands, printing
+/// the missing ones.
+///
+/// # Arguments
+///
+/// * `machine` - Machine name
+/// * `config` - Machine configuration
+///
+fn has_values(machine: &str, config: &MachineConfig) -> bool {
+    match config.check_values() {
+        Ok(()) => true,
+        Err(error) => {
+            println!("Config `{}` is incomplete: {}.", machine, error);
+            false
+        }
+    }
+}
+
+/// Copy option arguments (`--recursive`, `--preserve`, `--follow-symlinks`,
+/// `--no-follow`, `--exclude`, `--exclude-from`)
+fn copy_args() -> Vec<Arg<'static, 'static>> {
+    vec![
+        Arg::with_name("recursive")
+            .long("recursive")
+            .short("r")
+            .help("copy directory trees"),
+        Arg::with_name("preserve")
+            .long("preserve")
+            .help("keep modes and modification times"),
+        Arg::with_name("follow-symlinks")
+            .long("follow-symlinks")
+            .help("copy the targets of symbolic links (default)"),
+        Arg::with_name("no-follow")
+            .long("no-follow")
+            .conflicts_with("follow-symlinks")
+            .help("copy symbolic links as links, with rsync"),
+        Arg::with_name("exclude")
+            .value_name("PATTERN")
+            .long("exclude")
+            .requires("recursive")
+            .help("skip files matching a pattern in recursive copies")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1),
+        Arg::with_name("exclude-from")
+            .value_name("FILE")
+            .long("exclude-from")
+            .requires("recursive")
+            .help("skip files matching the patterns of a file, like `.psshignore`")
+            .takes_value(true)
+    ]
+}
+
+/// Get the copy options of a machine, from the arguments and its defaults.
+///
+/// # Arguments
+///
+/// * `args` - Command line arguments
+/// * `config` - Machine configuration
+/// * `local_sources` - Local sources, whose `.psshignore` files apply
+///
+fn copy_options<S: AsRef<str>>(args: &ArgMatches, config: &MachineConfig, local_sources: &[S]) -> CopyOptions {
+    let follow_symlinks = if args.is_present("no-follow") {
+        false
+    } else {
+        args.is_present("follow-symlinks") || config.follows_symlinks()
+    };
+
+    let recursive = args.is_present("recursive");
+    let mut excludes: Vec<String> = args.values_of("exclude").map(|x| x.map(String::from).collect()).unwrap_or_default();
+    if recursive {
+        match exclude_patterns(local_sources, args.value_of("exclude-from")) {
+            Ok(patterns) => excludes.extend(patterns.into_iter().filter(|x| !excludes.contains(x)).collect::<Vec<_>>()),
+            Err(error) => exit_with(&error)
+        }
+    }
+
+    CopyOptions {
+        recursive,
+        preserve: args.is_present("preserve") || config.is_preserving(),
+        follow_symlinks,
+        excludes
+    }
+}
+
+/// Machine value override arguments (`--ip`, `--port`, `--user`, `--identity`)
+fn override_args() -> Vec<Arg<'static, 'static>> {
+    vec![
+        Arg::with_name("ip")
+            .long("ip")
+            .value_name("IP")
+            .help("override the IP")
+            .takes_value(true),
+        Arg::with_name("port")
+            .long("port")
+            .value_name("PORT")
+            .help("override the port")
+            .takes_value(true),
+        Arg::with_name("user")
+            .long("user")
+            .short("u")
+            .value_name("USER")
+            .help("override the user")
+            .takes_value(true),
+        Arg::with_name("identity")
+            .long("identity")
+            .value_name("IDENTITY")
+            .help("override the identity key")
+            .takes_value(true)
+    ]
+}
+
+/// Get machine value overrides, to merge on top of configured values.
+///
+/// Exits on a bad port.
+///
+/// # Arguments
+///
+/// * `args` - Subcommand arguments, with `override_args`
+///
+fn overrides_or_exit(args: &ArgMatches) -> MachineConfig {
+    let port = match args.value_of("port").map(|x| x.parse::<u16>()) {
+        Some(Ok(x)) => Some(x),
+        Some(Err(_)) => {
+            println!("Bad port: {}", args.value_of("port").unwrap());
+            process::exit(1);
+        },
+        None => None
+    };
+
+    MachineConfig {
+        ip: args.value_of("ip").map(String::from),
+        port: port.map(|x| vec![x]),
+        user: args.value_of("user").map(String::from),
+        identity: args.value_of("identity").map(|x| vec![x.to_string()]),
+        ..Default::default()
+    }
+}
+
+fn filter_arg() -> Arg<'static, 'static> {
+    Arg::with_name("filter")
+        .value_name("EXPRESSION")
+        .long("filter")
+        .help("filter expression over machine values (e.g. `tag=web and user=deploy`)")
+        .takes_value(true)
+}
+
+/// Narrow selected machines with the `--filter` expression, if any.
+///
+/// Returns `None` on a bad expression.
+///
+/// # Arguments
+///
+/// * `args` - Subcommand arguments, with `filter_arg`
+/// * `config_content` - Configuration
+/// * `machine_names` - Selected machines
+///
+fn filter_selection(args: &ArgMatches, config_content: &ConfigResult, machine_names: Vec<String>) -> Option<Vec<String>> {
+    let expression = match args.value_of("filter") {
+        Some(x) => x,
+        None => return Some(machine_names)
+    };
+
+    match Filter::parse(expression) {
+        Ok(filter) => Some(filter_machines(machine_names, &filter, &config_content.machine_values)),
+        Err(error) => {
+            println!("Bad filter: {}.", error);
+            None
+        }
+    }
+}
+
+fn yes_arg() -> Arg<'static, 'static> {
+    Arg::with_name("yes")
+        .long("yes")
+        .short("y")
+        .help("do not ask for confirmation")
+}
+
+/// Confirm an operation on machines, when it targets more machines than the
+/// configured threshold or protected machines.
+///
+/// # Arguments
+///
+/// * `config_content` - Configuration
+/// * `machine_names` - Target machines
+/// * `action` - Operation description, as "rebooted"
+/// * `threshold` - Machine count above which confirmation is needed
+/// * `yes` - Skip the confirmation
+///
+fn confirm_operation(config_content: &ConfigResult, machine_names: &[String], action: &str, threshold: usize, yes: bool) -> bool {
+    let protected: Vec<&String> = machine_names.iter()
+        .filter(|x| config_content.machine_values[*x].is_protected())
+        .collect();
+
+    if yes || (machine_names.len() <= threshold && protected.is_empty()) {
+        return true;
+    }
+
+    println!("The following machines will be {}:", action);
+    for name in machine_names {
+        let mark = if protected.contains(&name) { " (protected)" } else { "" };
+        println!("> {}{}", name, mark);
+    }
+
+    if !io::stdin().is_terminal() {
+        println!("Confirmation needed, use --yes to proceed.");
+        return false;
+    }
+
+    if confirm("Continue?") {
+        true
+    } else {
+        println!("Aborted.");
+        false
+    }
+}
+
+/// Save an edited configuration, warning first when its comments are about
+/// to be lost. On a terminal the rewrite needs a confirmation, otherwise it
+/// goes on, as the previous file is kept as a backup.
+///
+/// # Arguments
+///
+/// * `editor` - Edited configuration
+///
+fn save_configuration(editor: &ConfigEditor) -> bool {
+    if editor.has_comments() {
+        eprintln!("Warning: {} has comments, which are lost when it is rewritten (the current file is kept in {}.bak).", editor.path(), editor.path());
+        if io::stdin().is_terminal() && !confirm("Rewrite it?") {
+            println!("Aborted.");
+            return false;
+        }
+    }
+
+    editor.save().unwrap_or_else(|error| exit_with(&error));
+    true
+}
+
+/// Build the command line definition, also used to generate the man pages
+pub fn build_cli() -> App<'static, 'static> {
+    App::new("pssh")
         .version(VERSION)
         .author("Denis B. <bourge.denis@gmail.com>")
         .about("pssh")
+        .after_help("Run `pssh help topics` for the configuration format, selectors and transports.")
+        .setting(AppSettings::AllowExternalSubcommands)
+        .setting(AppSettings::DisableHelpSubcommand)
         .arg(Arg::with_name("file")
             .long("file")
             .short("f")
@@ -55,6 +691,12 @@ pub fn init_shell() {
             .long("verbose")
             .short("v")
             .help("verbose mode"))
+        .arg(Arg::with_name("strict")
+            .long("strict")
+            .help("require explicit addresses, users and identities for all machines"))
+        .arg(Arg::with_name("sync")
+            .long("sync")
+            .help("pull the shared configuration repository first"))
             
         .subcommand(SubCommand::with_name("connect")
             .about("connect to a machine")
@@ -63,22 +705,49 @@ pub fn init_shell() {
                 .help("machine name")
                 .required(true)
                 .takes_value(true))
-            .arg(Arg::with_name("user")
-                .value_name("USERNAME")
-                .long("user")
-                .short("u")
-                .help("set username")
-                .takes_value(true))
+            .args(&override_args())
             .arg(Arg::with_name("tmux")
                 .long("tmux")
                 .short("t")
-                .help("use tmux")))
+                .help("use tmux"))
+            .arg(Arg::with_name("restricted")
+                .long("restricted")
+                .short("r")
+                .conflicts_with("tmux")
+                .help("force the restricted command of the machine (rbash by default)"))
+            .arg(Arg::with_name("no-banner")
+                .long("no-banner")
+                .help("hide the server banner (`-o LogLevel=ERROR`)")))
         
         .subcommand(SubCommand::with_name("list")
-            .about("list available machines"))
+            .about("list available machines")
+            .arg(Arg::with_name("prefix")
+                .value_name("PREFIX")
+                .help("only list machines under this namespace (e.g. `prod:`)")
+                .takes_value(true))
+            .arg(Arg::with_name("depth")
+                .value_name("N")
+                .long("depth")
+                .short("d")
+                .help("collapse machines deeper than N levels")
+                .conflicts_with("long")
+                .takes_value(true))
+            .arg(filter_arg())
+            .arg(Arg::with_name("long")
+                .long("long")
+                .short("l")
+                .help("show addresses and machine state"))
+            .arg(Arg::with_name("status")
+                .long("status")
+                .short("s")
+                .help("probe the SSH port of machines"))
+            .arg(Arg::with_name("all")
+                .long("all")
+                .short("a")
+                .help("also list expired machines")))
         
         .subcommand(SubCommand::with_name("push")
-            .about("push file to a machine")
+            .about("push files to a machine")
             .arg(Arg::with_name("machine")
                 .value_name("MACHINE")
                 .help("machine name")
@@ -86,17 +755,40 @@ pub fn init_shell() {
                 .takes_value(true))
             .arg(Arg::with_name("source")
                 .value_name("FILE")
-                .help("source filename")
+                .help("source filenames or globs (e.g. `dist/*.js`)")
                 .required(true)
+                .multiple(true)
                 .takes_value(true))
             .arg(Arg::with_name("destination")
                 .value_name("FILE")
-                .help("destination filename")
+                .help("destination filename, or directory for several files")
                 .required(true)
-                .takes_value(true)))
+                .takes_value(true))
+            .arg(Arg::with_name("atomic")
+                .long("atomic")
+                .conflicts_with("recursive")
+                .help("upload to a temporary name, then move the file into place"))
+            .arg(Arg::with_name("become")
+                .long("become")
+                .short("b")
+                .requires("atomic")
+                .help("move the file into place with sudo"))
+            .arg(Arg::with_name("backup")
+                .long("backup")
+                .requires("atomic")
+                .help("keep the replaced file with a `.bak` suffix"))
+            .arg(Arg::with_name("then")
+                .value_name("COMMAND")
+                .long("then")
+                .help("remote command run after a successful copy, instead of the `on_push` value")
+                .takes_value(true))
+            .args(&copy_args())
+            .args(&override_args())
+            .arg(force_arg())
+            .arg(notify_arg()))
         
         .subcommand(SubCommand::with_name("pull")
-            .about("pull file from a machine")
+            .about("pull files from a machine")
             .arg(Arg::with_name("machine")
                 .value_name("MACHINE")
                 .help("machine name")
@@ -104,149 +796,3636 @@ pub fn init_shell() {
                 .takes_value(true))
             .arg(Arg::with_name("source")
                 .value_name("FILE")
-                .help("source filename")
+                .help("remote filenames or globs, quoted (e.g. `'/var/log/*.log'`)")
                 .required(true)
+                .multiple(true)
                 .takes_value(true))
             .arg(Arg::with_name("destination")
                 .value_name("FILE")
-                .help("destination filename")
+                .help("destination filename, or directory for several files")
                 .required(true)
-                .takes_value(true)))
+                .takes_value(true))
+            .args(&copy_args())
+            .args(&override_args()))
+
+        .subcommand(SubCommand::with_name("cp")
+            .about("copy files, scp-style, between the local host and machines")
+            .arg(Arg::with_name("source")
+                .value_name("SOURCE")
+                .help("local paths or `MACHINE:PATH`, all on the same side")
+                .required(true)
+                .multiple(true)
+                .takes_value(true))
+            .arg(Arg::with_name("destination")
+                .value_name("DESTINATION")
+                .help("local path or `MACHINE:PATH`")
+                .required(true)
+                .takes_value(true))
+            .args(&copy_args())
+            .arg(force_arg()))
+
+        .subcommand(SubCommand::with_name("transfer")
+            .about("stream files from a machine to another one, without a local copy")
+            .arg(Arg::with_name("source")
+                .value_name("MACHINE:PATH")
+                .help("remote files or directories, all on the same machine")
+                .required(true)
+                .multiple(true)
+                .takes_value(true))
+            .arg(Arg::with_name("destination")
+                .value_name("MACHINE:DIR")
+                .help("remote directory, created if needed")
+                .required(true)
+                .takes_value(true))
+            .arg(Arg::with_name("preserve")
+                .long("preserve")
+                .help("keep modes and modification times"))
+            .arg(Arg::with_name("no-follow")
+                .long("no-follow")
+                .help("copy symbolic links as links"))
+            .arg(Arg::with_name("exclude")
+                .value_name("PATTERN")
+                .long("exclude")
+                .help("skip files matching a pattern")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1))
+            .arg(force_arg()))
                 
         .subcommand(SubCommand::with_name("ping")
-            .about("ping a machine")
+            .about("ping machines concurrently")
+            .arg(Arg::with_name("selector")
+                .value_name("SELECTOR")
+                .help("machine names or glob patterns, comma-separated")
+                .required_unless("all")
+                .conflicts_with("all")
+                .takes_value(true))
+            .arg(Arg::with_name("all")
+                .long("all")
+                .short("a")
+                .help("ping all machines"))
+            .arg(Arg::with_name("count")
+                .value_name("COUNT")
+                .long("count")
+                .short("c")
+                .help("number of echo requests per machine (3 by default)")
+                .takes_value(true))
+            .arg(filter_arg()))
+        
+        .subcommand(SubCommand::with_name("location")
+            .about("show the current network location, and the local network it is detected from"))
+
+        .subcommand(SubCommand::with_name("top")
+            .about("watch the processes of a machine with htop (or top)")
+            .arg(Arg::with_name("machine")
+                .value_name("MACHINE")
+                .help("machine name")
+                .required(true)
+                .takes_value(true))
+            .args(&override_args()))
+
+        .subcommand(SubCommand::with_name("console")
+            .about("attach to the out-of-band console of a machine (IPMI or conserver)")
             .arg(Arg::with_name("machine")
                 .value_name("MACHINE")
                 .help("machine name")
                 .required(true)
                 .takes_value(true)))
-        
-        .subcommand(SubCommand::with_name("show")
-            .about("show machine info")
+
+        .subcommand(SubCommand::with_name("code")
+            .about("open a machine folder in VS Code, exporting machines to the SSH configuration first")
             .arg(Arg::with_name("machine")
                 .value_name("MACHINE")
                 .help("machine name")
                 .required(true)
-                .takes_value(true)));
-            
-    let matches = app.get_matches_from_safe_borrow(&mut env::args_os());    
-    match matches {
-        Ok(result) => {
-            let level = if result.is_present("verbose") {
-                log::LogLevelFilter::Debug
-            } else {
-                log::LogLevelFilter::Info
-            };
-            
-            init_logger(level).expect("Failed to initialize logger.");
-                     
-            let config_file = result.value_of("file");
-            
-            match result.subcommand() {
-                ("list", _) => handle_list(config_file),
-                ("show", Some(args)) => handle_show(config_file, args.value_of("machine").unwrap()),
-                ("pull", Some(args)) => handle_pull(
-                    config_file,
-                    args.value_of("machine").unwrap(),
-                    args.value_of("source").unwrap(),
-                    args.value_of("destination").unwrap()
-                ),
-                ("push", Some(args)) => handle_push(
-                    config_file,
-                    args.value_of("machine").unwrap(),
-                    args.value_of("source").unwrap(),
-                    args.value_of("destination").unwrap()
-                ),
-                ("ping", Some(args)) => handle_ping(config_file, args.value_of("machine").unwrap()),
-                ("connect", Some(args)) => handle_connect(
-                    config_file,
-                    args.value_of("machine").unwrap(),
-                    args.value_of("user"),
-                    args.is_present("tmux")
-                ),
-                _ => {
-                    app.print_help().ok();
-                }
-            }
+                .takes_value(true))
+            .arg(Arg::with_name("path")
+                .value_name("REMOTE_PATH")
+                .help("remote folder to open")
+                .takes_value(true)))
+
+        .subcommand(SubCommand::with_name("git-remote")
+            .about("add a git remote pointing to a repository on a machine, in the current repository")
+            .arg(Arg::with_name("machine")
+                .value_name("MACHINE")
+                .help("machine name")
+                .required(true)
+                .takes_value(true))
+            .arg(Arg::with_name("path")
+                .value_name("REPO_PATH")
+                .help("repository path on the machine, relative to the home directory unless absolute")
+                .required(true)
+                .takes_value(true))
+            .arg(Arg::with_name("name")
+                .value_name("NAME")
+                .help("remote name, the machine name by default")
+                .takes_value(true))
+            .arg(Arg::with_name("ssh-config")
+                .long("ssh-config")
+                .help("go through the exported SSH configuration instead of an ssh:// URL")))
+
+        .subcommand(SubCommand::with_name("clip")
+            .about("move small snippets between the local and remote machines")
+            .subcommand(clip_subcommand("send", "store the standard input on a machine"))
+            .subcommand(clip_subcommand("get", "print the stored snippet of a machine")))
+
+        .subcommand(SubCommand::with_name("ps")
+            .about("list the processes of a machine")
+            .arg(Arg::with_name("machine")
+                .value_name("MACHINE")
+                .help("machine name")
+                .required(true)
+                .takes_value(true))
+            .arg(Arg::with_name("pattern")
+                .value_name("PATTERN")
+                .help("PID or command line substring")
+                .takes_value(true)))
+
+        .subcommand(SubCommand::with_name("kill")
+            .about("signal processes of a machine, after confirmation")
+            .arg(Arg::with_name("machine")
+                .value_name("MACHINE")
+                .help("machine name")
+                .required(true)
+                .takes_value(true))
+            .arg(Arg::with_name("pattern")
+                .value_name("PATTERN")
+                .help("PID or command line substring")
+                .required(true)
+                .takes_value(true))
+            .arg(Arg::with_name("signal")
+                .value_name("SIGNAL")
+                .long("signal")
+                .short("s")
+                .help("signal to send")
+                .default_value(DEFAULT_SIGNAL)
+                .takes_value(true))
+            .arg(yes_arg())
+            .arg(force_arg()))
+
+        .subcommand(SubCommand::with_name("envdiff")
+            .about("run the same inspection command on two machines and compare outputs side by side")
+            .arg(Arg::with_name("left")
+                .value_name("MACHINE_A")
+                .help("first machine name")
+                .required(true)
+                .takes_value(true))
+            .arg(Arg::with_name("right")
+                .value_name("MACHINE_B")
+                .help("second machine name")
+                .required(true)
+                .takes_value(true))
+            .arg(Arg::with_name("command")
+                .value_name("COMMAND")
+                .long("command")
+                .short("c")
+                .help("inspection command, like `dpkg -l`")
+                .default_value(ENVDIFF_COMMAND)
+                .takes_value(true))
+            .arg(Arg::with_name("all")
+                .long("all")
+                .short("a")
+                .help("also show lines which are the same"))
+            .arg(Arg::with_name("width")
+                .value_name("COLUMNS")
+                .long("width")
+                .short("w")
+                .help("output width, defaults to $COLUMNS")
+                .takes_value(true)))
+
+        .subcommand(SubCommand::with_name("consensus")
+            .about("run a command on machines and group them by identical output")
+            .after_help("The largest group is shown first, and the other ones as diffs against it. Exits with 1 when outputs differ or machines fail.")
+            .setting(AppSettings::TrailingVarArg)
+            .arg(Arg::with_name("selector")
+                .value_name("SELECTOR")
+                .help("machine names or glob patterns, comma-separated (`-` to read them from stdin)")
+                .required(true)
+                .takes_value(true))
+            .arg(Arg::with_name("rolling")
+                .value_name("COUNT")
+                .long("rolling")
+                .short("r")
+                .help("maximum number of machines processed at once")
+                .takes_value(true))
+            .arg(filter_arg())
+            .arg(yes_arg())
+            .arg(force_arg())
+            .arg(Arg::with_name("command")
+                .value_name("COMMAND")
+                .help("inspection command, like `nginx -v 2>&1`")
+                .required(true)
+                .multiple(true)))
+
+        .subcommand(SubCommand::with_name("du")
+            .about("show disk usage and the largest directories of a machine")
+            .arg(Arg::with_name("machine")
+                .value_name("MACHINE")
+                .help("machine name")
+                .required(true)
+                .takes_value(true))
+            .arg(Arg::with_name("path")
+                .value_name("PATH")
+                .help("directory whose subdirectories are measured")
+                .default_value("/")
+                .takes_value(true))
+            .arg(Arg::with_name("top")
+                .value_name("COUNT")
+                .long("top")
+                .short("n")
+                .help("number of directories to show")
+                .default_value("10")
+                .takes_value(true)))
+
+        .subcommand(SubCommand::with_name("speedtest")
+            .about("measure connection throughput to a machine")
+            .arg(Arg::with_name("machine")
+                .value_name("MACHINE")
+                .help("machine name")
+                .required(true)
+                .takes_value(true))
+            .arg(Arg::with_name("size")
+                .value_name("MEGABYTES")
+                .long("size")
+                .short("s")
+                .help("amount of data to transfer in each direction")
+                .default_value("10")
+                .takes_value(true)))
+
+        .subcommand(SubCommand::with_name("report")
+            .about("report uptime, load, memory and disk usage of machines")
+            .arg(Arg::with_name("selector")
+                .value_name("SELECTOR")
+                .help("machine names or glob patterns, comma-separated")
+                .required(true)
+                .takes_value(true))
+            .arg(Arg::with_name("sort")
+                .value_name("COLUMN")
+                .long("sort")
+                .short("s")
+                .help("sort column")
+                .possible_values(&["name", "uptime", "load", "memory", "disk"])
+                .default_value("name")
+                .takes_value(true))
+            .arg(filter_arg())
+            .arg(Arg::with_name("json")
+                .long("json")
+                .help("output as JSON")))
+
+        .subcommand(SubCommand::with_name("check")
+            .about("check that the SSH port of machines is reachable")
+            .alias("status")
+            .arg(Arg::with_name("selector")
+                .value_name("SELECTOR")
+                .help("machine names or glob patterns, comma-separated")
+                .required(true)
+                .takes_value(true))
+            .arg(filter_arg())
+            .arg(Arg::with_name("watch")
+                .long("watch")
+                .short("w")
+                .help("check again at each interval, until interrupted"))
+            .arg(Arg::with_name("interval")
+                .value_name("DURATION")
+                .long("interval")
+                .short("i")
+                .help("interval between two checks (`30s` by default)")
+                .requires("watch")
+                .takes_value(true))
+            .arg(Arg::with_name("on-change")
+                .value_name("COMMAND")
+                .long("on-change")
+                .help("local command run when a machine changes state, with PSSH_MACHINE, PSSH_PREVIOUS_STATE, PSSH_STATE and PSSH_VAR_<KEY> set")
+                .requires("watch")
+                .takes_value(true))
+            .arg(Arg::with_name("oneline")
+                .long("oneline")
+                .conflicts_with("watch")
+                .help("print `<machine> <state> <latency> <address>` lines, for scripts and chat bots")))
+
+        .subcommand(power_subcommand("reboot", "reboot machines"))
+        .subcommand(power_subcommand("shutdown", "power off machines"))
+
+        .subcommand(SubCommand::with_name("exec")
+            .about("run a command on machines")
+            .setting(AppSettings::TrailingVarArg)
+            .arg(Arg::with_name("selector")
+                .value_name("SELECTOR")
+                .help("machine names or glob patterns, comma-separated (`-` to read them from stdin)")
+                .required(true)
+                .takes_value(true))
+            .arg(Arg::with_name("rolling")
+                .value_name("COUNT")
+                .long("rolling")
+                .short("r")
+                .help("maximum number of machines processed at once")
+                .takes_value(true))
+            .arg(filter_arg())
+            .arg(Arg::with_name("outdir")
+                .value_name("DIR")
+                .long("outdir")
+                .short("o")
+                .help("save outputs to `<DIR>/<machine>.out` and `.err` instead of printing them")
+                .takes_value(true))
+            .arg(Arg::with_name("timestamp")
+                .long("timestamp")
+                .help("prefix output lines with the time they were received"))
+            .arg(Arg::with_name("timeout")
+                .value_name("DURATION")
+                .long("timeout")
+                .short("t")
+                .help("kill the command on machines where it runs longer (`30s`, `5m`...)")
+                .takes_value(true))
+            .arg(Arg::with_name("no-stdin")
+                .long("no-stdin")
+                .help("do not pass the standard input to the command (streamed to a single machine, read whole before running on several ones)"))
+            .arg(Arg::with_name("tty")
+                .long("tty")
+                .conflicts_with_all(&["rolling", "outdir", "timestamp", "no-stdin"])
+                .help("run interactive programs (editors, database shells...) with a pseudo-terminal, one machine at a time"))
+            .arg(Arg::with_name("canary")
+                .value_name("CANARY")
+                .long("canary")
+                .help("run first on these machines (names, globs or a percentage like `10%`), then confirm")
+                .takes_value(true))
+            .arg(Arg::with_name("canary-auto")
+                .long("canary-auto")
+                .requires("canary")
+                .help("continue without confirmation when all canaries succeed"))
+            .args(&failure_args())
+            .args(&wave_args())
+            .args(&override_args())
+            .arg(yes_arg())
+            .arg(force_arg())
+            .arg(notify_arg())
+            .arg(Arg::with_name("command")
+                .value_name("COMMAND")
+                .help("command to run, where `{machine}`, `{ip}`, `{user}`, `{port}`, `{tag:KEY}` and `{var:KEY}` are replaced per machine")
+                .required(true)
+                .multiple(true)))
+
+        .subcommand(SubCommand::with_name("collect")
+            .about("pull the same path from machines into per-machine directories")
+            .arg(Arg::with_name("selector")
+                .value_name("SELECTOR")
+                .help("machine names or glob patterns, comma-separated (`-` to read them from stdin)")
+                .required(true)
+                .takes_value(true))
+            .arg(Arg::with_name("source")
+                .value_name("REMOTE")
+                .help("remote file or directory")
+                .required(true)
+                .takes_value(true))
+            .arg(Arg::with_name("destination")
+                .value_name("DIR")
+                .help("local directory")
+                .required(true)
+                .takes_value(true))
+            .arg(Arg::with_name("flatten")
+                .long("flatten")
+                .help("name files `<machine>_<name>` directly in the local directory"))
+            .arg(Arg::with_name("overwrite")
+                .long("overwrite")
+                .help("replace existing files instead of adding a numeric suffix"))
+            .arg(Arg::with_name("rolling")
+                .value_name("COUNT")
+                .long("rolling")
+                .short("r")
+                .help("maximum number of machines processed at once")
+                .takes_value(true))
+            .arg(filter_arg()))
+
+        .subcommand(SubCommand::with_name("backup")
+            .about("save a compressed archive of a remote path")
+            .arg(Arg::with_name("machine")
+                .value_name("MACHINE")
+                .help("machine name")
+                .required(true)
+                .takes_value(true))
+            .arg(Arg::with_name("path")
+                .value_name("REMOTE")
+                .help("remote file or directory")
+                .required(true)
+                .takes_value(true))
+            .arg(Arg::with_name("dest")
+                .value_name("DIR")
+                .long("dest")
+                .short("d")
+                .help("local directory of archives (default: current directory)")
+                .takes_value(true))
+            .arg(Arg::with_name("keep")
+                .value_name("COUNT")
+                .long("keep")
+                .short("k")
+                .help("keep only the latest archives of the path")
+                .takes_value(true)))
+
+        .subcommand(SubCommand::with_name("cmp")
+            .about("compare a local file with a remote one, without copying it")
+            .after_help("Exits with 0 for identical files, 1 for different ones and 2 on errors.")
+            .arg(Arg::with_name("machine")
+                .value_name("MACHINE")
+                .help("machine name")
+                .required(true)
+                .takes_value(true))
+            .arg(Arg::with_name("local")
+                .value_name("LOCAL")
+                .help("local file")
+                .required(true)
+                .takes_value(true))
+            .arg(Arg::with_name("remote")
+                .value_name("REMOTE")
+                .help("remote file")
+                .required(true)
+                .takes_value(true)))
+
+        .subcommand(SubCommand::with_name("file-audit")
+            .about("report the state of a remote file across machines, to spot drift")
+            .after_help("Exits with 1 when machines fail or, with --hash, when their versions differ.")
+            .arg(Arg::with_name("selector")
+                .value_name("SELECTOR")
+                .help("machine names or glob patterns, comma-separated (`-` to read them from stdin)")
+                .required(true)
+                .takes_value(true))
+            .arg(Arg::with_name("path")
+                .value_name("REMOTE")
+                .help("remote file")
+                .required(true)
+                .takes_value(true))
+            .arg(Arg::with_name("hash")
+                .long("hash")
+                .help("show the SHA-256 digest of the file and group machines by version"))
+            .arg(Arg::with_name("grep")
+                .value_name("PATTERN")
+                .long("grep")
+                .help("check whether the file has lines matching a `grep` pattern")
+                .conflicts_with("hash")
+                .takes_value(true))
+            .arg(Arg::with_name("rolling")
+                .value_name("COUNT")
+                .long("rolling")
+                .short("r")
+                .help("maximum number of machines processed at once")
+                .takes_value(true))
+            .arg(filter_arg()))
+
+        .subcommand(SubCommand::with_name("restore")
+            .about("extract an archive made by `pssh backup` on a machine")
+            .arg(Arg::with_name("machine")
+                .value_name("MACHINE")
+                .help("machine name")
+                .required(true)
+                .takes_value(true))
+            .arg(Arg::with_name("archive")
+                .value_name("ARCHIVE")
+                .help("local archive")
+                .required(true)
+                .takes_value(true))
+            .arg(Arg::with_name("dir")
+                .value_name("REMOTE_DIR")
+                .help("remote directory to extract into, the parent of the backed up path")
+                .required(true)
+                .takes_value(true))
+            .arg(force_arg())
+            .arg(yes_arg()))
+
+        .subcommand(SubCommand::with_name("warm")
+            .about("start shared connections to machines, reused by later commands")
+            .arg(Arg::with_name("selector")
+                .value_name("SELECTOR")
+                .help("machine names or glob patterns, comma-separated")
+                .required(true)
+                .takes_value(true))
+            .arg(Arg::with_name("persist")
+                .value_name("DURATION")
+                .long("persist")
+                .short("p")
+                .help("time connections stay open after their last use (`10m` by default)")
+                .takes_value(true))
+            .arg(Arg::with_name("stop")
+                .long("stop")
+                .help("stop the shared connections instead")
+                .conflicts_with("persist")))
+
+        .subcommand(SubCommand::with_name("exporter")
+            .about("serve the reachability of machines as Prometheus metrics")
+            .arg(Arg::with_name("selector")
+                .value_name("SELECTOR")
+                .help("machine names or glob patterns, comma-separated (all machines by default)")
+                .default_value("*")
+                .takes_value(true))
+            .arg(Arg::with_name("listen")
+                .value_name("ADDRESS")
+                .long("listen")
+                .short("l")
+                .help("listening address")
+                .default_value(EXPORTER_LISTEN)
+                .takes_value(true))
+            .arg(Arg::with_name("interval")
+                .value_name("DURATION")
+                .long("interval")
+                .short("i")
+                .help("interval between two probes (`30s` by default)")
+                .takes_value(true)))
+
+        .subcommand(SubCommand::with_name("daemon")
+            .about("keep configurations loaded in a background process, used by later commands")
+            .subcommand(SubCommand::with_name("start")
+                .about("start the daemon")
+                .arg(Arg::with_name("warm")
+                    .value_name("SELECTOR")
+                    .long("warm")
+                    .short("w")
+                    .help("keep shared connections to these machines open")
+                    .takes_value(true))
+                .arg(Arg::with_name("foreground")
+                    .long("foreground")
+                    .help("run in the current process")))
+            .subcommand(SubCommand::with_name("stop")
+                .about("stop the daemon"))
+            .subcommand(SubCommand::with_name("status")
+                .about("show whether the daemon runs")))
+
+        .subcommand(SubCommand::with_name("serve")
+            .about("serve the inventory over a REST API")
+            .arg(Arg::with_name("listen")
+                .value_name("ADDRESS")
+                .long("listen")
+                .short("l")
+                .help("listening address")
+                .default_value(DEFAULT_LISTEN)
+                .takes_value(true))
+            .arg(Arg::with_name("token-env")
+                .value_name("VARIABLE")
+                .long("token-env")
+                .help("environment variable holding the bearer token required by requests")
+                .takes_value(true))
+            .arg(Arg::with_name("allow-exec")
+                .long("allow-exec")
+                .help("accept `POST /exec` requests, which need a token")
+                .requires("token-env")))
+
+        .subcommand(SubCommand::with_name("update")
+            .about("upgrade packages on machines")
+            .arg(Arg::with_name("selector")
+                .value_name("SELECTOR")
+                .help("machine names or glob patterns, comma-separated")
+                .required(true)
+                .takes_value(true))
+            .arg(Arg::with_name("become")
+                .long("become")
+                .short("b")
+                .help("run privileged commands with sudo"))
+            .arg(yes_arg())
+            .arg(force_arg())
+            .arg(Arg::with_name("dry-run")
+                .long("dry-run")
+                .short("n")
+                .help("only list upgradable packages"))
+            .arg(Arg::with_name("rolling")
+                .value_name("COUNT")
+                .long("rolling")
+                .short("r")
+                .help("maximum number of machines processed at once")
+                .takes_value(true))
+            .args(&failure_args())
+            .args(&wave_args()))
+
+        .subcommand(SubCommand::with_name("discover")
+            .about("discover SSH hosts on the local network")
+            .arg(Arg::with_name("subnet")
+                .value_name("CIDR")
+                .long("subnet")
+                .short("s")
+                .help("subnet to scan (defaults to the local /24 subnet)")
+                .takes_value(true))
+            .arg(Arg::with_name("port")
+                .value_name("PORT")
+                .long("port")
+                .short("p")
+                .help("SSH port to probe")
+                .default_value("22")
+                .takes_value(true))
+            .arg(Arg::with_name("namespace")
+                .value_name("NAMESPACE")
+                .long("namespace")
+                .short("n")
+                .help("namespace for added machines")
+                .takes_value(true)))
+
+        .subcommand(SubCommand::with_name("cloud")
+            .about("cloud inventory")
+            .subcommand(SubCommand::with_name("sync")
+                .about("sync machines from a cloud provider")
+                .arg(Arg::with_name("provider")
+                    .value_name("PROVIDER")
+                    .help("cloud provider")
+                    .possible_values(&["do", "hetzner", "linode", "azure"])
+                    .required(true)
+                    .takes_value(true))
+                .arg(Arg::with_name("namespace")
+                    .value_name("NAMESPACE")
+                    .long("namespace")
+                    .short("n")
+                    .help("namespace replaced by synced machines (defaults to the provider name)")
+                    .takes_value(true))
+                .arg(Arg::with_name("token")
+                    .value_name("TOKEN")
+                    .long("token")
+                    .help("API token (defaults to the provider environment variable)")
+                    .takes_value(true))
+                .arg(Arg::with_name("subscription")
+                    .value_name("SUBSCRIPTION")
+                    .long("subscription")
+                    .help("Azure subscription (defaults to the az CLI one)")
+                    .takes_value(true))
+                .arg(Arg::with_name("resource-group")
+                    .value_name("GROUP")
+                    .long("resource-group")
+                    .short("g")
+                    .help("Azure resource group (defaults to all groups)")
+                    .takes_value(true))
+                .arg(Arg::with_name("dry-run")
+                    .long("dry-run")
+                    .help("print synced machines instead of writing them"))
+                .arg(notify_arg())))
+
+        .subcommand(SubCommand::with_name("clone")
+            .about("copy a machine entry under a new name")
+            .arg(Arg::with_name("machine")
+                .value_name("MACHINE")
+                .help("existing machine name")
+                .required(true)
+                .takes_value(true))
+            .arg(Arg::with_name("new")
+                .value_name("NEW")
+                .help("new machine name")
+                .required(true)
+                .takes_value(true))
+            .args(&override_args())
+            .arg(Arg::with_name("template")
+                .long("template")
+                .value_name("TEMPLATE")
+                .help("override the template")
+                .takes_value(true)))
+
+        .subcommand(SubCommand::with_name("mv")
+            .about("rename a machine or move a namespace")
+            .arg(Arg::with_name("old")
+                .value_name("OLD")
+                .help("current machine or namespace name")
+                .required(true)
+                .takes_value(true))
+            .arg(Arg::with_name("new")
+                .value_name("NEW")
+                .help("new name")
+                .required(true)
+                .takes_value(true)))
+
+        .subcommand(SubCommand::with_name("init")
+            .about("create a starter configuration file")
+            .arg(Arg::with_name("force")
+                .long("force")
+                .help("overwrite an existing configuration file")))
+
+        .subcommand(SubCommand::with_name("owner")
+            .about("show who owns a machine, and how to reach them")
+            .arg(Arg::with_name("machine")
+                .value_name("MACHINE")
+                .help("machine name")
+                .required(true)
+                .takes_value(true)))
+
+        .subcommand(SubCommand::with_name("maintenance")
+            .about("put a machine in maintenance, skipped by `check` and `report`, or take it out")
+            .arg(Arg::with_name("mode")
+                .value_name("MODE")
+                .help("enter (`on`) or leave (`off`) maintenance")
+                .possible_values(&["on", "off"])
+                .required(true)
+                .takes_value(true))
+            .arg(Arg::with_name("machine")
+                .value_name("MACHINE")
+                .help("machine name")
+                .required(true)
+                .takes_value(true))
+            .arg(Arg::with_name("reason")
+                .value_name("REASON")
+                .long("reason")
+                .short("m")
+                .help("reason, shown by `list`")
+                .takes_value(true)))
+
+        .subcommand(SubCommand::with_name("audit")
+            .about("query the audit log")
+            .subcommand(SubCommand::with_name("show")
+                .about("show audit log entries")
+                .arg(Arg::with_name("machine")
+                    .value_name("MACHINE")
+                    .long("machine")
+                    .short("m")
+                    .help("machine name or glob pattern")
+                    .takes_value(true))
+                .arg(Arg::with_name("since")
+                    .value_name("SINCE")
+                    .long("since")
+                    .short("s")
+                    .help("oldest entries to show, as an age (`12h`, `7d`), a date or a RFC 3339 time")
+                    .takes_value(true))))
+
+        .subcommand(SubCommand::with_name("jobs")
+            .about("inspect and repeat past fleet operations")
+            .subcommand(SubCommand::with_name("list")
+                .about("list recent jobs")
+                .arg(Arg::with_name("limit")
+                    .value_name("COUNT")
+                    .long("limit")
+                    .short("n")
+                    .help("number of jobs listed")
+                    .default_value("20")
+                    .takes_value(true)))
+            .subcommand(SubCommand::with_name("show")
+                .about("show the results of a job")
+                .arg(Arg::with_name("id")
+                    .value_name("ID")
+                    .help("job identifier")
+                    .required(true)
+                    .takes_value(true)))
+            .subcommand(SubCommand::with_name("rerun")
+                .about("run a job again, on the same machines")
+                .arg(Arg::with_name("id")
+                    .value_name("ID")
+                    .help("job identifier")
+                    .required(true)
+                    .takes_value(true))
+                .arg(Arg::with_name("failed")
+                    .long("failed")
+                    .help("only run on the machines where the job failed"))))
+
+        .subcommand(SubCommand::with_name("schedule")
+            .about("run fleet operations at recurring times")
+            .subcommand(SubCommand::with_name("add")
+                .about("schedule an operation, run by the daemon")
+                .setting(AppSettings::TrailingVarArg)
+                .arg(Arg::with_name("cron")
+                    .value_name("CRON")
+                    .help("cron expression (`0 3 * * *`, `@daily`...)")
+                    .required(true)
+                    .takes_value(true))
+                .arg(Arg::with_name("arguments")
+                    .value_name("OPERATION")
+                    .help("pssh operation and its arguments (`exec web:* -- cleanup.sh`)")
+                    .required(true)
+                    .allow_hyphen_values(true)
+                    .multiple(true)))
+            .subcommand(SubCommand::with_name("list")
+                .about("list scheduled operations"))
+            .subcommand(SubCommand::with_name("remove")
+                .about("remove a scheduled operation")
+                .arg(Arg::with_name("id")
+                    .value_name("ID")
+                    .help("schedule identifier")
+                    .required(true)
+                    .takes_value(true)))
+            .subcommand(SubCommand::with_name("crontab")
+                .about("print scheduled operations as crontab entries, to run them without the daemon")))
+
+        .subcommand(SubCommand::with_name("summary")
+            .about("summarize the inventory")
+            .arg(filter_arg()))
+
+        .subcommand(SubCommand::with_name("diff")
+            .about("compare resolved machines of two configurations")
+            .arg(Arg::with_name("old")
+                .value_name("OLD")
+                .help("old configuration file")
+                .required_unless("staged"))
+            .arg(Arg::with_name("new")
+                .value_name("NEW")
+                .help("new configuration file")
+                .required_unless("staged"))
+            .arg(Arg::with_name("staged")
+                .long("staged")
+                .help("compare the committed configuration with local edits")
+                .conflicts_with_all(&["old", "new"])))
+
+        .subcommand(SubCommand::with_name("config")
+            .about("maintain the configuration file")
+            .subcommand(SubCommand::with_name("upgrade")
+                .about("rewrite the configuration in the current layout version")
+                .arg(Arg::with_name("dry-run")
+                    .long("dry-run")
+                    .short("n")
+                    .help("print the upgraded configuration instead of writing it"))))
+
+        .subcommand(SubCommand::with_name("lint")
+            .about("check the configuration for invalid machine names")
+            .arg(Arg::with_name("fix-names")
+                .long("fix-names")
+                .help("suggest valid names for invalid ones")))
+
+        .subcommand(SubCommand::with_name("completion")
+            .about("print a shell completion script, completing machine names and remote paths")
+            .arg(Arg::with_name("shell")
+                .value_name("SHELL")
+                .help("shell")
+                .possible_values(&["zsh", "fish"])
+                .required(true)
+                .takes_value(true)))
+
+        .subcommand(SubCommand::with_name("complete")
+            .setting(AppSettings::Hidden)
+            .subcommand(SubCommand::with_name("subcommands"))
+            .subcommand(SubCommand::with_name("machines"))
+            .subcommand(SubCommand::with_name("path")
+                .arg(Arg::with_name("machine")
+                    .required(true)
+                    .takes_value(true))
+                .arg(Arg::with_name("prefix")
+                    .allow_hyphen_values(true)
+                    .takes_value(true))))
+
+        .subcommand(SubCommand::with_name("export")
+            .about("export the inventory to other tools")
+            .subcommand(SubCommand::with_name("ssh-config")
+                .about("export machines as an OpenSSH client configuration")
+                .arg(Arg::with_name("install")
+                    .long("install")
+                    .help("write ~/.ssh/config.d/pssh.conf and include it from ~/.ssh/config"))
+                .arg(Arg::with_name("watch")
+                    .long("watch")
+                    .help("install again whenever the configuration changes")
+                    .requires("install")))
+            .subcommand(inventory_subcommand("json", "export resolved machines as a JSON array"))
+            .subcommand(inventory_subcommand("csv", "export resolved machines as CSV, for spreadsheets"))
+            .subcommand(SubCommand::with_name("graph")
+                .about("export the machine hierarchy as a graph")
+                .arg(Arg::with_name("selector")
+                    .value_name("SELECTOR")
+                    .help("machine names or glob patterns, comma-separated, all machines by default")
+                    .takes_value(true))
+                .arg(Arg::with_name("format")
+                    .long("format")
+                    .value_name("FORMAT")
+                    .help("graph file format")
+                    .possible_values(&["dot", "d2"])
+                    .default_value("dot")
+                    .takes_value(true))))
+
+        .subcommand(SubCommand::with_name("repo")
+            .about("share the configuration directory through git")
+            .subcommand(SubCommand::with_name("init")
+                .about("initialize the configuration directory as a git repository")
+                .arg(Arg::with_name("url")
+                    .value_name("URL")
+                    .help("team repository to clone")
+                    .takes_value(true)))
+            .subcommand(SubCommand::with_name("pull")
+                .about("pull remote changes"))
+            .subcommand(SubCommand::with_name("push")
+                .about("commit local edits and push them")
+                .arg(Arg::with_name("message")
+                    .value_name("MESSAGE")
+                    .long("message")
+                    .short("m")
+                    .help("commit message")
+                    .takes_value(true))))
+
+        .subcommand(SubCommand::with_name("show")
+            .about("show machine info")
+            .arg(Arg::with_name("machine")
+                .value_name("MACHINE")
+                .help("machine name")
+                .required(true)
+                .takes_value(true))
+            .arg(Arg::with_name("explain")
+                .long("explain")
+                .short("e")
+                .help("show values filled from the environment")))
+
+        // Replaces the help subcommand of clap, which rejects help topics
+        .subcommand(SubCommand::with_name("help")
+            .about("print this message, the help of a subcommand or a help topic")
+            .arg(Arg::with_name("words")
+                .value_name("SUBCOMMAND|TOPIC")
+                .help("subcommand, with its own subcommands, or help topic")
+                .multiple(true)
+                .takes_value(true)))
+}
+
+/// Initialize shell
+pub fn init_shell() {
+    let mut app = build_cli();
+    let matches = app.get_matches_from_safe_borrow(env::args_os());
+    match matches {
+        Ok(result) => {
+            let level = if result.is_present("verbose") {
+                log::LogLevelFilter::Debug
+            } else {
+                log::LogLevelFilter::Info
+            };
+            
+            if let Err(error) = init_logger(level) {
+                eprintln!("Could not initialize logging: {}", error);
+            }
+                     
+            let config_file = result.value_of("file");
+
+            if result.is_present("strict") {
+                enable_strict_mode();
+            }
+
+            if result.is_present("sync") {
+                if let Err(error) = repo::pull(&get_user_configuration_dir()) {
+                    eprintln!("Could not sync the configuration: {}", error);
+                }
+            }
+            
+            match result.subcommand() {
+                ("list", Some(args)) => handle_list(config_file, args),
+                ("show", Some(args)) => handle_show(config_file, args.value_of("machine").unwrap(), args.is_present("explain")),
+                ("pull", Some(args)) => handle_pull(config_file, args),
+                ("cp", Some(args)) => handle_cp(config_file, args),
+                ("transfer", Some(args)) => handle_transfer(config_file, args),
+                ("push", Some(args)) => handle_push(config_file, args),
+                ("ping", Some(args)) => handle_ping(config_file, args),
+                ("location", Some(_)) => handle_location(config_file),
+                ("reboot", Some(args)) => handle_power(config_file, args, PowerAction::Reboot),
+                ("shutdown", Some(args)) => handle_power(config_file, args, PowerAction::Shutdown),
+                ("diff", Some(args)) => handle_diff(config_file, args),
+                ("summary", Some(args)) => handle_summary(config_file, args),
+                ("config", Some(args)) => match args.subcommand() {
+                    ("upgrade", Some(args)) => handle_config_upgrade(config_file, args.is_present("dry-run")),
+                    _ => println!("{}", args.usage())
+                },
+                ("lint", Some(args)) => handle_lint(config_file, args.is_present("fix-names")),
+                ("help", Some(args)) => handle_help(&app, &args.values_of("words").map(|x| x.collect::<Vec<_>>()).unwrap_or_default()),
+                ("completion", Some(args)) => match args.value_of("shell").unwrap() {
+                    "zsh" => print!("{}", ZSH_SCRIPT),
+                    _ => print!("{}", FISH_SCRIPT)
+                },
+                ("complete", Some(args)) => match args.subcommand() {
+                    ("subcommands", Some(_)) => {
+                        for (name, _) in subcommands(&app) {
+                            println!("{}", name);
+                        }
+                    },
+                    ("machines", Some(_)) => {
+                        let today = Local::now().date_naive();
+                        let mut names: Vec<String> = load_configuration_file(config_file).map(|x| x.machine_values).unwrap_or_default().into_iter()
+                            .filter(|x| !x.1.is_expired(today))
+                            .map(|x| x.0)
+                            .collect();
+                        names.sort();
+                        println!("{}", names.join("\n"));
+                    },
+                    ("path", Some(args)) => {
+                        let machines = load_configuration_file(config_file).map(|x| x.machine_values).unwrap_or_default();
+                        if let Some(config) = machines.get(args.value_of("machine").unwrap()) {
+                            for path in complete_remote_paths(config, args.value_of("prefix").unwrap_or("")).unwrap_or_default() {
+                                println!("{}", path);
+                            }
+                        }
+                    },
+                    _ => ()
+                },
+                ("export", Some(args)) => match args.subcommand() {
+                    ("ssh-config", Some(args)) => handle_export_ssh_config(config_file, args),
+                    ("json", Some(args)) => handle_export_inventory(config_file, args, false),
+                    ("csv", Some(args)) => handle_export_inventory(config_file, args, true),
+                    ("graph", Some(args)) => {
+                        let machines = selected_machines(config_file, args.value_of("selector"));
+                        print!("{}", render_graph(&machines, GraphFormat::from_name(args.value_of("format").unwrap()).unwrap()));
+                    },
+                    _ => println!("{}", args.usage())
+                },
+                ("repo", Some(args)) => match args.subcommand() {
+                    ("init", Some(args)) => handle_repo(repo::init(&get_user_configuration_dir(), args.value_of("url"))),
+                    ("pull", Some(_)) => handle_repo(repo::pull(&get_user_configuration_dir())),
+                    ("push", Some(args)) => handle_repo(repo::push(
+                        &get_user_configuration_dir(),
+                        args.value_of("message").unwrap_or(repo::DEFAULT_COMMIT_MESSAGE)
+                    )),
+                    _ => println!("{}", args.usage())
+                },
+                ("schedule", Some(args)) => match args.subcommand() {
+                    ("add", Some(args)) => handle_schedule_add(config_file, args),
+                    ("list", Some(_)) => handle_schedule_list(),
+                    ("remove", Some(args)) => handle_schedule_remove(args.value_of("id").unwrap()),
+                    ("crontab", Some(_)) => print!("{}", render_crontab(&read_schedules(&schedules_path()), &current_program())),
+                    _ => println!("{}", args.usage())
+                },
+                ("jobs", Some(args)) => match args.subcommand() {
+                    ("list", Some(args)) => handle_jobs_list(args.value_of("limit").unwrap()),
+                    ("show", Some(args)) => handle_jobs_show(args.value_of("id").unwrap()),
+                    ("rerun", Some(args)) => handle_jobs_rerun(args.value_of("id").unwrap(), args.is_present("failed")),
+                    _ => println!("{}", args.usage())
+                },
+                ("audit", Some(args)) => match args.subcommand() {
+                    ("show", Some(args)) => handle_audit_show(args.value_of("machine"), args.value_of("since")),
+                    _ => println!("{}", args.usage())
+                },
+                ("cloud", Some(args)) => match args.subcommand() {
+                    ("sync", Some(args)) => handle_cloud_sync(config_file, args),
+                    _ => println!("{}", args.usage())
+                },
+                ("clone", Some(args)) => handle_clone(config_file, args),
+                ("mv", Some(args)) => handle_move(
+                    config_file,
+                    args.value_of("old").unwrap(),
+                    args.value_of("new").unwrap()
+                ),
+                ("init", Some(args)) => handle_init(config_file, args.is_present("force")),
+                ("discover", Some(args)) => handle_discover(config_file, args),
+                ("exec", Some(args)) => handle_exec(config_file, args),
+                ("collect", Some(args)) => handle_collect(config_file, args),
+                ("backup", Some(args)) => handle_backup(config_file, args),
+                ("restore", Some(args)) => handle_restore(config_file, args),
+                ("cmp", Some(args)) => handle_cmp(config_file, args),
+                ("maintenance", Some(args)) => handle_maintenance(config_file, args),
+                ("owner", Some(args)) => handle_owner(config_file, args.value_of("machine").unwrap()),
+                ("file-audit", Some(args)) => handle_file_audit(config_file, args),
+                ("top", Some(args)) => handle_top(config_file, args.value_of("machine").unwrap(), &overrides_or_exit(args)),
+                ("console", Some(args)) => handle_console(config_file, args.value_of("machine").unwrap()),
+                ("code", Some(args)) => handle_code(config_file, args.value_of("machine").unwrap(), args.value_of("path")),
+                ("git-remote", Some(args)) => handle_git_remote(config_file, args),
+                ("clip", Some(args)) => match args.subcommand() {
+                    ("send", Some(args)) => handle_clip(config_file, args, true),
+                    ("get", Some(args)) => handle_clip(config_file, args, false),
+                    _ => println!("{}", args.usage())
+                },
+                ("ps", Some(args)) => handle_ps(config_file, args.value_of("machine").unwrap(), args.value_of("pattern")),
+                ("kill", Some(args)) => handle_kill(config_file, args),
+                ("envdiff", Some(args)) => handle_envdiff(config_file, args),
+                ("consensus", Some(args)) => handle_consensus(config_file, args),
+                ("du", Some(args)) => handle_du(
+                    config_file,
+                    args.value_of("machine").unwrap(),
+                    args.value_of("path").unwrap(),
+                    args.value_of("top").unwrap()
+                ),
+                ("warm", Some(args)) => handle_warm(config_file, args),
+                ("serve", Some(args)) => handle_serve(config_file, args),
+                ("exporter", Some(args)) => handle_exporter(config_file, args),
+                ("daemon", Some(args)) => match args.subcommand() {
+                    ("start", Some(args)) => handle_daemon_start(config_file, args),
+                    ("stop", Some(_)) => handle_daemon_stop(),
+                    ("status", Some(_)) => handle_daemon_status(),
+                    _ => println!("{}", args.usage())
+                },
+                ("update", Some(args)) => handle_update(config_file, args),
+                ("report", Some(args)) => handle_report(config_file, args),
+                ("check", Some(args)) => handle_check(config_file, args),
+                ("speedtest", Some(args)) => handle_speedtest(
+                    config_file,
+                    args.value_of("machine").unwrap(),
+                    args.value_of("size").unwrap()
+                ),
+                ("connect", Some(args)) => handle_connect(
+                    config_file,
+                    args.value_of("machine").unwrap(),
+                    &MachineConfig {
+                        quiet: if args.is_present("no-banner") { Some(true) } else { None },
+                        ..overrides_or_exit(args)
+                    },
+                    args.is_present("tmux"),
+                    args.is_present("restricted")
+                ),
+                (name, Some(args)) => handle_plugin(config_file, name, args),
+                _ => {
+                    app.print_help().ok();
+                }
+            }
         },
         
         Err(error) => {
-            eprintln!("{}", error.to_string());
+            eprintln!("{}", error);
+        }
+    }
+}
+
+fn handle_list(config_file: Option<&str>, args: &ArgMatches) {
+    let (long, status) = (args.is_present("long"), args.is_present("status"));
+    let depth: Option<usize> = match args.value_of("depth").map(|x| x.parse()) {
+        None => None,
+        Some(Ok(x)) => Some(x),
+        Some(Err(_)) => {
+            println!("Bad depth: `{}`.", args.value_of("depth").unwrap());
+            return;
+        }
+    };
+
+    let config_content = load_configuration_or_exit(config_file);
+    let prefix = args.value_of("prefix").unwrap_or("");
+    let today = Local::now().date_naive();
+    let mut machine_names: Vec<String> = config_content.machine_values.keys()
+        .filter(|name| prefix_match(prefix, name))
+        .filter(|name| args.is_present("all") || !config_content.machine_values[*name].is_expired(today))
+        .cloned()
+        .collect();
+    machine_names.sort();
+    let machine_names = match filter_selection(args, &config_content, machine_names) {
+        Some(x) => x,
+        None => return
+    };
+
+    if let Some(depth) = depth {
+        let mut groups: Vec<(String, usize)> = vec![];
+        for name in &machine_names {
+            let (group, count) = match collapse_name(name, depth) {
+                Some(group) => (group, 1),
+                None => (name.clone(), 0)
+            };
+            match groups.last_mut() {
+                Some(last) if last.0 == group && count > 0 => last.1 += 1,
+                _ => groups.push((group, count))
+            }
+        }
+
+        for (group, count) in groups {
+            match count {
+                0 => println!("> {}{}", group, machine_marks(&config_content.machine_values[&group], today)),
+                1 => println!("> {} (1 machine)", group),
+                _ => println!("> {} ({} machines)", group, count)
+            }
+        }
+        return;
+    }
+
+    let reachability = if status { probe_machines(&config_content.machine_values, &machine_names) } else { HashMap::new() };
+    let colored = io::stdout().is_terminal();
+    let state = StateStore::load_default();
+    let status_mark = |name: &str| {
+        let mut mark = match reachability.get(name) {
+            Some(x) => format!(" {}", paint_reachability(*x, colored)),
+            None => String::new()
+        };
+        if let Some(ref maintenance) = state.get(name).maintenance {
+            mark.push_str(" \u{1f527}");
+            if let Some(ref reason) = maintenance.reason {
+                mark.push_str(&format!(" {}", reason));
+            }
+        }
+        mark
+    };
+
+    if !long {
+        for key in &machine_names {
+            println!("> {}{}{}", key, machine_marks(&config_content.machine_values[key], today), status_mark(key));
+        }
+        return;
+    }
+
+    let now = Utc::now();
+    let rows: Vec<[String; 5]> = machine_names.iter().map(|name| {
+        let machine_state = state.get(name);
+        let last_check = match machine_state.last_check {
+            Some(ref check) if check.error.is_some() => "failed".to_string(),
+            Some(_) => "ok".to_string(),
+            None => "-".to_string()
+        };
+
+        [
+            format!("{}{}", name, machine_marks(&config_content.machine_values[name], today)),
+            config_content.machine_values[name].address(),
+            config_content.machine_values[name].owner.clone().unwrap_or_else(|| "-".to_string()),
+            format_time(machine_state.last_seen, now),
+            last_check
+        ]
+    }).collect();
+
+    let header = ["MACHINE", "ADDRESS", "OWNER", "LAST SEEN", "LAST CHECK"];
+    let mut widths: Vec<usize> = header.iter().map(|x| x.len()).collect();
+    for row in &rows {
+        for (i, cell) in row.iter().enumerate() {
+            widths[i] = widths[i].max(cell.len());
+        }
+    }
+
+    let status_header = if status { "  STATUS" } else { "" };
+    println!("{:w0$}  {:w1$}  {:w2$}  {:w3$}  {:w4$}{}", header[0], header[1], header[2], header[3], header[4], status_header,
+        w0 = widths[0], w1 = widths[1], w2 = widths[2], w3 = widths[3], w4 = widths[4]);
+    for (name, row) in machine_names.iter().zip(&rows) {
+        let line = format!("{:w0$}  {:w1$}  {:w2$}  {:w3$}  {:w4$}{}", row[0], row[1], row[2], row[3], row[4], status_mark(name),
+            w0 = widths[0], w1 = widths[1], w2 = widths[2], w3 = widths[3], w4 = widths[4]);
+        println!("{}", line.trim_end());
+    }
+}
+
+/// Paint the local banner of a machine: white on red for protected
+/// machines, yellow otherwise.
+///
+/// # Arguments
+///
+/// * `banner` - Banner text
+/// * `protected` - Is the machine protected?
+/// * `colored` - Use colors
+///
+fn paint_banner(banner: &str, protected: bool, colored: bool) -> String {
+    let text = format!(" {} ", banner);
+    if !colored {
+        return text;
+    }
+
+    if protected {
+        Colour::White.on(Colour::Red).bold().paint(text).to_string()
+    } else {
+        Colour::Yellow.bold().paint(text).to_string()
+    }
+}
+
+fn paint_reachability(reachability: Reachability, colored: bool) -> String {
+    let text = format!("[{}]", reachability.name());
+    if !colored {
+        return text;
+    }
+
+    match reachability {
+        Reachability::Up => Colour::Green.paint(text).to_string(),
+        Reachability::Down => Colour::Red.paint(text).to_string(),
+        Reachability::Unknown => Colour::Yellow.paint(text).to_string()
+    }
+}
+
+fn protected_mark(machine_config: &MachineConfig) -> &'static str {
+    if machine_config.is_protected() { " (protected)" } else { "" }
+}
+
+fn machine_marks(machine_config: &MachineConfig, today: NaiveDate) -> String {
+    let expired = if machine_config.is_expired(today) { " (expired)" } else { "" };
+    format!("{}{}", protected_mark(machine_config), expired)
+}
+
+fn handle_show(config_file: Option<&str>, machine: &str, explain: bool) {
+    let config_content = load_configuration_or_exit(config_file);
+    let machine_config = config_content.machine_values.get(machine);
+    
+    if machine_config.is_none() {
+        println!("Config `{}` does not exist.", machine);
+        return;
+    }
+    
+    let machine_config = machine_config.unwrap();
+    machine_config.show_info(machine);
+    if explain {
+        match config_content.fallbacks.get(machine) {
+            Some(fields) => println!("  Filled from the environment: {}", fields.join(", ")),
+            None => println!("  No value filled from the environment.")
+        }
+        if machine_config.location_ips.is_some() {
+            println!("  Location: {}", config_content.location.as_deref().unwrap_or("unknown"));
+        }
+        match config_content.matched.get(machine) {
+            Some(blocks) => println!("  Match blocks applied: {}", blocks.join("; ")),
+            None => println!("  No match block applied.")
+        }
+    }
+    StateStore::load_default().get(machine).show_info();
+}
+
+fn handle_pull(config_file: Option<&str>, args: &ArgMatches) {
+    let machine = args.value_of("machine").unwrap();
+    let sources: Vec<&str> = args.values_of("source").unwrap().collect();
+    let destination = args.value_of("destination").unwrap();
+
+    let mut config_content = load_configuration_or_exit(config_file);
+    let ad_hoc = config_content.add_ad_hoc_machine(machine).unwrap_or_else(|error| exit_with(&error));
+    let machine_config = config_content.machine_values.get(machine);    
+    
+    if machine_config.is_none() {
+        println!("Config `{}` does not exist.", machine);
+        return;
+    }
+    
+    let machine_config = machine_config.unwrap().merge(&overrides_or_exit(args));
+    if !has_values(machine, &machine_config) {
+        return;
+    }
+
+    if let Err(error) = check_remote(&machine_config, &sources) {
+        exit_with(&error);
+    }
+
+    let options = copy_options(args, &machine_config, &[] as &[&str]);
+    let command = copy(&machine_config, &sources, destination, ScpDirection::Pull, &options);
+    audit::record(machine, "pull", &command.to_string());
+    if execute_or_exit(command).success() && ad_hoc {
+        offer_to_save(config_file, machine);
+    }
+}
+
+fn handle_cp(config_file: Option<&str>, args: &ArgMatches) {
+    let config_content = load_configuration_or_exit(config_file);
+    let machines = &config_content.machine_values;
+    let sources: Vec<Endpoint> = args.values_of("source").unwrap().map(|x| parse_endpoint(x, machines)).collect();
+    let destination = parse_endpoint(args.value_of("destination").unwrap(), machines);
+    let from = match source_machine(&sources) {
+        Ok(x) => x,
+        Err(error) => exit_with(&error)
+    };
+    let paths: Vec<&str> = sources.iter().map(Endpoint::path).collect();
+
+    if let Some(to) = destination.machine() {
+        if !check_protected(&config_content, &[to.to_string()], args.is_present("force")) {
+            return;
+        }
+    }
+    for name in from.iter().chain(destination.machine().iter()) {
+        if !has_values(name, &machines[*name]) {
+            return;
+        }
+    }
+    if let Some(from) = from {
+        if let Err(error) = check_remote(&machines[from], &paths) {
+            exit_with(&error);
+        }
+    }
+
+    let status = match (from, &destination) {
+        (Some(from), Endpoint::Local(path)) => {
+            let config = &machines[from];
+            let command = copy(config, &paths, path, ScpDirection::Pull, &copy_options(args, config, &[] as &[&str]));
+            audit::record(from, "pull", &command.to_string());
+            execute_or_exit(command)
+        },
+        (None, Endpoint::Machine(to, path)) => {
+            let files = match expand_local(&paths) {
+                Ok(x) => x,
+                Err(error) => exit_with(&error)
+            };
+            let config = &machines[to];
+            let command = copy(config, &files, path, ScpDirection::Push, &copy_options(args, config, &files));
+            audit::record(to, "push", &command.to_string());
+            execute_or_exit(command)
+        },
+        (Some(from), Endpoint::Machine(to, path)) => {
+            let detail = format!("{}:{} -> {}:{}", from, paths.join(" "), to, path);
+            audit::record(from, "pull", &detail);
+            audit::record(to, "push", &detail);
+            let options = copy_options(args, &machines[from], &[] as &[&str]);
+            relay(&machines[from], &paths, &machines[to], path, &options).unwrap_or_else(|error| exit_with(&error))
+        },
+        (None, Endpoint::Local(_)) => {
+            println!("No path is on a machine: write remote paths as `MACHINE:PATH`.");
+            return;
+        }
+    };
+
+    if !status.success() {
+        process::exit(status.code().unwrap_or(1));
+    }
+}
+
+fn handle_transfer(config_file: Option<&str>, args: &ArgMatches) {
+    let config_content = load_configuration_or_exit(config_file);
+    let machines = &config_content.machine_values;
+    let sources: Vec<Endpoint> = args.values_of("source").unwrap().map(|x| parse_endpoint(x, machines)).collect();
+    let destination = parse_endpoint(args.value_of("destination").unwrap(), machines);
+
+    let from = match source_machine(&sources) {
+        Ok(Some(x)) => x,
+        Ok(None) => {
+            println!("Bad source: write it as `MACHINE:PATH`.");
+            return;
+        }
+        Err(error) => exit_with(&error)
+    };
+    let to = match destination.machine() {
+        Some(x) => x,
+        None => {
+            println!("Bad destination: write it as `MACHINE:DIR`.");
+            return;
+        }
+    };
+    if !check_protected(&config_content, &[to.to_string()], args.is_present("force")) {
+        return;
+    }
+    if !has_values(from, &machines[from]) || !has_values(to, &machines[to]) {
+        return;
+    }
+
+    let paths: Vec<&str> = sources.iter().map(Endpoint::path).collect();
+    if let Err(error) = check_remote(&machines[from], &paths) {
+        exit_with(&error);
+    }
+
+    let options = CopyOptions {
+        preserve: args.is_present("preserve") || machines[from].is_preserving(),
+        follow_symlinks: !args.is_present("no-follow") && machines[from].follows_symlinks(),
+        excludes: args.values_of("exclude").map(|x| x.map(String::from).collect()).unwrap_or_default(),
+        ..CopyOptions::default()
+    };
+    let (source, sink) = transfer_commands(&machines[from], &paths, &machines[to], destination.path(), &options);
+    let detail = format!("{}:{} -> {}:{}", from, paths.join(" "), to, destination.path());
+    audit::record(from, "transfer", &detail);
+    audit::record(to, "transfer", &detail);
+
+    let status = execute_pipe(source, sink).unwrap_or_else(|error| exit_with(&error));
+    if !status.success() {
+        process::exit(status.code().unwrap_or(1));
+    }
+}
+
+fn handle_push(config_file: Option<&str>, args: &ArgMatches) {
+    let machine = args.value_of("machine").unwrap();
+    let sources: Vec<String> = args.values_of("source").unwrap().map(String::from).collect();
+    let destination = args.value_of("destination").unwrap();
+    let atomic = if args.is_present("atomic") {
+        Some(AtomicOptions { sudo: args.is_present("become"), backup: args.is_present("backup") })
+    } else {
+        None
+    };
+
+    let files = match expand_local(&sources) {
+        Ok(x) => x,
+        Err(error) => exit_with(&error)
+    };
+    if atomic.is_some() && files.len() > 1 && !destination.ends_with('/') {
+        println!("Several files are pushed atomically into a directory: end `{}` with `/`.", destination);
+        return;
+    }
+
+    let mut config_content = load_configuration_or_exit(config_file);
+    let ad_hoc = config_content.add_ad_hoc_machine(machine).unwrap_or_else(|error| exit_with(&error));
+    let machine_config = config_content.machine_values.get(machine);
+        
+    if machine_config.is_none() {
+        println!("Config `{}` does not exist.", machine);
+        return;
+    }
+
+    if !check_protected(&config_content, &[machine.to_string()], args.is_present("force")) {
+        return;
+    }
+
+    let machine_config = machine_config.unwrap().merge(&overrides_or_exit(args));
+    if !has_values(machine, &machine_config) {
+        return;
+    }
+
+    let copy_options = copy_options(args, &machine_config, &files);
+    let started = Instant::now();
+    let (success, outcome) = match atomic {
+        Some(options) => {
+            audit::record(machine, "push", &format!("{} -> {} (atomic)", files.join(" "), destination));
+            let mut result = (true, String::new());
+            for file in &files {
+                result = match push_atomic(&machine_config, file, destination, options, &copy_options) {
+                    Ok(status) => (status.success(), describe_status(&status)),
+                    Err(error) => {
+                        println!("{}", error);
+                        (false, error.to_string())
+                    }
+                };
+                if !result.0 {
+                    break;
+                }
+            }
+            result
+        },
+        None => {
+            let command = copy(&machine_config, &files, destination, ScpDirection::Push, &copy_options);
+            audit::record(machine, "push", &command.to_string());
+            let status = execute_or_exit(command);
+            (status.success(), describe_status(&status))
+        }
+    };
+
+    // Like `exec`, the command runs in the remote shell after placeholders are expanded
+    let hook = args.value_of("then").map(String::from).or_else(|| machine_config.on_push.clone());
+    let (success, outcome) = match hook {
+        Some(ref hook) if success => match expand_placeholders(hook, machine, &machine_config) {
+            Ok(command) => {
+                audit::record(machine, "exec", &command);
+                let status = execute_or_exit(ssh_remote(&machine_config, &command));
+                if status.success() {
+                    (true, outcome)
+                } else {
+                    println!("The command after the push failed: {}.", describe_status(&status));
+                    (false, format!("then {}", describe_status(&status)))
+                }
+            },
+            Err(error) => {
+                println!("{}", error);
+                (false, error.to_string())
+            }
+        },
+        _ => (success, outcome)
+    };
+
+    let then = args.value_of("then").map(String::from);
+    let operation = JobOperation::Push { sources: sources.clone(), destination: destination.to_string(), atomic, copy: copy_options, then };
+    let mut job = Job::new(config_file, operation, &[machine.to_string()]);
+    job.duration = started.elapsed();
+    job.results = vec![MachineResult { machine: machine.to_string(), success, outcome }];
+    record_job_or_warn(job);
+    if ad_hoc && success {
+        offer_to_save(config_file, machine);
+    }
+
+    let message = format!("{} to {}:{}", files.join(" "), machine, destination);
+    notify_done(&config_content.settings, args.is_present("notify"), started, "pssh push", &message, success);
+}
+
+fn handle_location(config_file: Option<&str>) {
+    let config_content = load_configuration_or_exit(config_file);
+    let networks = &config_content.networks;
+    // Every fact is shown, to help writing rules
+    let surroundings = Surroundings::detect(true, true, true);
+
+    if networks.is_empty() {
+        println!("No network location configured.");
+    } else {
+        println!("Location: {}", locate(networks, &surroundings).unwrap_or("none"));
+    }
+    let unknown = || "unknown".to_string();
+    println!("  Local address: {}", surroundings.address.map(|x| x.to_string()).unwrap_or_else(unknown));
+    println!("  Gateway MAC: {}", surroundings.gateway_mac.unwrap_or_else(unknown));
+    println!("  Wi-Fi: {}", surroundings.ssid.unwrap_or_else(unknown));
+}
+
+fn handle_ping(config_file: Option<&str>, args: &ArgMatches) {
+    let selector = args.value_of("selector").unwrap_or("*");
+    let count: u32 = match args.value_of("count").map(str::parse) {
+        None => DEFAULT_PING_COUNT,
+        Some(Ok(x)) if x > 0 => x,
+        Some(_) => {
+            println!("Bad count: `{}`.", args.value_of("count").unwrap());
+            return;
+        }
+    };
+
+    let config_content = load_configuration_or_exit(config_file);
+    let machine_names = select_machines(selector, &config_content.machine_values).unwrap_or_else(|error| exit_with(&error));
+    let machine_names = match filter_selection(args, &config_content, machine_names) {
+        Some(x) => x,
+        None => return
+    };
+    if machine_names.is_empty() {
+        println!("No machine matches `{}`.", selector);
+        return;
+    }
+
+    let rows = run_parallel(&machine_names, DEFAULT_PARALLELISM, |name| {
+        ping_machine(&config_content.machine_values[name], count)
+    });
+    print!("{}", render_pings(&rows, io::stdout().is_terminal()));
+    if rows.iter().any(|x| x.1.state != Reachability::Up) {
+        process::exit(1);
+    }
+}
+
+fn handle_power(config_file: Option<&str>, args: &ArgMatches, action: PowerAction) {
+    let selector = args.value_of("selector").unwrap();
+    let rolling: usize = match args.value_of("rolling").unwrap_or("0").parse() {
+        Ok(x) => x,
+        Err(_) => {
+            println!("Bad rolling count: `{}`.", args.value_of("rolling").unwrap());
+            return;
+        }
+    };
+
+    let config_content = load_configuration_or_exit(config_file);
+    let machine_names = select_machines(selector, &config_content.machine_values).unwrap_or_else(|error| exit_with(&error));
+    let policy = match failure_policy(args, &config_content.settings) {
+        Some(x) => x,
+        None => return
+    };
+    let (batch_size, batch_delay) = match wave_values(args) {
+        Some(x) => x,
+        None => return
+    };
+    let machine_names = exclude_primaries_or_report(selector, &config_content, machine_names);
+    let machine_names = order_selection(args, &config_content, machine_names);
+
+    if machine_names.is_empty() {
+        println!("No machine matches `{}`.", selector);
+        return;
+    }
+
+    // Power actions are always confirmed
+    if !confirm_operation(&config_content, &machine_names, action.done(), 0, args.is_present("yes")) {
+        return;
+    }
+
+    let wait = args.is_present("wait");
+    let started = Instant::now();
+    let failed = |outcome: &PowerOutcome| matches!(*outcome, PowerOutcome::Failed(_) | PowerOutcome::Timeout);
+    let results = run_in_waves(&machine_names, rolling, batch_size, batch_delay, policy, |name| {
+        let machine_config = &config_content.machine_values[name];
+        audit::record(name, action.name(), &action.remote_command());
+        let outcome = execute_power_action(machine_config, action, wait);
+        println!("{}: {}", name, outcome.describe(action));
+        outcome
+    }, failed);
+
+    let skipped: Vec<String> = results.iter().filter(|x| x.1.is_none()).map(|x| x.0.clone()).collect();
+    report_skipped(&skipped);
+    post_summary(&config_content.webhooks, &JobSummary {
+        operation: action.name().to_string(),
+        detail: String::new(),
+        hosts: machine_names.clone(),
+        failures: results.iter()
+            .filter(|&(_, outcome)| outcome.as_ref().map(failed).unwrap_or(false))
+            .map(|(name, _)| name.clone())
+            .collect(),
+        duration: started.elapsed()
+    });
+}
+
+fn handle_summary(config_file: Option<&str>, args: &ArgMatches) {
+    let config_content = load_configuration_or_exit(config_file);
+    let machine_names: Vec<String> = config_content.machine_values.keys().cloned().collect();
+    let machine_names = match filter_selection(args, &config_content, machine_names) {
+        Some(x) => x,
+        None => return
+    };
+
+    let machines: ConfigMap = machine_names.into_iter()
+        .map(|name| {
+            let config = config_content.machine_values[&name].clone();
+            (name, config)
+        })
+        .collect();
+    println!("{}", Summary::from_machines(&machines).render());
+}
+
+fn handle_diff(config_file: Option<&str>, args: &ArgMatches) {
+    let (old, new) = if args.is_present("staged") {
+        let path = get_configuration_path(config_file);
+        let dir = get_user_configuration_dir();
+        let committed = match repo::committed_file(&dir, Path::new(&path)) {
+            Ok(x) => x,
+            Err(error) => exit_with(&error)
+        };
+
+        let mut old = load_configuration_string(&committed).unwrap_or_else(|error| exit_with(&error));
+        old.apply_fallbacks(&environment_fallbacks());
+        (old, load_configuration_or_exit(Some(&path)))
+    } else {
+        (load_configuration_or_exit(args.value_of("old")), load_configuration_or_exit(args.value_of("new")))
+    };
+
+    let colored = io::stdout().is_terminal();
+    for change in diff_machines(&old.machine_values, &new.machine_values) {
+        println!("{}", change.render(colored));
+    }
+}
+
+/// Offer to save an ad-hoc target to the configuration, when interactive.
+///
+/// # Arguments
+///
+/// * `config_file` - Configuration file
+/// * `target` - Ad-hoc `[user@]host[:port]` target
+///
+fn offer_to_save(config_file: Option<&str>, target: &str) {
+    if !io::stdin().is_terminal() {
+        return;
+    }
+
+    let name = ask(&format!("Save `{}` as (machine name, empty to skip)", target), "");
+    if name.is_empty() {
+        return;
+    }
+
+    let mut editor = ConfigEditor::open(config_file).unwrap_or_else(|error| exit_with(&error));
+    if editor.contains(&name) {
+        println!("Config `{}` already exists.", name);
+        return;
+    }
+
+    editor.set_machine(&name, &MachineConfig::from_address(target).unwrap());
+    if !save_configuration(&editor) {
+        return;
+    }
+    println!("Saved `{}` in {}.", name, editor.path());
+}
+
+fn handle_config_upgrade(config_file: Option<&str>, dry_run: bool) {
+    let mut editor = ConfigEditor::open(config_file).unwrap_or_else(|error| exit_with(&error));
+    let applied = match editor.upgrade() {
+        Ok(Some(x)) => x,
+        Ok(None) => {
+            println!("{} is up to date (version {}).", editor.path(), CONFIG_VERSION);
+            return;
+        },
+        Err(error) => exit_with(&error)
+    };
+
+    for migration in &applied {
+        println!("Version {}: {}", migration.version, migration.description);
+    }
+    if dry_run {
+        print!("{}", editor.to_yaml_string());
+        return;
+    }
+
+    if !save_configuration(&editor) {
+        return;
+    }
+    println!("Upgraded {} to version {} (backup in {}.bak).", editor.path(), CONFIG_VERSION, editor.path());
+}
+
+fn handle_help(app: &App<'static, 'static>, words: &[&str]) {
+    if words.is_empty() {
+        app.clone().print_help().ok();
+        println!();
+        return;
+    }
+    if words == ["topics"] {
+        print!("{}", render_topics());
+        return;
+    }
+    if let [word] = words {
+        if let Some(topic) = find_topic(word) {
+            page(topic);
+            return;
+        }
+    }
+
+    // Parsing `pssh SUBCOMMAND... --help` prints the help of the subcommand
+    let argv = ["pssh"].iter().chain(words).chain(&["--help"]).copied();
+    match app.clone().get_matches_from_safe(argv) {
+        Err(error) => error.exit(),
+        // Unknown words are taken as plugins
+        Ok(_) => exit_with(&Error::Selection(format!("no subcommand or help topic `{}`", words.join(" "))))
+    }
+}
+
+fn handle_lint(config_file: Option<&str>, fix_names: bool) {
+    let path = get_configuration_path(config_file);
+    let contents = match fs::read_to_string(&path) {
+        Ok(x) => x,
+        Err(error) => exit_with(&Error::io(format!("could not read {}", path), error))
+    };
+
+    let errors = check_names(&contents, Some(&path));
+    if errors.is_empty() {
+        println!("No problems found in {}.", path);
+        return;
+    }
+
+    for error in &errors {
+        println!("{}", error);
+        if fix_names {
+            let suggestion: Vec<String> = error.name.split(':')
+                .map(|x| if validate_key(x).is_ok() { x.to_string() } else { sanitize_key(x) })
+                .collect();
+            println!("    suggested name: `{}`", suggestion.join(":"));
+        }
+    }
+    exit_with(&Error::Config(format!("Found {} invalid machine names.", errors.len())));
+}
+
+/// Load the machines matching an optional selector, all of them by default
+fn selected_machines(config_file: Option<&str>, selector: Option<&str>) -> ConfigMap {
+    let machines = load_configuration_or_exit(config_file).machine_values;
+    match selector {
+        Some(selector) => select_machines(selector, &machines).unwrap_or_else(|error| exit_with(&error)).into_iter()
+            .map(|name| (name.clone(), machines[&name].clone()))
+            .collect(),
+        None => machines
+    }
+}
+
+fn handle_export_inventory(config_file: Option<&str>, args: &ArgMatches, csv: bool) {
+    let fields: Vec<&str> = args.value_of("fields").unwrap().split(',').map(|x| x.trim()).filter(|x| !x.is_empty()).collect();
+    let machines = selected_machines(config_file, args.value_of("selector"));
+    match export_rows(&machines, &fields) {
+        Ok(ref rows) if csv => print!("{}", render_csv(rows, &fields)),
+        Ok(rows) => println!("{}", serde_json::to_string_pretty(&rows).unwrap()),
+        Err(error) => exit_with(&error)
+    }
+}
+
+fn handle_export_ssh_config(config_file: Option<&str>, args: &ArgMatches) {
+    let path = get_configuration_path(config_file);
+    if !args.is_present("install") {
+        print!("{}", render_ssh_config(&load_configuration_or_exit(Some(&path)).machine_values, &path));
+        return;
+    }
+
+    let ssh_dir = env::home_dir().unwrap_or_default().join(".ssh");
+    let install = |machines: &ConfigMap| match install_ssh_config(&ssh_dir, &render_ssh_config(machines, &path)) {
+        Ok((installed, added)) => {
+            println!("Exported {} machines to {}.", machines.len(), installed.display());
+            if added {
+                println!("Included it from {}.", ssh_dir.join("config").display());
+            }
+        },
+        Err(error) => exit_with(&error)
+    };
+    install(&load_configuration_or_exit(Some(&path)).machine_values);
+
+    if !args.is_present("watch") {
+        return;
+    }
+
+    let modified = || fs::metadata(&path).and_then(|x| x.modified()).ok();
+    let mut last_modified = modified();
+    println!("Watching {} for changes...", path);
+
+    loop {
+        thread::sleep(EXPORT_WATCH_INTERVAL);
+        let current = modified();
+        if current == last_modified {
+            continue;
+        }
+        last_modified = current;
+
+        // Keep the previous export while the configuration is being edited
+        match load_configuration_file(Some(&path)) {
+            Ok(config_content) => install(&config_content.machine_values),
+            Err(error) => eprintln!("Could not load {} ({}), keeping the previous export.", path, error)
+        }
+    }
+}
+
+fn handle_repo(result: Result<(), Error>) {
+    if let Err(error) = result {
+        exit_with(&error);
+    }
+}
+
+fn handle_audit_show(machine: Option<&str>, since: Option<&str>) {
+    let since = match since {
+        Some(value) => match parse_since(value, Utc::now()) {
+            Some(x) => Some(x),
+            None => {
+                println!("Bad --since value: `{}`.", value);
+                return;
+            }
+        },
+        None => None
+    };
+
+    let entries = filter_entries(read_entries(&audit_log_path()), machine, since);
+    for entry in &entries {
+        println!("{}  {}  {}  {}  {}",
+            entry.time.with_timezone(&chrono::Local).format("%Y-%m-%d %H:%M:%S"),
+            entry.user, entry.machine, entry.operation, entry.command);
+    }
+}
+
+/// Get the path of the running pssh executable
+fn current_program() -> String {
+    env::current_exe().map(|x| x.to_string_lossy().into_owned()).unwrap_or_else(|_| "pssh".to_string())
+}
+
+fn handle_schedule_add(config_file: Option<&str>, args: &ArgMatches) {
+    let cron = args.value_of("cron").unwrap();
+    let mut arguments: Vec<String> = args.values_of("arguments").unwrap().map(String::from).collect();
+
+    // Scheduled runs are unattended: adding them is the confirmation
+    if arguments[0] == "exec" && arguments.len() > 1 {
+        for flag in &["--no-stdin", "--yes"] {
+            if !arguments.iter().any(|x| x == flag) {
+                arguments.insert(2, flag.to_string());
+            }
+        }
+    }
+
+    let config_file = config_file.map(|x| fs::canonicalize(x).map(|x| x.to_string_lossy().into_owned()).unwrap_or_else(|_| x.to_string()));
+    match add_schedule(&schedules_path(), cron, config_file.as_deref(), &arguments) {
+        Ok(id) => {
+            println!("Added schedule {}: {} pssh {}", id, cron, quote::join(&arguments));
+            if daemon::status().is_none() {
+                println!("Start the daemon to run it (`pssh daemon start`), or install `pssh schedule crontab` entries.");
+            }
+        },
+        Err(error) => exit_with(&error)
+    }
+}
+
+fn handle_schedule_list() {
+    let now = chrono::Local::now().naive_local();
+    for schedule in read_schedules(&schedules_path()) {
+        let next = CronSchedule::parse(&schedule.cron).ok()
+            .and_then(|x| x.next_after(&now))
+            .map(|x| x.format("%Y-%m-%d %H:%M").to_string())
+            .unwrap_or_else(|| "never".to_string());
+        println!("{:>4}  {:15}  next {}  pssh {}", schedule.id, schedule.cron, next, quote::join(&schedule.arguments));
+    }
+}
+
+fn handle_schedule_remove(id: &str) {
+    let id: u64 = match id.parse() {
+        Ok(x) => x,
+        Err(_) => {
+            println!("Bad schedule identifier: `{}`.", id);
+            return;
+        }
+    };
+
+    match remove_schedule(&schedules_path(), id) {
+        Ok(true) => println!("Removed schedule {}.", id),
+        Ok(false) => println!("Schedule {} does not exist.", id),
+        Err(error) => exit_with(&error)
+    }
+}
+
+/// Describe the exit status of a local command, like remote ones
+fn describe_status(status: &ExitStatus) -> String {
+    match status.code() {
+        Some(code) => format!("exit {}", code),
+        None => "killed".to_string()
+    }
+}
+
+/// Record a job in the history, warning on failures
+fn record_job_or_warn(job: Job) {
+    if let Err(error) = record_job(&jobs_path(), job) {
+        warn!("Could not record the job: {}", error);
+    }
+}
+
+/// Find a job from its identifier argument, printing errors
+fn job_or_none(id: &str) -> Option<Job> {
+    let id: u64 = match id.parse() {
+        Ok(x) => x,
+        Err(_) => {
+            println!("Bad job identifier: `{}`.", id);
+            return None;
+        }
+    };
+
+    let job = find_job(&jobs_path(), id);
+    if job.is_none() {
+        println!("Job {} does not exist.", id);
+    }
+    job
+}
+
+fn handle_jobs_list(limit: &str) {
+    let limit: usize = match limit.parse() {
+        Ok(x) => x,
+        Err(_) => {
+            println!("Bad limit: `{}`.", limit);
+            return;
+        }
+    };
+
+    let jobs = read_jobs(&jobs_path());
+    for job in &jobs[jobs.len().saturating_sub(limit)..] {
+        let failures = job.failures().len();
+        let result = if failures == 0 { "ok".to_string() } else { format!("{} failed", failures) };
+        println!("{:>4}  {}  {}  {} machines  {}",
+            job.id, job.time.with_timezone(&chrono::Local).format("%Y-%m-%d %H:%M:%S"),
+            job.operation.describe(), job.targets.len(), result);
+    }
+}
+
+fn handle_jobs_show(id: &str) {
+    let job = match job_or_none(id) {
+        Some(x) => x,
+        None => return
+    };
+
+    println!("Job {}: {}", job.id, job.operation.describe());
+    println!("  Started: {}", job.time.with_timezone(&chrono::Local).format("%Y-%m-%d %H:%M:%S"));
+    println!("  Duration: {:.1}s", job.duration.as_secs_f64());
+    if let Some(ref config_file) = job.config_file {
+        println!("  Configuration: {}", config_file);
+    }
+    println!("  Machines:");
+    let width = job.results.iter().map(|x| x.machine.len()).max().unwrap_or(0);
+    for result in &job.results {
+        println!("    {:width$}  {}  {}", result.machine, if result.success { "ok    " } else { "failed" }, result.outcome, width = width);
+    }
+}
+
+fn handle_jobs_rerun(id: &str, failed: bool) {
+    let job = match job_or_none(id) {
+        Some(x) => x,
+        None => return
+    };
+
+    let targets = if failed { job.failures() } else { job.targets.clone() };
+    if targets.is_empty() {
+        println!("Job {} has no failed machine.", job.id);
+        return;
+    }
+
+    let mut command = Command::new(env::current_exe().unwrap_or_else(|_| PathBuf::from("pssh")));
+    if let Some(ref config_file) = job.config_file {
+        command.arg("-f").arg(config_file);
+    }
+    command.args(job.operation.arguments(&targets));
+    println!("Running job {} again on {} machines.", job.id, targets.len());
+
+    let status = execute_command(command).unwrap_or_else(|error| exit_with(&error));
+    process::exit(status.code().unwrap_or(1));
+}
+
+fn handle_cloud_sync(config_file: Option<&str>, args: &ArgMatches) {
+    let provider = Provider::from_name(args.value_of("provider").unwrap()).unwrap();
+    let namespace = args.value_of("namespace").unwrap_or_else(|| provider.name());
+
+    let options = SyncOptions {
+        token: args.value_of("token").map(String::from),
+        subscription: args.value_of("subscription").map(String::from),
+        resource_group: args.value_of("resource-group").map(String::from)
+    };
+
+    let settings = load_configuration_or_exit(config_file).settings;
+    let forced = args.is_present("notify");
+    let started = Instant::now();
+    let title = format!("pssh cloud sync {}", provider.name());
+
+    let machines = match fetch_machines(provider, &options) {
+        Ok(x) => x,
+        Err(error) => {
+            println!("Could not list {} machines: {}", provider.name(), error);
+            notify_done(&settings, forced, started, &title, &error.to_string(), false);
+            return;
+        }
+    };
+
+    let mut editor = ConfigEditor::open(config_file).unwrap_or_else(|error| exit_with(&error));
+    editor.merge_subtree(namespace, machines_to_yaml(&machines));
+
+    if args.is_present("dry-run") {
+        print!("{}", editor.to_yaml_string());
+    } else if save_configuration(&editor) {
+        println!("Synced {} machine(s) into `{}`.", machines.len(), namespace);
+    } else {
+        return;
+    }
+
+    let message = format!("{} machine(s) synced into `{}`", machines.len(), namespace);
+    notify_done(&settings, forced, started, &title, &message, true);
+}
+
+fn handle_plugin(config_file: Option<&str>, name: &str, args: &ArgMatches) {
+    let plugin_args: Vec<&str> = args.values_of("").map(|x| x.collect()).unwrap_or_default();
+
+    // Script subcommands take precedence over plugins
+    if let Some(script) = find_script(name) {
+        let config_content = load_configuration_or_exit(config_file);
+        match run_script(&script, &plugin_args, &config_content.machine_values) {
+            Ok(code) => process::exit(code),
+            Err(error) => {
+                eprintln!("Script {} failed: {}", script.display(), error);
+                process::exit(error.exit_code());
+            }
+        }
+    }
+
+    let plugin = match find_plugin(name) {
+        Some(x) => x,
+        None => {
+            eprintln!("Unknown subcommand `{}` (no script `{}.rhai`, nor `{}{}` found on PATH).", name, name, PLUGIN_PREFIX, name);
+            process::exit(1);
+        }
+    };
+
+    let config_path = get_configuration_path(config_file);
+    let config_content = if Path::new(&config_path).exists() {
+        Some(load_configuration_or_exit(config_file))
+    } else {
+        None
+    };
+
+    let machines = config_content.as_ref().map(|x| &x.machine_values);
+    let command = plugin_command(&plugin, &plugin_args, &config_path, machines);
+    let status = execute_command(command).unwrap_or_else(|error| exit_with(&error));
+    process::exit(status.code().unwrap_or(1));
+}
+
+fn handle_clone(config_file: Option<&str>, args: &ArgMatches) {
+    let machine = args.value_of("machine").unwrap();
+    let new = args.value_of("new").unwrap();
+
+    let mut editor = ConfigEditor::open(config_file).unwrap_or_else(|error| exit_with(&error));
+    let machine_config = match editor.get_machine(machine) {
+        Some(x) => x,
+        None => {
+            println!("Config `{}` does not exist.", machine);
+            return;
+        }
+    };
+    if let Err(reason) = validate_name(new) {
+        println!("Bad machine name: {}.", reason);
+        return;
+    }
+    if editor.contains(new) {
+        println!("Config `{}` already exists.", new);
+        return;
+    }
+
+    let overrides = MachineConfig {
+        template: args.value_of("template").map(String::from),
+        ..overrides_or_exit(args)
+    };
+
+    editor.set_machine(new, &machine_config.merge(&overrides));
+    if !save_configuration(&editor) {
+        return;
+    }
+    println!("Created `{}` from `{}` in {}.", new, machine, editor.path());
+}
+
+fn handle_move(config_file: Option<&str>, old_name: &str, new_name: &str) {
+    let mut editor = ConfigEditor::open(config_file).unwrap_or_else(|error| exit_with(&error));
+    if let Err(error) = editor.rename(old_name, new_name) {
+        println!("Could not move: {}.", error);
+        return;
+    }
+    if !save_configuration(&editor) {
+        return;
+    }
+
+    StateStore::update_default(|state| state.rename(old_name, new_name));
+
+    println!("Moved `{}` to `{}`.", old_name, new_name);
+}
+
+fn handle_init(config_file: Option<&str>, force: bool) {
+    let path = get_configuration_path(config_file);
+    if Path::new(&path).exists() && !force {
+        println!("Configuration file {} already exists, use --force to overwrite it.", path);
+        return;
+    }
+
+    println!("Creating {}.", path);
+    let local_user = env::var("USER").unwrap_or_default();
+    let default_user = ask("Default user", &local_user);
+
+    println!("\nFirst machine:");
+    let name = ask("  Name", "");
+    let host = ask("  Host or IP", "");
+    if name.is_empty() || host.is_empty() {
+        println!("A machine name and a host are required.");
+        return;
+    }
+    if let Err(reason) = validate_key(&name) {
+        println!("Bad machine name `{}`: {}.", name, reason);
+        return;
+    }
+    let user = ask("  User", &default_user);
+    let identity = ask("  Identity file (empty for none)", "");
+
+    let machine = (name, MachineConfig {
+        ip: Some(host),
+        user: if user != default_user { Some(user) } else { None },
+        identity: if identity.is_empty() { None } else { Some(vec![identity]) },
+        ..Default::default()
+    });
+
+    let ssh_config_path = env::home_dir().unwrap_or_default().join(".ssh").join("config");
+    let imported = match fs::read_to_string(&ssh_config_path) {
+        Ok(contents) => {
+            let hosts = parse_ssh_config(&contents);
+            let question = format!("\nImport {} hosts from {} under `{}`?",
+                hosts.len(), ssh_config_path.display(), SSH_CONFIG_NAMESPACE);
+            if !hosts.is_empty() && confirm(&question) { hosts } else { vec![] }
+        },
+        Err(_) => vec![]
+    };
+
+    if let Some(parent) = Path::new(&path).parent() {
+        fs::create_dir_all(parent).ok();
+    }
+    File::create(&path)
+        .and_then(|mut f| f.write_all(starter_config(&default_user, &machine, &imported).as_bytes()))
+        .unwrap_or_else(|error| exit_with(&Error::io(format!("could not write {}", path), error)));
+
+    println!("\nWrote {}. Next steps:", path);
+    println!("  pssh list               list configured machines");
+    println!("  pssh show {:13} show the values of a machine", machine.0);
+    println!("  pssh connect {:10} connect to it", machine.0);
+    println!("  pssh discover           find SSH hosts on the local network");
+}
+
+fn handle_discover(config_file: Option<&str>, args: &ArgMatches) {
+    let subnet = match args.value_of("subnet").map(String::from).or_else(local_subnet) {
+        Some(x) => x,
+        None => {
+            println!("Could not detect the local subnet, use --subnet.");
+            return;
+        }
+    };
+
+    let addresses = match parse_subnet(&subnet) {
+        Some(x) => x,
+        None => {
+            println!("Bad subnet: `{}` (expected CIDR notation, /16 or smaller).", subnet);
+            return;
+        }
+    };
+
+    let port: u16 = match args.value_of("port").unwrap().parse() {
+        Ok(x) => x,
+        Err(_) => {
+            println!("Bad port: `{}`.", args.value_of("port").unwrap());
+            return;
+        }
+    };
+
+    println!("Scanning {} ({} addresses)...", subnet, addresses.len());
+    let hosts = merge_hosts(scan(&addresses, port, Duration::from_millis(300)), browse_mdns());
+    if hosts.is_empty() {
+        println!("No SSH host found.");
+        return;
+    }
+
+    let config_content = load_configuration_or_exit(config_file);
+    for (i, host) in hosts.iter().enumerate() {
+        let known = config_content.machine_values.iter()
+            .find(|&(_, config)| config.ip.as_ref() == Some(&host.ip))
+            .map(|(name, _)| format!(" (configured as `{}`)", name))
+            .unwrap_or_default();
+
+        println!("[{}] {}:{}  {}{}{}",
+            i + 1,
+            host.ip,
+            host.port,
+            host.hostname.as_ref().map(|x| &x[..]).unwrap_or("-"),
+            if host.mdns { " [mdns]" } else { "" },
+            known);
+    }
+
+    let answer = ask("Hosts to add (comma-separated numbers, empty to skip)", "");
+    let selected: Vec<usize> = answer.split(',')
+        .filter_map(|x| x.trim().parse::<usize>().ok())
+        .filter(|&x| x >= 1 && x <= hosts.len())
+        .collect();
+
+    if selected.is_empty() {
+        return;
+    }
+
+    let mut editor = ConfigEditor::open(config_file).unwrap_or_else(|error| exit_with(&error));
+    let mut added = 0;
+    for index in selected {
+        let host = &hosts[index - 1];
+        let default_name = match args.value_of("namespace") {
+            Some(namespace) => format!("{}:{}", namespace, host.suggested_name()),
+            None => host.suggested_name()
+        };
+
+        let name = ask(&format!("Machine name for {}", host.ip), &default_name);
+        if editor.contains(&name) {
+            println!("Config `{}` already exists, skipping.", name);
+            continue;
+        }
+
+        editor.set_machine(&name, &MachineConfig {
+            ip: Some(host.ip.clone()),
+            port: if host.port == 22 { None } else { Some(vec![host.port]) },
+            ..Default::default()
+        });
+        added += 1;
+    }
+
+    if added > 0 && save_configuration(&editor) {
+        println!("Added {} machine(s) to {}.", added, editor.path());
+    }
+}
+
+/// Tell if the standard input holds data to pass to remote commands: a pipe
+/// or a file. Terminals, sockets and devices inherited from the parent
+/// process are left alone, as reading them may never end.
+#[cfg(unix)]
+fn stdin_has_data() -> bool {
+    use std::os::unix::fs::FileTypeExt;
+
+    fs::metadata("/dev/stdin")
+        .map(|x| x.file_type().is_fifo() || x.file_type().is_file())
+        .unwrap_or(false)
+}
+
+#[cfg(not(unix))]
+fn stdin_has_data() -> bool {
+    !io::stdin().is_terminal()
+}
+
+fn handle_exec(config_file: Option<&str>, args: &ArgMatches) {
+    let selector = args.value_of("selector").unwrap();
+    let rolling: usize = match args.value_of("rolling").map(|x| x.parse()) {
+        None => DEFAULT_PARALLELISM,
+        Some(Ok(x)) => x,
+        Some(Err(_)) => {
+            println!("Bad rolling count: `{}`.", args.value_of("rolling").unwrap());
+            return;
+        }
+    };
+    let command: Vec<&str> = args.values_of("command").unwrap().collect();
+    let command = command.join(" ");
+    let overrides = overrides_or_exit(args);
+    let outdir = args.value_of("outdir").map(Path::new);
+    let mut options = ExecOptions {
+        timestamp: args.is_present("timestamp"),
+        timeout: match args.value_of("timeout").map(parse_duration) {
+            None => None,
+            Some(Some(x)) => Some(x),
+            Some(None) => {
+                println!("Bad timeout: `{}`.", args.value_of("timeout").unwrap());
+                return;
+            }
+        },
+        input: None,
+        tty: args.is_present("tty")
+    };
+    if options.tty && !io::stdin().is_terminal() {
+        println!("--tty needs a terminal.");
+        return;
+    }
+
+    if let Some(dir) = outdir {
+        if let Err(error) = fs::create_dir_all(dir) {
+            println!("Could not create `{}`: {}", dir.display(), error);
+            return;
+        }
+    }
+
+    let mut config_content = load_configuration_or_exit(config_file);
+    for target in selector.split(',') {
+        config_content.add_ad_hoc_machine(target.trim()).unwrap_or_else(|error| exit_with(&error));
+    }
+    let machine_names = select_machines(selector, &config_content.machine_values).unwrap_or_else(|error| exit_with(&error));
+    let machine_names = match filter_selection(args, &config_content, machine_names) {
+        Some(x) => x,
+        None => return
+    };
+    let policy = match failure_policy(args, &config_content.settings) {
+        Some(x) => x,
+        None => return
+    };
+    let (batch_size, batch_delay) = match wave_values(args) {
+        Some(x) => x,
+        None => return
+    };
+    let machine_names = exclude_primaries_or_report(selector, &config_content, machine_names);
+    let machine_names = order_selection(args, &config_content, machine_names);
+
+    if machine_names.is_empty() {
+        println!("No machine matches `{}`.", selector);
+        return;
+    }
+
+    let (canaries, remaining) = match args.value_of("canary").map(|x| split_canaries(&machine_names, x)) {
+        None => (vec![], machine_names.clone()),
+        Some(Ok(x)) => x,
+        Some(Err(error)) => {
+            println!("Bad canary: {}.", error);
+            return;
+        }
+    };
+
+    if !check_protected(&config_content, &machine_names, args.is_present("force")) {
+        return;
+    }
+
+    let threshold = config_content.settings.confirm_threshold;
+    let action = format!("affected by `{}`", command);
+    if !confirm_operation(&config_content, &machine_names, &action, threshold, args.is_present("yes")) {
+        return;
+    }
+
+    // A single machine gets the input as it comes, while for several ones
+    // it is read once, and passed to every machine
+    let mut input = vec![];
+    if !args.is_present("no-stdin") && !options.tty && stdin_has_data() {
+        if machine_names.len() == 1 {
+            options.input = Some(ExecInput::Stream);
+        } else {
+            if let Err(error) = io::stdin().read_to_end(&mut input) {
+                println!("Could not read the standard input: {}", error);
+                return;
+            }
+            options.input = Some(ExecInput::Data(&input));
+        }
+    }
+
+    // Interactive programs share the terminal, one machine at a time
+    let rolling = if options.tty { 1 } else { rolling };
+    let started = Instant::now();
+    let run = |names: &[String]| run_in_waves(names, rolling, batch_size, batch_delay, policy, |name| {
+        if options.tty && machine_names.len() > 1 {
+            println!("[{}]", name);
+        }
+        let machine_config = config_content.machine_values[name].merge(&overrides);
+        let result = expand_placeholders(&command, name, &machine_config).and_then(|command| {
+            audit::record(name, "exec", &command);
+            exec_command_with(&machine_config, &command, &options)
+        });
+
+        // Outputs are saved as soon as possible, to keep them if the run is interrupted
+        if let Some(dir) = outdir {
+            let saved = match result {
+                Ok(ref output) => save_output(dir, name, &output.stdout, &output.stderr),
+                Err(ref error) => save_output(dir, name, "", &format!("{}\n", error))
+            };
+            if let Err(error) = saved {
+                eprintln!("[{}] could not save output: {}", name, error);
+            }
+        }
+        result
+    }, |result| !result.as_ref().map(|x| x.success()).unwrap_or(false));
+
+    let mut failures: Vec<String> = vec![];
+    let mut skipped: Vec<String> = vec![];
+    let mut timeouts: Vec<String> = vec![];
+    let mut job_results: HashMap<String, MachineResult> = HashMap::new();
+    let mut remaining = remaining;
+    if !canaries.is_empty() {
+        println!("Canaries: {}", canaries.join(", "));
+        let results = run(&canaries);
+        print_exec_results(&results, outdir.is_none(), &mut failures, &mut skipped, &mut timeouts);
+        job_results.extend(results.iter().map(|(name, result)| (name.clone(), MachineResult::from_exec(name, result.as_ref()))));
+
+        let proceed = if !failures.is_empty() || !skipped.is_empty() {
+            println!("Canaries failed.");
+            false
+        } else if remaining.is_empty() || args.is_present("canary-auto") {
+            true
+        } else if !io::stdin().is_terminal() {
+            println!("Confirmation needed, use --canary-auto to proceed.");
+            false
+        } else {
+            confirm(&format!("Canaries succeeded. Continue with the {} remaining machines?", remaining.len()))
+        };
+
+        if !proceed {
+            skipped.append(&mut remaining);
+        }
+    }
+    if !remaining.is_empty() {
+        let results = run(&remaining);
+        print_exec_results(&results, outdir.is_none(), &mut failures, &mut skipped, &mut timeouts);
+        job_results.extend(results.iter().map(|(name, result)| (name.clone(), MachineResult::from_exec(name, result.as_ref()))));
+    }
+    let skipped = report_skipped(&skipped);
+    if !timeouts.is_empty() {
+        println!("Timed out on {} machines: {}", timeouts.len(), timeouts.join(", "));
+    }
+
+    let operation = JobOperation::Exec { command: command.clone(), timeout: args.value_of("timeout").map(String::from) };
+    let mut job = Job::new(config_file, operation, &machine_names);
+    job.duration = started.elapsed();
+    job.results = machine_names.iter()
+        .map(|name| job_results.remove(name).unwrap_or_else(|| MachineResult::from_exec(name, None)))
+        .collect();
+    record_job_or_warn(job);
+
+    let message = format!("`{}`: {} of {} machines failed", command, failures.len(), machine_names.len());
+    notify_done(&config_content.settings, args.is_present("notify"), started, "pssh exec", &message, failures.is_empty());
+    post_summary(&config_content.webhooks, &JobSummary {
+        operation: "exec".to_string(),
+        detail: command.clone(),
+        hosts: machine_names.clone(),
+        failures: failures.clone(),
+        duration: started.elapsed()
+    });
+
+    if !failures.is_empty() || skipped > 0 {
+        if skipped > 0 {
+            eprintln!("{} of {} machines failed, {} skipped.", failures.len(), machine_names.len(), skipped);
+        } else {
+            eprintln!("{} of {} machines failed.", failures.len(), machine_names.len());
+        }
+        process::exit(1);
+    }
+}
+
+/// Print the results of `exec`, collecting failed and skipped machines.
+///
+/// # Arguments
+///
+/// * `results` - Results, per machine
+/// * `show_output` - Print the command outputs
+/// * `failures` - Failed machines
+/// * `skipped` - Skipped machines
+/// * `timeouts` - Timed out machines, also failed
+///
+fn print_exec_results(results: &[(String, Option<Result<ExecResult, Error>>)], show_output: bool,
+                      failures: &mut Vec<String>, skipped: &mut Vec<String>, timeouts: &mut Vec<String>) {
+    for (name, result) in results {
+        match *result {
+            None => skipped.push(name.clone()),
+            Some(Ok(ref output)) => {
+                println!("[{}] {}", name, output.describe());
+                if show_output {
+                    print!("{}", output.stdout);
+                    eprint!("{}", output.stderr);
+                }
+                if !output.success() {
+                    failures.push(name.clone());
+                }
+                if output.timed_out {
+                    timeouts.push(name.clone());
+                }
+            },
+            Some(Err(ref error)) => {
+                println!("[{}] {}", name, error);
+                failures.push(name.clone());
+            }
+        }
+    }
+}
+
+fn handle_backup(config_file: Option<&str>, args: &ArgMatches) {
+    let machine = args.value_of("machine").unwrap();
+    let remote_path = args.value_of("path").unwrap();
+    let dir = Path::new(args.value_of("dest").unwrap_or("."));
+    let keep: Option<usize> = match args.value_of("keep").map(|x| x.parse()) {
+        None => None,
+        Some(Ok(x)) if x > 0 => Some(x),
+        Some(_) => {
+            println!("Bad archive count: `{}`.", args.value_of("keep").unwrap());
+            return;
+        }
+    };
+
+    let config_content = load_configuration_or_exit(config_file);
+    let machine_config = match config_content.machine_values.get(machine) {
+        Some(x) => x,
+        None => {
+            println!("Config `{}` does not exist.", machine);
+            return;
+        }
+    };
+    if !has_values(machine, machine_config) {
+        return;
+    }
+
+    let archive = archive_path(dir, machine, remote_path, chrono::Local::now());
+    audit::record(machine, "backup", &format!("{} -> {}", remote_path, archive.display()));
+    match backup_path(machine_config, remote_path, &archive) {
+        Ok(size) => println!("Saved {} ({}).", archive.display(), format_size(size.div_ceil(1024))),
+        Err(error) => exit_with(&error)
+    }
+
+    if let Some(keep) = keep {
+        match rotate_archives(dir, machine, remote_path, keep) {
+            Ok(removed) => for path in removed {
+                println!("Removed {}.", path.display());
+            },
+            Err(error) => exit_with(&error)
+        }
+    }
+}
+
+fn handle_cmp(config_file: Option<&str>, args: &ArgMatches) {
+    let machine = args.value_of("machine").unwrap();
+    let local = Path::new(args.value_of("local").unwrap());
+    let remote = args.value_of("remote").unwrap();
+
+    let config_content = load_configuration_or_exit(config_file);
+    let machine_config = match config_content.machine_values.get(machine) {
+        Some(x) => x,
+        None => {
+            println!("Config `{}` does not exist.", machine);
+            process::exit(2);
+        }
+    };
+    if !has_values(machine, machine_config) {
+        process::exit(2);
+    }
+
+    let local_digest = local_digest(local).unwrap_or_else(|error| {
+        println!("{}", error);
+        process::exit(2)
+    });
+    let remote_digest = match remote_digest(machine_config, remote) {
+        Ok(Some(x)) => x,
+        Ok(None) => {
+            println!("`{}:{}` does not exist.", machine, remote);
+            process::exit(2);
+        },
+        Err(error) => {
+            println!("{}", error);
+            process::exit(2);
+        }
+    };
+
+    if local_digest == remote_digest {
+        println!("Identical: {} bytes, sha256 {}.", local_digest.size, local_digest.sha256);
+    } else {
+        println!("Different:");
+        println!("  {:>10} bytes  sha256 {}  {}", local_digest.size, local_digest.sha256, local.display());
+        println!("  {:>10} bytes  sha256 {}  {}:{}", remote_digest.size, remote_digest.sha256, machine, remote);
+        process::exit(1);
+    }
+}
+
+fn handle_owner(config_file: Option<&str>, machine: &str) {
+    let config_content = load_configuration_or_exit(config_file);
+    let machine_config = match config_content.machine_values.get(machine) {
+        Some(x) => x,
+        None => {
+            println!("Config `{}` does not exist.", machine);
+            return;
+        }
+    };
+
+    match (&machine_config.owner, &machine_config.contact) {
+        (Some(owner), Some(contact)) => println!("{} ({})", owner, contact),
+        (Some(owner), None) => println!("{}", owner),
+        (None, Some(contact)) => println!("No owner, contact: {}", contact),
+        (None, None) => {
+            println!("`{}` has no owner.", machine);
+            process::exit(1);
+        }
+    }
+}
+
+fn handle_maintenance(config_file: Option<&str>, args: &ArgMatches) {
+    let machine = args.value_of("machine").unwrap();
+    let enabled = args.value_of("mode") == Some("on");
+    let reason = args.value_of("reason").map(String::from);
+
+    let config_content = load_configuration_or_exit(config_file);
+    if !config_content.machine_values.contains_key(machine) {
+        println!("Config `{}` does not exist.", machine);
+        return;
+    }
+
+    let mut changed = false;
+    StateStore::update_default(|state| changed = state.set_maintenance(machine, enabled, reason));
+    audit::record(machine, "maintenance", if enabled { "on" } else { "off" });
+
+    match (enabled, changed) {
+        (true, true) => println!("`{}` is now in maintenance.", machine),
+        (true, false) => println!("`{}` is already in maintenance.", machine),
+        (false, true) => println!("`{}` is out of maintenance.", machine),
+        (false, false) => println!("`{}` is not in maintenance.", machine)
+    }
+}
+
+fn handle_file_audit(config_file: Option<&str>, args: &ArgMatches) {
+    let selector = args.value_of("selector").unwrap();
+    let remote_path = args.value_of("path").unwrap();
+    let probe = match args.value_of("grep") {
+        Some(pattern) => AuditProbe::Grep(pattern.to_string()),
+        None if args.is_present("hash") => AuditProbe::Hash,
+        None => AuditProbe::Presence
+    };
+    let rolling: usize = match args.value_of("rolling").map(|x| x.parse()) {
+        None => DEFAULT_PARALLELISM,
+        Some(Ok(x)) => x,
+        Some(Err(_)) => {
+            println!("Bad rolling count: `{}`.", args.value_of("rolling").unwrap());
+            return;
+        }
+    };
+
+    let config_content = load_configuration_or_exit(config_file);
+    let machine_names = select_machines(selector, &config_content.machine_values).unwrap_or_else(|error| exit_with(&error));
+    let machine_names = match filter_selection(args, &config_content, machine_names) {
+        Some(x) => x,
+        None => return
+    };
+
+    if machine_names.is_empty() {
+        println!("No machine matches `{}`.", selector);
+        return;
+    }
+
+    let results = run_parallel(&machine_names, rolling, |name| audit_file(&config_content.machine_values[name], remote_path, &probe));
+    println!("{}", render_audit(&results));
+
+    let versions = if probe == AuditProbe::Hash { render_versions(&results) } else { String::new() };
+    if !versions.is_empty() {
+        println!("\n{}", versions);
+    }
+
+    let failures = results.iter().filter(|x| x.1.is_err()).count();
+    if failures > 0 {
+        eprintln!("{} of {} machines failed.", failures, results.len());
+    }
+    if failures > 0 || !versions.is_empty() {
+        process::exit(1);
+    }
+}
+
+fn handle_restore(config_file: Option<&str>, args: &ArgMatches) {
+    let machine = args.value_of("machine").unwrap();
+    let archive = Path::new(args.value_of("archive").unwrap());
+    let remote_dir = args.value_of("dir").unwrap();
+
+    if !archive.is_file() {
+        println!("Archive `{}` does not exist.", archive.display());
+        return;
+    }
+
+    let config_content = load_configuration_or_exit(config_file);
+    let machine_config = match config_content.machine_values.get(machine) {
+        Some(x) => x,
+        None => {
+            println!("Config `{}` does not exist.", machine);
+            return;
+        }
+    };
+    if !has_values(machine, machine_config) || !check_protected(&config_content, &[machine.to_string()], args.is_present("force")) {
+        return;
+    }
+
+    if !args.is_present("yes") {
+        if !io::stdin().is_terminal() {
+            println!("Confirmation needed, use --yes to proceed.");
+            return;
+        }
+        if !confirm(&format!("Extract {} into {}:{}, replacing existing files?", archive.display(), machine, remote_dir)) {
+            println!("Aborted.");
+            return;
+        }
+    }
+
+    audit::record(machine, "restore", &format!("{} -> {}", archive.display(), remote_dir));
+    match restore_archive(machine_config, archive, remote_dir) {
+        Ok(()) => println!("Restored {} into {}:{}.", archive.display(), machine, remote_dir),
+        Err(error) => exit_with(&error)
+    }
+}
+
+fn handle_collect(config_file: Option<&str>, args: &ArgMatches) {
+    let selector = args.value_of("selector").unwrap();
+    let source = args.value_of("source").unwrap();
+    let local_dir = Path::new(args.value_of("destination").unwrap());
+    let rolling: usize = match args.value_of("rolling").map(|x| x.parse()) {
+        None => DEFAULT_PARALLELISM,
+        Some(Ok(x)) => x,
+        Some(Err(_)) => {
+            println!("Bad rolling count: `{}`.", args.value_of("rolling").unwrap());
+            return;
+        }
+    };
+
+    let config_content = load_configuration_or_exit(config_file);
+    let machine_names = select_machines(selector, &config_content.machine_values).unwrap_or_else(|error| exit_with(&error));
+    let machine_names = match filter_selection(args, &config_content, machine_names) {
+        Some(x) => x,
+        None => return
+    };
+
+    if machine_names.is_empty() {
+        println!("No machine matches `{}`.", selector);
+        return;
+    }
+
+    // Destinations are chosen upfront, so that suffixes do not depend on timing
+    let destinations: HashMap<String, PathBuf> = machine_names.iter().map(|name| {
+        let destination = collect_destination(local_dir, name, source, args.is_present("flatten"));
+        let destination = if args.is_present("overwrite") { destination } else { unused_path(&destination) };
+        (name.clone(), destination)
+    }).collect();
+
+    let started = Instant::now();
+    let results = run_parallel(&machine_names, rolling, |name| {
+        let config = &config_content.machine_values[name];
+        audit::record(name, "pull", &format!("{} -> {}", source, destinations[name].display()));
+        collect_path(config, source, &destinations[name])
+    });
+
+    let mut failures: Vec<String> = vec![];
+    for (name, result) in &results {
+        match *result {
+            Ok(()) => println!("[{}] {}", name, destinations[name].display()),
+            Err(ref error) => {
+                println!("[{}] {}", name, error);
+                failures.push(name.clone());
+            }
+        }
+    }
+
+    post_summary(&config_content.webhooks, &JobSummary {
+        operation: "collect".to_string(),
+        detail: source.to_string(),
+        hosts: machine_names.clone(),
+        failures: failures.clone(),
+        duration: started.elapsed()
+    });
+
+    if !failures.is_empty() {
+        eprintln!("{} of {} machines failed.", failures.len(), results.len());
+        process::exit(1);
+    }
+}
+
+fn handle_warm(config_file: Option<&str>, args: &ArgMatches) {
+    let selector = args.value_of("selector").unwrap();
+    let persist = match args.value_of("persist").map(parse_duration) {
+        None => DEFAULT_PERSIST,
+        Some(Some(x)) => x,
+        Some(None) => {
+            println!("Bad duration: `{}`.", args.value_of("persist").unwrap());
+            return;
+        }
+    };
+
+    let config_content = load_configuration_or_exit(config_file);
+    let machine_names: Vec<String> = select_machines(selector, &config_content.machine_values).unwrap_or_else(|error| exit_with(&error)).into_iter()
+        .filter(|name| has_values(name, &config_content.machine_values[name]))
+        .collect();
+    if machine_names.is_empty() {
+        println!("No machine matches `{}`.", selector);
+        return;
+    }
+
+    let stopping = args.is_present("stop");
+    let results = run_parallel(&machine_names, DEFAULT_PARALLELISM, |name| {
+        let config = &config_content.machine_values[name];
+        if stopping {
+            control::stop(config).map(|x| if x { "stopped" } else { "not running" })
+        } else {
+            control::warm(config, persist).map(|x| match x {
+                WarmOutcome::Started => "started",
+                WarmOutcome::Running => "already running"
+            })
+        }
+    });
+
+    let width = machine_names.iter().map(|x| x.len()).max().unwrap_or(0);
+    let mut failed = false;
+    for (name, result) in &results {
+        match *result {
+            Ok(state) => println!("{:width$}  {}", name, state, width = width),
+            Err(ref error) => {
+                failed = true;
+                println!("{:width$}  failed: {}", name, error, width = width);
+            }
+        }
+    }
+
+    if failed {
+        process::exit(1);
+    }
+}
+
+fn handle_daemon_start(config_file: Option<&str>, args: &ArgMatches) {
+    let warm = args.value_of("warm");
+    if args.is_present("foreground") {
+        if let Err(error) = daemon::serve(config_file, warm) {
+            exit_with(&error);
+        }
+        return;
+    }
+
+    match daemon::start(config_file, warm) {
+        Ok(pid) => println!("Daemon started (pid {}), logging to {}.", pid, daemon::get_log_path().display()),
+        Err(error) => exit_with(&error)
+    }
+}
+
+fn handle_daemon_stop() {
+    match daemon::stop() {
+        Ok(true) => println!("Daemon stopped."),
+        Ok(false) => println!("The daemon is not running."),
+        Err(error) => exit_with(&error)
+    }
+}
+
+fn handle_daemon_status() {
+    let status = match daemon::status() {
+        Some(x) => x,
+        None => {
+            println!("The daemon is not running.");
+            process::exit(1);
         }
+    };
+
+    println!("Daemon running (pid {}), started {}.", status.pid, format_age(chrono::Duration::from_std(status.uptime).unwrap_or_else(|_| chrono::Duration::zero())));
+    if let Some(ref selector) = status.warm {
+        println!("Keeping `{}` warm.", selector);
+    }
+    for path in &status.configurations {
+        println!("  {}", path);
     }
 }
 
-fn handle_list(config_file: Option<&str>) {
-    let config_content = load_configuration_file(config_file);
-    let mut machine_names: Vec<String> = config_content.machine_values.keys().cloned().collect();
-    machine_names.sort();
-    
-    for key in &machine_names {
-        println!("> {}", key);
+fn handle_serve(config_file: Option<&str>, args: &ArgMatches) {
+    let token = match args.value_of("token-env") {
+        None => None,
+        Some(name) => match env::var(name) {
+            Ok(ref x) if !x.is_empty() => Some(x.clone()),
+            _ => {
+                println!("Bad token variable: `{}` is not set.", name);
+                return;
+            }
+        }
+    };
+
+    let listen = args.value_of("listen").unwrap();
+    let options = ServerOptions {
+        config_file: config_file.map(String::from),
+        allow_exec: args.is_present("allow-exec"),
+        token
+    };
+    println!("Serving the inventory on http://{}", listen);
+    if let Err(error) = api::serve(listen, options) {
+        exit_with(&error);
+    }
+}
+
+fn handle_exporter(config_file: Option<&str>, args: &ArgMatches) {
+    let interval = match args.value_of("interval").map(parse_duration) {
+        None => EXPORTER_INTERVAL,
+        Some(Some(x)) if x > Duration::from_secs(0) => x,
+        Some(_) => {
+            println!("Bad interval: `{}`.", args.value_of("interval").unwrap());
+            return;
+        }
+    };
+
+    let listen = args.value_of("listen").unwrap();
+    println!("Serving metrics on http://{}/metrics", listen);
+    if let Err(error) = exporter::serve(listen, config_file, args.value_of("selector").unwrap(), interval) {
+        exit_with(&error);
+    }
+}
+
+fn handle_update(config_file: Option<&str>, args: &ArgMatches) {
+    let selector = args.value_of("selector").unwrap();
+    let rolling: usize = match args.value_of("rolling").map(|x| x.parse()) {
+        None => DEFAULT_PARALLELISM,
+        Some(Ok(x)) => x,
+        Some(Err(_)) => {
+            println!("Bad rolling count: `{}`.", args.value_of("rolling").unwrap());
+            process::exit(1);
+        }
+    };
+
+    let config_content = load_configuration_or_exit(config_file);
+    let machine_names = select_machines(selector, &config_content.machine_values).unwrap_or_else(|error| exit_with(&error));
+
+    if machine_names.is_empty() {
+        println!("No machine matches `{}`.", selector);
+        return;
+    }
+
+    let policy = match failure_policy(args, &config_content.settings) {
+        Some(x) => x,
+        None => process::exit(1)
+    };
+    let (batch_size, batch_delay) = match wave_values(args) {
+        Some(x) => x,
+        None => process::exit(1)
+    };
+    let machine_names = exclude_primaries_or_report(selector, &config_content, machine_names);
+    let machine_names = exclude_maintenance_or_report(machine_names);
+    let machine_names = order_selection(args, &config_content, machine_names);
+    let sudo = args.is_present("become");
+    let dry_run = args.is_present("dry-run");
+    if !dry_run && !check_protected(&config_content, &machine_names, args.is_present("force")) {
+        return;
+    }
+    let threshold = config_content.settings.confirm_threshold;
+    if !dry_run && !confirm_operation(&config_content, &machine_names, "upgraded", threshold, args.is_present("yes")) {
+        return;
+    }
+    let started = Instant::now();
+    let failed = |result: &UpdateResult| matches!(result.outcome, UpdateOutcome::Failed(_));
+    let results = run_in_waves(&machine_names, rolling, batch_size, batch_delay, policy, |name| {
+        if !dry_run {
+            audit::record(name, "update", if sudo { "upgrade packages (sudo)" } else { "upgrade packages" });
+        }
+        update_packages(&config_content.machine_values[name], sudo, dry_run)
+    }, failed);
+
+    let skipped: Vec<String> = results.iter().filter(|x| x.1.is_none()).map(|x| x.0.clone()).collect();
+    let results: Vec<_> = results.into_iter().filter_map(|(name, result)| result.map(|x| (name, x))).collect();
+
+    let width = machine_names.iter().map(|x| x.len()).max().unwrap_or(0);
+    for (name, result) in &results {
+        let manager = result.manager.map(|x| x.name()).unwrap_or("-");
+        println!("{:width$}  {:6}  {}", name, manager, result.describe(), width = width);
+
+        if let UpdateOutcome::Upgradable(ref packages) = result.outcome {
+            for package in packages {
+                println!("{:width$}    - {}", "", package, width = width);
+            }
+        }
+    }
+    let skipped = report_skipped(&skipped);
+    let failures: Vec<String> = results.iter()
+        .filter(|&(_, result)| failed(result))
+        .map(|(name, _)| name.clone())
+        .collect();
+
+    if !dry_run {
+        post_summary(&config_content.webhooks, &JobSummary {
+            operation: "update".to_string(),
+            detail: String::new(),
+            hosts: machine_names.clone(),
+            failures: failures.clone(),
+            duration: started.elapsed()
+        });
+    }
+
+    if !failures.is_empty() || skipped > 0 {
+        if skipped > 0 {
+            eprintln!("{} of {} machines failed, {} skipped.", failures.len(), machine_names.len(), skipped);
+        } else {
+            eprintln!("{} of {} machines failed.", failures.len(), machine_names.len());
+        }
+        process::exit(1);
+    }
+}
+
+fn handle_report(config_file: Option<&str>, args: &ArgMatches) {
+    let selector = args.value_of("selector").unwrap();
+    let sort = args.value_of("sort").unwrap();
+    let config_content = load_configuration_or_exit(config_file);
+    let machine_names = select_machines(selector, &config_content.machine_values).unwrap_or_else(|error| exit_with(&error));
+    let machine_names = match filter_selection(args, &config_content, machine_names) {
+        Some(x) => x,
+        None => return
+    };
+    let machine_names = exclude_maintenance_or_report(machine_names);
+
+    if machine_names.is_empty() {
+        println!("No machine matches `{}`.", selector);
+        return;
+    }
+
+    let mut rows = run_parallel(&machine_names, DEFAULT_PARALLELISM, |name| {
+        gather(&config_content.machine_values[name])
+    });
+    sort_rows(&mut rows, ReportSort::from_name(sort).unwrap());
+
+    StateStore::update_default(|state| {
+        for (name, result) in &rows {
+            state.record_check(name, result.as_ref().err().map(|x| x.to_string()));
+        }
+    });
+
+    if args.is_present("json") {
+        println!("{}", to_json(&rows));
+    } else {
+        print!("{}", render_table(&rows, io::stdout().is_terminal()));
+    }
+}
+
+fn handle_check(config_file: Option<&str>, args: &ArgMatches) {
+    let selector = args.value_of("selector").unwrap();
+    let interval = match args.value_of("interval").map(parse_duration) {
+        None => CHECK_INTERVAL,
+        Some(Some(x)) if x > Duration::from_secs(0) => x,
+        Some(_) => {
+            println!("Bad interval: `{}`.", args.value_of("interval").unwrap());
+            return;
+        }
+    };
+
+    let config_content = load_configuration_or_exit(config_file);
+    let machine_names = select_machines(selector, &config_content.machine_values).unwrap_or_else(|error| exit_with(&error));
+    let machine_names = match filter_selection(args, &config_content, machine_names) {
+        Some(x) => x,
+        None => return
+    };
+    let machine_names = exclude_maintenance_or_report(machine_names);
+    if machine_names.is_empty() {
+        println!("No machine matches `{}`.", selector);
+        return;
+    }
+
+    let (watch, hook) = (args.is_present("watch"), args.value_of("on-change"));
+    let in_place = io::stdout().is_terminal();
+    let mut previous: HashMap<String, Check> = HashMap::new();
+    let mut history: Vec<String> = vec![];
+    loop {
+        let rows = run_parallel(&machine_names, DEFAULT_PARALLELISM, |name| {
+            check_machine(&config_content.machine_values[name], CHECK_TIMEOUT)
+        });
+        StateStore::update_default(|state| {
+            for (name, check) in &rows {
+                if check.state != Reachability::Unknown {
+                    state.record_probe(name, check.state == Reachability::Up);
+                }
+            }
+        });
+
+        if !watch {
+            if args.is_present("oneline") {
+                print!("{}", render_oneline(&rows, &config_content.machine_values));
+            } else {
+                print!("{}", render_checks(&rows, in_place));
+            }
+            if rows.iter().any(|x| x.1.state != Reachability::Up) {
+                process::exit(1);
+            }
+            return;
+        }
+
+        let now = chrono::Local::now();
+        let changes = detect_changes(&previous, &rows);
+        for change in &changes {
+            history.push(render_change(change, now));
+            if let Some(hook) = hook {
+                let vars = config_content.machine_values[&change.machine].vars.clone().unwrap_or_default();
+                if let Err(error) = run_hook(hook, change, &vars) {
+                    history.push(format!("[{}] {}", now.format("%H:%M:%S"), error));
+                }
+            }
+        }
+
+        // Terminals show the latest checks in place, other outputs get the
+        // first checks, then the changes
+        if in_place {
+            let start = history.len().saturating_sub(CHECK_HISTORY);
+            print!("{}Every {}, last check at {}\n\n{}", CLEAR_SCREEN, args.value_of("interval").unwrap_or("30s"), now.format("%H:%M:%S"), render_checks(&rows, true));
+            if !history.is_empty() {
+                println!("\n{}", history[start..].join("\n"));
+            }
+        } else if previous.is_empty() {
+            print!("{}", render_checks(&rows, false));
+        } else {
+            for line in history.drain(..) {
+                println!("{}", line);
+            }
+        }
+        io::stdout().flush().ok();
+
+        previous = rows.into_iter().collect();
+        thread::sleep(interval);
     }
 }
 
-fn handle_show(config_file: Option<&str>, machine: &str) {
-    let config_content = load_configuration_file(config_file);
+fn handle_console(config_file: Option<&str>, machine: &str) {
+    let config_content = load_configuration_or_exit(config_file);
     let machine_config = config_content.machine_values.get(machine);
-    
+
     if machine_config.is_none() {
         println!("Config `{}` does not exist.", machine);
         return;
     }
-    
+
+    let console_config = match machine_config.unwrap().console {
+        Some(ref x) => x,
+        None => {
+            println!("Machine `{}` has no console configured.", machine);
+            return;
+        }
+    };
+
+    let command = console(console_config);
+    audit::record(machine, "console", &command.to_string());
+    execute_or_exit(command);
+}
+
+/// Install the exported SSH configuration, for tools connecting with the
+/// SSH client
+///
+/// # Arguments
+///
+/// * `config_path` - Configuration file path
+/// * `machines` - Machines
+///
+fn export_machines(config_path: &str, machines: &ConfigMap) {
+    let ssh_dir = env::home_dir().unwrap_or_default().join(".ssh");
+    match install_ssh_config(&ssh_dir, &render_ssh_config(machines, config_path)) {
+        Ok((_, true)) => println!("Included exported machines from {}.", ssh_dir.join("config").display()),
+        Ok((_, false)) => (),
+        Err(error) => exit_with(&error)
+    }
+}
+
+fn handle_git_remote(config_file: Option<&str>, args: &ArgMatches) {
+    let machine = args.value_of("machine").unwrap();
+    let config_path = get_configuration_path(config_file);
+    let config_content = load_configuration_or_exit(Some(&config_path));
+    let machine_config = config_content.machine_values.get(machine);
+
+    if machine_config.is_none() {
+        println!("Config `{}` does not exist.", machine);
+        return;
+    }
+
     let machine_config = machine_config.unwrap();
-    machine_config.show_info(machine);
+    if !has_values(machine, machine_config) {
+        return;
+    }
+
+    let ssh_config = args.is_present("ssh-config") || needs_ssh_config(machine_config);
+    if ssh_config {
+        export_machines(&config_path, &config_content.machine_values);
+    }
+
+    let url = git_url(machine, machine_config, args.value_of("path").unwrap(), ssh_config);
+    let name = args.value_of("name").map(String::from).unwrap_or_else(|| host_alias(machine));
+    match repo::set_remote(Path::new("."), &name, &url) {
+        Ok(true) => println!("Added remote `{}`: {}", name, url),
+        Ok(false) => println!("Updated remote `{}`: {}", name, url),
+        Err(error) => exit_with(&error)
+    }
 }
 
-fn handle_pull(config_file: Option<&str>, machine: &str, source: &str, destination: &str) {
-    let config_content = load_configuration_file(config_file);
-    let machine_config = config_content.machine_values.get(machine);    
-    
+fn handle_code(config_file: Option<&str>, machine: &str, path: Option<&str>) {
+    let config_path = get_configuration_path(config_file);
+    let config_content = load_configuration_or_exit(Some(&config_path));
+    let machine_config = config_content.machine_values.get(machine);
+
     if machine_config.is_none() {
         println!("Config `{}` does not exist.", machine);
         return;
     }
-    
-    let command = scp(machine_config.unwrap(), source, destination, ScpDirection::Pull);
-    execute(command, "Failed to execute scp");
+
+    if !has_values(machine, machine_config.unwrap()) {
+        return;
+    }
+
+    // VS Code connects through the SSH client configuration
+    export_machines(&config_path, &config_content.machine_values);
+
+    let command = vscode(&host_alias(machine), path);
+    audit::record(machine, "code", &command.to_string());
+    execute_or_exit(command);
 }
 
-fn handle_push(config_file: Option<&str>, machine: &str, source: &str, destination: &str) {
-    let config_content = load_configuration_file(config_file);
+fn handle_clip(config_file: Option<&str>, args: &ArgMatches, send: bool) {
+    let machine = args.value_of("machine").unwrap();
+    let config_content = load_configuration_or_exit(config_file);
     let machine_config = config_content.machine_values.get(machine);
-        
+
     if machine_config.is_none() {
         println!("Config `{}` does not exist.", machine);
         return;
     }
 
-    let command = scp(machine_config.unwrap(), source, destination, ScpDirection::Push);
-    execute(command, "Failed to execute scp");
+    let target = if args.is_present("x11") {
+        ClipTarget::X11
+    } else {
+        ClipTarget::File(args.value_of("file").unwrap().to_string())
+    };
+
+    let command = if send {
+        let command = ssh_remote(machine_config.unwrap(), &target.send_command());
+        audit::record(machine, "clip send", &command.to_string());
+        command
+    } else {
+        ssh_remote(machine_config.unwrap(), &target.get_command())
+    };
+
+    if !execute_or_exit(command).success() {
+        process::exit(1);
+    }
 }
 
-fn handle_ping(config_file: Option<&str>, machine: &str) {
-    let config_content = load_configuration_file(config_file);
+fn handle_top(config_file: Option<&str>, machine: &str, overrides: &MachineConfig) {
+    let mut config_content = load_configuration_or_exit(config_file);
+    config_content.add_ad_hoc_machine(machine).unwrap_or_else(|error| exit_with(&error));
     let machine_config = config_content.machine_values.get(machine);
-    
+
     if machine_config.is_none() {
         println!("Config `{}` does not exist.", machine);
         return;
     }
-    
-    let command = ping(machine_config.as_ref().unwrap().ip.as_ref().unwrap());
-    execute(command, "Failed to execute ping");
+
+    let machine_config = machine_config.unwrap().merge(overrides);
+    if !has_values(machine, &machine_config) {
+        return;
+    }
+
+    let command = ssh_tty(&machine_config, TOP_COMMAND);
+    audit::record(machine, "top", &command.to_string());
+    execute_or_exit(command);
 }
 
-fn handle_connect(config_file: Option<&str>, machine: &str, user: Option<&str>, tmux: bool) {
-    let config_content = load_configuration_file(config_file);
+fn handle_ps(config_file: Option<&str>, machine: &str, pattern: Option<&str>) {
+    let config_content = load_configuration_or_exit(config_file);
+    let machine_config = config_content.machine_values.get(machine);
+
+    if machine_config.is_none() {
+        println!("Config `{}` does not exist.", machine);
+        return;
+    }
+
+    match list_processes(machine_config.unwrap()) {
+        Ok(processes) => {
+            let processes = match pattern {
+                Some(pattern) => match_processes(processes, pattern),
+                None => processes
+            };
+            println!("{}", render_processes(&processes));
+        },
+        Err(error) => {
+            println!("Could not list processes of `{}`: {}", machine, error);
+            process::exit(error.exit_code());
+        }
+    }
+}
+
+fn handle_kill(config_file: Option<&str>, args: &ArgMatches) {
+    let machine = args.value_of("machine").unwrap();
+    let pattern = args.value_of("pattern").unwrap();
+    let signal = match parse_signal(args.value_of("signal").unwrap()) {
+        Some(x) => x,
+        None => {
+            println!("Bad signal: `{}`.", args.value_of("signal").unwrap());
+            return;
+        }
+    };
+
+    let config_content = load_configuration_or_exit(config_file);
+    let machine_config = match config_content.machine_values.get(machine) {
+        Some(x) => x,
+        None => {
+            println!("Config `{}` does not exist.", machine);
+            return;
+        }
+    };
+
+    if !check_protected(&config_content, &[machine.to_string()], args.is_present("force")) {
+        return;
+    }
+
+    let processes = match list_processes(machine_config) {
+        Ok(x) => match_processes(x, pattern),
+        Err(error) => {
+            println!("Could not list processes of `{}`: {}", machine, error);
+            process::exit(error.exit_code());
+        }
+    };
+
+    if processes.is_empty() {
+        println!("No process of `{}` matches `{}`.", machine, pattern);
+        return;
+    }
+
+    println!("The following processes will receive SIG{}:", signal);
+    println!("{}", render_processes(&processes));
+
+    if !args.is_present("yes") {
+        if !io::stdin().is_terminal() {
+            println!("Confirmation needed, use --yes to proceed.");
+            return;
+        }
+        if !confirm("Continue?") {
+            println!("Aborted.");
+            return;
+        }
+    }
+
+    let pids: Vec<u32> = processes.iter().map(|x| x.pid).collect();
+    let pid_list: Vec<String> = pids.iter().map(|x| x.to_string()).collect();
+    audit::record(machine, "kill", &format!("kill -s {} {}", signal, pid_list.join(" ")));
+
+    match kill_processes(machine_config, &pids, &signal) {
+        Ok(ref result) if result.success() => println!("Signaled {} process(es).", pids.len()),
+        Ok(result) => {
+            println!("Could not signal processes of `{}`: {}: {}", machine, result.describe(), result.stderr.trim());
+            process::exit(1);
+        },
+        Err(error) => {
+            println!("Could not signal processes of `{}`: {}", machine, error);
+            process::exit(error.exit_code());
+        }
+    }
+}
+
+fn handle_envdiff(config_file: Option<&str>, args: &ArgMatches) {
+    let width = match args.value_of("width").map(String::from).or_else(|| env::var("COLUMNS").ok()) {
+        Some(width) => match width.parse() {
+            Ok(x) => x,
+            Err(_) => {
+                println!("Bad width: `{}`.", width);
+                return;
+            }
+        },
+        None => ENVDIFF_WIDTH
+    };
+
+    let config_content = load_configuration_or_exit(config_file);
+    let names = vec![args.value_of("left").unwrap().to_string(), args.value_of("right").unwrap().to_string()];
+    for name in &names {
+        if !config_content.machine_values.contains_key(name) {
+            println!("Config `{}` does not exist.", name);
+            return;
+        }
+    }
+
+    let command = args.value_of("command").unwrap();
+    let results = run_parallel(&names, 2, |name| {
+        audit::record(name, "envdiff", command);
+        exec_command(&config_content.machine_values[name], command)
+    });
+
+    let mut outputs = vec![];
+    for (name, result) in results {
+        match result {
+            Ok(ref result) if result.success() => outputs.push(result.stdout.clone()),
+            Ok(result) => {
+                println!("Command failed on `{}` ({}): {}", name, result.describe(), result.stderr.trim());
+                process::exit(1);
+            },
+            Err(error) => {
+                println!("Could not run the command on `{}`: {}", name, error);
+                process::exit(error.exit_code());
+            }
+        }
+    }
+
+    let lines = diff_lines(&outputs[0], &outputs[1]);
+    let headers = (&names[0][..], &names[1][..]);
+    println!("{}", render_side_by_side(&lines, headers, width, args.is_present("all"), io::stdout().is_terminal()));
+}
+
+fn handle_consensus(config_file: Option<&str>, args: &ArgMatches) {
+    let selector = args.value_of("selector").unwrap();
+    let rolling: usize = match args.value_of("rolling").map(|x| x.parse()) {
+        None => DEFAULT_PARALLELISM,
+        Some(Ok(x)) => x,
+        Some(Err(_)) => {
+            println!("Bad rolling count: `{}`.", args.value_of("rolling").unwrap());
+            return;
+        }
+    };
+    let command: Vec<&str> = args.values_of("command").unwrap().collect();
+    let command = command.join(" ");
+
+    let config_content = load_configuration_or_exit(config_file);
+    let machine_names = select_machines(selector, &config_content.machine_values).unwrap_or_else(|error| exit_with(&error));
+    let machine_names = match filter_selection(args, &config_content, machine_names) {
+        Some(x) => x,
+        None => return
+    };
+    let machine_names = exclude_primaries_or_report(selector, &config_content, machine_names);
+    let machine_names = exclude_maintenance_or_report(machine_names);
+
+    if machine_names.is_empty() {
+        println!("No machine matches `{}`.", selector);
+        return;
+    }
+
+    // The command is arbitrary: it gets the confirmations of `exec`
+    if !check_protected(&config_content, &machine_names, args.is_present("force")) {
+        return;
+    }
+    let threshold = config_content.settings.confirm_threshold;
+    let action = format!("affected by `{}`", command);
+    if !confirm_operation(&config_content, &machine_names, &action, threshold, args.is_present("yes")) {
+        return;
+    }
+
+    let results = run_parallel(&machine_names, rolling, |name| {
+        audit::record(name, "consensus", &command);
+        exec_command(&config_content.machine_values[name], &command)
+    });
+
+    let mut outputs = vec![];
+    let mut failures = vec![];
+    for (name, result) in results {
+        match result {
+            // A failing command is a divergence too
+            Ok(ref result) if result.success() => outputs.push((name, result.stdout.clone())),
+            Ok(result) => outputs.push((name, format!("{}[{}]\n", result.stdout, result.describe()))),
+            Err(error) => failures.push(format!("[{}] {}", name, error))
+        }
+    }
+
+    let groups = group_outputs(&outputs);
+    if !groups.is_empty() {
+        println!("{}", render_groups(&groups, io::stdout().is_terminal()));
+    }
+
+    for failure in &failures {
+        eprintln!("{}", failure);
+    }
+    if groups.len() > 1 || !failures.is_empty() {
+        process::exit(1);
+    }
+}
+
+fn handle_du(config_file: Option<&str>, machine: &str, path: &str, top: &str) {
+    let top: usize = match top.parse() {
+        Ok(x) => x,
+        Err(_) => {
+            println!("Bad directory count: `{}`.", top);
+            return;
+        }
+    };
+
+    let config_content = load_configuration_or_exit(config_file);
+    let machine_config = config_content.machine_values.get(machine);
+
+    if machine_config.is_none() {
+        println!("Config `{}` does not exist.", machine);
+        return;
+    }
+
+    match gather_disk_usage(machine_config.unwrap(), path) {
+        Ok(report) => println!("{}", report.render(top)),
+        Err(error) => {
+            println!("Could not get disk usage of `{}`: {}", machine, error);
+            process::exit(error.exit_code());
+        }
+    }
+}
+
+fn handle_speedtest(config_file: Option<&str>, machine: &str, size: &str) {
+    let size: u64 = match size.parse::<u64>().map(|x| x.checked_mul(1024 * 1024)) {
+        Ok(Some(x)) => x,
+        Ok(None) => {
+            println!("Size `{}` is too large.", size);
+            process::exit(1);
+        },
+        Err(_) => {
+            println!("Bad size: `{}`.", size);
+            process::exit(1);
+        }
+    };
+
+    let config_content = load_configuration_or_exit(config_file);
+    let machine_config = config_content.machine_values.get(machine);
+
+    if machine_config.is_none() {
+        println!("Config `{}` does not exist.", machine);
+        return;
+    }
+
+    let machine_config = machine_config.unwrap();
+    if !has_values(machine, machine_config) {
+        return;
+    }
+
+    let result = speedtest(machine_config, size).unwrap_or_else(|error| exit_with(&error));
+    result.show_info(machine);
+}
+
+fn handle_connect(config_file: Option<&str>, machine: &str, overrides: &MachineConfig, tmux: bool, restricted: bool) {
+    let mut config_content = load_configuration_or_exit(config_file);
+    let ad_hoc = config_content.add_ad_hoc_machine(machine).unwrap_or_else(|error| exit_with(&error));
     let machine_config = config_content.machine_values.get(machine);
     
     if machine_config.is_none() {
         println!("Config `{}` does not exist.", machine);
         return;
     }
+
+    let machine_config = machine_config.unwrap().merge(overrides);
+    if !has_values(machine, &machine_config) {
+        return;
+    }
+
+    if let Some(ref banner) = machine_config.banner {
+        println!("{}", paint_banner(banner, machine_config.is_protected(), io::stdout().is_terminal()));
+    }
+
+    if machine_config.is_expired(Local::now().date_naive()) {
+        let expired = format!("`{}` expired on {}", machine, machine_config.expires.as_ref().unwrap());
+        if !io::stdin().is_terminal() {
+            println!("{}.", expired);
+            return;
+        }
+        if !confirm(&format!("{}, connect anyway?", expired)) {
+            println!("Aborted.");
+            return;
+        }
+    }
+
+    if machine_config.is_protected() {
+        let answer = ask(&format!("`{}` is protected, type its name to connect", machine), "");
+        if answer != machine {
+            println!("Aborted.");
+            return;
+        }
+    }
+
+    if let Some(ref vpn) = machine_config.vpn {
+        if !is_reachable(&machine_config) && !vpn_is_up(vpn) {
+            let unreachable = format!("`{}` is unreachable and its VPN is down", machine);
+            if !io::stdin().is_terminal() {
+                warn!("{}.", unreachable);
+            } else if confirm(&format!("{}, run `{}`?", unreachable, vpn.up)) {
+                bring_up(machine, &machine_config);
+            }
+        }
+    }
         
-    let command = ssh(machine_config.unwrap(), user, tmux);
-    execute(command, "Failed to execute ssh");
+    let command = if restricted {
+        ssh_restricted(&machine_config, None)
+    } else {
+        ssh(&machine_config, None, tmux)
+    };
+    audit::record(machine, "connect", &command.to_string());
+
+    let title_format = &config_content.settings.title_format;
+    let titled = !title_format.is_empty() && io::stdout().is_terminal();
+    if titled {
+        print!("{}", set_title(&render_title(title_format, machine, &machine_config)));
+        io::stdout().flush().ok();
+    }
+    let status = match machine_config.expect {
+        Some(ref steps) if !steps.is_empty() => run_expect(&command, steps),
+        _ => execute(command)
+    };
+    if titled {
+        print!("{}", RESTORE_TITLE);
+        io::stdout().flush().ok();
+    }
+    let status = status.unwrap_or_else(|error| exit_with(&error));
+
+    // Exit code 255 is reserved to SSH errors, anything else comes from the remote shell
+    if status.code() == Some(255) {
+        return;
+    }
+
+    if ad_hoc {
+        offer_to_save(config_file, machine);
+    } else {
+        StateStore::update_default(|state| state.record_connect(machine));
+    }
 }
@@ -0,0 +1,184 @@
+//! Connection throughput measurement
+
+use std::io::{self, Write};
+use std::process::Stdio;
+use std::time::{Duration, Instant};
+
+use config::MachineConfig;
+use error::Error;
+use wrapper::ssh_remote;
+
+/// Size of the chunks written to the connection
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Speedtest result
+#[derive(Debug)]
+pub struct SpeedtestResult {
+    /// Number of bytes transferred in each direction
+    pub size: u64,
+    /// Connection setup time
+    pub latency: Duration,
+    /// Upload time (host -> machine), without connection setup
+    pub upload: Duration,
+    /// Download time (machine -> host), without connection setup
+    pub download: Duration
+}
+
+impl SpeedtestResult {
+    /// Show speedtest result to stdout.
+    ///
+    /// # Arguments
+    ///
+    /// * `machine` - Machine name
+    ///
+    pub fn show_info(&self, machine: &str) {
+        println!("Speedtest for `{}` ({}):", machine, format_size(self.size));
+        println!("  Latency: {} ms", self.latency.as_millis());
+        println!("  Upload: {}", format_rate(self.size, self.upload));
+        println!("  Download: {}", format_rate(self.size, self.download));
+    }
+}
+
+/// Stream data through a SSH connection in both directions.
+///
+/// The connection setup time is measured first with a no-op command, and
+/// subtracted from each transfer time.
+///
+/// # Arguments
+///
+/// * `config` - Machine configuration
+/// * `size` - Number of bytes to transfer in each direction
+///
+pub fn speedtest(config: &MachineConfig, size: u64) -> Result<SpeedtestResult, Error> {
+    let latency = measure_latency(config)?;
+    let upload = measure_upload(config, size)?;
+    let download = measure_download(config, size)?;
+
+    Ok(SpeedtestResult {
+        size,
+        latency,
+        upload: upload.checked_sub(latency).unwrap_or(upload),
+        download: download.checked_sub(latency).unwrap_or(download)
+    })
+}
+
+/// Format a byte count in a human-readable way
+///
+/// # Arguments
+///
+/// * `size` - Byte count
+///
+pub fn format_size(size: u64) -> String {
+    const UNITS: [&str; 4] = ["B", "KB", "MB", "GB"];
+
+    let mut value = size as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+
+    if unit == 0 {
+        format!("{} {}", size, UNITS[unit])
+    } else {
+        format!("{:.2} {}", value, UNITS[unit])
+    }
+}
+
+/// Format a transfer rate in a human-readable way
+///
+/// # Arguments
+///
+/// * `size` - Transferred byte count
+/// * `elapsed` - Transfer time
+///
+pub fn format_rate(size: u64, elapsed: Duration) -> String {
+    let seconds = elapsed.as_secs_f64();
+    if seconds <= 0.0 {
+        return "n/a".to_string();
+    }
+
+    format!("{}/s", format_size((size as f64 / seconds) as u64))
+}
+
+fn measure_latency(config: &MachineConfig) -> Result<Duration, Error> {
+    let start = Instant::now();
+    let status = ssh_remote(config, "true").interactive_command().status().map_err(|e| Error::io("failed to execute ssh", e))?;
+    check_status(status.success())?;
+
+    Ok(start.elapsed())
+}
+
+fn measure_upload(config: &MachineConfig, size: u64) -> Result<Duration, Error> {
+    let mut command = ssh_remote(config, "cat > /dev/null").interactive_command();
+    command.stdin(Stdio::piped());
+
+    let start = Instant::now();
+    let mut child = command.spawn().map_err(|e| Error::io("failed to execute ssh", e))?;
+    let written = {
+        let stdin = child.stdin.as_mut().unwrap();
+        let chunk = [0u8; CHUNK_SIZE];
+        let mut remaining = size;
+        let mut result = Ok(());
+
+        while remaining > 0 && result.is_ok() {
+            let length = remaining.min(CHUNK_SIZE as u64) as usize;
+            result = stdin.write_all(&chunk[..length]);
+            remaining -= length as u64;
+        }
+        result
+    };
+    drop(child.stdin.take());
+
+    // A connection closed early is reported by the exit status
+    let status = child.wait().map_err(|e| Error::io("failed to wait on ssh", e))?;
+    check_status(status.success())?;
+    written.map_err(|e| Error::io("failed to write to ssh", e))?;
+
+    Ok(start.elapsed())
+}
+
+fn measure_download(config: &MachineConfig, size: u64) -> Result<Duration, Error> {
+    let mut command = ssh_remote(config, &format!("head -c {} /dev/zero", size)).interactive_command();
+    command.stdout(Stdio::piped());
+
+    let start = Instant::now();
+    let mut child = command.spawn().map_err(|e| Error::io("failed to execute ssh", e))?;
+    let received = io::copy(child.stdout.as_mut().unwrap(), &mut io::sink());
+
+    let status = child.wait().map_err(|e| Error::io("failed to wait on ssh", e))?;
+    check_status(status.success())?;
+    let received = received.map_err(|e| Error::io("failed to read from ssh", e))?;
+    if received != size {
+        return Err(Error::Transport(format!("speedtest failed: received {} of {} bytes", received, size)));
+    }
+
+    Ok(start.elapsed())
+}
+
+fn check_status(success: bool) -> Result<(), Error> {
+    if success {
+        Ok(())
+    } else {
+        Err(Error::Transport("speedtest failed: remote command did not complete".to_string()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn sizes() {
+        assert_eq!(format_size(512), "512 B");
+        assert_eq!(format_size(2048), "2.00 KB");
+        assert_eq!(format_size(10 * 1024 * 1024), "10.00 MB");
+    }
+
+    #[test]
+    fn rates() {
+        assert_eq!(format_rate(1024 * 1024, Duration::from_secs(1)), "1.00 MB/s");
+        assert_eq!(format_rate(1024 * 1024, Duration::from_millis(500)), "2.00 MB/s");
+        assert_eq!(format_rate(1024, Duration::from_secs(0)), "n/a");
+    }
+}
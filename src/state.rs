@@ -0,0 +1,557 @@
+//! Local state
+//!
+//! Files managed by pssh live in `~/.pssh/state/`, outside of the user
+//! configuration file. Concurrent pssh invocations are serialized with lock
+//! files, and state files are replaced atomically.
+//!
+//! Per-machine metadata (last connection, last check) is kept in
+//! `~/.pssh/state/machines.yml`.
+
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, prelude::*};
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, Duration, Utc};
+use fs2::FileExt;
+use yaml_rust::{Yaml, YamlEmitter, YamlLoader};
+use yaml_rust::yaml::Hash;
+
+use config::get_user_configuration_dir;
+
+/// Result of the last check of a machine
+#[derive(Debug, Clone, PartialEq)]
+pub struct CheckResult {
+    /// Check time
+    pub time: DateTime<Utc>,
+    /// Error message, if the check failed
+    pub error: Option<String>
+}
+
+/// Result of the last reachability probe of a machine
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProbeResult {
+    /// Probe time
+    pub time: DateTime<Utc>,
+    /// Whether the SSH port was reachable
+    pub reachable: bool
+}
+
+/// Planned downtime of a machine
+#[derive(Debug, Clone, PartialEq)]
+pub struct Maintenance {
+    /// Time the machine entered maintenance
+    pub since: DateTime<Utc>,
+    /// Reason, as shown by `list`
+    pub reason: Option<String>
+}
+
+/// Persisted metadata of a machine
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MachineState {
+    /// Last time the machine answered, to any operation
+    pub last_seen: Option<DateTime<Utc>>,
+    /// Last successful interactive connection
+    pub last_connected: Option<DateTime<Utc>>,
+    /// Last check result
+    pub last_check: Option<CheckResult>,
+    /// Last reachability probe
+    pub last_probe: Option<ProbeResult>,
+    /// Ongoing maintenance, skipped by checks and reports
+    pub maintenance: Option<Maintenance>
+}
+
+/// Exclusive lock on a state file, released when dropped
+#[derive(Debug)]
+pub struct StateLock {
+    file: File
+}
+
+/// Machine state store
+#[derive(Debug)]
+pub struct StateStore {
+    path: PathBuf,
+    machines: HashMap<String, MachineState>
+}
+
+impl MachineState {
+    /// Show machine state to stdout, after `MachineConfig::show_info`.
+    pub fn show_info(&self) {
+        let now = Utc::now();
+
+        println!("  Last seen: {}", format_time(self.last_seen, now));
+        println!("  Last connected: {}", format_time(self.last_connected, now));
+        if let Some(ref check) = self.last_check {
+            let result = match check.error {
+                Some(ref error) => format!("failed: {}", error),
+                None => "ok".to_string()
+            };
+            println!("  Last check: {} ({})", result, format_time(Some(check.time), now));
+        }
+        if let Some(ref maintenance) = self.maintenance {
+            match maintenance.reason {
+                Some(ref reason) => println!("  Maintenance: {} ({})", reason, format_time(Some(maintenance.since), now)),
+                None => println!("  Maintenance: yes ({})", format_time(Some(maintenance.since), now))
+            }
+        }
+    }
+
+    /// Check if the machine is in maintenance
+    pub fn in_maintenance(&self) -> bool {
+        self.maintenance.is_some()
+    }
+
+    /// Get the last probe result, if it is younger than `ttl`.
+    ///
+    /// # Arguments
+    ///
+    /// * `now` - Current time
+    /// * `ttl` - Maximum probe age
+    ///
+    pub fn cached_probe(&self, now: DateTime<Utc>, ttl: Duration) -> Option<bool> {
+        self.last_probe.as_ref()
+            .filter(|probe| now.signed_duration_since(probe.time) < ttl)
+            .map(|probe| probe.reachable)
+    }
+}
+
+impl StateLock {
+    /// Lock a state file, waiting for other pssh invocations to release it.
+    ///
+    /// The lock is taken on a `.lock` file next to the state file.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - State file path
+    ///
+    pub fn acquire(path: &Path) -> io::Result<StateLock> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let mut lock_path = path.as_os_str().to_owned();
+        lock_path.push(".lock");
+        let file = OpenOptions::new().create(true).truncate(false).write(true).open(lock_path)?;
+        file.lock_exclusive()?;
+
+        Ok(StateLock { file })
+    }
+}
+
+impl Drop for StateLock {
+    fn drop(&mut self) {
+        FileExt::unlock(&self.file).ok();
+    }
+}
+
+/// Get the state directory (`~/.pssh/state`)
+pub fn get_state_dir() -> PathBuf {
+    get_user_configuration_dir().join("state")
+}
+
+/// Replace a file atomically, writing a temporary file first. The file
+/// keeps the permissions of the one it replaces.
+///
+/// # Arguments
+///
+/// * `path` - File path
+/// * `contents` - File contents
+///
+pub fn write_atomic(path: &Path, contents: &str) -> io::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    let mut temp_path = path.as_os_str().to_owned();
+    temp_path.push(format!(".{}.tmp", std::process::id()));
+
+    let mut f = File::create(&temp_path)?;
+    f.write_all(contents.as_bytes())?;
+    if let Ok(metadata) = fs::metadata(path) {
+        f.set_permissions(metadata.permissions())?;
+    }
+    f.sync_all()?;
+    fs::rename(&temp_path, path)
+}
+
+impl StateStore {
+    /// Load the user state store.
+    ///
+    /// A missing or unreadable state file results in an empty store.
+    pub fn load_default() -> StateStore {
+        StateStore::load(&default_path())
+    }
+
+    /// Update the user state store, holding its lock from load to save.
+    ///
+    /// # Arguments
+    ///
+    /// * `update` - Function modifying the store
+    ///
+    pub fn update_default<F: FnOnce(&mut StateStore)>(update: F) {
+        let path = default_path();
+        let _lock = match StateLock::acquire(&path) {
+            Ok(lock) => Some(lock),
+            Err(error) => {
+                warn!("Could not lock {}: {}", path.display(), error);
+                None
+            }
+        };
+
+        let mut store = StateStore::load(&path);
+        update(&mut store);
+        store.save();
+    }
+
+    /// Load a state store from a file path.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - State file path
+    ///
+    pub fn load(path: &Path) -> StateStore {
+        let mut contents = String::new();
+        let machines = match File::open(path).and_then(|mut f| f.read_to_string(&mut contents)) {
+            Ok(_) => parse_state(&contents),
+            Err(_) => HashMap::new()
+        };
+
+        StateStore { path: path.to_path_buf(), machines }
+    }
+
+    /// Save the state store to its file.
+    ///
+    /// Use `update_default` to save the user state store, so that concurrent
+    /// updates are not lost.
+    pub fn save(&self) {
+        if let Err(error) = write_atomic(&self.path, &dump_state(&self.machines)) {
+            warn!("Could not save state to {}: {}", self.path.display(), error);
+        }
+    }
+
+    /// Get the state of a machine (default state if unknown).
+    ///
+    /// # Arguments
+    ///
+    /// * `machine` - Machine name
+    ///
+    pub fn get(&self, machine: &str) -> MachineState {
+        self.machines.get(machine).cloned().unwrap_or_default()
+    }
+
+    /// Record a successful interactive connection.
+    ///
+    /// # Arguments
+    ///
+    /// * `machine` - Machine name
+    ///
+    pub fn record_connect(&mut self, machine: &str) {
+        let now = Utc::now();
+        let state = self.machines.entry(machine.to_string()).or_default();
+        state.last_seen = Some(now);
+        state.last_connected = Some(now);
+    }
+
+    /// Record a check result.
+    ///
+    /// # Arguments
+    ///
+    /// * `machine` - Machine name
+    /// * `error` - Error message, if the check failed
+    ///
+    pub fn record_check(&mut self, machine: &str, error: Option<String>) {
+        let now = Utc::now();
+        let state = self.machines.entry(machine.to_string()).or_default();
+        if error.is_none() {
+            state.last_seen = Some(now);
+        }
+        state.last_check = Some(CheckResult { time: now, error });
+    }
+
+    /// Record a reachability probe.
+    ///
+    /// # Arguments
+    ///
+    /// * `machine` - Machine name
+    /// * `reachable` - Whether the SSH port was reachable
+    ///
+    pub fn record_probe(&mut self, machine: &str, reachable: bool) {
+        let now = Utc::now();
+        let state = self.machines.entry(machine.to_string()).or_default();
+        if reachable {
+            state.last_seen = Some(now);
+        }
+        state.last_probe = Some(ProbeResult { time: now, reachable });
+    }
+
+    /// Put a machine in maintenance, or take it out.
+    ///
+    /// Returns `false` when the machine was already in that mode.
+    ///
+    /// # Arguments
+    ///
+    /// * `machine` - Machine name
+    /// * `enabled` - Enter maintenance
+    /// * `reason` - Reason, kept when entering maintenance
+    ///
+    pub fn set_maintenance(&mut self, machine: &str, enabled: bool, reason: Option<String>) -> bool {
+        let state = self.machines.entry(machine.to_string()).or_default();
+        let changed = state.maintenance.is_some() != enabled;
+        if !enabled {
+            state.maintenance = None;
+        } else if changed || reason.is_some() {
+            // A new reason replaces the previous one, not the start time
+            let since = state.maintenance.as_ref().map(|x| x.since).unwrap_or_else(Utc::now);
+            state.maintenance = Some(Maintenance { since, reason });
+        }
+
+        changed
+    }
+
+    /// Rename a machine, or all machines under a namespace.
+    ///
+    /// # Arguments
+    ///
+    /// * `old_name` - Current machine or namespace name
+    /// * `new_name` - New name
+    ///
+    pub fn rename(&mut self, old_name: &str, new_name: &str) {
+        let prefix = format!("{}:", old_name);
+        let names: Vec<String> = self.machines.keys()
+            .filter(|x| *x == old_name || x.starts_with(&prefix))
+            .cloned()
+            .collect();
+
+        for name in names {
+            let state = self.machines.remove(&name).unwrap();
+            self.machines.insert(format!("{}{}", new_name, &name[old_name.len()..]), state);
+        }
+    }
+}
+
+/// Format an optional time relatively to now
+///
+/// # Arguments
+///
+/// * `time` - Time to format
+/// * `now` - Current time
+///
+pub fn format_time(time: Option<DateTime<Utc>>, now: DateTime<Utc>) -> String {
+    match time {
+        Some(time) => format_age(now.signed_duration_since(time)),
+        None => "never".to_string()
+    }
+}
+
+/// Format a duration as an age ("3 days ago")
+///
+/// # Arguments
+///
+/// * `age` - Duration since the event
+///
+pub fn format_age(age: Duration) -> String {
+    let (value, unit) = if age.num_days() > 0 {
+        (age.num_days(), "day")
+    } else if age.num_hours() > 0 {
+        (age.num_hours(), "hour")
+    } else if age.num_minutes() > 0 {
+        (age.num_minutes(), "minute")
+    } else {
+        return "just now".to_string();
+    };
+
+    format!("{} {}{} ago", value, unit, if value > 1 { "s" } else { "" })
+}
+
+/// Machine state path, moving the state file of older versions if needed
+fn default_path() -> PathBuf {
+    let path = get_state_dir().join("machines.yml");
+    let legacy_path = get_user_configuration_dir().join("state.yml");
+
+    if !path.exists() && legacy_path.exists() {
+        fs::create_dir_all(get_state_dir()).ok();
+        if let Err(error) = fs::rename(&legacy_path, &path) {
+            warn!("Could not move {}: {}", legacy_path.display(), error);
+            return legacy_path;
+        }
+    }
+
+    path
+}
+
+fn parse_time(value: &Yaml) -> Option<DateTime<Utc>> {
+    value.as_str()
+        .and_then(|x| DateTime::parse_from_rfc3339(x).ok())
+        .map(|x| x.with_timezone(&Utc))
+}
+
+fn parse_state(contents: &str) -> HashMap<String, MachineState> {
+    let mut machines = HashMap::new();
+    let docs = match YamlLoader::load_from_str(contents) {
+        Ok(docs) => docs,
+        Err(_) => return machines
+    };
+
+    let entries = match docs.first().and_then(|x| x["machines"].as_hash()) {
+        Some(entries) => entries,
+        None => return machines
+    };
+
+    for (name, values) in entries {
+        let name = match name.as_str() {
+            Some(name) => name.to_string(),
+            None => continue
+        };
+
+        let last_check = parse_time(&values["last_check"]).map(|time| CheckResult {
+            time,
+            error: values["last_check_error"].as_str().map(String::from)
+        });
+
+        let last_probe = parse_time(&values["last_probe"]).map(|time| ProbeResult {
+            time,
+            reachable: values["last_probe_result"].as_str() == Some("up")
+        });
+
+        let maintenance = parse_time(&values["maintenance"]).map(|since| Maintenance {
+            since,
+            reason: values["maintenance_reason"].as_str().map(String::from)
+        });
+
+        machines.insert(name, MachineState {
+            last_seen: parse_time(&values["last_seen"]),
+            last_connected: parse_time(&values["last_connected"]),
+            last_check,
+            last_probe,
+            maintenance
+        });
+    }
+
+    machines
+}
+
+fn dump_state(machines: &HashMap<String, MachineState>) -> String {
+    let mut names: Vec<&String> = machines.keys().collect();
+    names.sort();
+
+    let mut entries = Hash::new();
+    for name in names {
+        let state = &machines[name];
+        let mut values = Hash::new();
+        let mut insert = |key: &str, value: String| {
+            values.insert(Yaml::String(key.to_string()), Yaml::String(value));
+        };
+
+        if let Some(time) = state.last_seen {
+            insert("last_seen", time.to_rfc3339());
+        }
+        if let Some(time) = state.last_connected {
+            insert("last_connected", time.to_rfc3339());
+        }
+        if let Some(ref check) = state.last_check {
+            insert("last_check", check.time.to_rfc3339());
+            if let Some(ref error) = check.error {
+                insert("last_check_error", error.clone());
+            }
+        }
+        if let Some(ref probe) = state.last_probe {
+            insert("last_probe", probe.time.to_rfc3339());
+            insert("last_probe_result", if probe.reachable { "up" } else { "down" }.to_string());
+        }
+        if let Some(ref maintenance) = state.maintenance {
+            insert("maintenance", maintenance.since.to_rfc3339());
+            if let Some(ref reason) = maintenance.reason {
+                insert("maintenance_reason", reason.clone());
+            }
+        }
+
+        entries.insert(Yaml::String(name.clone()), Yaml::Hash(values));
+    }
+
+    let mut root = Hash::new();
+    root.insert(Yaml::String("machines".to_string()), Yaml::Hash(entries));
+
+    let mut output = String::new();
+    YamlEmitter::new(&mut output).dump(&Yaml::Hash(root)).unwrap();
+    output.push('\n');
+    output
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::env;
+
+    #[test]
+    fn ages() {
+        assert_eq!(format_age(Duration::seconds(30)), "just now");
+        assert_eq!(format_age(Duration::minutes(1)), "1 minute ago");
+        assert_eq!(format_age(Duration::hours(5)), "5 hours ago");
+        assert_eq!(format_age(Duration::days(12)), "12 days ago");
+        assert_eq!(format_time(None, Utc::now()), "never");
+    }
+
+    #[test]
+    fn roundtrip() {
+        let mut store = StateStore { path: PathBuf::new(), machines: HashMap::new() };
+        store.record_connect("prod:web1");
+        store.record_check("prod:web1", None);
+        store.record_check("prod:db", Some("Connection refused".to_string()));
+        store.record_probe("prod:db", false);
+        assert!(store.set_maintenance("prod:db", true, Some("disk swap".to_string())));
+        assert!(!store.set_maintenance("prod:db", true, None));
+        assert!(store.set_maintenance("prod:web2", true, None));
+        assert!(store.set_maintenance("prod:web2", false, None));
+
+        let machines = parse_state(&dump_state(&store.machines));
+        assert_eq!(machines.len(), 3);
+
+        let web1 = &machines["prod:web1"];
+        assert!(web1.last_connected.is_some());
+        assert!(web1.last_seen.is_some());
+        assert_eq!(web1.last_check.as_ref().unwrap().error, None);
+
+        let db = &machines["prod:db"];
+        assert!(db.last_seen.is_none());
+        assert_eq!(db.last_check.as_ref().unwrap().error, Some("Connection refused".to_string()));
+        assert_eq!(db.cached_probe(Utc::now(), Duration::minutes(1)), Some(false));
+        assert_eq!(db.cached_probe(Utc::now() + Duration::minutes(2), Duration::minutes(1)), None);
+        assert_eq!(web1.cached_probe(Utc::now(), Duration::minutes(1)), None);
+        assert_eq!(db.maintenance.as_ref().unwrap().reason.as_deref(), Some("disk swap"));
+        assert!(!web1.in_maintenance() && !machines["prod:web2"].in_maintenance());
+    }
+
+    #[test]
+    fn rename() {
+        let mut store = StateStore { path: PathBuf::new(), machines: HashMap::new() };
+        store.record_connect("staging:web");
+        store.record_connect("staging:web:api");
+        store.record_connect("staging:webapp");
+
+        store.rename("staging:web", "prod:web");
+        assert!(store.machines.contains_key("prod:web"));
+        assert!(store.machines.contains_key("prod:web:api"));
+        assert!(store.machines.contains_key("staging:webapp"));
+        assert!(!store.machines.contains_key("staging:web"));
+    }
+
+    #[test]
+    fn locked_updates() {
+        let dir = env::temp_dir().join(format!("pssh-state-{}", std::process::id()));
+        let path = dir.join("machines.yml");
+
+        let lock = StateLock::acquire(&path).unwrap();
+        let mut store = StateStore::load(&path);
+        store.record_connect("one");
+        store.save();
+        drop(lock);
+
+        let lock = StateLock::acquire(&path).unwrap();
+        let store = StateStore::load(&path);
+        assert!(store.get("one").last_connected.is_some());
+        drop(lock);
+
+        fs::remove_dir_all(&dir).ok();
+    }
+}
@@ -0,0 +1,147 @@
+//! Inventory summary
+//!
+//! Counts machines per top-level namespace, tag, user and identity file, and
+//! flags machines which use password authentication or the default SSH port,
+//! for periodic reviews of big inventories.
+
+use std::collections::BTreeMap;
+
+use config::ConfigMap;
+
+/// Label of machines without a value
+const NONE_LABEL: &str = "(none)";
+
+/// Inventory summary
+#[derive(Debug, Default)]
+pub struct Summary {
+    /// Machine count
+    pub total: usize,
+    /// Machine count per top-level namespace
+    pub namespaces: BTreeMap<String, usize>,
+    /// Machine count per tag
+    pub tags: BTreeMap<String, usize>,
+    /// Machine count per user
+    pub users: BTreeMap<String, usize>,
+    /// Machine count per identity file
+    pub identities: BTreeMap<String, usize>,
+    /// Machines with a password
+    pub password_auth: Vec<String>,
+    /// Machines on port 22
+    pub default_port: Vec<String>
+}
+
+impl Summary {
+    /// Summarize machines.
+    ///
+    /// # Arguments
+    ///
+    /// * `machines` - Machine configuration map
+    ///
+    pub fn from_machines(machines: &ConfigMap) -> Summary {
+        let mut summary = Summary { total: machines.len(), ..Default::default() };
+        let mut names: Vec<&String> = machines.keys().collect();
+        names.sort();
+
+        for name in names {
+            let config = &machines[name];
+            let namespace = name.split(':').next().unwrap_or(name);
+            *summary.namespaces.entry(namespace.to_string()).or_insert(0) += 1;
+
+            for tag in config.tags.iter().flatten() {
+                *summary.tags.entry(tag.clone()).or_insert(0) += 1;
+            }
+
+            let user = config.user.as_ref().map(|x| &x[..]).unwrap_or(NONE_LABEL);
+            *summary.users.entry(user.to_string()).or_insert(0) += 1;
+            if config.identities().is_empty() {
+                *summary.identities.entry(NONE_LABEL.to_string()).or_insert(0) += 1;
+            }
+            for identity in config.identities() {
+                *summary.identities.entry(identity.clone()).or_insert(0) += 1;
+            }
+
+            if config.pass.is_some() {
+                summary.password_auth.push(name.clone());
+            }
+            if config.ports().contains(&22) {
+                summary.default_port.push(name.clone());
+            }
+        }
+
+        summary
+    }
+
+    /// Render the summary as text.
+    pub fn render(&self) -> String {
+        let mut lines = vec![format!("{} machines", self.total)];
+
+        for &(title, counts) in &[("Namespaces", &self.namespaces), ("Tags", &self.tags),
+                                  ("Users", &self.users), ("Identities", &self.identities)] {
+            if counts.is_empty() {
+                continue;
+            }
+
+            lines.push(String::new());
+            lines.push(format!("{}:", title));
+            let width = counts.keys().map(|x| x.len()).max().unwrap_or(0);
+            for (key, count) in counts.iter() {
+                lines.push(format!("  {:width$}  {}", key, count, width = width));
+            }
+        }
+
+        for &(title, names) in &[("Password authentication", &self.password_auth),
+                                 ("Default port 22", &self.default_port)] {
+            if names.is_empty() {
+                continue;
+            }
+
+            lines.push(String::new());
+            lines.push(format!("{} ({}):", title, names.len()));
+            for name in names.iter() {
+                lines.push(format!("  ! {}", name));
+            }
+        }
+
+        lines.join("\n")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use config::MachineConfig;
+
+    #[test]
+    fn summary() {
+        let machines = hashmap!(
+            "prod:web1".to_string() => MachineConfig {
+                user: Some("deploy".to_string()),
+                identity: Some(vec!["~/.ssh/prod".to_string()]),
+                port: Some(vec![2222]),
+                tags: Some(vec!["web".to_string()]),
+                ..Default::default()
+            },
+            "prod:db".to_string() => MachineConfig {
+                user: Some("deploy".to_string()),
+                pass: Some("secret".to_string()),
+                ..Default::default()
+            },
+            "localhost".to_string() => MachineConfig::default()
+        );
+
+        let summary = Summary::from_machines(&machines);
+        assert_eq!(summary.total, 3);
+        assert_eq!(summary.namespaces["prod"], 2);
+        assert_eq!(summary.tags["web"], 1);
+        assert_eq!(summary.users["deploy"], 2);
+        assert_eq!(summary.users[NONE_LABEL], 1);
+        assert_eq!(summary.identities[NONE_LABEL], 2);
+        assert_eq!(summary.password_auth, vec!["prod:db"]);
+        assert_eq!(summary.default_port, vec!["localhost", "prod:db"]);
+
+        let text = summary.render();
+        assert!(text.starts_with("3 machines\n\nNamespaces:\n  localhost  1\n  prod       2\n"));
+        assert!(text.contains("Password authentication (1):\n  ! prod:db"));
+    }
+}
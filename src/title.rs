@@ -0,0 +1,64 @@
+//! Terminal titles
+//!
+//! Interactive sessions name the terminal window or tab after the machine,
+//! through OSC escape sequences. The previous title is saved on the title
+//! stack of the terminal (xterm window operations), and restored on exit.
+
+use config::MachineConfig;
+
+/// Default title format
+pub const DEFAULT_TITLE_FORMAT: &str = "{name}";
+
+/// Save the current title on the title stack
+const PUSH_TITLE: &str = "\x1b[22;0t";
+
+/// Restore the title from the title stack
+pub const RESTORE_TITLE: &str = "\x1b[23;0t";
+
+/// Render a title from a format, where `{name}`, `{user}`, `{ip}` and
+/// `{address}` are replaced by the machine values.
+///
+/// # Arguments
+///
+/// * `format` - Title format
+/// * `name` - Machine name
+/// * `config` - Machine configuration
+///
+pub fn render_title(format: &str, name: &str, config: &MachineConfig) -> String {
+    let title = format.replace("{name}", name)
+        .replace("{user}", config.user.as_deref().unwrap_or(""))
+        .replace("{ip}", config.ip.as_deref().unwrap_or(""))
+        .replace("{address}", &config.address());
+
+    // Control characters could end the sequence early
+    title.chars().filter(|c| !c.is_control()).collect()
+}
+
+/// Get the sequence saving the current title, then setting a new one.
+///
+/// # Arguments
+///
+/// * `title` - New title
+///
+pub fn set_title(title: &str) -> String {
+    format!("{}\x1b]0;{}\x07", PUSH_TITLE, title)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn titles() {
+        let config = MachineConfig {
+            ip: Some("10.0.0.1".to_string()),
+            user: Some("deploy".to_string()),
+            ..Default::default()
+        };
+
+        assert_eq!(render_title(DEFAULT_TITLE_FORMAT, "prod:web1", &config), "prod:web1");
+        assert_eq!(render_title("{user}@{name} ({ip})", "web1", &config), "deploy@web1 (10.0.0.1)");
+        assert_eq!(render_title("{address}\x07\x1b]0;x", "web1", &config), "deploy@10.0.0.1:22]0;x");
+        assert_eq!(set_title("web1"), "\x1b[22;0t\x1b]0;web1\x07");
+    }
+}
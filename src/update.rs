@@ -0,0 +1,219 @@
+//! Package update orchestration
+
+
+use config::MachineConfig;
+use error::Error;
+use wrapper::{execute_capture, ssh_remote};
+
+/// Remote script printing the name of the available package manager
+const DETECT_SCRIPT: &str = "\
+if command -v apt-get >/dev/null 2>&1; then echo apt; \
+elif command -v dnf >/dev/null 2>&1; then echo dnf; \
+elif command -v pacman >/dev/null 2>&1; then echo pacman; \
+fi";
+
+/// Remote package manager
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PackageManager {
+    /// Debian, Ubuntu
+    Apt,
+    /// Fedora, RHEL
+    Dnf,
+    /// Arch Linux
+    Pacman
+}
+
+/// Update outcome for a machine
+#[derive(Debug, PartialEq)]
+pub enum UpdateOutcome {
+    /// Upgradable packages, when running in dry-run mode
+    Upgradable(Vec<String>),
+    /// Packages were upgraded
+    Upgraded,
+    /// Update failed
+    Failed(String)
+}
+
+/// Update result for a machine
+#[derive(Debug)]
+pub struct UpdateResult {
+    /// Detected package manager
+    pub manager: Option<PackageManager>,
+    /// Update outcome
+    pub outcome: UpdateOutcome
+}
+
+impl PackageManager {
+    /// Parse a package manager name.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - Package manager name
+    ///
+    pub fn from_name(name: &str) -> Option<PackageManager> {
+        match name {
+            "apt" => Some(PackageManager::Apt),
+            "dnf" => Some(PackageManager::Dnf),
+            "pacman" => Some(PackageManager::Pacman),
+            _ => None
+        }
+    }
+
+    /// Package manager name
+    pub fn name(&self) -> &'static str {
+        match *self {
+            PackageManager::Apt => "apt",
+            PackageManager::Dnf => "dnf",
+            PackageManager::Pacman => "pacman"
+        }
+    }
+
+    /// Remote command listing upgradable packages, one per line.
+    ///
+    /// # Arguments
+    ///
+    /// * `sudo` - Run privileged commands with `sudo`
+    ///
+    pub fn list_command(&self, sudo: bool) -> String {
+        let sudo = sudo_prefix(sudo);
+        match *self {
+            PackageManager::Apt => format!(
+                "{}apt-get update -qq >/dev/null && apt list --upgradable 2>/dev/null",
+                sudo
+            ),
+            // `check-update` exits with 100 when updates are available
+            PackageManager::Dnf => "dnf check-update -q; test $? -ne 1".to_string(),
+            PackageManager::Pacman => "(checkupdates 2>/dev/null || pacman -Qu); true".to_string()
+        }
+    }
+
+    /// Remote command upgrading all packages.
+    ///
+    /// # Arguments
+    ///
+    /// * `sudo` - Run privileged commands with `sudo`
+    ///
+    pub fn upgrade_command(&self, sudo: bool) -> String {
+        let sudo = sudo_prefix(sudo);
+        match *self {
+            PackageManager::Apt => format!(
+                "{0}apt-get update -q && {0}env DEBIAN_FRONTEND=noninteractive apt-get upgrade -y -q",
+                sudo
+            ),
+            PackageManager::Dnf => format!("{}dnf upgrade -y -q", sudo),
+            PackageManager::Pacman => format!("{}pacman -Syu --noconfirm", sudo)
+        }
+    }
+}
+
+impl UpdateResult {
+    /// Result summary
+    pub fn describe(&self) -> String {
+        match self.outcome {
+            UpdateOutcome::Upgradable(ref packages) if packages.is_empty() => "up to date".to_string(),
+            UpdateOutcome::Upgradable(ref packages) => format!("{} upgradable", packages.len()),
+            UpdateOutcome::Upgraded => "upgraded".to_string(),
+            UpdateOutcome::Failed(ref error) => format!("failed ({})", error)
+        }
+    }
+}
+
+/// Update packages on a machine.
+///
+/// # Arguments
+///
+/// * `config` - Machine configuration
+/// * `sudo` - Run privileged commands with `sudo`
+/// * `dry_run` - Only list upgradable packages
+///
+pub fn update_packages(config: &MachineConfig, sudo: bool, dry_run: bool) -> UpdateResult {
+    let manager = match run(config, DETECT_SCRIPT) {
+        Ok(output) => PackageManager::from_name(output.trim()),
+        Err(error) => return UpdateResult { manager: None, outcome: UpdateOutcome::Failed(error.to_string()) }
+    };
+
+    let outcome = match manager {
+        None => UpdateOutcome::Failed("no supported package manager".to_string()),
+        Some(manager) if dry_run => match run(config, &manager.list_command(sudo)) {
+            Ok(output) => UpdateOutcome::Upgradable(parse_package_list(&output)),
+            Err(error) => UpdateOutcome::Failed(error.to_string())
+        },
+        Some(manager) => match run(config, &manager.upgrade_command(sudo)) {
+            Ok(_) => UpdateOutcome::Upgraded,
+            Err(error) => UpdateOutcome::Failed(error.to_string())
+        }
+    };
+
+    UpdateResult { manager, outcome }
+}
+
+/// Parse a package list, keeping the package names only. The `Listing...`
+/// header of apt and the metadata line of dnf are skipped.
+///
+/// # Arguments
+///
+/// * `output` - List command output
+///
+pub fn parse_package_list(output: &str) -> Vec<String> {
+    output.lines()
+        .map(|x| x.trim())
+        .filter(|x| !x.is_empty() && !x.starts_with("Listing") && !x.starts_with("Last metadata"))
+        .filter_map(|x| x.split(|c: char| c == '/' || c.is_whitespace()).next())
+        .map(String::from)
+        .collect()
+}
+
+fn sudo_prefix(sudo: bool) -> &'static str {
+    if sudo { "sudo -n " } else { "" }
+}
+
+fn run(config: &MachineConfig, remote_command: &str) -> Result<String, Error> {
+    config.check_values()?;
+    let output = execute_capture(ssh_remote(config, remote_command))?;
+
+    if output.status.success() {
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    } else {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        Err(Error::Transport(stderr.lines().last().unwrap_or("unknown error").to_string()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn commands() {
+        assert_eq!(PackageManager::Dnf.upgrade_command(true), "sudo -n dnf upgrade -y -q");
+        assert_eq!(PackageManager::Pacman.upgrade_command(false), "pacman -Syu --noconfirm");
+        assert!(PackageManager::Apt.list_command(true).starts_with("sudo -n apt-get update"));
+    }
+
+    #[test]
+    fn package_lists() {
+        let apt = "Listing...\n\
+                   curl/jammy-updates 7.81.0-1ubuntu1.16 amd64 [upgradable from: 7.81.0-1ubuntu1.15]\n\
+                   libssl3/jammy-security 3.0.2-0ubuntu1.15 amd64 [upgradable from: 3.0.2-0ubuntu1.14]\n";
+        assert_eq!(parse_package_list(apt), vec!["curl", "libssl3"]);
+        assert!(parse_package_list("Listing... Done\n").is_empty());
+
+        let dnf = "\nkernel.x86_64    6.8.9-300.fc40    updates\n";
+        assert_eq!(parse_package_list(dnf), vec!["kernel.x86_64"]);
+
+        let pacman = "linux 6.8.8.arch1-1 -> 6.8.9.arch1-1\n";
+        assert_eq!(parse_package_list(pacman), vec!["linux"]);
+    }
+
+    #[test]
+    fn descriptions() {
+        let result = UpdateResult { manager: None, outcome: UpdateOutcome::Upgradable(vec![]) };
+        assert_eq!(result.describe(), "up to date");
+
+        let result = UpdateResult {
+            manager: Some(PackageManager::Apt),
+            outcome: UpdateOutcome::Upgradable(vec!["curl".to_string()])
+        };
+        assert_eq!(result.describe(), "1 upgradable");
+    }
+}
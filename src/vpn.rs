@@ -0,0 +1,136 @@
+//! VPN hints
+//!
+//! Machines only reachable through a VPN, or whose names only resolve
+//! through its DNS servers, can name it with a `vpn` machine value: the
+//! local command bringing it up, and optionally one checking that it is up.
+//!
+//! When such a machine is unreachable and its VPN is down, `pssh connect`
+//! offers to bring the VPN up, then waits for the machine before
+//! connecting.
+
+use std::net::ToSocketAddrs;
+use std::process::Stdio;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use config::{MachineConfig, VpnConfig};
+use probe::{jump_address, probe_address, probe_reachability, Reachability};
+use wrapper::{execute, local_shell};
+
+/// Connection timeout of reachability probes
+const PROBE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Time waited for the machine once the VPN is brought up
+pub const UP_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Delay between two probes while waiting for the machine
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Check if a VPN is up, with its check command.
+///
+/// VPNs without check command are considered down.
+///
+/// # Arguments
+///
+/// * `vpn` - VPN configuration
+///
+pub fn vpn_is_up(vpn: &VpnConfig) -> bool {
+    let check = match vpn.check {
+        Some(ref check) => check,
+        None => return false
+    };
+
+    local_shell(check).command()
+        .stdin(Stdio::null()).stdout(Stdio::null()).stderr(Stdio::null())
+        .status()
+        .map(|x| x.success())
+        .unwrap_or(false)
+}
+
+/// Check if a machine can be reached: its SSH port, or the one of its first
+/// jump host. Names which cannot be resolved are unreachable.
+///
+/// Machines behind a proxy command cannot be probed, and are taken as
+/// reachable.
+///
+/// # Arguments
+///
+/// * `config` - Machine configuration
+///
+pub fn is_reachable(config: &MachineConfig) -> bool {
+    if config.proxy_command.is_some() {
+        return true;
+    }
+
+    match config.jump_hosts().first() {
+        Some(jump) => {
+            let (host, port) = jump_address(jump);
+            (host.as_str(), port).to_socket_addrs()
+                .map(|mut x| x.any(|address| probe_address(&address, PROBE_TIMEOUT).is_some()))
+                .unwrap_or(false)
+        },
+        None => probe_reachability(config, PROBE_TIMEOUT) == Reachability::Up
+    }
+}
+
+/// Bring the VPN of an unreachable machine up, then wait for the machine.
+///
+/// Returns `false` when the VPN command failed or the machine is still
+/// unreachable.
+///
+/// # Arguments
+///
+/// * `name` - Machine name
+/// * `config` - Machine configuration
+///
+pub fn bring_up(name: &str, config: &MachineConfig) -> bool {
+    let vpn = match config.vpn {
+        Some(ref vpn) => vpn,
+        None => return false
+    };
+
+    // The command may ask for a password, it keeps the terminal
+    match execute(local_shell(&vpn.up)) {
+        Ok(status) if status.success() => (),
+        Ok(status) => {
+            println!("VPN command `{}` failed with {}.", vpn.up, status);
+            return false;
+        },
+        Err(error) => {
+            println!("{}", error);
+            return false;
+        }
+    }
+
+    println!("Waiting for `{}`...", name);
+    let start = Instant::now();
+    while !is_reachable(config) {
+        if start.elapsed() >= UP_TIMEOUT {
+            println!("`{}` is still unreachable.", name);
+            return false;
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+
+    true
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn checks() {
+        let vpn = |check: Option<&str>| VpnConfig { up: "true".to_string(), check: check.map(String::from) };
+        assert!(vpn_is_up(&vpn(Some("true"))));
+        assert!(!vpn_is_up(&vpn(Some("exit 1"))));
+        assert!(!vpn_is_up(&vpn(None)));
+
+        let unresolved = MachineConfig { ip: Some("nonexistent.invalid".to_string()), ..Default::default() };
+        assert!(!is_reachable(&unresolved));
+        let behind = MachineConfig { via: Some(vec!["bastion.invalid".to_string()]), ..unresolved };
+        assert!(!is_reachable(&behind));
+        let proxied = MachineConfig { proxy_command: Some("nc %h %p".to_string()), ..behind };
+        assert!(is_reachable(&proxied));
+    }
+}
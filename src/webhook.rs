@@ -0,0 +1,177 @@
+//! Webhook notifications for fleet jobs
+//!
+//! Job summaries are posted as JSON with `curl`, shaped for Slack, Discord or
+//! generic webhooks.
+
+use std::io::Write;
+use std::process::{Command, Stdio};
+use std::time::Duration;
+
+use serde_json::Value;
+
+use error::Error;
+
+/// Webhook payload format
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WebhookFormat {
+    /// Slack incoming webhook (`text`)
+    Slack,
+    /// Discord webhook (`content`)
+    Discord,
+    /// Full job summary as JSON
+    Json
+}
+
+/// Configured webhook
+#[derive(Debug, Clone, PartialEq)]
+pub struct Webhook {
+    /// Webhook URL
+    pub url: String,
+    /// Payload format
+    pub format: WebhookFormat
+}
+
+/// Summary of a fleet job
+#[derive(Debug, Clone)]
+pub struct JobSummary {
+    /// Operation name (`exec`, `update`...)
+    pub operation: String,
+    /// Operation detail, as the executed command
+    pub detail: String,
+    /// Target machines
+    pub hosts: Vec<String>,
+    /// Failed machines
+    pub failures: Vec<String>,
+    /// Job duration
+    pub duration: Duration
+}
+
+impl WebhookFormat {
+    /// Parse a webhook format name.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - Format name
+    ///
+    pub fn from_name(name: &str) -> Option<WebhookFormat> {
+        match name {
+            "slack" => Some(WebhookFormat::Slack),
+            "discord" => Some(WebhookFormat::Discord),
+            "json" => Some(WebhookFormat::Json),
+            _ => None
+        }
+    }
+}
+
+impl JobSummary {
+    /// One-line summary text
+    pub fn text(&self) -> String {
+        let mut text = format!("pssh {}", self.operation);
+        if !self.detail.is_empty() {
+            text.push_str(&format!(" `{}`", self.detail));
+        }
+        text.push_str(&format!(": {} host(s), ", self.hosts.len()));
+
+        if self.failures.is_empty() {
+            text.push_str("all succeeded");
+        } else {
+            text.push_str(&format!("{} failed ({})", self.failures.len(), self.failures.join(", ")));
+        }
+
+        format!("{} in {}s", text, self.duration.as_secs())
+    }
+}
+
+impl Webhook {
+    /// Build the payload for a job summary.
+    ///
+    /// # Arguments
+    ///
+    /// * `summary` - Job summary
+    ///
+    pub fn payload(&self, summary: &JobSummary) -> Value {
+        match self.format {
+            WebhookFormat::Slack => json!({"text": summary.text()}),
+            WebhookFormat::Discord => json!({"content": summary.text()}),
+            WebhookFormat::Json => json!({
+                "operation": summary.operation,
+                "detail": summary.detail,
+                "hosts": summary.hosts,
+                "failures": summary.failures,
+                "success": summary.failures.is_empty(),
+                "duration": summary.duration.as_secs_f64()
+            })
+        }
+    }
+
+    /// Post a job summary.
+    ///
+    /// # Arguments
+    ///
+    /// * `summary` - Job summary
+    ///
+    pub fn post(&self, summary: &JobSummary) -> Result<(), Error> {
+        let mut child = Command::new("curl")
+            .args(["--silent", "--show-error", "--fail", "--location", "--max-time", "10"])
+            .args(["--header", "Content-Type: application/json", "--data-binary", "@-"])
+            .arg(&self.url)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| Error::io("failed to execute curl", e))?;
+
+        child.stdin.take().unwrap()
+            .write_all(self.payload(summary).to_string().as_bytes())
+            .map_err(|e| Error::io("failed to send payload", e))?;
+
+        let output = child.wait_with_output().map_err(|e| Error::io("failed to wait on curl", e))?;
+        if output.status.success() {
+            Ok(())
+        } else {
+            Err(Error::Transport(String::from_utf8_lossy(&output.stderr).trim().to_string()))
+        }
+    }
+}
+
+/// Post a job summary to all webhooks, warning on failures.
+///
+/// # Arguments
+///
+/// * `webhooks` - Configured webhooks
+/// * `summary` - Job summary
+///
+pub fn post_summary(webhooks: &[Webhook], summary: &JobSummary) {
+    for webhook in webhooks {
+        if let Err(error) = webhook.post(summary) {
+            warn!("Could not post to webhook {}: {}", webhook.url, error);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn payloads() {
+        let summary = JobSummary {
+            operation: "exec".to_string(),
+            detail: "uptime".to_string(),
+            hosts: vec!["web1".to_string(), "web2".to_string()],
+            failures: vec!["web2".to_string()],
+            duration: Duration::from_secs(12)
+        };
+        let text = "pssh exec `uptime`: 2 host(s), 1 failed (web2) in 12s";
+
+        let slack = Webhook { url: String::new(), format: WebhookFormat::Slack };
+        assert_eq!(slack.payload(&summary), json!({"text": text}));
+
+        let discord = Webhook { url: String::new(), format: WebhookFormat::Discord };
+        assert_eq!(discord.payload(&summary)["content"], text);
+
+        let generic = Webhook { url: String::new(), format: WebhookFormat::Json };
+        assert_eq!(generic.payload(&summary)["success"], false);
+        assert_eq!(generic.payload(&summary)["hosts"][1], "web2");
+    }
+}
@@ -1,8 +1,223 @@
 //! Shell wrappers
 
-use std::process::Command;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::process::{Command, ExitStatus, Output, Stdio};
+use std::time::Duration;
 
-use config::MachineConfig;
+use agent::add_identities;
+use knock::knock;
+use config::{ConsoleConfig, MachineConfig};
+use control::active_control_path;
+use error::Error;
+use ignore::tar_pattern;
+use probe::{select_jump, select_port};
+use quote;
+
+/// Remote command used for restricted connections, unless configured
+pub const DEFAULT_RESTRICTED_COMMAND: &str = "rbash -l";
+
+/// Remote process viewer, `htop` when installed
+pub const TOP_COMMAND: &str = "if command -v htop >/dev/null 2>&1; then exec htop; else exec top; fi";
+
+/// Connection timeout when choosing between candidate ports or jump hosts
+const PORT_PROBE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Option bundles for devices which need non-default SSH settings
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CompatPreset {
+    /// Old network gear and appliances: SHA-1 key exchanges, RSA/DSA host
+    /// keys and CBC ciphers
+    Legacy
+}
+
+impl CompatPreset {
+    /// Parse a preset name.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - Preset name
+    ///
+    pub fn from_name(name: &str) -> Option<CompatPreset> {
+        match name {
+            "legacy" => Some(CompatPreset::Legacy),
+            _ => None
+        }
+    }
+
+    /// Preset name
+    pub fn name(&self) -> &'static str {
+        match *self {
+            CompatPreset::Legacy => "legacy"
+        }
+    }
+
+    /// Options passed to `ssh` and `scp`
+    ///
+    /// Algorithms are appended to the OpenSSH defaults, so that modern
+    /// algorithms are still preferred.
+    pub fn options(&self) -> &'static [&'static str] {
+        match *self {
+            CompatPreset::Legacy => &[
+                "-o", "KexAlgorithms=+diffie-hellman-group1-sha1,diffie-hellman-group14-sha1,diffie-hellman-group-exchange-sha1",
+                "-o", "HostKeyAlgorithms=+ssh-rsa,ssh-dss",
+                "-o", "PubkeyAcceptedAlgorithms=+ssh-rsa",
+                "-o", "Ciphers=+aes128-cbc,aes256-cbc,3des-cbc",
+                "-o", "MACs=+hmac-sha1"
+            ]
+        }
+    }
+}
+
+/// Command line built by the wrappers, before conversion to a `Command`
+///
+/// Keeping the program and its arguments as plain strings lets tests check
+/// the exact argument order, and lets callers log or audit the command line.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct CommandSpec {
+    /// Program name
+    pub program: String,
+    /// Arguments, in order
+    pub args: Vec<String>,
+    /// Environment variables set for the program
+    pub envs: Vec<(String, String)>,
+    /// Machine connected to, whose connection is prepared when the command
+    /// is spawned
+    pub machine: Option<MachineConfig>,
+    /// Command whose output is piped into this one, as in `tar | ssh`, run
+    /// by `execute`
+    pub input: Option<Box<CommandSpec>>
+}
+
+impl CommandSpec {
+    /// Create a command line without arguments.
+    ///
+    /// # Arguments
+    ///
+    /// * `program` - Program name
+    ///
+    pub fn new(program: &str) -> CommandSpec {
+        CommandSpec {
+            program: program.to_string(),
+            ..Default::default()
+        }
+    }
+
+    /// Append an argument.
+    pub fn arg<S: AsRef<str>>(&mut self, arg: S) -> &mut CommandSpec {
+        self.args.push(arg.as_ref().to_string());
+        self
+    }
+
+    /// Append arguments.
+    pub fn args<I, S>(&mut self, args: I) -> &mut CommandSpec where I: IntoIterator<Item = S>, S: AsRef<str> {
+        self.args.extend(args.into_iter().map(|x| x.as_ref().to_string()));
+        self
+    }
+
+    /// Set an environment variable.
+    pub fn env(&mut self, key: &str, value: &str) -> &mut CommandSpec {
+        self.envs.push((key.to_string(), value.to_string()));
+        self
+    }
+
+    /// Build the `Command` to execute, right before spawning it.
+    ///
+    /// The command line holds the first candidate port and jump host of the
+    /// machine, so that building it never touches the network. The knock
+    /// sequence, and the probes choosing the ones accepting connections,
+    /// happen here instead.
+    pub fn command(&self) -> Command {
+        let args = match self.machine {
+            Some(ref config) => {
+                knock(config);
+                select_connection(&self.args, config)
+            },
+            None => self.args.clone()
+        };
+
+        let mut command = Command::new(&self.program);
+        command.args(&args);
+        command.envs(self.envs.iter().map(|(key, value)| (key, value)));
+
+        debug!("Executing {:?}", command);
+        command
+    }
+
+    /// Build the `Command` of an interactive session, right before spawning
+    /// it, first adding the identities of the machine missing from the SSH
+    /// agent: their passphrases are asked on the terminal.
+    pub fn interactive_command(&self) -> Command {
+        if let Some(ref config) = self.machine {
+            add_identities(config);
+        }
+
+        self.command()
+    }
+}
+
+/// Replace the first candidate port and jump host of a machine, in command
+/// arguments, by the first ones accepting connections.
+///
+/// # Arguments
+///
+/// * `args` - Command arguments, as built
+/// * `config` - Machine configuration
+///
+fn select_connection(args: &[String], config: &MachineConfig) -> Vec<String> {
+    let mut args = args.to_vec();
+
+    let (first, port) = (config.first_port().to_string(), select_port(config, PORT_PROBE_TIMEOUT).to_string());
+    if port != first {
+        // `ssh -p PORT`, `scp -P PORT`, or the `ssh` command of `rsync -e`
+        // which ends with the port
+        let rsync_suffix = format!(" -p {}", first);
+        for index in 1..args.len() {
+            match args[index - 1].as_str() {
+                "-p" | "-P" if args[index] == first => args[index] = port.clone(),
+                "-e" if args[index].ends_with(&rsync_suffix) => {
+                    let kept = args[index].len() - first.len();
+                    args[index] = format!("{}{}", &args[index][..kept], port);
+                },
+                _ => continue
+            }
+            break;
+        }
+    }
+
+    if let (Some(first), Some(jump)) = (config.jump_hosts().first(), select_jump(config, PORT_PROBE_TIMEOUT)) {
+        if first != &jump {
+            let (first, jump) = (format!("ProxyJump={}", first), format!("ProxyJump={}", jump));
+            for arg in args.iter_mut() {
+                *arg = arg.replace(&first, &jump);
+            }
+        }
+    }
+
+    args
+}
+
+impl fmt::Display for CommandSpec {
+    /// Program and arguments separated by spaces, without quoting:
+    /// environment values are left out, as they can hold secrets.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(ref input) = self.input {
+            write!(f, "{} | ", input)?;
+        }
+        write!(f, "{}", self.program)?;
+        for arg in &self.args {
+            write!(f, " {}", arg)?;
+        }
+        Ok(())
+    }
+}
+
+impl From<CommandSpec> for Command {
+    fn from(spec: CommandSpec) -> Command {
+        spec.command()
+    }
+}
 
 /// SSH Copy direction
 pub enum ScpDirection {
@@ -12,17 +227,49 @@ pub enum ScpDirection {
     Pull
 }
 
-/// Ping a machine
+/// Options of file copies
+#[derive(Debug, Clone, PartialEq)]
+pub struct CopyOptions {
+    /// Copy directory trees
+    pub recursive: bool,
+    /// Keep modes and modification times
+    pub preserve: bool,
+    /// Copy the targets of symbolic links, instead of the links
+    pub follow_symlinks: bool,
+    /// Patterns of the files skipped by recursive copies
+    pub excludes: Vec<String>
+}
+
+impl Default for CopyOptions {
+    fn default() -> CopyOptions {
+        CopyOptions { recursive: false, preserve: false, follow_symlinks: true, excludes: vec![] }
+    }
+}
+
+/// Ping a machine, printing only the summary
 ///
 /// # Arguments
 ///
 /// * `ip` - Machine IP
+/// * `count` - Number of echo requests
+///
+pub fn ping(ip: &str, count: u32) -> CommandSpec {
+    let mut command = CommandSpec::new("ping");
+    command.arg("-q").arg("-c").arg(count.to_string()).arg(ip);
+
+    command
+}
+
+/// Run a command with the local shell
+///
+/// # Arguments
+///
+/// * `shell_command` - Shell command
 ///
-pub fn ping(ip: &str) -> Command {
-    let mut command = Command::new("ping");
-    command.arg(ip);      
-        
-    debug!("Executing {}", format!("{:?}", command));
+pub fn local_shell(shell_command: &str) -> CommandSpec {
+    let mut command = CommandSpec::new("sh");
+    command.args(["-c", shell_command]);
+
     command
 }
 
@@ -34,50 +281,139 @@ pub fn ping(ip: &str) -> Command {
 /// * `source` - Source path
 /// * `destination` - Destination path
 ///
-pub fn scp(config: &MachineConfig, source: &str, destination: &str, direction: ScpDirection) -> Command {
-    let mut command = Command::new("scp");
-    
-    if config.identity.is_some() {
-        command.args(&["-i", config.identity.as_ref().unwrap()]);
-    }
-    
-    if config.port.is_some() {
-        command.args(&["-P", &(config.port.as_ref().unwrap().to_string())]);
-    } else {
-        command.args(&["-P", "22"]);
-    }
-
-    let machine_path = match direction {
-        ScpDirection::Push => destination,
-        ScpDirection::Pull => source
-    };
-        
-    let user_path = if config.user.is_some() {
-        format!("{}@{}:{}",
-            &config.user.as_ref().unwrap(),
-            &config.ip.as_ref().unwrap(),
-            machine_path
-        )
-    } else {
-        format!("{}:{}",
-            &config.ip.as_ref().unwrap(),
-            machine_path
-        )
-    };
-    
+pub fn scp(config: &MachineConfig, source: &str, destination: &str, direction: ScpDirection) -> CommandSpec {
+    scp_command(config, &[], &[source], destination, direction)
+}
+
+/// Copy a file or a directory tree, quietly
+///
+/// # Arguments
+///
+/// * `config` - Machine configuration
+/// * `source` - Source path
+/// * `destination` - Destination path
+///
+pub fn scp_recursive(config: &MachineConfig, source: &str, destination: &str, direction: ScpDirection) -> CommandSpec {
+    scp_command(config, &["-r", "-q"], &[source], destination, direction)
+}
+
+/// Copy files with the given options, through `scp`, or `rsync` to copy
+/// symbolic links as links, which `scp` cannot do. Recursive copies with
+/// exclusions go through `rsync` too, or a `tar` pipe for pushes following
+/// links, which then extract into the destination directory.
+///
+/// # Arguments
+///
+/// * `config` - Machine configuration
+/// * `sources` - Source paths
+/// * `destination` - Destination path
+/// * `direction` - Copy direction
+/// * `options` - Copy options
+///
+pub fn copy<S: AsRef<str>>(config: &MachineConfig, sources: &[S], destination: &str, direction: ScpDirection, options: &CopyOptions) -> CommandSpec {
+    let sources: Vec<&str> = sources.iter().map(|x| x.as_ref()).collect();
+    let excluding = options.recursive && !options.excludes.is_empty();
+    match direction {
+        _ if !options.follow_symlinks => return rsync_command(config, &sources, destination, direction, options),
+        ScpDirection::Pull if excluding => return rsync_command(config, &sources, destination, direction, options),
+        ScpDirection::Push if excluding => return tar_push_command(config, &sources, destination, options),
+        _ => ()
+    }
+
+    let mut flags = vec![];
+    if options.recursive {
+        flags.push("-r");
+    }
+    if options.preserve {
+        flags.push("-p");
+    }
+    scp_command(config, &flags, &sources, destination, direction)
+}
+
+fn rsync_command(config: &MachineConfig, sources: &[&str], destination: &str, direction: ScpDirection, options: &CopyOptions) -> CommandSpec {
+    let mut ssh = vec!["ssh".to_string()];
+    ssh.extend(control_options(config, None));
+    ssh.extend(jump_options(config));
+    ssh.extend(agent_options(config));
+    ssh.extend(algorithm_options(config));
+    for identity in config.identities() {
+        ssh.extend(["-i".to_string(), identity.to_string()]);
+    }
+    ssh.extend(["-p".to_string(), config.first_port().to_string()]);
+
+    // Links are copied as links unless followed, `-p -t` being the mode and
+    // time part of `-a`
+    let mut flags = if options.follow_symlinks { "-L" } else { "-l" }.to_string();
+    if options.recursive {
+        flags.push('r');
+    }
+    if options.preserve {
+        flags.push_str("pt");
+    }
+
+    let mut command = CommandSpec::new("rsync");
+    command.machine = Some(config.clone());
+    set_agent(&mut command, config);
+    command.arg(flags);
+    command.args(["-e", &quote::join(&ssh)]);
+    command.args(options.excludes.iter().map(|x| format!("--exclude={}", x)));
+    copy_paths(&mut command, &host_path(config, None), sources, destination, direction);
+    command
+}
+
+fn tar_push_command(config: &MachineConfig, sources: &[&str], destination: &str, options: &CopyOptions) -> CommandSpec {
+    let mut tar = CommandSpec::new("tar");
+    tar.args(["-ch", "-f", "-"]);
+    tar.args(options.excludes.iter().map(|x| format!("--exclude={}", tar_pattern(x))));
+    for source in sources {
+        // Relative `-C` options add up, absolute ones do not
+        let path = fs::canonicalize(source).unwrap_or_else(|_| Path::new(source).to_path_buf());
+        let parent = path.parent().map(|x| x.to_string_lossy().into_owned()).filter(|x| !x.is_empty()).unwrap_or_else(|| ".".to_string());
+        let name = path.file_name().map(|x| x.to_string_lossy().into_owned()).unwrap_or_else(|| source.to_string());
+        tar.args(["-C".to_string(), parent, name]);
+    }
+
+    let extract = if options.preserve { "-xp" } else { "-xm" };
+    let remote = format!("mkdir -p {0} && tar {1} -f - -C {0}", quote::quote_path(destination), extract);
+    let mut command = ssh_remote(config, &remote);
+    command.input = Some(Box::new(tar));
+    command
+}
+
+fn scp_command(config: &MachineConfig, options: &[&str], sources: &[&str], destination: &str, direction: ScpDirection) -> CommandSpec {
+    let mut command = CommandSpec::new("scp");
+    command.machine = Some(config.clone());
+    command.args(options);
+    command.args(control_options(config, None));
+    command.args(jump_options(config));
+    command.args(agent_options(config));
+    command.args(algorithm_options(config));
+    set_agent(&mut command, config);
+
+    for identity in config.identities() {
+        command.args(["-i", identity]);
+    }
+
+    command.args(["-P", &config.first_port().to_string()]);
+    copy_paths(&mut command, &host_path(config, None), sources, destination, direction);
+    command
+}
+
+/// Add the source and destination arguments of a copy, machine paths being
+/// prefixed with the host
+fn copy_paths(command: &mut CommandSpec, host: &str, sources: &[&str], destination: &str, direction: ScpDirection) {
     match direction {
         ScpDirection::Push => {
-            command.arg(&source);
-            command.arg(&user_path);
+            command.args(sources);
+            command.arg(format!("{}:{}", host, destination));
         },
         ScpDirection::Pull => {
-            command.arg(&user_path);
-            command.arg(&destination);
+            for source in sources {
+                command.arg(format!("{}:{}", host, source));
+            }
+            command.arg(destination);
         }
     }
-    
-    debug!("Executing {}", format!("{:?}", command));
-    command
 }
 
 /// Execute an SSH connection
@@ -88,75 +424,407 @@ pub fn scp(config: &MachineConfig, source: &str, destination: &str, direction: S
 /// * `user` - Username
 /// * `tmux` - Use `tmux`
 ///
-pub fn ssh(config: &MachineConfig, user: Option<&str>, tmux: bool) -> Command {
-    let mut command = Command::new("ssh");
-    
-    if config.identity.is_some() {
-        command.args(&["-i", config.identity.as_ref().unwrap()]);
-    }
-    
-    if config.port.is_some() {
-        command.args(&["-p", &(config.port.as_ref().unwrap().to_string())]);
-    } else {
-        command.args(&["-p", "22"]);
-    }
-    
-    let user_name: Option<&str>;
-    if user.is_none() {
-        if !config.user.is_none() {
-            user_name = config.user.as_ref().map(|x| &x[..]);
-        } else {
-            user_name = None
-        }
-    } else {
-        user_name = user;
-    }
-    
-    let user_path = if user_name.is_some() {
-        format!("{}@{}",
-            user_name.unwrap(),
-            config.ip.as_ref().unwrap()
-        )
-    } else {
-        format!("{}",
-            config.ip.as_ref().unwrap()
-        )
-    };
-    
-    command.arg(&user_path);
-    
+pub fn ssh(config: &MachineConfig, user: Option<&str>, tmux: bool) -> CommandSpec {
+    let mut command = ssh_base(config, user, &[]);
+
     if tmux {
         command.arg("tmux attach || tmux new");
     }
-    
-    debug!("Executing {}", format!("{:?}", command));
+
+    command
+}
+
+/// Execute a restricted SSH connection, forcing the remote command
+///
+/// The command is the machine `restricted_command`, or a restricted login
+/// shell by default.
+///
+/// # Arguments
+///
+/// * `config` - Machine configuration
+/// * `user` - Username
+///
+pub fn ssh_restricted(config: &MachineConfig, user: Option<&str>) -> CommandSpec {
+    let mut command = ssh_base(config, user, &["-t"]);
+    command.arg(config.restricted_command.as_deref().unwrap_or(DEFAULT_RESTRICTED_COMMAND));
+
+    command
+}
+
+/// Execute an interactive command on a machine, with a pseudo-terminal
+///
+/// # Arguments
+///
+/// * `config` - Machine configuration
+/// * `remote_command` - Command to execute on the machine
+///
+pub fn ssh_tty(config: &MachineConfig, remote_command: &str) -> CommandSpec {
+    let mut command = ssh_base(config, None, &["-t"]);
+    command.arg(remote_command);
+
+    command
+}
+
+/// Execute a command on a machine through SSH
+///
+/// # Arguments
+///
+/// * `config` - Machine configuration
+/// * `remote_command` - Command to execute on the machine
+///
+pub fn ssh_remote(config: &MachineConfig, remote_command: &str) -> CommandSpec {
+    let mut command = ssh_base(config, None, &[]);
+    command.arg(remote_command);
+
+    command
+}
+
+/// Start a master connection in the background, listening on a control
+/// socket
+///
+/// # Arguments
+///
+/// * `config` - Machine configuration
+/// * `control_path` - Control socket path
+/// * `persist` - Time the connection stays open after its last session
+///
+pub fn ssh_master(config: &MachineConfig, control_path: &Path, persist: Duration) -> CommandSpec {
+    let persist = format!("ControlPersist={}", persist.as_secs());
+    let control_path = format!("ControlPath={}", control_path.display());
+
+    ssh_base(config, None, &["-M", "-N", "-f", "-o", &persist, "-o", &control_path])
+}
+
+/// Send a control command (`check`, `exit`) to the master connection of a
+/// machine
+///
+/// # Arguments
+///
+/// * `config` - Machine configuration
+/// * `control_command` - Control command
+///
+pub fn ssh_control(config: &MachineConfig, control_command: &str) -> CommandSpec {
+    ssh_base(config, None, &["-O", control_command])
+}
+
+/// Build a SSH command with identities, port and destination set
+///
+/// With several candidate ports, the first one accepting connections is
+/// used when the command is spawned, and likewise with several jump hosts.
+/// When a master connection is running for the machine, the command goes
+/// through it.
+///
+/// # Arguments
+///
+/// * `config` - Machine configuration
+/// * `user` - Username (overrides the configuration user)
+/// * `options` - Leading options, as `-t` to force a pseudo-terminal
+///
+fn ssh_base(config: &MachineConfig, user: Option<&str>, options: &[&str]) -> CommandSpec {
+    let mut command = CommandSpec::new("ssh");
+    command.machine = Some(config.clone());
+    command.args(options);
+    command.args(control_options(config, user));
+    command.args(jump_options(config));
+    command.args(agent_options(config));
+    command.args(algorithm_options(config));
+    command.args(locale_options(config));
+    set_agent(&mut command, config);
+
+    // Banners are printed at the `INFO` level
+    if config.is_quiet() {
+        command.args(["-o", "LogLevel=ERROR"]);
+    }
+
+    for identity in config.identities() {
+        command.args(["-i", identity]);
+    }
+
+    // `ssh` sends its own `TERM` with the pseudo-terminal request, which
+    // servers always accept
+    if let Some(ref term) = config.term {
+        command.env("TERM", term);
+    }
+
+    command.args(["-p", &config.first_port().to_string()]);
+    command.arg(host_path(config, user));
     command
 }
 
-/// Execute a command
+/// Attach to the out-of-band console of a machine, with `ipmitool` or the
+/// conserver `console` client
+///
+/// The IPMI password is passed through the environment, to keep it out of
+/// the process list.
+///
+/// # Arguments
+///
+/// * `console` - Console configuration
+///
+pub fn console(console: &ConsoleConfig) -> CommandSpec {
+    match *console {
+        ConsoleConfig::Ipmi { ref host, ref user, ref pass } => {
+            let mut command = CommandSpec::new("ipmitool");
+            command.args(["-I", "lanplus", "-H", host]);
+            if let Some(user) = user.as_ref() {
+                command.args(["-U", user]);
+            }
+            match pass.as_ref() {
+                Some(pass) => {
+                    command.arg("-E");
+                    command.env("IPMI_PASSWORD", pass);
+                },
+                None => {
+                    command.arg("-a");
+                }
+            }
+            command.args(["sol", "activate"]);
+            command
+        },
+        ConsoleConfig::Conserver { ref name, ref master } => {
+            let mut command = CommandSpec::new("console");
+            if let Some(master) = master.as_ref() {
+                command.args(["-M", master]);
+            }
+            command.arg(name);
+            command
+        }
+    }
+}
+
+/// Open a remote folder in VS Code, through its Remote-SSH extension
+///
+/// # Arguments
+///
+/// * `host` - Host alias, as known by the SSH client configuration
+/// * `path` - Remote folder, none for an empty window
+///
+pub fn vscode(host: &str, path: Option<&str>) -> CommandSpec {
+    let mut command = CommandSpec::new("code");
+    command.args(["--remote", &format!("ssh-remote+{}", host)]);
+    if let Some(path) = path {
+        command.arg(path);
+    }
+
+    command
+}
+
+/// Build the `-o` options selecting the machine algorithms
+///
+/// Explicit algorithms come before the compatibility preset, and win as
+/// `ssh` keeps the first value of an option.
+///
+/// # Arguments
+///
+/// * `config` - Machine configuration
+///
+pub fn algorithm_options(config: &MachineConfig) -> Vec<String> {
+    let algorithms = [
+        ("Ciphers", &config.ciphers),
+        ("MACs", &config.macs),
+        ("KexAlgorithms", &config.kex),
+        ("HostKeyAlgorithms", &config.host_key_algorithms)
+    ];
+
+    let mut options = vec![];
+    for &(option, value) in &algorithms {
+        if let Some(ref value) = *value {
+            options.push("-o".to_string());
+            options.push(format!("{}={}", option, value));
+        }
+    }
+
+    if let Some(preset) = config.compat.as_ref().and_then(|x| CompatPreset::from_name(x)) {
+        options.extend(preset.options().iter().map(|x| x.to_string()));
+    }
+    options
+}
+
+/// Build the `-o ControlPath=` option going through the running master
+/// connection of a machine, if any
+///
+/// # Arguments
+///
+/// * `config` - Machine configuration
+/// * `user` - Username (overrides the configuration user)
+///
+fn control_options(config: &MachineConfig, user: Option<&str>) -> Vec<String> {
+    match active_control_path(config, user) {
+        Some(path) => vec!["-o".to_string(), format!("ControlPath={}", path.display())],
+        None => vec![]
+    }
+}
+
+/// Build the `-o ProxyCommand=` option of a machine, or the
+/// `-o ProxyJump=` option going through its first jump host, if any
+///
+/// # Arguments
+///
+/// * `config` - Machine configuration
+///
+fn jump_options(config: &MachineConfig) -> Vec<String> {
+    if let Some(ref proxy_command) = config.proxy_command {
+        return vec!["-o".to_string(), format!("ProxyCommand={}", proxy_command)];
+    }
+
+    match config.jump_hosts().first() {
+        Some(jump) => vec!["-o".to_string(), format!("ProxyJump={}", jump)],
+        None => vec![]
+    }
+}
+
+/// Build the `-o IdentityAgent=` option selecting the SSH agent of a
+/// machine, if any
+///
+/// # Arguments
+///
+/// * `config` - Machine configuration
+///
+fn agent_options(config: &MachineConfig) -> Vec<String> {
+    match config.agent_socket_path() {
+        Some(path) => vec!["-o".to_string(), format!("IdentityAgent={}", path.display())],
+        None => vec![]
+    }
+}
+
+/// Set `SSH_AUTH_SOCK` to the SSH agent of a machine, if any, for the
+/// processes `ssh` spawns itself, as `ProxyJump` connections
+///
+/// # Arguments
+///
+/// * `command` - Command
+/// * `config` - Machine configuration
+///
+fn set_agent(command: &mut CommandSpec, config: &MachineConfig) {
+    if let Some(path) = config.agent_socket_path() {
+        command.env("SSH_AUTH_SOCK", &path.to_string_lossy());
+    }
+}
+
+/// Build the `-o SetEnv=` option setting the machine locale
+///
+/// The server must accept the variable (`AcceptEnv LC_*`, the default on
+/// most distributions).
+///
+/// # Arguments
+///
+/// * `config` - Machine configuration
+///
+pub fn locale_options(config: &MachineConfig) -> Vec<String> {
+    match config.locale {
+        Some(ref locale) => vec!["-o".to_string(), format!("SetEnv=LC_ALL={}", locale)],
+        None => vec![]
+    }
+}
+
+/// Build the `user@ip` part of a machine address
+///
+/// # Arguments
+///
+/// * `config` - Machine configuration
+/// * `user` - Username (overrides the configuration user)
+///
+fn host_path(config: &MachineConfig, user: Option<&str>) -> String {
+    let ip = config.ip.as_ref().unwrap();
+
+    match user.or(config.user.as_deref()) {
+        Some(user_name) => format!("{}@{}", user_name, ip),
+        None => ip.to_string()
+    }
+}
+
+/// Execute a command without input, capturing its status and outputs
 ///
 /// # Arguments
 ///
 /// * `command` - Command to execute
-/// * `error_message` - Error message
-/// 
-pub fn execute(mut command: Command, error_message: &str) {
-    let mut child = command.spawn().expect(error_message);
-    child.wait().expect("Failed to wait on child");
+///
+pub fn execute_capture<C: Into<Command>>(command: C) -> Result<Output, Error> {
+    let mut command = command.into();
+    let program = command.get_program().to_string_lossy().into_owned();
+    command.stdin(Stdio::null())
+        .output()
+        .map_err(|e| Error::io(format!("failed to execute {}", program), e))
+}
+
+/// Execute an interactive command, keeping the terminal, waiting for it to
+/// exit
+///
+/// A command with an input command is run as a pipe, see `execute_pipe`.
+///
+/// # Arguments
+///
+/// * `command` - Command to execute
+///
+pub fn execute(mut command: CommandSpec) -> Result<ExitStatus, Error> {
+    match command.input.take() {
+        Some(input) => execute_pipe(*input, command),
+        None => execute_command(command.interactive_command())
+    }
+}
+
+/// Execute two commands, the output of the first one piped into the second
+/// one.
+///
+/// Returns the status of the first command when it failed, else the status
+/// of the second one.
+///
+/// # Arguments
+///
+/// * `source` - Command writing the data
+/// * `sink` - Command reading the data
+///
+pub fn execute_pipe(source: CommandSpec, sink: CommandSpec) -> Result<ExitStatus, Error> {
+    let mut source_child = source.interactive_command().stdin(Stdio::null()).stdout(Stdio::piped()).spawn()
+        .map_err(|e| Error::io(format!("failed to execute {}", source.program), e))?;
+    let output = source_child.stdout.take().map(Stdio::from).unwrap_or_else(Stdio::null);
+
+    let sink_status = sink.interactive_command().stdin(output).status();
+    let source_status = source_child.wait().map_err(|e| Error::io(format!("failed to execute {}", source.program), e))?;
+    let sink_status = sink_status.map_err(|e| Error::io(format!("failed to execute {}", sink.program), e))?;
+
+    Ok(if source_status.success() { sink_status } else { source_status })
+}
+
+/// Execute a local command, keeping the terminal, waiting for it to exit
+///
+/// # Arguments
+///
+/// * `command` - Command to execute
+///
+pub fn execute_command(mut command: Command) -> Result<ExitStatus, Error> {
+    let program = command.get_program().to_string_lossy().into_owned();
+    let mut child = command.spawn().map_err(|e| Error::io(format!("failed to execute {}", program), e))?;
+    child.wait().map_err(|e| Error::io(format!("failed to wait on {}", program), e))
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
 
-    fn format_command(command: &Command) -> String {
-        let debug_cmd = format!("{:?}", command);
-        debug_cmd.split(" ").map(|s| {
-            let length = s.len();
-            s.chars().skip(1).take(length - 2).collect::<String>()
-        })
-        .collect::<Vec<_>>()
-        .join(" ")
+    use std::env;
+    use std::fs;
+    use std::net::TcpListener;
+    use std::path::Path;
+
+    use quote::join;
+    use proptest::prelude::*;
+
+    /// Golden file of the command lines built for a matrix of machines
+    const GOLDEN_COMMANDS: &str = "testdata/commands.golden";
+
+    fn format_command(command: &CommandSpec) -> String {
+        command.to_string()
+    }
+
+    /// Render a command line with shell quoting, so that argument boundaries
+    /// show in golden files.
+    fn golden_line(name: &str, command: &CommandSpec) -> String {
+        let mut line = format!("{}: {} {}", name, command.program, join(&command.args));
+        for (key, _) in &command.envs {
+            line.push_str(&format!(" [env {}]", key));
+        }
+        line
     }
 
     #[test]
@@ -171,11 +839,255 @@ mod test {
 
         let command = scp(&config, "/toto", "./tutu", ScpDirection::Pull);        
         assert_eq!(format_command(&command), "scp -P 22 localhost:/toto ./tutu");
+        let command = scp_recursive(&config, "/toto", "./tutu", ScpDirection::Pull);
+        assert_eq!(format_command(&command), "scp -r -q -P 22 localhost:/toto ./tutu");
+        let command = copy(&config, &["a.js", "b.js"], "/var/www/", ScpDirection::Push, &CopyOptions::default());
+        assert_eq!(format_command(&command), "scp -P 22 a.js b.js localhost:/var/www/");
+        let command = copy(&config, &["/var/log/*.log", "/tmp/x"], "logs", ScpDirection::Pull, &CopyOptions::default());
+        assert_eq!(format_command(&command), "scp -P 22 localhost:/var/log/*.log localhost:/tmp/x logs");
+        let options = CopyOptions { recursive: true, preserve: true, ..Default::default() };
+        let command = copy(&config, &["dist"], "/srv/", ScpDirection::Push, &options);
+        assert_eq!(format_command(&command), "scp -r -p -P 22 dist localhost:/srv/");
+        let command = copy(&config, &["/srv/app"], ".", ScpDirection::Pull, &CopyOptions { follow_symlinks: false, ..options.clone() });
+        assert_eq!(join(&command.args), "-lrpt -e 'ssh -p 22' localhost:/srv/app .");
+
+        let options = CopyOptions { excludes: vec![".git/".to_string(), "*.pem".to_string()], ..options };
+        let command = copy(&config, &["/srv/app"], ".", ScpDirection::Pull, &options);
+        assert_eq!(join(&command.args), "-Lrpt -e 'ssh -p 22' --exclude=.git/ '--exclude=*.pem' localhost:/srv/app .");
+        let command = copy(&config, &["/nonexistent/app"], "/srv", ScpDirection::Push, &options);
+        assert_eq!(join(&command.input.as_ref().unwrap().args), "-ch -f - --exclude=.git '--exclude=*.pem' -C /nonexistent app");
+        assert_eq!(join(&command.args), "-p 22 localhost 'mkdir -p /srv && tar -xp -f - -C /srv'");
+        assert!(command.to_string().starts_with("tar -ch -f - --exclude=.git --exclude=*.pem -C /nonexistent app | ssh -p 22 localhost"));
+
+        // A failing archive fails the copy, whatever the extraction does
+        let mut failing = CommandSpec::new("true");
+        failing.input = Some(Box::new(CommandSpec::new("false")));
+        assert!(!execute(failing).unwrap().success());
 
         let command = ssh(&config, None, false);
         assert_eq!(format_command(&command), "ssh -p 22 localhost");
 
         let command = ssh(&config, Some("toto"), false);
         assert_eq!(format_command(&command), "ssh -p 22 toto@localhost");
+
+        let command = ssh_restricted(&config, None);
+        assert_eq!(command.args.last().unwrap(), DEFAULT_RESTRICTED_COMMAND);
+
+        let config = MachineConfig {
+            restricted_command: Some("htop".to_string()),
+            .. config
+        };
+        let command = ssh_restricted(&config, None);
+        assert_eq!(format_command(&command), "ssh -t -p 22 localhost htop");
+
+        let command = ssh_tty(&config, TOP_COMMAND);
+        assert_eq!(command.args[0], "-t");
+        assert_eq!(command.args.last().unwrap(), TOP_COMMAND);
+
+        let config = MachineConfig {
+            ip: Some("localhost".to_string()),
+            port: Some(vec![2222]),
+            identity: Some(vec!["~/.ssh/a".to_string(), "~/.ssh/b".to_string()]),
+            .. Default::default()
+        };
+        let command = ssh(&config, None, false);
+        assert_eq!(format_command(&command), "ssh -i ~/.ssh/a -i ~/.ssh/b -p 2222 localhost");
+        let command = scp(&config, "/toto", "./tutu", ScpDirection::Push);
+        assert_eq!(format_command(&command), "scp -i ~/.ssh/a -i ~/.ssh/b -P 2222 /toto localhost:./tutu");
+
+        let config = MachineConfig {
+            ip: Some("localhost".to_string()),
+            ciphers: Some("aes256-gcm@openssh.com,chacha20-poly1305@openssh.com".to_string()),
+            kex: Some("+diffie-hellman-group14-sha1".to_string()),
+            .. Default::default()
+        };
+        let command = ssh(&config, None, false);
+        assert_eq!(format_command(&command),
+            "ssh -o Ciphers=aes256-gcm@openssh.com,chacha20-poly1305@openssh.com -o KexAlgorithms=+diffie-hellman-group14-sha1 -p 22 localhost");
+        let command = scp(&config, "/toto", "./tutu", ScpDirection::Pull);
+        assert_eq!(format_command(&command),
+            "scp -o Ciphers=aes256-gcm@openssh.com,chacha20-poly1305@openssh.com -o KexAlgorithms=+diffie-hellman-group14-sha1 -P 22 localhost:/toto ./tutu");
+
+        let config = MachineConfig {
+            ip: Some("localhost".to_string()),
+            kex: Some("diffie-hellman-group1-sha1".to_string()),
+            compat: Some("legacy".to_string()),
+            .. Default::default()
+        };
+        let args = ssh(&config, None, false).args;
+        assert_eq!(&args[..2], ["-o", "KexAlgorithms=diffie-hellman-group1-sha1"]);
+        assert!(args.contains(&"HostKeyAlgorithms=+ssh-rsa,ssh-dss".to_string()));
+        assert_eq!(args.len(), 2 + CompatPreset::Legacy.options().len() + 3);
+        assert_eq!(CompatPreset::from_name("legacy").map(|x| x.name()), Some("legacy"));
+
+        let command = console(&ConsoleConfig::Ipmi {
+            host: "10.0.9.1".to_string(),
+            user: Some("ADMIN".to_string()),
+            pass: Some("secret".to_string())
+        });
+        assert_eq!(format_command(&command), "ipmitool -I lanplus -H 10.0.9.1 -U ADMIN -E sol activate");
+        assert_eq!(command.envs, vec![("IPMI_PASSWORD".to_string(), "secret".to_string())]);
+        assert!(command.command().get_envs().any(|(key, value)| key == "IPMI_PASSWORD" && value.unwrap() == "secret"));
+
+        let command = console(&ConsoleConfig::Conserver { name: "web1".to_string(), master: Some("cons".to_string()) });
+        assert_eq!(format_command(&command), "console -M cons web1");
+
+        let mut command = Command::new("sh");
+        command.args(["-c", "echo out; echo err >&2; exit 3"]);
+        let output = execute_capture(command).unwrap();
+        assert_eq!((output.status.code(), &output.stdout[..], &output.stderr[..]), (Some(3), &b"out\n"[..], &b"err\n"[..]));
+        assert!(execute_capture(CommandSpec::new("pssh-missing-program")).unwrap_err().to_string().starts_with("failed to execute pssh-missing-program"));
+
+        let command = vscode("web.w1", Some("/srv/app"));
+        assert_eq!(format_command(&command), "code --remote ssh-remote+web.w1 /srv/app");
+        assert_eq!(vscode("web.w1", None).args.len(), 2);
+
+        let config = MachineConfig {
+            ip: Some("localhost".to_string()),
+            term: Some("xterm".to_string()),
+            locale: Some("C.UTF-8".to_string()),
+            .. Default::default()
+        };
+        let command = ssh(&config, None, false);
+        assert_eq!(format_command(&command), "ssh -o SetEnv=LC_ALL=C.UTF-8 -p 22 localhost");
+        assert_eq!(command.envs, vec![("TERM".to_string(), "xterm".to_string())]);
+
+        let config = MachineConfig { quiet: Some(true), ..config };
+        assert_eq!(format_command(&ssh(&config, None, false)), "ssh -o SetEnv=LC_ALL=C.UTF-8 -o LogLevel=ERROR -p 22 localhost");
+
+        let config = MachineConfig { via: Some(vec!["admin@bastion".to_string()]), locale: None, quiet: None, ..config };
+        assert_eq!(format_command(&ssh(&config, None, false)), "ssh -o ProxyJump=admin@bastion -p 22 localhost");
+        assert_eq!(format_command(&scp(&config, "a", "b", ScpDirection::Push)), "scp -o ProxyJump=admin@bastion -P 22 a localhost:b");
+        let proxied = MachineConfig { proxy_command: Some("cloudflared access ssh --hostname %h".to_string()), ..config.clone() };
+        assert_eq!(format_command(&ssh(&proxied, None, false)), "ssh -o ProxyCommand=cloudflared access ssh --hostname %h -p 22 localhost");
+
+        let config = MachineConfig { via: None, term: None, agent_socket: Some("/run/agent.sock".to_string()), ..config };
+        let command = ssh_remote(&config, "ls");
+        assert_eq!(format_command(&command), "ssh -o IdentityAgent=/run/agent.sock -p 22 localhost ls");
+        assert_eq!(command.envs, vec![("SSH_AUTH_SOCK".to_string(), "/run/agent.sock".to_string())]);
+    }
+
+    #[test]
+    fn golden_commands() {
+        let base = MachineConfig {
+            ip: Some("10.0.0.1".to_string()),
+            ..Default::default()
+        };
+        let full = MachineConfig {
+            ip: Some("fd00::1".to_string()),
+            user: Some("deploy".to_string()),
+            port: Some(vec![2222]),
+            identity: Some(vec!["~/.ssh/id ed25519".to_string()]),
+            ciphers: Some("aes256-ctr".to_string()),
+            macs: Some("hmac-sha2-256".to_string()),
+            kex: Some("curve25519-sha256".to_string()),
+            host_key_algorithms: Some("ssh-ed25519".to_string()),
+            restricted_command: Some("rbash --noprofile".to_string()),
+            term: Some("vt100".to_string()),
+            locale: Some("en_US.UTF-8".to_string()),
+            ..Default::default()
+        };
+        let legacy = MachineConfig {
+            compat: Some("legacy".to_string()),
+            ..base.clone()
+        };
+
+        let mut lines = vec![];
+        for &(name, config) in &[("base", &base), ("full", &full), ("legacy", &legacy)] {
+            lines.push(golden_line(&format!("{} ssh", name), &ssh(config, None, false)));
+            lines.push(golden_line(&format!("{} ssh tmux", name), &ssh(config, Some("root"), true)));
+            lines.push(golden_line(&format!("{} ssh restricted", name), &ssh_restricted(config, None)));
+            lines.push(golden_line(&format!("{} ssh tty", name), &ssh_tty(config, TOP_COMMAND)));
+            lines.push(golden_line(&format!("{} ssh remote", name), &ssh_remote(config, "echo 'a b' \"$HOME\"")));
+            lines.push(golden_line(&format!("{} scp push", name), &scp(config, "my file", "/srv/my file", ScpDirection::Push)));
+            lines.push(golden_line(&format!("{} scp pull", name), &scp_recursive(config, "/var/log", ".", ScpDirection::Pull)));
+        }
+        lines.push(golden_line("console ipmi", &console(&ConsoleConfig::Ipmi {
+            host: "10.0.9.1".to_string(),
+            user: None,
+            pass: Some("secret".to_string())
+        })));
+        lines.push(golden_line("vscode", &vscode("web.w1", Some("/srv/my app"))));
+        let rendered = lines.join("\n") + "\n";
+
+        // `PSSH_UPDATE_GOLDEN=1 cargo test` rewrites the golden file
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join(GOLDEN_COMMANDS);
+        if env::var_os("PSSH_UPDATE_GOLDEN").is_some() {
+            fs::write(&path, &rendered).unwrap();
+        }
+        assert_eq!(rendered, fs::read_to_string(&path).unwrap());
+    }
+
+    #[test]
+    fn spawn_time_ports() {
+        let closed = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let open = listener.local_addr().unwrap().port();
+        let config = MachineConfig {
+            ip: Some("127.0.0.1".to_string()),
+            port: Some(vec![closed, open]),
+            ..Default::default()
+        };
+
+        // Building commands keeps the configured port, spawning them probes
+        let command = ssh_remote(&config, "ls");
+        assert_eq!(format_command(&command), format!("ssh -p {} 127.0.0.1 ls", closed));
+        assert_eq!(select_connection(&command.args, &config), ["-p", &open.to_string(), "127.0.0.1", "ls"]);
+        let command = scp(&config, "a", "b", ScpDirection::Push);
+        assert!(select_connection(&command.args, &config).windows(2).any(|x| x == ["-P", &open.to_string()]));
+        let command = copy(&config, &["a"], "b", ScpDirection::Pull, &CopyOptions { follow_symlinks: false, ..Default::default() });
+        assert_eq!(select_connection(&command.args, &config)[2], format!("ssh -p {}", open));
+
+        let proxied = MachineConfig { proxy_command: Some("nc %h %p".to_string()), ..config.clone() };
+        assert_eq!(select_connection(&ssh_remote(&proxied, "ls").args, &proxied)[3], closed.to_string());
+    }
+
+    proptest! {
+        // Any combination of options keeps the same layout: options, then
+        // the port, then the destination, then the remote command as a
+        // single argument
+        #[test]
+        fn option_ordering(
+            user in proptest::option::of("[a-z_][a-z0-9_-]{0,8}"),
+            identity in proptest::collection::vec("~?[a-zA-Z0-9 ._/-]{1,20}", 0..4),
+            ciphers in proptest::option::of(prop_oneof!["aes256-ctr", "aes128-gcm@openssh.com,chacha20-poly1305@openssh.com"]),
+            compat in proptest::option::of(Just("legacy")),
+            port in 1u16..,
+            ip in "[a-z0-9][a-z0-9.-]{0,20}",
+            remote_command in ".*",
+            source in "[^:]+",
+            destination_path in ".+",
+        ) {
+            let config = MachineConfig {
+                ip: Some(ip),
+                port: Some(vec![port]),
+                user,
+                identity: Some(identity.clone()),
+                ciphers: ciphers.map(|x| x.to_string()),
+                compat: compat.map(|x| x.to_string()),
+                ..Default::default()
+            };
+            let options = algorithm_options(&config);
+            let destination = host_path(&config, None);
+
+            for tty in [false, true] {
+                let command = if tty { ssh_tty(&config, &remote_command) } else { ssh_remote(&config, &remote_command) };
+                let mut expected = vec![];
+                if tty {
+                    expected.push("-t".to_string());
+                }
+                expected.extend(options.iter().cloned());
+                for path in &identity {
+                    expected.extend(vec!["-i".to_string(), path.clone()]);
+                }
+                expected.extend(vec!["-p".to_string(), port.to_string(), destination.clone(), remote_command.clone()]);
+                prop_assert_eq!(&command.args, &expected);
+            }
+
+            let command = scp(&config, &source, &destination_path, ScpDirection::Push);
+            let length = command.args.len();
+            prop_assert_eq!(&command.args[..options.len()], &options[..]);
+            prop_assert_eq!(&command.args[length - 4..], &["-P".to_string(), port.to_string(), source.clone(), format!("{}:{}", destination, destination_path)][..]);
+        }
     }
 }
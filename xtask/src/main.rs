@@ -0,0 +1,33 @@
+//! Build tasks, run with `cargo xtask TASK`
+//!
+//! * `man [DIR]` - write the man pages of pssh, generated from its command
+//!   line definition, to `DIR` (`target/man` by default)
+
+extern crate pssh_rs;
+
+use std::env;
+use std::path::Path;
+use std::process;
+
+use pssh_rs::help::write_man_pages;
+use pssh_rs::shell::{build_cli, VERSION};
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    match args.first().map(|x| x.as_str()) {
+        Some("man") => {
+            let dir = args.get(1).map(|x| x.as_str()).unwrap_or("target/man");
+            match write_man_pages(&build_cli(), VERSION, Path::new(dir)) {
+                Ok(pages) => println!("Wrote {} man pages to {}.", pages.len(), dir),
+                Err(error) => {
+                    eprintln!("{}", error);
+                    process::exit(1);
+                }
+            }
+        },
+        _ => {
+            eprintln!("Usage: cargo xtask man [DIR]");
+            process::exit(2);
+        }
+    }
+}